@@ -0,0 +1,104 @@
+/// The replication backlog: the most recent bytes of the replication stream
+/// in a fixed-size circular buffer, so a replica that lost its link can
+/// continue from its offset instead of resyncing everything.
+#[derive(Debug, Clone)]
+pub struct ReplicationBacklog {
+    buffer: Vec<u8>,
+    /// Index the next byte is written at.
+    write_index: usize,
+    /// Number of bytes of history in the buffer.
+    histlen: usize,
+    /// Replication offset of the oldest byte in the buffer.
+    first_byte_offset: i64,
+}
+
+impl ReplicationBacklog {
+    /// Creates an empty backlog of `size` bytes whose first byte will have
+    /// the replication offset `offset`.
+    pub fn new(size: usize, offset: i64) -> ReplicationBacklog {
+        ReplicationBacklog {
+            buffer: vec![0; size.max(1)],
+            write_index: 0,
+            histlen: 0,
+            first_byte_offset: offset,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn histlen(&self) -> usize {
+        self.histlen
+    }
+
+    pub fn first_byte_offset(&self) -> i64 {
+        self.first_byte_offset
+    }
+
+    /// Appends bytes of the replication stream, overwriting the oldest ones
+    /// once the buffer is full.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let size = self.size();
+        let next_offset = self.first_byte_offset + self.histlen as i64 + bytes.len() as i64;
+        // Only the last `size` bytes can survive.
+        let bytes = &bytes[bytes.len().saturating_sub(size)..];
+        let mut written = 0;
+        while written < bytes.len() {
+            let count = (size - self.write_index).min(bytes.len() - written);
+            self.buffer[self.write_index..self.write_index + count]
+                .copy_from_slice(&bytes[written..written + count]);
+            self.write_index = (self.write_index + count) % size;
+            written += count;
+        }
+        self.histlen = (self.histlen + bytes.len()).min(size);
+        self.first_byte_offset = next_offset - self.histlen as i64;
+    }
+
+    /// The history from replication offset `offset` on, or `None` if part of
+    /// it was already overwritten or it hasn't been written yet.
+    pub fn since(&self, offset: i64) -> Option<Vec<u8>> {
+        let skip = usize::try_from(offset - self.first_byte_offset).ok()?;
+        if skip > self.histlen {
+            return None;
+        }
+        let size = self.size();
+        let oldest = (self.write_index + size - self.histlen) % size;
+        let start = (oldest + skip) % size;
+        let count = self.histlen - skip;
+        let mut bytes = Vec::with_capacity(count);
+        if start + count <= size {
+            bytes.extend_from_slice(&self.buffer[start..start + count]);
+        } else {
+            bytes.extend_from_slice(&self.buffer[start..]);
+            bytes.extend_from_slice(&self.buffer[..start + count - size]);
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backlog::ReplicationBacklog;
+
+    #[test]
+    fn test_backlog_wraps_around() {
+        let mut backlog = ReplicationBacklog::new(8, 1);
+        backlog.feed(b"abcde");
+        assert_eq!(Some(b"abcde".to_vec()), backlog.since(1));
+        assert_eq!(Some(b"de".to_vec()), backlog.since(4));
+        assert_eq!(Some(Vec::new()), backlog.since(6));
+        assert_eq!(None, backlog.since(7));
+
+        backlog.feed(b"fghij");
+        assert_eq!(8, backlog.histlen());
+        assert_eq!(3, backlog.first_byte_offset());
+        assert_eq!(Some(b"cdefghij".to_vec()), backlog.since(3));
+        assert_eq!(Some(b"hij".to_vec()), backlog.since(8));
+        assert_eq!(None, backlog.since(2));
+
+        backlog.feed(b"0123456789");
+        assert_eq!(13, backlog.first_byte_offset());
+        assert_eq!(Some(b"23456789".to_vec()), backlog.since(13));
+    }
+}
@@ -0,0 +1,83 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use chrono::Utc;
+use clap::Parser;
+
+use redis_starter_rust::data_core::{inspect_rdb, RdbKey};
+
+/// Prints the keys of an RDB file with their types, sizes and TTLs, or only
+/// verifies its checksum.
+#[derive(clap::Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// The RDB file, or an AOF file starting with an RDB preamble.
+    file: PathBuf,
+
+    /// Only verify the file and its checksum.
+    #[arg(long)]
+    check: bool,
+}
+
+fn describe_ttl(key: &RdbKey, now_ms: i64) -> String {
+    match key.expiry_ms {
+        None => "none".to_string(),
+        Some(expiry_ms) if expiry_ms <= now_ms => "expired".to_string(),
+        Some(expiry_ms) => format!("{}ms", expiry_ms - now_ms),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let bytes = match fs::read(&args.file) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("cannot read {}: {}", args.file.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let summary = match inspect_rdb(&bytes) {
+        Ok(summary) => summary,
+        Err(err) => {
+            eprintln!("{}: {}", args.file.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if !args.check {
+        for (name, value) in &summary.aux {
+            println!("aux {}={}", name, value);
+        }
+        let now_ms = Utc::now().timestamp_millis();
+        for key in &summary.keys {
+            println!(
+                "{:?} type={} size={} ttl={}",
+                key.key,
+                key.value_type,
+                key.size,
+                describe_ttl(key, now_ms)
+            );
+        }
+    }
+
+    let checksum = if summary.checksum == 0 {
+        "disabled".to_string()
+    } else {
+        format!("{:#018x} ok", summary.checksum)
+    };
+    println!(
+        "{} keys, {} bytes, checksum {}",
+        summary.keys.len(),
+        summary.length,
+        checksum
+    );
+    if bytes.len() > summary.length {
+        println!(
+            "followed by {} bytes of AOF commands",
+            bytes.len() - summary.length
+        );
+    }
+    ExitCode::SUCCESS
+}
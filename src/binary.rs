@@ -0,0 +1,199 @@
+//! Redis strings are arbitrary bytes. Command arguments, keys, members and
+//! bulk replies are carried as [`ByteString`]s from the parser to storage
+//! and back to the serializer, so NUL bytes and invalid UTF-8 survive:
+//!
+//! ```
+//! use redis_starter_rust::binary::ByteString;
+//!
+//! let value = ByteString::from(&b"nul \x00 invalid \xff"[..]);
+//! assert_eq!(b"nul \x00 invalid \xff", value.as_bytes());
+//! assert!(value.to_str().is_none());
+//! ```
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// An owned Redis string, with the text helpers commands need to read
+/// keywords and numbers out of their arguments. Strings compare and sort by
+/// their bytes, like Redis compares them.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteString(Vec<u8>);
+
+/// An argument that doesn't spell a value of the expected type, including
+/// arguments that aren't valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("cannot parse the argument")]
+pub struct ParseError;
+
+impl ByteString {
+    pub fn new(bytes: Vec<u8>) -> ByteString {
+        ByteString(bytes)
+    }
+
+    pub fn as_bytes(self: &ByteString) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self: ByteString) -> Vec<u8> {
+        self.0
+    }
+
+    /// The string as text, if it is valid UTF-8.
+    pub fn to_str(self: &ByteString) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// The string lowercased, for matching keywords and command names. Bytes
+    /// that aren't UTF-8 are replaced, as no keyword contains them.
+    pub fn to_lowercase(self: &ByteString) -> String {
+        String::from_utf8_lossy(&self.0).to_lowercase()
+    }
+
+    pub fn eq_ignore_ascii_case(self: &ByteString, keyword: &str) -> bool {
+        self.0.eq_ignore_ascii_case(keyword.as_bytes())
+    }
+
+    /// Parses a number or another value spelled out in the string.
+    pub fn parse<T: FromStr>(self: &ByteString) -> Result<T, ParseError> {
+        self.to_str()
+            .ok_or(ParseError)?
+            .parse()
+            .map_err(|_| ParseError)
+    }
+}
+
+impl Deref for ByteString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for ByteString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for ByteString {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for ByteString {
+    fn from(bytes: Vec<u8>) -> ByteString {
+        ByteString(bytes)
+    }
+}
+
+impl From<&[u8]> for ByteString {
+    fn from(bytes: &[u8]) -> ByteString {
+        ByteString(bytes.to_vec())
+    }
+}
+
+impl From<String> for ByteString {
+    fn from(string: String) -> ByteString {
+        ByteString(string.into_bytes())
+    }
+}
+
+impl From<&str> for ByteString {
+    fn from(string: &str) -> ByteString {
+        ByteString(string.as_bytes().to_vec())
+    }
+}
+
+impl PartialEq<[u8]> for ByteString {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&[u8]> for ByteString {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<str> for ByteString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for ByteString {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<ByteString> for str {
+    fn eq(&self, other: &ByteString) -> bool {
+        self.as_bytes() == other.0
+    }
+}
+
+impl PartialEq<ByteString> for &str {
+    fn eq(&self, other: &ByteString) -> bool {
+        self.as_bytes() == other.0
+    }
+}
+
+/// Shows the string as text, replacing the bytes that aren't UTF-8, for
+/// error messages and logs.
+impl fmt::Display for ByteString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for ByteString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{ByteString, ParseError};
+
+    #[test]
+    fn test_keeps_any_bytes() {
+        let all = (0..=255).collect::<Vec<u8>>();
+        let string = ByteString::from(all.clone());
+        assert_eq!(all, string.as_bytes());
+        assert_eq!(None, string.to_str());
+        assert_eq!(
+            "\"nul\\x00 \\xff\"",
+            format!("{:?}", ByteString::from(&b"nul\x00 \xff"[..]))
+        );
+        assert_eq!("caf\u{e9}", ByteString::from("caf\u{e9}").to_str().unwrap());
+    }
+
+    #[test]
+    fn test_compares_by_bytes() {
+        let mut strings = [&b"\x80"[..], "\u{e9}".as_bytes(), b"a"].map(ByteString::from);
+        strings.sort();
+        assert_eq!(
+            [&b"a"[..], b"\x80", "\u{e9}".as_bytes()],
+            strings.each_ref().map(|string| string.as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_reads_keywords_and_numbers() {
+        assert!(ByteString::from("WithScores").eq_ignore_ascii_case("withscores"));
+        assert_eq!("withscores", ByteString::from("WithScores").to_lowercase());
+        assert_eq!(Ok(-42), ByteString::from("-42").parse::<i64>());
+        assert_eq!(Err(ParseError), ByteString::from("4x").parse::<i64>());
+        assert_eq!(
+            Err(ParseError),
+            ByteString::from(&b"4\xff"[..]).parse::<i64>()
+        );
+    }
+}
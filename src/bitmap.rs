@@ -0,0 +1,274 @@
+/// Largest bit offset accepted by the bit commands, matching Redis' 512MB
+/// string size limit.
+pub const MAX_BIT_OFFSET: u64 = (512 * 1024 * 1024 * 8) - 1;
+
+/// Reads the bit at `offset`, where bit 0 is the most significant bit of the
+/// first byte. Bits past the end of `bytes` read as zero.
+pub fn get_bit(bytes: &[u8], offset: u64) -> bool {
+    let byte = (offset / 8) as usize;
+    let mask = 0x80 >> (offset % 8);
+    bytes.get(byte).is_some_and(|byte| byte & mask != 0)
+}
+
+/// Writes the bit at `offset`, zero-extending `bytes` as needed, and returns
+/// the previous value of the bit.
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, value: bool) -> bool {
+    let byte = (offset / 8) as usize;
+    let mask = 0x80 >> (offset % 8);
+    if bytes.len() <= byte {
+        bytes.resize(byte + 1, 0);
+    }
+    let previous = bytes[byte] & mask != 0;
+    match value {
+        true => bytes[byte] |= mask,
+        false => bytes[byte] &= !mask,
+    }
+    previous
+}
+
+/// Counts set bits eight bytes at a time.
+pub fn count_ones(bytes: &[u8]) -> u64 {
+    let mut words = bytes.chunks_exact(8);
+    let mut count = words
+        .by_ref()
+        .map(|word| u64::from_ne_bytes(word.try_into().unwrap()).count_ones() as u64)
+        .sum::<u64>();
+    count += words
+        .remainder()
+        .iter()
+        .map(|byte| byte.count_ones() as u64)
+        .sum::<u64>();
+    count
+}
+
+/// Mask selecting bits `first..=last` (0 is the most significant) of a byte.
+fn byte_mask(first: u64, last: u64) -> u8 {
+    (0xffu8 >> first) & (0xffu8 << (7 - last))
+}
+
+/// Counts set bits between the bit offsets `start` and `end`, both inclusive
+/// and within `bytes`.
+pub fn count_bits(bytes: &[u8], start: u64, end: u64) -> u64 {
+    let (first_byte, last_byte) = ((start / 8) as usize, (end / 8) as usize);
+    if first_byte == last_byte {
+        let mask = byte_mask(start % 8, end % 8);
+        return (bytes[first_byte] & mask).count_ones() as u64;
+    }
+    let head = (bytes[first_byte] & byte_mask(start % 8, 7)).count_ones() as u64;
+    let tail = (bytes[last_byte] & byte_mask(0, end % 8)).count_ones() as u64;
+    head + count_ones(&bytes[first_byte + 1..last_byte]) + tail
+}
+
+/// Finds the first bit equal to `bit` between the bit offsets `start` and
+/// `end`, both inclusive and within `bytes`. Whole words that can't contain a
+/// match are skipped without inspecting their bits.
+pub fn position(bytes: &[u8], bit: bool, start: u64, end: u64) -> Option<u64> {
+    // Bytes made only of the bit we are *not* looking for.
+    let skip_byte = if bit { 0x00 } else { 0xff };
+    let skip_word = u64::from_ne_bytes([skip_byte; 8]);
+    let mut offset = start;
+    while offset <= end {
+        if offset & 63 == 0 && offset + 63 <= end {
+            let byte = (offset / 8) as usize;
+            let word = u64::from_ne_bytes(bytes[byte..byte + 8].try_into().unwrap());
+            if word == skip_word {
+                offset += 64;
+                continue;
+            }
+        }
+        if offset & 7 == 0 && offset + 7 <= end && bytes[(offset / 8) as usize] == skip_byte {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bytes, offset) == bit {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Combines `sources` byte by byte. Shorter sources are treated as padded
+/// with zero bytes up to the longest one, so AND with a shorter source
+/// clears the tail. NOT uses only the first source.
+pub fn bit_op(op: BitOp, sources: &[&[u8]]) -> Vec<u8> {
+    let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+    let byte = |source: &[u8], index: usize| source.get(index).copied().unwrap_or(0);
+    (0..len)
+        .map(|index| {
+            let mut bytes = sources.iter().map(|source| byte(source, index));
+            let first = bytes.next().unwrap_or(0);
+            match op {
+                BitOp::And => bytes.fold(first, |acc, byte| acc & byte),
+                BitOp::Or => bytes.fold(first, |acc, byte| acc | byte),
+                BitOp::Xor => bytes.fold(first, |acc, byte| acc ^ byte),
+                BitOp::Not => !first,
+            }
+        })
+        .collect()
+}
+
+/// The integer type of a BITFIELD operation, such as `i5` or `u16`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitFieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+
+/// What BITFIELD SET and INCRBY do with results that don't fit their type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl BitFieldType {
+    /// Parses `i1`..`i64` or `u1`..`u63`.
+    pub fn parse(encoding: &str) -> Option<BitFieldType> {
+        let signed = match encoding.as_bytes().first()? {
+            b'i' | b'I' => true,
+            b'u' | b'U' => false,
+            _ => return None,
+        };
+        let bits = encoding[1..].parse::<u32>().ok()?;
+        let max_bits = if signed { 64 } else { 63 };
+        (1..=max_bits)
+            .contains(&bits)
+            .then_some(BitFieldType { signed, bits })
+    }
+
+    fn min(&self) -> i128 {
+        match self.signed {
+            true => -(1i128 << (self.bits - 1)),
+            false => 0,
+        }
+    }
+
+    fn max(&self) -> i128 {
+        match self.signed {
+            true => (1i128 << (self.bits - 1)) - 1,
+            false => (1i128 << self.bits) - 1,
+        }
+    }
+
+    /// Reads the field at bit `offset`. Bits past the end of `bytes` read as zero.
+    pub fn get(&self, bytes: &[u8], offset: u64) -> i64 {
+        let mut value = 0u64;
+        for bit in 0..self.bits as u64 {
+            value = (value << 1) | get_bit(bytes, offset + bit) as u64;
+        }
+        if self.signed && self.bits < 64 && value >> (self.bits - 1) & 1 == 1 {
+            // Sign-extend from the field's width.
+            value |= u64::MAX << self.bits;
+        }
+        value as i64
+    }
+
+    /// Writes `value` truncated to the field's width at bit `offset`.
+    pub fn set(&self, bytes: &mut Vec<u8>, offset: u64, value: i64) {
+        let value = value as u64;
+        for bit in 0..self.bits as u64 {
+            let shift = self.bits as u64 - 1 - bit;
+            set_bit(bytes, offset + bit, value >> shift & 1 == 1);
+        }
+    }
+
+    /// Fits `value` into the field's range according to `overflow`, or
+    /// returns None when it overflows with [`Overflow::Fail`].
+    pub fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = (self.min(), self.max());
+        if (min..=max).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Wrap => {
+                let modulus = 1i128 << self.bits;
+                Some(((value - min).rem_euclid(modulus) + min) as i64)
+            }
+            Overflow::Sat => Some(value.clamp(min, max) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get_bits() {
+        let mut bytes = Vec::new();
+        assert!(!set_bit(&mut bytes, 7, true));
+        assert_eq!(vec![0x01], bytes);
+        assert!(set_bit(&mut bytes, 7, true));
+        assert!(!set_bit(&mut bytes, 17, true));
+        assert_eq!(vec![0x01, 0x00, 0x40], bytes);
+        assert!(get_bit(&bytes, 17));
+        assert!(!get_bit(&bytes, 16));
+        assert!(!get_bit(&bytes, 1000));
+    }
+
+    #[test]
+    fn test_count_and_position() {
+        let mut bytes = vec![0u8; 20];
+        bytes[0] = 0b0011_0000;
+        bytes[19] = 0b0000_0001;
+        assert_eq!(3, count_ones(&bytes));
+        assert_eq!(2, count_bits(&bytes, 0, 7));
+        assert_eq!(1, count_bits(&bytes, 3, 3));
+        assert_eq!(1, count_bits(&bytes, 4, 159));
+
+        assert_eq!(Some(2), position(&bytes, true, 0, 159));
+        assert_eq!(Some(159), position(&bytes, true, 4, 159));
+        assert_eq!(None, position(&bytes, true, 4, 158));
+        assert_eq!(Some(4), position(&bytes, false, 2, 159));
+
+        let ones = vec![0xffu8; 16];
+        assert_eq!(None, position(&ones, false, 0, 127));
+        assert_eq!(128, count_ones(&ones));
+    }
+
+    #[test]
+    fn test_bit_op_pads_shorter_sources() {
+        let long: &[u8] = &[0xff, 0x0f];
+        let short: &[u8] = &[0xf0];
+        assert_eq!(vec![0xf0, 0x00], bit_op(BitOp::And, &[long, short]));
+        assert_eq!(vec![0xff, 0x0f], bit_op(BitOp::Or, &[long, short]));
+        assert_eq!(vec![0x0f, 0x0f], bit_op(BitOp::Xor, &[long, short]));
+        assert_eq!(vec![0x0f], bit_op(BitOp::Not, &[short]));
+        assert!(bit_op(BitOp::Or, &[&[], &[]]).is_empty());
+    }
+
+    #[test]
+    fn test_bit_fields() {
+        let i8 = BitFieldType::parse("i8").unwrap();
+        let u4 = BitFieldType::parse("u4").unwrap();
+        assert_eq!(None, BitFieldType::parse("u64"));
+        assert_eq!(None, BitFieldType::parse("i0"));
+
+        let mut bytes = Vec::new();
+        i8.set(&mut bytes, 4, -2);
+        assert_eq!(vec![0x0f, 0xe0], bytes);
+        assert_eq!(-2, i8.get(&bytes, 4));
+        assert_eq!(15, u4.get(&bytes, 4));
+
+        assert_eq!(Some(-128), i8.fit(128, Overflow::Wrap));
+        assert_eq!(Some(127), i8.fit(300, Overflow::Sat));
+        assert_eq!(Some(0), u4.fit(-5, Overflow::Sat));
+        assert_eq!(Some(11), u4.fit(-5, Overflow::Wrap));
+        assert_eq!(None, u4.fit(16, Overflow::Fail));
+
+        let i64 = BitFieldType::parse("i64").unwrap();
+        i64.set(&mut bytes, 0, i64::MIN);
+        assert_eq!(i64::MIN, i64.get(&bytes, 0));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::{thread_rng, Rng};
+
+use crate::cluster::bus::BUS_PORT_OFFSET;
+
+pub mod bus;
+
+/// The number of hash slots the key space of a cluster is split into.
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+/// CRC16 as used by Redis Cluster: the XMODEM variant, polynomial 0x1021
+/// with a zero initial value.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The part of a key that is hashed: the content of its first `{...}` hash
+/// tag when that is not empty, so related keys can share a slot, or the
+/// whole key otherwise.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    let Some(open) = key.iter().position(|byte| *byte == b'{') else {
+        return key;
+    };
+    match key[open + 1..].iter().position(|byte| *byte == b'}') {
+        Some(length) if length > 0 => &key[open + 1..open + 1 + length],
+        _ => key,
+    }
+}
+
+/// The hash slot of a key.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) & (CLUSTER_SLOTS - 1)
+}
+
+/// A node of the cluster, as known to this one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterNode {
+    /// 40 random hex characters.
+    pub id: String,
+    pub host: String,
+    pub port: u64,
+    /// The epoch at which the node last claimed its slots.
+    pub config_epoch: u64,
+}
+
+impl ClusterNode {
+    pub fn new(host: String, port: u64) -> ClusterNode {
+        let mut rng = thread_rng();
+        let id = (0..40)
+            .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+            .collect();
+        ClusterNode {
+            id,
+            host,
+            port,
+            config_epoch: 0,
+        }
+    }
+
+    /// The address redirected clients are sent to.
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// The address of the node's cluster bus.
+    pub fn bus_address(&self) -> String {
+        format!("{}:{}", self.host, self.port + BUS_PORT_OFFSET)
+    }
+}
+
+/// Which node serves each hash slot, and the slots being moved between
+/// nodes.
+#[derive(Debug, Clone)]
+pub struct ClusterState {
+    myself: String,
+    /// The highest epoch this node has seen in the cluster.
+    current_epoch: u64,
+    nodes: BTreeMap<String, ClusterNode>,
+    slots: Vec<Option<String>>,
+    /// Slots this node is handing over, with the node receiving them.
+    migrating: HashMap<u16, String>,
+    /// Slots this node is receiving, with the node handing them over.
+    importing: HashMap<u16, String>,
+    /// Clients that sent ASKING, for their next command only.
+    asking: HashSet<u64>,
+}
+
+impl ClusterState {
+    pub fn new(myself: ClusterNode) -> ClusterState {
+        ClusterState {
+            myself: myself.id.clone(),
+            current_epoch: 0,
+            nodes: BTreeMap::from([(myself.id.clone(), myself)]),
+            slots: vec![None; CLUSTER_SLOTS as usize],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            asking: HashSet::new(),
+        }
+    }
+
+    pub fn myself(&self) -> &ClusterNode {
+        &self.nodes[&self.myself]
+    }
+
+    pub fn is_myself(&self, node: &ClusterNode) -> bool {
+        node.id == self.myself
+    }
+
+    pub fn node(&self, id: &str) -> Option<&ClusterNode> {
+        self.nodes.get(id)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &ClusterNode> {
+        self.nodes.values()
+    }
+
+    pub fn current_epoch(&self) -> u64 {
+        self.current_epoch
+    }
+
+    pub fn add_node(&mut self, node: ClusterNode) {
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    /// Gives this node a config epoch no other node has, so its claims on
+    /// slots win over older ones.
+    pub fn bump_epoch(&mut self) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        if let Some(myself) = self.nodes.get_mut(&self.myself) {
+            myself.config_epoch = epoch;
+        }
+    }
+
+    /// Updates the view of the cluster with what another node says about
+    /// itself. It takes over the slots it claims that are unassigned or
+    /// served by a node with an older config epoch. When both nodes have the
+    /// same config epoch, the one with the smaller ID bumps its own, so that
+    /// epochs end up unique.
+    pub fn update_from(&mut self, sender: &ClusterNode, current_epoch: u64, slots: &[(u16, u16)]) {
+        if sender.id == self.myself {
+            return;
+        }
+        self.current_epoch = self.current_epoch.max(current_epoch);
+        self.add_node(sender.clone());
+
+        for slot in slots.iter().flat_map(|(start, end)| *start..=*end) {
+            if slot >= CLUSTER_SLOTS {
+                continue;
+            }
+            let claim_wins = match self.slot_owner(slot) {
+                None => true,
+                Some(owner) => owner.id != sender.id && owner.config_epoch < sender.config_epoch,
+            };
+            if claim_wins {
+                self.assign_slot(slot, &sender.id);
+            }
+        }
+
+        if sender.config_epoch == self.myself().config_epoch && self.myself < sender.id {
+            self.bump_epoch();
+        }
+    }
+
+    /// Adds a node another one gossiped about, unless it is already known.
+    pub fn learn_node(&mut self, node: &ClusterNode) {
+        if !self.nodes.contains_key(&node.id) {
+            self.add_node(ClusterNode {
+                config_epoch: 0,
+                ..node.clone()
+            });
+        }
+    }
+
+    pub fn slot_owner(&self, slot: u16) -> Option<&ClusterNode> {
+        self.slots[slot as usize]
+            .as_ref()
+            .and_then(|id| self.nodes.get(id))
+    }
+
+    /// Makes `id` the owner of `slot`, ending any migration of it.
+    pub fn assign_slot(&mut self, slot: u16, id: &str) {
+        self.slots[slot as usize] = Some(id.to_string());
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    pub fn unassign_slot(&mut self, slot: u16) {
+        self.slots[slot as usize] = None;
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    pub fn assigned_slots(&self) -> usize {
+        self.slots.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    /// The slots a node serves, as inclusive ranges in ascending order.
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..CLUSTER_SLOTS {
+            if self.slots[slot as usize].as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    pub fn migrating_to(&self, slot: u16) -> Option<&ClusterNode> {
+        self.migrating.get(&slot).and_then(|id| self.nodes.get(id))
+    }
+
+    pub fn importing_from(&self, slot: u16) -> Option<&ClusterNode> {
+        self.importing.get(&slot).and_then(|id| self.nodes.get(id))
+    }
+
+    pub fn set_migrating(&mut self, slot: u16, id: &str) {
+        self.migrating.insert(slot, id.to_string());
+    }
+
+    pub fn set_importing(&mut self, slot: u16, id: &str) {
+        self.importing.insert(slot, id.to_string());
+    }
+
+    pub fn set_stable(&mut self, slot: u16) {
+        self.migrating.remove(&slot);
+        self.importing.remove(&slot);
+    }
+
+    pub fn set_asking(&mut self, client: u64) {
+        self.asking.insert(client);
+    }
+
+    /// Whether `client` sent ASKING before this command, clearing it.
+    pub fn take_asking(&mut self, client: u64) -> bool {
+        self.asking.remove(&client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cluster::{crc16, key_hash_slot, ClusterNode, ClusterState};
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+        assert_eq!(12182, key_hash_slot(b"foo"));
+        assert_eq!(5061, key_hash_slot(b"bar"));
+        assert_eq!(
+            key_hash_slot(b"user1000"),
+            key_hash_slot(b"{user1000}.following")
+        );
+        assert_eq!(
+            key_hash_slot(b"user1000"),
+            key_hash_slot(b"foo{user1000}{bar}")
+        );
+        // Empty or unterminated tags hash the whole key.
+        assert_eq!(crc16(b"foo{}{bar}") & 16383, key_hash_slot(b"foo{}{bar}"));
+        assert_eq!(crc16(b"foo{bar") & 16383, key_hash_slot(b"foo{bar"));
+    }
+
+    #[test]
+    fn test_slot_ranges() {
+        let myself = ClusterNode::new("127.0.0.1".to_string(), 7000);
+        let id = myself.id.clone();
+        let mut cluster = ClusterState::new(myself);
+        for slot in [0, 1, 2, 5, 16383] {
+            cluster.assign_slot(slot, &id);
+        }
+        assert_eq!(
+            vec![(0, 2), (5, 5), (16383, 16383)],
+            cluster.slot_ranges(&id)
+        );
+        assert_eq!(5, cluster.assigned_slots());
+        assert!(cluster.slot_ranges("other").is_empty());
+    }
+}
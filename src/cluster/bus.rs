@@ -0,0 +1,321 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+use crate::binary::ByteString;
+use crate::cluster::ClusterNode;
+use crate::parser::ParserValue;
+use crate::replication::parse_command;
+use crate::tokenizer;
+
+/// Nodes listen for the cluster bus on their client port plus this.
+pub const BUS_PORT_OFFSET: u64 = 10000;
+
+/// How long a node waits to connect to a peer and hear its reply.
+const BUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusMessageType {
+    /// Asks a node to join the sender's cluster.
+    Meet,
+    /// A heartbeat.
+    Ping,
+    /// The reply to MEET and PING.
+    Pong,
+}
+
+impl BusMessageType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BusMessageType::Meet => "MEET",
+            BusMessageType::Ping => "PING",
+            BusMessageType::Pong => "PONG",
+        }
+    }
+}
+
+/// What nodes tell each other: who the sender is, its epochs and the slots
+/// it serves, along with some of the nodes it knows so that joining one
+/// node is enough to discover the whole cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusMessage {
+    pub kind: BusMessageType,
+    pub sender: ClusterNode,
+    pub current_epoch: u64,
+    pub slots: Vec<(u16, u16)>,
+    pub gossip: Vec<ClusterNode>,
+}
+
+impl BusMessage {
+    /// Encodes the message as a RESP array:
+    /// `type id host port config-epoch current-epoch slots [id host port ...]`,
+    /// the slots as comma separated `start-end` ranges.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let slots = self
+            .slots
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut arguments = vec![
+            self.kind.as_str().to_string(),
+            self.sender.id.clone(),
+            self.sender.host.clone(),
+            self.sender.port.to_string(),
+            self.sender.config_epoch.to_string(),
+            self.current_epoch.to_string(),
+            slots,
+        ];
+        for node in &self.gossip {
+            arguments.extend([node.id.clone(), node.host.clone(), node.port.to_string()]);
+        }
+        let message = ParserValue::Array(
+            arguments
+                .into_iter()
+                .map(|argument| ParserValue::BulkString(argument.into()))
+                .collect(),
+        );
+        tokenizer::serialize_tokens_to_bytes(&message.to_tokens())
+            .expect("bus messages are always serializable")
+    }
+
+    pub fn from_arguments(arguments: &[ByteString]) -> anyhow::Result<BusMessage> {
+        if arguments.len() < 7 || !(arguments.len() - 7).is_multiple_of(3) {
+            bail!("malformed bus message: {:?}", arguments);
+        }
+        // Every field of a bus message is text.
+        let arguments = arguments
+            .iter()
+            .map(|argument| {
+                argument
+                    .to_str()
+                    .ok_or_else(|| anyhow!("malformed bus message: {:?}", argument))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let kind = match arguments[0] {
+            "MEET" => BusMessageType::Meet,
+            "PING" => BusMessageType::Ping,
+            "PONG" => BusMessageType::Pong,
+            kind => bail!("unknown bus message type {:?}", kind),
+        };
+        let node = |fields: &[&str]| -> anyhow::Result<ClusterNode> {
+            if fields[0].len() != 40 {
+                bail!("invalid node ID {:?}", fields[0]);
+            }
+            Ok(ClusterNode {
+                id: fields[0].to_string(),
+                host: fields[1].to_string(),
+                port: fields[2].parse()?,
+                config_epoch: 0,
+            })
+        };
+        let mut sender = node(&arguments[1..4])?;
+        sender.config_epoch = arguments[4].parse()?;
+        let slots = arguments[6]
+            .split(',')
+            .filter(|range| !range.is_empty())
+            .map(|range| {
+                let (start, end) = range
+                    .split_once('-')
+                    .ok_or_else(|| anyhow!("invalid slot range {:?}", range))?;
+                Ok((start.parse()?, end.parse()?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(BusMessage {
+            kind,
+            sender,
+            current_epoch: arguments[5].parse()?,
+            slots,
+            gossip: arguments[7..]
+                .chunks(3)
+                .map(node)
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        })
+    }
+}
+
+/// A message that arrived on the bus. MEET and PING from peers expect the
+/// data core's PONG on `reply`; PONGs to this node's own messages have none.
+#[derive(Debug)]
+pub struct BusEnvelope {
+    pub message: BusMessage,
+    pub reply: Option<oneshot::Sender<BusMessage>>,
+}
+
+/// The data core's end of the cluster bus: the messages peers send arrive
+/// on `messages`, as do their replies to the messages this node sends.
+/// Dropping it closes the listener and the connections.
+#[derive(Debug)]
+pub struct ClusterBus {
+    messages: Receiver<BusEnvelope>,
+    tx: Sender<BusEnvelope>,
+    task: JoinHandle<()>,
+}
+
+impl ClusterBus {
+    pub fn spawn(listener: TcpListener) -> ClusterBus {
+        let (tx, messages) = mpsc::channel::<BusEnvelope>(32);
+        ClusterBus {
+            messages,
+            task: tokio::spawn(accept_peers(listener, tx.clone())),
+            tx,
+        }
+    }
+
+    pub async fn recv(self: &mut ClusterBus) -> Option<BusEnvelope> {
+        self.messages.recv().await
+    }
+
+    /// Sends `message` to the bus at `address` in the background. The
+    /// peer's reply comes back through [`ClusterBus::recv`].
+    pub fn send(self: &ClusterBus, address: String, message: BusMessage) {
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            match timeout(BUS_TIMEOUT, exchange(&address, &message)).await {
+                Ok(Ok(reply)) => {
+                    let _ = tx
+                        .send(BusEnvelope {
+                            message: reply,
+                            reply: None,
+                        })
+                        .await;
+                }
+                Ok(Err(err)) => warn!("cluster bus error with {}: {:#}", address, err),
+                Err(_) => warn!("cluster bus timeout with {}", address),
+            }
+        });
+    }
+}
+
+impl Drop for ClusterBus {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_peers(listener: TcpListener, tx: Sender<BusEnvelope>) {
+    // Dropped with this task, which aborts the connections.
+    let mut peers = JoinSet::new();
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                peers.spawn(serve_peer(socket, tx.clone()));
+            }
+            Err(err) => warn!("cannot accept cluster bus connection: {}", err),
+        }
+        while peers.try_join_next().is_some() {}
+    }
+}
+
+/// Hands the messages of a peer to the data core and writes back its
+/// replies, until the peer disconnects.
+async fn serve_peer(mut socket: TcpStream, tx: Sender<BusEnvelope>) {
+    let mut buffer = Vec::new();
+    loop {
+        let message = match read_message(&mut socket, &mut buffer).await {
+            Ok(message) => message,
+            Err(err) => {
+                debug!("cluster bus connection closed: {:#}", err);
+                break;
+            }
+        };
+        let (reply_tx, reply_rx) = oneshot::channel::<BusMessage>();
+        let envelope = BusEnvelope {
+            message,
+            reply: Some(reply_tx),
+        };
+        if tx.send(envelope).await.is_err() {
+            break;
+        }
+        let Ok(reply) = reply_rx.await else {
+            break;
+        };
+        if socket.write_all(&reply.to_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends a message to a peer and reads its reply.
+async fn exchange(address: &str, message: &BusMessage) -> anyhow::Result<BusMessage> {
+    let mut socket = TcpStream::connect(address).await?;
+    socket.write_all(&message.to_bytes()).await?;
+    read_message(&mut socket, &mut Vec::new()).await
+}
+
+async fn read_message(socket: &mut TcpStream, buffer: &mut Vec<u8>) -> anyhow::Result<BusMessage> {
+    loop {
+        if let Some((arguments, length)) = parse_command(buffer)? {
+            buffer.drain(..length);
+            return BusMessage::from_arguments(&arguments);
+        }
+        let mut chunk = [0; 4096];
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("connection closed");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use crate::cluster::bus::{BusMessage, BusMessageType, ClusterBus};
+    use crate::cluster::ClusterNode;
+    use crate::replication::parse_command;
+
+    fn message(kind: BusMessageType) -> BusMessage {
+        let mut sender = ClusterNode::new("127.0.0.1".to_string(), 7000);
+        sender.config_epoch = 3;
+        BusMessage {
+            kind,
+            sender,
+            current_epoch: 5,
+            slots: vec![(0, 100), (200, 200)],
+            gossip: vec![ClusterNode::new("10.0.0.2".to_string(), 7001)],
+        }
+    }
+
+    #[test]
+    fn test_bus_message_round_trip() {
+        let ping = message(BusMessageType::Ping);
+        let (arguments, _) = parse_command(&ping.to_bytes()).unwrap().unwrap();
+        assert_eq!("PING", arguments[0]);
+        assert_eq!("0-100,200-200", arguments[6]);
+        assert_eq!(ping, BusMessage::from_arguments(&arguments).unwrap());
+
+        let mut meet = message(BusMessageType::Meet);
+        meet.slots.clear();
+        meet.gossip.clear();
+        let (arguments, _) = parse_command(&meet.to_bytes()).unwrap().unwrap();
+        assert_eq!(meet, BusMessage::from_arguments(&arguments).unwrap());
+        assert!(BusMessage::from_arguments(&arguments[..6]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bus_exchanges_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut bus = ClusterBus::spawn(listener);
+
+        let ping = message(BusMessageType::Ping);
+        bus.send(address, ping.clone());
+        let received = bus.recv().await.unwrap();
+        assert_eq!(ping, received.message);
+        let pong = message(BusMessageType::Pong);
+        received.reply.unwrap().send(pong.clone()).unwrap();
+
+        let reply = bus.recv().await.unwrap();
+        assert_eq!(pong, reply.message);
+        assert!(reply.reply.is_none());
+    }
+}
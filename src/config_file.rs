@@ -0,0 +1,217 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::data_core::CONFIG_PARAMETERS;
+
+/// Directives whose values add up over several lines instead of replacing
+/// each other.
+const ACCUMULATED_DIRECTIVES: &[&str] = &["save"];
+
+/// Directives that may be given several times, each on its own.
+const REPEATED_DIRECTIVES: &[&str] = &["rename-command"];
+
+/// Other names Redis accepts for a directive.
+const ALIASES: &[(&str, &str)] = &[
+    ("slaveof", "replicaof"),
+    ("slave-announce-ip", "replica-announce-ip"),
+    ("slave-announce-port", "replica-announce-port"),
+];
+
+/// Splits a line into words like Redis does: double quoted words may
+/// contain spaces and escapes, single quoted ones are taken as is.
+fn split_words(line: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(words);
+        };
+        let mut word = String::new();
+        match first {
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => word.push('\n'),
+                        Some('r') => word.push('\r'),
+                        Some('t') => word.push('\t'),
+                        Some(c) => word.push(c),
+                        None => bail!("unbalanced quotes"),
+                    },
+                    Some(c) => word.push(c),
+                    None => bail!("unbalanced quotes"),
+                }
+            },
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => word.push(c),
+                    None => bail!("unbalanced quotes"),
+                }
+            },
+            c => {
+                word.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+            }
+        }
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            bail!("closing quote must be followed by a space");
+        }
+        words.push(word);
+    }
+}
+
+/// The directives of a redis.conf file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    /// The directives that are also flags, as `--name value` arguments, so
+    /// they go through the same parsing as the command line and flags given
+    /// after them override them.
+    pub arguments: Vec<String>,
+    /// The other directives, CONFIG SET parameters to set as `name value`.
+    pub parameters: Vec<(String, String)>,
+}
+
+/// Parses the directives of a redis.conf file. Only the flags in `known`
+/// and the parameters CONFIG SET accepts are allowed.
+pub fn parse_config(contents: &str, known: &[&str]) -> anyhow::Result<ConfigFile> {
+    let mut directives: Vec<(String, String)> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = split_words(line).with_context(|| format!("line {}", number + 1))?;
+        let name = words[0].to_lowercase();
+        let name = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map_or(name.as_str(), |(_, name)| name)
+            .to_string();
+        if !(known.contains(&name.as_str()) || CONFIG_PARAMETERS.contains(&name.as_str()))
+            || words.len() < 2
+        {
+            bail!(
+                "Bad directive or wrong number of arguments at line {}: {}",
+                number + 1,
+                line
+            );
+        }
+        let value = words[1..].join(" ");
+        if REPEATED_DIRECTIVES.contains(&name.as_str()) {
+            directives.push((name, value));
+            continue;
+        }
+        match directives
+            .iter_mut()
+            .find(|(previous, _)| *previous == name)
+        {
+            Some((_, previous)) if ACCUMULATED_DIRECTIVES.contains(&name.as_str()) => {
+                if value.is_empty() {
+                    previous.clear();
+                } else if previous.is_empty() {
+                    *previous = value;
+                } else {
+                    previous.push(' ');
+                    previous.push_str(&value);
+                }
+            }
+            Some((_, previous)) => *previous = value,
+            None => directives.push((name, value)),
+        }
+    }
+    let mut config = ConfigFile::default();
+    for (name, value) in directives {
+        if known.contains(&name.as_str()) {
+            config.arguments.extend([format!("--{}", name), value]);
+        } else {
+            config.parameters.push((name, value));
+        }
+    }
+    Ok(config)
+}
+
+/// Reads and parses the config file at `path`, see [`parse_config`].
+pub fn read_config_file(path: &Path, known: &[&str]) -> anyhow::Result<ConfigFile> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| anyhow!("cannot read {}: {}", path.display(), err))?;
+    parse_config(&contents, known).with_context(|| format!("in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config_file::{parse_config, split_words};
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(vec!["save", ""], split_words(r#"save """#).unwrap());
+        assert_eq!(
+            vec!["requirepass", "a b\n", "c d"],
+            split_words(r#"  requirepass "a b\n" 'c d' "#).unwrap()
+        );
+        assert!(split_words(r#"dir "unterminated"#).is_err());
+        assert!(split_words(r#"dir "a"b"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let known = [
+            "port",
+            "dir",
+            "save",
+            "replicaof",
+            "appendonly",
+            "rename-command",
+        ];
+        let contents = "# A comment\n\nport 7000\nPORT 7001\nsave 3600 1\nsave 300 100\nslaveof 127.0.0.1 6379\nrename-command save \"\"\nrename-command config cfg\ndir \"/tmp/redis data\"\n";
+        assert_eq!(
+            vec![
+                "--port",
+                "7001",
+                "--save",
+                "3600 1 300 100",
+                "--replicaof",
+                "127.0.0.1 6379",
+                "--rename-command",
+                "save ",
+                "--rename-command",
+                "config cfg",
+                "--dir",
+                "/tmp/redis data",
+            ],
+            parse_config(contents, &known).unwrap().arguments
+        );
+        assert_eq!(
+            vec!["--save", ""],
+            parse_config("save 60 1\nsave \"\"\n", &known)
+                .unwrap()
+                .arguments
+        );
+        assert!(parse_config("port 1\nmaxmemory 1gb\n", &known)
+            .unwrap_err()
+            .to_string()
+            .contains("line 2"));
+        assert!(parse_config("appendonly\n", &known).is_err());
+    }
+
+    #[test]
+    fn test_parse_config_parameters() {
+        let contents = "port 7000\ntimeout 30\nmin-replicas-to-write 1\nrepl-ping-replica-period 5\nlua-time-limit 100\nset-max-intset-entries 64\ntimeout 60\n";
+        let config = parse_config(contents, &["port"]).unwrap();
+        assert_eq!(vec!["--port", "7000"], config.arguments);
+        assert_eq!(
+            vec![
+                ("timeout".to_string(), "60".to_string()),
+                ("min-replicas-to-write".to_string(), "1".to_string()),
+                ("repl-ping-replica-period".to_string(), "5".to_string()),
+                ("lua-time-limit".to_string(), "100".to_string()),
+                ("set-max-intset-entries".to_string(), "64".to_string()),
+            ],
+            config.parameters
+        );
+    }
+}
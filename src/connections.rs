@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::tokenizer::Token;
+
+/// A client connection as the rest of the server sees it.
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    pub id: u64,
+    /// The peer address of the connection, when known.
+    pub address: Option<SocketAddr>,
+    pub connected_at: Instant,
+    /// Delivers messages to the connection outside of command replies.
+    pub push_channel: UnboundedSender<Vec<Token>>,
+    /// Set with CLIENT SETNAME.
+    pub name: Option<String>,
+    /// The last command run, `name|subcommand` for container commands.
+    pub last_command: String,
+    pub last_interaction: Instant,
+    /// The ACL user the connection authenticated as with AUTH.
+    pub user: Option<String>,
+    kill: Arc<Notify>,
+}
+
+/// Every open client connection by ID. The server registers connections
+/// as it accepts them and they leave when they close; the data core looks
+/// them up to describe, name and kill them.
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    connections: Arc<Mutex<BTreeMap<u64, ConnectionHandle>>>,
+    last_id: Arc<AtomicU64>,
+}
+
+impl Connections {
+    fn lock(self: &Connections) -> MutexGuard<'_, BTreeMap<u64, ConnectionHandle>> {
+        // Handles stay consistent even if a holder of the lock panicked.
+        self.connections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Registers a new connection under the next client ID. It stays
+    /// registered until the returned [`Registration`] is dropped.
+    pub fn register(
+        self: &Connections,
+        address: Option<SocketAddr>,
+        push_channel: UnboundedSender<Vec<Token>>,
+    ) -> Registration {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = Arc::new(Notify::new());
+        let now = Instant::now();
+        self.lock().insert(
+            id,
+            ConnectionHandle {
+                id,
+                address,
+                connected_at: now,
+                push_channel,
+                name: None,
+                last_command: String::new(),
+                last_interaction: now,
+                user: None,
+                kill: kill.clone(),
+            },
+        );
+        Registration {
+            id,
+            kill,
+            connections: self.clone(),
+        }
+    }
+
+    /// How many connections were ever registered.
+    pub fn total_registered(self: &Connections) -> u64 {
+        self.last_id.load(Ordering::Relaxed)
+    }
+
+    pub fn get(self: &Connections, id: u64) -> Option<ConnectionHandle> {
+        self.lock().get(&id).cloned()
+    }
+
+    /// The open connections, ordered by ID.
+    pub fn list(self: &Connections) -> Vec<ConnectionHandle> {
+        self.lock().values().cloned().collect()
+    }
+
+    /// Changes the handle of connection `id`, returning `None` when it is
+    /// not registered.
+    pub fn update<T>(
+        self: &Connections,
+        id: u64,
+        update: impl FnOnce(&mut ConnectionHandle) -> T,
+    ) -> Option<T> {
+        self.lock().get_mut(&id).map(update)
+    }
+
+    /// Tells connection `id` to close, returning whether it was open.
+    pub fn kill(self: &Connections, id: u64) -> bool {
+        match self.lock().get(&id) {
+            Some(connection) => {
+                connection.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a connection registered while its task runs.
+#[derive(Debug)]
+pub struct Registration {
+    pub id: u64,
+    kill: Arc<Notify>,
+    connections: Connections,
+}
+
+impl Registration {
+    /// Completes once the connection was killed.
+    pub async fn killed(self: &Registration) {
+        self.kill.notified().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    use crate::connections::Connections;
+    use crate::tokenizer::Token;
+
+    #[tokio::test]
+    async fn test_connections_are_registered_until_dropped() {
+        let connections = Connections::default();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let first = connections.register(None, push_tx.clone());
+        let second = connections.register("127.0.0.1:5000".parse().ok(), push_tx);
+        assert_eq!((1, 2), (first.id, second.id));
+
+        connections.update(second.id, |connection| {
+            connection.name = Some("worker".to_string())
+        });
+        let listed = connections.list();
+        assert_eq!(vec![1, 2], listed.iter().map(|c| c.id).collect::<Vec<_>>());
+        assert_eq!(Some("worker".to_string()), listed[1].name);
+
+        drop(first);
+        assert!(connections.get(1).is_none());
+        assert_eq!(1, connections.list().len());
+    }
+
+    #[tokio::test]
+    async fn test_kill_wakes_the_connection() {
+        let connections = Connections::default();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let registration = connections.register(None, push_tx);
+
+        assert!(connections.kill(registration.id));
+        timeout(Duration::from_secs(1), registration.killed())
+            .await
+            .expect("the kill should be seen even before waiting for it");
+        drop(registration);
+        assert!(!connections.kill(1));
+    }
+}
@@ -1,55 +1,224 @@
+use anyhow::anyhow;
 use chrono::{TimeDelta, Utc};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt;
-use std::ops::Add;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::Receiver;
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
 use tokio::sync::oneshot::Sender;
+use tokio::time::{sleep_until, Instant};
+use tracing::{error, info, trace, warn};
 
+use crate::backlog::ReplicationBacklog;
+use crate::binary::ByteString;
+use crate::cluster::bus::{BusEnvelope, ClusterBus};
+use crate::cluster::ClusterState;
+use crate::connections::Connections;
 use crate::parser::ParserValue;
-use crate::tokenizer;
+use crate::replication::{MasterConnection, MasterLink, PsyncReply};
+use crate::server::ConnectionConfig;
+use crate::set::{RedisSet, SetLimits};
+use crate::sorted_set::{SortedSet, SortedSetLimits};
+use crate::stream::Stream;
+use crate::string::RedisString;
 use crate::tokenizer::Token;
 
+mod access;
+mod acl;
+mod aof;
+mod bitmaps;
+mod blocking;
+mod clients;
+mod cluster;
+mod commands;
+mod config;
+mod debug;
+mod expiry;
+mod functions;
+mod geo;
+mod hyperloglogs;
+mod info;
+mod keys;
+mod latency;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod replicas;
+mod scripting;
+mod sets;
+mod sorted_sets;
+mod streams;
+mod transactions;
+
+pub use aof::AppendFsync;
+pub use config::{parse_memory, CONFIG_PARAMETERS};
+pub use rdb::{inspect_rdb, RdbKey, RdbSummary};
+
+use access::AccessInfo;
+use acl::Acl;
+use aof::{wait_for_aof_rewrite, AofRewrite, AofWriter};
+use blocking::{BlockRequest, BlockedClients};
+use commands::CommandTable;
+use expiry::ExpiryIndex;
+use functions::Library;
+use info::ServerStats;
+use latency::LatencyMonitor;
+use persistence::{
+    default_save_rules, parse_save_rules, wait_for_background_save, BackgroundSave, SaveRule,
+};
+use pubsub::{PubSub, SubscriptionKind};
+use replicas::{PendingReplica, Replicas};
+use sets::SetOperation;
+use sorted_sets::{RangeKind, ZSetOperation};
+use transactions::{Transaction, WatchedKeys};
+
+/// The connection a command was sent from, with the channel used to push
+/// messages to it outside of command replies.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub id: u64,
+    pub push_channel: UnboundedSender<Vec<Token>>,
+    /// The peer address of the connection, when known.
+    pub address: Option<SocketAddr>,
+    /// Set while the client is a replica or a subscriber, which are not
+    /// closed for being idle.
+    pub no_idle_timeout: Arc<AtomicBool>,
+}
+
+impl Client {
+    pub fn new(id: u64, push_channel: UnboundedSender<Vec<Token>>) -> Client {
+        Client {
+            id,
+            push_channel,
+            address: None,
+            no_idle_timeout: Arc::default(),
+        }
+    }
+
+    pub fn with_address(self: Client, address: SocketAddr) -> Client {
+        Client {
+            address: Some(address),
+            ..self
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Command {
-    pub arguments: Arc<Vec<ParserValue>>,
+    /// The arguments as received, moved out of the connection's buffer. The
+    /// data core resolves renamed commands in place.
+    pub arguments: Vec<ByteString>,
     pub response_channel: Sender<Vec<Token>>,
+    pub client: Option<Client>,
+    /// For commands a replica applies from its master, the number of bytes
+    /// they took in the replication stream.
+    pub replication_length: Option<usize>,
 }
 
 impl Command {
-    pub fn new(arguments: Arc<Vec<ParserValue>>, response_channel: Sender<Vec<Token>>) -> Command {
+    pub fn new(arguments: Vec<ByteString>, response_channel: Sender<Vec<Token>>) -> Command {
         Command {
             arguments,
             response_channel,
+            client: None,
+            replication_length: None,
+        }
+    }
+
+    pub fn with_client(self: Command, client: Client) -> Command {
+        Command {
+            client: Some(client),
+            ..self
         }
     }
+
+    pub fn from_master(self: Command, replication_length: usize) -> Command {
+        Command {
+            replication_length: Some(replication_length),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("ERR Protocol error: expected an array of bulk strings")]
+    Protocol,
+    #[error("ERR unknown command '{0}'")]
+    UnknownCommand(ByteString),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(ByteString, String),
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Checks that a command was called with between `min` and `max` arguments,
+/// counting the command name itself.
+fn check_arity(arguments: &[ByteString], min: usize, max: usize) -> Result<(), CommandError> {
+    if arguments.len() < min || arguments.len() > max {
+        return Err(CommandError::WrongArity(arguments[0].to_lowercase()));
+    }
+    Ok(())
+}
+
+/// The error for commands that only make sense coming from a connection.
+fn client_required(command: &str) -> CommandError {
+    CommandError::Other(format!(
+        "ERR '{}' is only available to connected clients",
+        command
+    ))
+}
+
+#[derive(Debug)]
+enum Value {
+    /// Strings are bytes so bit and range commands can address them
+    /// directly, kept in the compact encodings of [`RedisString`].
+    String(RedisString),
+    Set(RedisSet),
+    SortedSet(SortedSet),
+    Stream(Stream),
 }
 
 #[derive(Debug)]
 struct DataValue {
-    parser_value: ParserValue,
+    value: Value,
     expiry_in_nanoseconds: Option<i64>,
+    access: AccessInfo,
 }
 
 impl DataValue {
-    pub fn new(parser_value: ParserValue) -> DataValue {
+    pub fn new(value: Value) -> DataValue {
         DataValue {
-            parser_value,
+            value,
             expiry_in_nanoseconds: None,
+            access: AccessInfo::new(),
         }
     }
 
-    pub fn set_expiry(self: &mut DataValue, milliseconds: i64) {
+    /// Expires the value `milliseconds` from now, or returns `None` when the
+    /// deadline can't be represented.
+    pub fn set_expiry(self: &mut DataValue, milliseconds: i64) -> Option<()> {
         let nano_seconds = Utc::now()
-            .add(TimeDelta::milliseconds(milliseconds))
-            .timestamp_nanos_opt()
-            .unwrap();
-        self.expiry_in_nanoseconds = Some(nano_seconds)
+            .checked_add_signed(TimeDelta::try_milliseconds(milliseconds)?)?
+            .timestamp_nanos_opt()?;
+        self.expiry_in_nanoseconds = Some(nano_seconds);
+        Some(())
     }
 
     pub fn has_expired(self: &DataValue) -> bool {
@@ -57,7 +226,7 @@ impl DataValue {
             return false;
         }
         let expiry_in_nanoseconds = self.expiry_in_nanoseconds.unwrap();
-        let now = Utc::now().timestamp_nanos_opt().unwrap();
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
         now > expiry_in_nanoseconds
     }
 }
@@ -77,21 +246,159 @@ impl fmt::Display for ReplicationRole {
     }
 }
 
+/// Hashes the keys of the keyspace. Both hashers are keyed randomly per
+/// process, so clients can't pick keys that collide; SipHash is the slower
+/// fallback when the `fast-hash` feature is off.
+#[cfg(feature = "fast-hash")]
+type KeyspaceHasher = ahash::RandomState;
+#[cfg(not(feature = "fast-hash"))]
+type KeyspaceHasher = std::collections::hash_map::RandomState;
+
+/// A key of the keyspace. The expiry index and WATCH hold clones of the
+/// keyspace's own key rather than copies of its bytes.
+type Key = Arc<[u8]>;
+
+type Keyspace = HashMap<Key, DataValue, KeyspaceHasher>;
+
+/// The keyspace and everything that changes it, owned by a single task.
+/// Connections send it [`Command`]s over a channel and wait for the reply
+/// on the command's own channel, so commands run one at a time, like in
+/// Redis, and MULTI, scripts and blocking commands need no locking.
 #[derive(Debug)]
 pub struct DataCore {
-    data_set: HashMap<String, DataValue>,
+    data_set: Keyspace,
+    expires: ExpiryIndex,
     rx: Receiver<Command>,
     replication_role: ReplicationRole,
-    connected_slaves: i64,
     master_replid: String,
+    /// The replication ID used before the current one, which replicas can
+    /// still continue from up to `second_reploffset`.
+    master_replid2: String,
     master_reploffset: i64,
     second_reploffset: i64,
-    repl_backlog_active: i64,
     repl_backlog_size: i64,
-    repl_backlog_first_byte_offset: i64,
-    repl_backlog_histlen: i64,
+    /// Created when the first replica attaches.
+    repl_backlog: Option<ReplicationBacklog>,
     master_host: Option<String>,
     master_port: Option<u64>,
+    set_limits: SetLimits,
+    sorted_set_limits: SortedSetLimits,
+    block_request: Option<BlockRequest>,
+    blocked_clients: BlockedClients,
+    /// The client of the command being run, if it came from a connection.
+    client: Option<Client>,
+    /// Every open client connection, shared with the server.
+    connections: Connections,
+    acl: Acl,
+    /// What clients call commands by, after `rename-command`.
+    command_table: CommandTable,
+    latency: LatencyMonitor,
+    stats: ServerStats,
+    pubsub: PubSub,
+    /// Replies sent ahead of the command's own, for commands like SUBSCRIBE
+    /// that answer once per argument.
+    pending_replies: Vec<ParserValue>,
+    /// Open MULTI blocks by client ID.
+    transactions: HashMap<u64, Transaction>,
+    /// Modification versions of the keys some client is watching.
+    key_versions: HashMap<Key, u64>,
+    watched_keys: HashMap<u64, WatchedKeys>,
+    /// Script bodies by their SHA1 digest, for EVALSHA.
+    scripts: HashMap<String, ByteString>,
+    /// Function libraries by name, loaded with FUNCTION LOAD.
+    libraries: BTreeMap<String, Library>,
+    /// How long a script runs before other clients get `-BUSY` replies.
+    lua_time_limit_ms: u64,
+    /// Whether expired keys are removed after every command, or only when
+    /// they are accessed. Toggled by DEBUG SET-ACTIVE-EXPIRE.
+    active_expire: bool,
+    replicas: Replicas,
+    /// What clients told with REPLCONF before sending PSYNC.
+    pending_replicas: HashMap<u64, PendingReplica>,
+    /// Whether full resyncs stream the RDB file to replicas that support it
+    /// instead of sending it with its length up front.
+    repl_diskless_sync: bool,
+    /// Set by commands that must not be answered, like REPLCONF ACK.
+    no_reply: bool,
+    /// Write commands are refused unless this many replicas acknowledged
+    /// the stream within the last `min_replicas_max_lag` seconds.
+    min_replicas_to_write: u64,
+    min_replicas_max_lag: u64,
+    /// Seconds between the heartbeats sent to replicas.
+    repl_ping_replica_period: u64,
+    /// How hard the access frequency counters of keys are to increment.
+    lfu_log_factor: u32,
+    /// The minutes without access for the counters to decay by one.
+    lfu_decay_time: u32,
+    next_replica_ping: Instant,
+    /// The port this server listens on, announced to masters.
+    port: u64,
+    /// Where the RDB file is read from at startup and saved to.
+    dir: String,
+    dbfilename: String,
+    background_save: Option<BackgroundSave>,
+    last_bgsave_ok: bool,
+    last_bgsave_duration: Option<Duration>,
+    last_bgsave_try: Option<Instant>,
+    appendonly: bool,
+    appendfsync: AppendFsync,
+    appendfilename: String,
+    /// Slot ownership, when cluster mode is enabled.
+    cluster: Option<ClusterState>,
+    /// The connection to the other nodes of the cluster, and when to next
+    /// send them heartbeats.
+    cluster_bus: Option<ClusterBus>,
+    /// Settings shared with the client connections.
+    connection_config: Arc<ConnectionConfig>,
+    next_cluster_ping: Instant,
+    /// The open append-only file, while appendonly is on.
+    aof: Option<AofWriter>,
+    aof_use_rdb_preamble: bool,
+    aof_rewrite: Option<AofRewrite>,
+    last_aof_rewrite_ok: bool,
+    last_aof_rewrite_duration: Option<Duration>,
+    save_rules: Vec<SaveRule>,
+    next_save_rules_check: Instant,
+    changes_since_last_save: u64,
+    /// Unix time of the last successful save.
+    last_save: i64,
+    /// The address announced to masters instead of the one they see, for
+    /// replicas behind NAT or in containers.
+    replica_announce_ip: Option<String>,
+    replica_announce_port: Option<u64>,
+    /// The link to the master while this node is a replica.
+    master_connection: Option<MasterConnection>,
+    /// When the last command came from the master, for INFO.
+    master_last_io: Option<Instant>,
+    /// When to next try to connect to the master, set by REPLICAOF and
+    /// when the link drops. Failed attempts back off exponentially.
+    reconnect_at: Option<Instant>,
+    reconnect_attempts: u32,
+    /// Whether the replication ID and offset are the master's, so a
+    /// reconnection can ask to continue from them.
+    synced_with_master: bool,
+}
+
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// The previous replication ID of a node that never changed its own.
+const NO_REPLICATION_ID: &str = "0000000000000000000000000000000000000000";
+
+fn new_replication_id() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect()
+}
+
+async fn recv_from_master(master_connection: Option<&mut MasterConnection>) -> Option<Command> {
+    master_connection?.recv().await
+}
+
+async fn recv_from_cluster_bus(cluster_bus: Option<&mut ClusterBus>) -> Option<BusEnvelope> {
+    cluster_bus?.recv().await
 }
 
 impl DataCore {
@@ -102,294 +409,743 @@ impl DataCore {
         master_port: Option<u64>,
     ) -> DataCore {
         DataCore {
-            data_set: HashMap::new(),
+            data_set: Keyspace::default(),
+            expires: ExpiryIndex::default(),
             rx,
             replication_role,
-            connected_slaves: 0,
-            master_replid: thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(40)
-                .map(char::from)
-                .collect(),
+            master_replid: new_replication_id(),
+            master_replid2: NO_REPLICATION_ID.to_string(),
             master_reploffset: 0,
             second_reploffset: -1,
-            repl_backlog_active: 0,
             repl_backlog_size: 1048576,
-            repl_backlog_first_byte_offset: 0,
-            repl_backlog_histlen: 0,
+            repl_backlog: None,
             master_host,
             master_port,
+            set_limits: SetLimits::default(),
+            sorted_set_limits: SortedSetLimits::default(),
+            block_request: None,
+            blocked_clients: BlockedClients::default(),
+            client: None,
+            connections: Connections::default(),
+            acl: Acl::default(),
+            command_table: CommandTable::default(),
+            latency: LatencyMonitor::default(),
+            stats: ServerStats::default(),
+            pubsub: PubSub::default(),
+            pending_replies: Vec::new(),
+            transactions: HashMap::new(),
+            key_versions: HashMap::new(),
+            watched_keys: HashMap::new(),
+            scripts: HashMap::new(),
+            libraries: BTreeMap::new(),
+            lua_time_limit_ms: 5000,
+            active_expire: true,
+            replicas: Replicas::new(),
+            pending_replicas: HashMap::new(),
+            repl_diskless_sync: true,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: 10,
+            no_reply: false,
+            repl_ping_replica_period: 10,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            next_replica_ping: Instant::now(),
+            port: 6379,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            background_save: None,
+            last_bgsave_ok: true,
+            last_bgsave_duration: None,
+            last_bgsave_try: None,
+            appendonly: false,
+            appendfsync: AppendFsync::Everysec,
+            appendfilename: "appendonly.aof".to_string(),
+            aof: None,
+            cluster: None,
+            cluster_bus: None,
+            connection_config: Arc::default(),
+            next_cluster_ping: Instant::now(),
+            aof_use_rdb_preamble: true,
+            aof_rewrite: None,
+            last_aof_rewrite_ok: true,
+            last_aof_rewrite_duration: None,
+            save_rules: default_save_rules(),
+            next_save_rules_check: Instant::now(),
+            changes_since_last_save: 0,
+            last_save: Utc::now().timestamp(),
+            replica_announce_ip: None,
+            replica_announce_port: None,
+            master_connection: None,
+            master_last_io: None,
+            reconnect_at: None,
+            reconnect_attempts: 0,
+            synced_with_master: false,
         }
     }
 
+    pub fn with_port(self: DataCore, port: u64) -> DataCore {
+        DataCore { port, ..self }
+    }
+
+    pub fn with_proto_max_bulk_len(self: DataCore, proto_max_bulk_len: usize) -> DataCore {
+        self.connection_config
+            .proto_max_bulk_len
+            .store(proto_max_bulk_len, Ordering::Relaxed);
+        self
+    }
+
+    pub fn with_tcp(
+        self: DataCore,
+        tcp_backlog: u32,
+        tcp_keepalive: u64,
+        tcp_nodelay: bool,
+    ) -> DataCore {
+        let config = &self.connection_config;
+        config.tcp_backlog.store(tcp_backlog, Ordering::Relaxed);
+        config.tcp_keepalive.store(tcp_keepalive, Ordering::Relaxed);
+        config.tcp_nodelay.store(tcp_nodelay, Ordering::Relaxed);
+        self
+    }
+
+    /// The settings the server applies to client connections.
+    pub fn connection_config(self: &DataCore) -> Arc<ConnectionConfig> {
+        self.connection_config.clone()
+    }
+
+    /// The registry the server adds client connections to.
+    pub fn connections(self: &DataCore) -> Connections {
+        self.connections.clone()
+    }
+
+    /// Sets the RDB save rules from their `CONFIG SET save` form.
+    pub fn with_save_rules(self: DataCore, save: &str) -> anyhow::Result<DataCore> {
+        let save_rules =
+            parse_save_rules(save).ok_or_else(|| anyhow!("invalid save rules: {:?}", save))?;
+        Ok(DataCore { save_rules, ..self })
+    }
+
+    /// Makes clients call the command `name` by `new_name`, or disables
+    /// it when `new_name` is empty.
+    pub fn with_renamed_command(
+        mut self: DataCore,
+        name: &str,
+        new_name: &str,
+    ) -> anyhow::Result<DataCore> {
+        self.command_table
+            .rename(name, new_name)
+            .map_err(|err| anyhow!(err))?;
+        Ok(self)
+    }
+
+    pub fn with_rdb_file(self: DataCore, dir: String, dbfilename: String) -> DataCore {
+        DataCore {
+            dir,
+            dbfilename,
+            ..self
+        }
+    }
+
+    pub fn with_replica_announce(
+        self: DataCore,
+        replica_announce_ip: Option<String>,
+        replica_announce_port: Option<u64>,
+    ) -> DataCore {
+        DataCore {
+            replica_announce_ip,
+            replica_announce_port,
+            ..self
+        }
+    }
+
+    /// Serves commands until every sender of the command channel is gone.
     pub async fn process_command(self: &mut DataCore) {
-        while let Some(command) = self.rx.recv().await {
-            eprintln!("Process Command {:?}", command);
-            let first = command
-                .arguments
-                .first()
-                .expect("arguments should have at least one argument");
-            match first.to_string().unwrap().to_lowercase().as_str() {
-                "ping" => {
-                    let parser_value = ParserValue::SimpleString(String::from("PONG"));
-                    let response = parser_value.to_tokens();
-                    eprintln!("PING response_tokens {:?}", response);
-                    command.response_channel.send(response).unwrap();
-                }
-                "echo" => {
-                    let mut tokens: Vec<tokenizer::Token> = Vec::new();
-                    let mut iter = command.arguments.iter();
-                    let _ = iter.next();
-                    // TODO: how to handle multiple strings passed to echo?
-                    while let Some(echo_str_token) = iter.next() {
-                        if let Some(echo_str) = echo_str_token.to_string() {
-                            let parser_value = ParserValue::BulkString(echo_str);
-                            let mut response_tokens = parser_value.to_tokens();
-                            tokens.append(&mut response_tokens);
+        loop {
+            let deadline = self.next_blocked_deadline();
+            let has_replicas = !self.replicas.is_empty();
+            let has_master = self.master_connection.is_some();
+            let reconnect_at = self.reconnect_at;
+            let has_background_save = self.background_save.is_some();
+            let has_aof_rewrite = self.aof_rewrite.is_some();
+            let has_cluster_bus = self.cluster_bus.is_some();
+            let check_save_rules = !has_background_save
+                && self.changes_since_last_save > 0
+                && !self.save_rules.is_empty();
+            let command = tokio::select! {
+                command = self.rx.recv() => command,
+                command = recv_from_master(self.master_connection.as_mut()), if has_master => {
+                    match command {
+                        Some(command) => {
+                            self.master_last_io = Some(Instant::now());
+                            Some(command)
                         }
-                    }
-                    command.response_channel.send(tokens).unwrap();
-                }
-                "set" => {
-                    let mut iter = command.arguments.iter().peekable();
-                    let _ = iter.next();
-                    let key = iter.next().expect("set command should have a key");
-                    let value = iter.next().expect("set command should have a value");
-                    eprintln!("Key: {:?}", key);
-                    eprintln!("Value: {:?}", value);
-
-                    if !key.is_string() {
-                        let response_value = ParserValue::NullBulkString;
-                        return command
-                            .response_channel
-                            .send(response_value.to_tokens())
-                            .unwrap();
-                    }
-
-                    let key = key
-                        .to_string()
-                        .expect("string parser value should be convertable to string");
-                    let mut data_value = DataValue::new(value.clone());
-
-                    if iter.peek().is_some_and(|pv| pv.is_string()) {
-                        let _ = iter.next().unwrap().to_string().unwrap();
-                        if iter.peek().is_some_and(|len| len.is_string()) {
-                            let len = iter.next().unwrap().to_string().unwrap();
-                            let len = len.parse::<i64>().expect("len string should be i64");
-                            data_value.set_expiry(len)
+                        None => {
+                            warn!("lost connection to master");
+                            self.master_connection = None;
+                            self.reconnect_at = Some(Instant::now());
+                            continue;
                         }
                     }
-                    self.data_set.insert(key, data_value);
-                    let parser_value = ParserValue::SimpleString(String::from("OK"));
-                    let response_tokens = parser_value.to_tokens();
-                    command.response_channel.send(response_tokens).unwrap();
                 }
-                "get" => {
-                    let mut iter = command.arguments.iter();
-                    let _ = iter.next();
-                    let key = iter.next().expect("get command should have a key");
-                    if !key.is_string() {
-                        let response_value = ParserValue::NullBulkString;
-                        return command
-                            .response_channel
-                            .send(response_value.to_tokens())
-                            .unwrap();
-                    }
-
-                    let key = key
-                        .to_string()
-                        .expect("string parser value should be convertable to a string");
-                    let value = self.data_set.get(&key);
-                    if value.is_none() {
-                        let response_value = ParserValue::NullBulkString;
-                        return command
-                            .response_channel
-                            .send(response_value.to_tokens())
-                            .unwrap();
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.time_out_blocked_clients();
+                    continue;
+                }
+                _ = sleep_until(self.next_replica_ping), if has_replicas => {
+                    self.ping_replicas();
+                    continue;
+                }
+                result = wait_for_background_save(self.background_save.as_mut()), if has_background_save => {
+                    if let Some(result) = result {
+                        self.finish_background_save(result);
                     }
-                    let value = value.unwrap();
-                    let now = Utc::now().timestamp_nanos_opt().unwrap();
-                    eprintln!("{:?} {:?}", value, now);
-                    if value.has_expired() {
-                        let _ = self.data_set.remove(&key);
-                        let response_value = ParserValue::NullBulkString;
-                        return command
-                            .response_channel
-                            .send(response_value.to_tokens())
-                            .unwrap();
+                    continue;
+                }
+                result = wait_for_aof_rewrite(self.aof_rewrite.as_mut()), if has_aof_rewrite => {
+                    if let Some(result) = result {
+                        self.finish_aof_rewrite(result);
                     }
-
-                    command
-                        .response_channel
-                        .send(value.parser_value.to_tokens())
-                        .unwrap()
+                    continue;
                 }
-                "command" => {
-                    let parser_value = ParserValue::SimpleString(String::from(""));
-                    let response = parser_value.to_tokens();
-                    eprintln!("COMMAND response_tokens {:?}", response);
-                    command.response_channel.send(response).unwrap();
+                _ = sleep_until(self.next_save_rules_check), if check_save_rules => {
+                    self.run_save_rules();
+                    continue;
                 }
-                "info" => {
-                    let str = format!(
-                        "# Replication\nrole:{}\nconnected_slaves:{}\nmaster_replid:{}\nmaster_repl_offset:{}\nsecond_repl_offset:{}\nrepl_backlog_active:{}\nrepl_backlog_size:{}\nrepl_backlog_first_byte_offset:{}\nrepl_backlog_histen:{}",
-                        self.replication_role.to_string(),
-                        self.connected_slaves,
-                        self.master_replid,
-                        self.master_reploffset,
-                        self.second_reploffset,
-                        self.repl_backlog_active,
-                        self.repl_backlog_size,
-                        self.repl_backlog_first_byte_offset,
-                        self.repl_backlog_histlen
-                    );
-                    let response_value = ParserValue::BulkString(str);
-                    return command
-                        .response_channel
-                        .send(response_value.to_tokens())
-                        .unwrap();
+                envelope = recv_from_cluster_bus(self.cluster_bus.as_mut()), if has_cluster_bus => {
+                    if let Some(envelope) = envelope {
+                        self.handle_bus_message(envelope);
+                    }
+                    continue;
                 }
-                "replconf" => {
-                    let parser_value = ParserValue::SimpleString(String::from("OK"));
-                    let response = parser_value.to_tokens();
-                    eprintln!("REPLCONF Response {:?}", response);
-                    command.response_channel.send(response).unwrap();
+                _ = sleep_until(self.next_cluster_ping), if has_cluster_bus => {
+                    self.ping_cluster_nodes();
+                    continue;
                 }
-                "psync" => {
-                    let parser_value = ParserValue::SimpleString(String::from(
-                        "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0",
-                    ));
-                    let response = parser_value.to_tokens();
-                    eprintln!("PSYNC Response {:?}", response);
-                    command.response_channel.send(response).unwrap();
+                _ = sleep_until(reconnect_at.unwrap_or_else(Instant::now)), if reconnect_at.is_some() => {
+                    self.reconnect_to_master().await;
+                    continue;
+                }
+            };
+            let Some(mut command) = command else {
+                break;
+            };
+
+            trace!(?command, "processing command");
+            let response = self.run_command(&mut command);
+            trace!(?response, "command response");
+            match self.block_request.take() {
+                Some(request) => self.park_blocked_client(command, request),
+                None => {
+                    let _ = command.response_channel.send(response);
+                    self.serve_blocked_clients();
                 }
-                _ => todo!(),
             }
 
-            self.remove_expired_values()
+            if self.active_expire {
+                self.remove_expired_values()
+            }
         }
     }
 
-    pub fn remove_expired_values(self: &mut DataCore) {
-        eprintln!("Remove Expired Values");
-        self.data_set.retain(|_, v| !v.has_expired())
+    /// Executes a command without ever blocking: blocking commands that find
+    /// nothing to serve reply as if they had timed out.
+    pub fn execute(self: &mut DataCore, arguments: &[ParserValue]) -> ParserValue {
+        let response = match arguments
+            .iter()
+            .map(|argument| argument.to_bytes())
+            .collect::<Option<Vec<ByteString>>>()
+        {
+            Some(mut arguments) => self.run(&mut arguments),
+            None => self.reply(Err(CommandError::Protocol)),
+        };
+        self.block_request = None;
+        self.pending_replies.clear();
+        self.no_reply = false;
+        response
     }
 
-    pub async fn initialize_slaves(
+    /// Runs a command on behalf of its client, returning the tokens of every
+    /// reply it produced.
+    fn run_command(self: &mut DataCore, command: &mut Command) -> Vec<Token> {
+        self.client = command.client.clone();
+        // Only the master expires keys on a replica, but its clients must
+        // not see them.
+        let hidden = if self.is_slave() && command.replication_length.is_none() {
+            self.hide_expired_keys()
+        } else {
+            Vec::new()
+        };
+        let response = self.run(&mut command.arguments);
+        self.restore_hidden_keys(hidden);
+        self.client = None;
+        if let Some(client) = &command.client {
+            let no_idle_timeout =
+                self.pubsub.is_subscribed(client.id) || self.replicas.contains_key(&client.id);
+            client
+                .no_idle_timeout
+                .store(no_idle_timeout, Ordering::Relaxed);
+        }
+        if let Some(length) = command.replication_length {
+            self.proxy_to_replicas(&command.arguments);
+            self.master_reploffset += length as i64;
+        }
+        if std::mem::take(&mut self.no_reply) {
+            self.pending_replies.clear();
+            return Vec::new();
+        }
+        let mut tokens = self
+            .pending_replies
+            .drain(..)
+            .flat_map(|reply| reply.to_tokens())
+            .collect::<Vec<_>>();
+        tokens.extend(response.to_tokens());
+        tokens
+    }
+
+    /// Replaces the name clients called a command by with its own, so the
+    /// rest of the data core, the AOF and replicas never see renames.
+    /// Commands not sent by clients already use their own names.
+    fn resolve_command_name(
+        self: &DataCore,
+        arguments: &mut [ByteString],
+    ) -> Result<(), CommandError> {
+        if self.client.is_none() {
+            return Ok(());
+        }
+        let spec = self
+            .command_table
+            .get(&arguments[0].to_lowercase())
+            .ok_or_else(|| CommandError::UnknownCommand(arguments[0].clone()))?;
+        if !arguments[0].eq_ignore_ascii_case(spec.name) {
+            arguments[0] = ByteString::from(spec.name);
+        }
+        Ok(())
+    }
+
+    fn run(self: &mut DataCore, arguments: &mut [ByteString]) -> ParserValue {
+        if arguments.is_empty() {
+            self.touch_client(arguments);
+            return self.reply(Err(CommandError::Protocol));
+        }
+        let started = Instant::now();
+        let resolved = self.resolve_command_name(arguments);
+        self.touch_client(arguments);
+        let result = resolved
+            .and_then(|()| self.check_cluster_redirect(arguments))
+            .and_then(|()| self.dispatch_isolated(arguments));
+        let fast = commands::lookup(&arguments[0].to_lowercase())
+            .is_some_and(|spec| spec.has_flag("fast"));
+        let event = if fast { "fast-command" } else { "command" };
+        self.latency.record(event, started.elapsed());
+        self.stats.total_commands_processed += 1;
+        self.reply(result)
+    }
+
+    /// Turns the result of a command into its reply, counting errors.
+    fn reply(self: &mut DataCore, result: Result<ParserValue, CommandError>) -> ParserValue {
+        let reply = result.unwrap_or_else(|err| ParserValue::Error(err.to_string()));
+        if matches!(reply, ParserValue::Error(_)) {
+            self.stats.total_error_replies += 1;
+        }
+        reply
+    }
+
+    /// Dispatches a command, turning a panic into an error reply so one bad
+    /// command can't stop the data core for every other client.
+    fn dispatch_isolated(
         self: &mut DataCore,
-        slave_port: u64,
-    ) -> anyhow::Result<(), Box<dyn Error>> {
-        let ping = ParserValue::Array(vec![ParserValue::SimpleString("PING".to_string())]);
-        let master_connection_string = format!(
-            "{}:{}",
-            self.master_host.as_ref().unwrap(),
-            self.master_port.unwrap()
-        );
-        eprintln!("Master connection string: {:?}", master_connection_string);
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.dispatch(arguments))).unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            error!(command = %arguments[0], message, "command panicked");
+            self.block_request = None;
+            self.pending_replies.clear();
+            Err(CommandError::Other(format!(
+                "ERR internal error running '{}'",
+                arguments[0].to_lowercase()
+            )))
+        })
+    }
 
-        let mut stream = TcpStream::connect(master_connection_string).await?;
-        stream.writable().await?;
+    fn dispatch(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        let name = arguments[0].to_lowercase();
+        self.check_subscribed_context(&name)?;
+        self.check_acl(&name, arguments)?;
+        if let Some(queued) = self.queue_in_transaction(&name, arguments) {
+            return queued;
+        }
+        self.check_min_replicas(&name)?;
+        let result = self.call(&name, arguments);
+        // Blocked commands changed nothing yet: they signal their keys and
+        // are counted and propagated once they are served.
+        if result.is_ok() && self.block_request.is_none() {
+            self.signal_modified_keys(&name, arguments);
+            self.count_changes(&name, arguments);
+            self.propagate(&name, arguments);
+        }
+        result
+    }
 
-        let ping = tokenizer::serialize_tokens(&ping.to_tokens())
-            .expect("ping parser value array should be serializable");
-        stream.write_all(ping.into_bytes().as_ref()).await?;
-        stream.flush().await?;
+    fn call(
+        self: &mut DataCore,
+        name: &str,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        match name {
+            "ping" => Ok(self
+                .subscribed_ping(arguments)
+                .unwrap_or_else(|| ParserValue::SimpleString(String::from("PONG")))),
+            "echo" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::BulkString(arguments[1].clone()))
+            }
+            // The server closes the connection once this reply is sent.
+            "quit" => Ok(ParserValue::SimpleString(String::from("OK"))),
+            "set" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                let mut iter = arguments.iter().skip(1).peekable();
+                let key = iter.next().unwrap();
+                let value = iter.next().unwrap();
 
-        let mut buff = [0; 8];
-        loop {
-            let response = stream.read(&mut buff).await?;
-            eprintln!("Ping Response Length: {:?}", response);
-            if response == 7 {
-                break;
+                let mut data_value = DataValue::new(Value::String(RedisString::from_slice(value)));
+
+                if iter.next().is_some() {
+                    if let Some(len) = iter.next() {
+                        let len = len.parse::<i64>().map_err(|_| CommandError::NotInteger)?;
+                        data_value
+                            .set_expiry(len)
+                            .filter(|_| len > 0)
+                            .ok_or_else(|| {
+                                CommandError::Other(
+                                    "ERR invalid expire time in 'set' command".to_string(),
+                                )
+                            })?;
+                    }
+                }
+                self.insert_value(key, data_value);
+                Ok(ParserValue::SimpleString(String::from("OK")))
             }
-        }
-        eprintln!(
-            "Initialize Slaves Ping Response: {:?}",
-            String::from_utf8(buff.to_vec())
-        );
+            "get" => {
+                check_arity(arguments, 2, 2)?;
+                let key = &arguments[1];
+                let value = match self.lookup(key) {
+                    Some(value) => value,
+                    None => return Ok(ParserValue::NullBulkString),
+                };
 
-        let listening_port = ParserValue::Array(vec![
-            ParserValue::SimpleString("REPLCONF".to_string()),
-            ParserValue::SimpleString("listening-port".to_string()),
-            ParserValue::SimpleString(slave_port.to_string()),
-        ]);
-        let listening_port = tokenizer::serialize_tokens(&listening_port.to_tokens())
-            .expect("listening-port parser value array should be serializable");
-        stream
-            .write_all(listening_port.into_bytes().as_ref())
-            .await?;
-        stream.flush().await?;
-
-        let mut buff = [0; 8];
-        loop {
-            let response = stream.read(&mut buff).await?;
-            eprintln!("Listening Port Response Length: {:?}", response);
-            if response == 5 {
-                break;
+                match &value.value {
+                    Value::String(string) => Ok(ParserValue::BulkString(ByteString::new(
+                        string.to_bytes().into_owned(),
+                    ))),
+                    _ => Err(CommandError::WrongType),
+                }
+            }
+            "mset" => {
+                if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+                    return Err(CommandError::WrongArity(name.to_string()));
+                }
+                for pair in arguments[1..].chunks(2) {
+                    let value = DataValue::new(Value::String(RedisString::from_slice(&pair[1])));
+                    self.insert_value(&pair[0], value);
+                }
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            "del" | "unlink" => self.del(arguments),
+            "command" => self.command_command(arguments),
+            "info" => self.info(arguments),
+            "replconf" => self.replconf(arguments),
+            "wait" => self.wait(arguments),
+            "replicaof" | "slaveof" => self.replicaof(arguments),
+            "psync" => self.psync(arguments),
+            "config" => self.config(arguments),
+            "object" => self.object(arguments),
+            "debug" => self.debug(arguments),
+            "cluster" => self.cluster(arguments),
+            "asking" => self.asking(arguments),
+            "client" => self.client_command(arguments),
+            "latency" => self.latency(arguments),
+            "memory" => self.memory(arguments),
+            "auth" => self.auth(arguments),
+            "acl" => self.acl(arguments),
+            "save" => self.save(arguments),
+            "bgsave" => self.bgsave(arguments),
+            "lastsave" => self.lastsave(arguments),
+            "bgrewriteaof" => self.bgrewriteaof(arguments),
+            "sadd" => self.sadd(arguments),
+            "srem" => self.srem(arguments),
+            "smembers" => self.smembers(arguments),
+            "sismember" => self.sismember(arguments),
+            "scard" => self.scard(arguments),
+            "smove" => self.smove(arguments),
+            "sinter" => self.set_operation_command(SetOperation::Intersection, arguments),
+            "sunion" => self.set_operation_command(SetOperation::Union, arguments),
+            "sdiff" => self.set_operation_command(SetOperation::Difference, arguments),
+            "sinterstore" => {
+                self.set_operation_store_command(SetOperation::Intersection, arguments)
             }
+            "sunionstore" => self.set_operation_store_command(SetOperation::Union, arguments),
+            "sdiffstore" => self.set_operation_store_command(SetOperation::Difference, arguments),
+            "zadd" => self.zadd(arguments),
+            "zrange" => self.zrange(arguments),
+            "zrank" => self.zrank(arguments, false),
+            "zrevrank" => self.zrank(arguments, true),
+            "zscore" => self.zscore(arguments),
+            "zmscore" => self.zmscore(arguments),
+            "zincrby" => self.zincrby(arguments),
+            "zcard" => self.zcard(arguments),
+            "zcount" => self.zcount(arguments),
+            "zlexcount" => self.zlexcount(arguments),
+            "zrandmember" => self.zrandmember(arguments),
+            "zscan" => self.zscan(arguments),
+            "zrangestore" => self.zrangestore(arguments),
+            "zunion" => self.zset_operation_command(ZSetOperation::Union, arguments),
+            "zinter" => self.zset_operation_command(ZSetOperation::Intersection, arguments),
+            "zdiff" => self.zset_operation_command(ZSetOperation::Difference, arguments),
+            "zunionstore" => self.zset_operation_store_command(ZSetOperation::Union, arguments),
+            "zinterstore" => {
+                self.zset_operation_store_command(ZSetOperation::Intersection, arguments)
+            }
+            "zdiffstore" => self.zset_operation_store_command(ZSetOperation::Difference, arguments),
+            "zrem" => self.zrem(arguments),
+            "zremrangebyrank" => self.zremrange(arguments, RangeKind::Rank),
+            "zremrangebyscore" => self.zremrange(arguments, RangeKind::Score),
+            "zremrangebylex" => self.zremrange(arguments, RangeKind::Lex),
+            "zpopmin" => self.zpop(arguments, false),
+            "zpopmax" => self.zpop(arguments, true),
+            "zmpop" => self.zmpop(arguments),
+            "bzpopmin" => self.bzpop(arguments, false),
+            "bzpopmax" => self.bzpop(arguments, true),
+            "bzmpop" => self.bzmpop(arguments),
+            "zrevrange" => self.zrange_legacy(RangeKind::Rank, true, arguments),
+            "zrangebyscore" => self.zrange_legacy(RangeKind::Score, false, arguments),
+            "zrevrangebyscore" => self.zrange_legacy(RangeKind::Score, true, arguments),
+            "zrangebylex" => self.zrange_legacy(RangeKind::Lex, false, arguments),
+            "zrevrangebylex" => self.zrange_legacy(RangeKind::Lex, true, arguments),
+            "xadd" => self.xadd(arguments),
+            "xrange" => self.xrange(arguments, false),
+            "xrevrange" => self.xrange(arguments, true),
+            "xlen" => self.xlen(arguments),
+            "xread" => self.xread(arguments),
+            "xreadgroup" => self.xreadgroup(arguments),
+            "xgroup" => self.xgroup(arguments),
+            "xack" => self.xack(arguments),
+            "xdel" => self.xdel(arguments),
+            "xtrim" => self.xtrim(arguments),
+            "xsetid" => self.xsetid(arguments),
+            "xinfo" => self.xinfo(arguments),
+            "setbit" => self.setbit(arguments),
+            "getbit" => self.getbit(arguments),
+            "bitcount" => self.bitcount(arguments),
+            "bitpos" => self.bitpos(arguments),
+            "bitop" => self.bitop(arguments),
+            "bitfield" => self.bitfield(arguments, false),
+            "bitfield_ro" => self.bitfield(arguments, true),
+            "pfadd" => self.pfadd(arguments),
+            "pfcount" => self.pfcount(arguments),
+            "pfmerge" => self.pfmerge(arguments),
+            "geoadd" => self.geoadd(arguments),
+            "geopos" => self.geopos(arguments),
+            "geodist" => self.geodist(arguments),
+            "subscribe" => self.subscribe(arguments, SubscriptionKind::Channel),
+            "psubscribe" => self.subscribe(arguments, SubscriptionKind::Pattern),
+            "unsubscribe" => self.unsubscribe(arguments, SubscriptionKind::Channel),
+            "punsubscribe" => self.unsubscribe(arguments, SubscriptionKind::Pattern),
+            "publish" => self.publish(arguments),
+            "pubsub" => self.pubsub(arguments),
+            "multi" => self.multi(arguments),
+            "exec" => self.exec(arguments),
+            "discard" => self.discard(arguments),
+            "watch" => self.watch(arguments),
+            "unwatch" => self.unwatch(arguments),
+            "eval" => self.eval(arguments),
+            "evalsha" => self.evalsha(arguments),
+            "script" => self.script(arguments),
+            "function" => self.function(arguments),
+            "fcall" => self.fcall(arguments, false),
+            "fcall_ro" => self.fcall(arguments, true),
+            _ => Err(CommandError::UnknownCommand(arguments[0].clone())),
         }
-        eprintln!(
-            "Initialize Slave listening-port Response: {:?}",
-            String::from_utf8(buff.to_vec())
-        );
+    }
 
-        let capabilities = ParserValue::Array(vec![
-            ParserValue::SimpleString("REPLCONF".to_string()),
-            ParserValue::SimpleString("capa".to_string()),
-            ParserValue::SimpleString("psync2".to_string()),
-        ]);
-        let capabilities = tokenizer::serialize_tokens(&capabilities.to_tokens())
-            .expect("capabilities parser value array should be serializable");
-        stream.write_all(capabilities.into_bytes().as_ref()).await?;
-        stream.flush().await?;
-
-        let mut buff = [0; 8];
-        loop {
-            let response = stream.read(&mut buff).await?;
-            eprintln!("Capa Response Length: {:?}", response);
-            if response == 5 {
-                break;
-            }
+    /// The value at `key`. Every command reads keys through this or
+    /// [`DataCore::lookup_mut`], which drop the key first if it expired, so
+    /// none of them sees stale values, and record the access.
+    fn lookup(self: &mut DataCore, key: &[u8]) -> Option<&DataValue> {
+        self.lookup_mut(key).map(|value| &*value)
+    }
+
+    fn lookup_mut(self: &mut DataCore, key: &[u8]) -> Option<&mut DataValue> {
+        self.expire_if_needed(key);
+        let (log_factor, decay_time) = (self.lfu_log_factor, self.lfu_decay_time);
+        let value = self.data_set.get_mut(key)?;
+        value.access.touch(log_factor, decay_time);
+        Some(value)
+    }
+
+    /// The copy of `key` the keyspace or WATCH already holds, if any, so
+    /// they share a single allocation per key.
+    fn intern_key(self: &DataCore, key: &[u8]) -> Key {
+        match self.data_set.get_key_value(key) {
+            Some((key, _)) => key.clone(),
+            None => self
+                .key_versions
+                .get_key_value(key)
+                .map_or_else(|| Key::from(key), |(key, _)| key.clone()),
         }
-        eprintln!(
-            "Initialize capabilities Response: {:?}",
-            String::from_utf8(buff.to_vec())
-        );
+    }
 
-        let psync = ParserValue::Array(vec![
-            ParserValue::BulkString("PSYNC".to_string()),
-            ParserValue::BulkString("?".to_string()),
-            ParserValue::BulkString("-1".to_string()),
-        ]);
-        let psync = tokenizer::serialize_tokens(&psync.to_tokens())
-            .expect("psync parser value array should be serializable");
-        stream.write_all(psync.into_bytes().as_ref()).await?;
-        stream.flush().await?;
-
-        let mut buff = [0; 58];
-        loop {
-            let response = stream.read(&mut buff).await?;
-            eprintln!("PSYNC Response Length: {:?}", response);
-            if response >= 56 {
-                break;
-            }
+    /// Like [`DataCore::lookup`], without counting as an access, for
+    /// commands that inspect keys like OBJECT.
+    fn peek(self: &mut DataCore, key: &[u8]) -> Option<&DataValue> {
+        self.expire_if_needed(key);
+        self.data_set.get(key)
+    }
+
+    /// Whether `key` exists and hasn't expired, for checks that can't
+    /// remove it.
+    fn has_live_key(self: &DataCore, key: &[u8]) -> bool {
+        self.data_set
+            .get(key)
+            .is_some_and(|value| !value.has_expired())
+    }
+
+    /// Drops `key` from the data set if its expiry has passed. Replicas
+    /// leave that to their master.
+    fn expire_if_needed(self: &mut DataCore, key: &[u8]) {
+        if !self.is_slave()
+            && self
+                .data_set
+                .get(key)
+                .is_some_and(|value| value.has_expired())
+        {
+            self.expire_key(key);
+        }
+    }
+
+    /// Removes an expired key, telling replicas to delete it too.
+    fn expire_key(self: &mut DataCore, key: &[u8]) {
+        self.remove_value(key);
+        self.stats.expired_keys += 1;
+        self.touch_key(key);
+        self.feed_aof(&["DEL".as_bytes(), key]);
+        self.feed_replicas(&["DEL".as_bytes(), key]);
+    }
+
+    /// Puts back keys taken by [`DataCore::hide_expired_keys`], unless the
+    /// command wrote them.
+    fn restore_hidden_keys(self: &mut DataCore, hidden: Vec<(Key, DataValue)>) {
+        for (key, value) in hidden {
+            self.data_set.entry(key).or_insert(value);
         }
-        eprintln!(
-            "Initialize capabilities Response: {:?}",
-            String::from_utf8(buff.to_vec())
+    }
+
+    /// Runs the replication handshake with the master and loads its data,
+    /// then starts following its command stream. After a lost link, the
+    /// master is asked to continue from the offset reached.
+    pub async fn initialize_slaves(self: &mut DataCore) -> anyhow::Result<(), Box<dyn Error>> {
+        let master_connection_string = format!(
+            "{}:{}",
+            self.master_host.as_ref().unwrap(),
+            self.master_port.unwrap()
+        );
+        info!(
+            master = master_connection_string,
+            "connecting to the master"
         );
 
-        let full_resync_response =
-            String::from_utf8(buff.to_vec()).expect("full resync response should be stringable");
-        let full_resync_response = full_resync_response.splitn(3, ' ').collect::<Vec<_>>();
-        let replica_id = full_resync_response
-            .get(1)
-            .expect("full resync response should have a replica_id");
-        eprintln!("Replica Id: {:?}", replica_id);
+        let max_bulk_len = self
+            .connection_config
+            .proto_max_bulk_len
+            .load(Ordering::Relaxed);
+        let mut link = MasterLink::connect(&master_connection_string)
+            .await?
+            .with_max_bulk_len(max_bulk_len);
+        let replication_id = self.master_replid.clone();
+        let resume = self
+            .synced_with_master
+            .then_some((replication_id.as_str(), self.master_reploffset + 1));
+        let listening_port = self.replica_announce_port.unwrap_or(self.port);
+        match link
+            .handshake(listening_port, self.replica_announce_ip.as_deref(), resume)
+            .await?
+        {
+            PsyncReply::FullResync {
+                replication_id,
+                offset,
+            } => {
+                info!(
+                    replication_id,
+                    offset, "full resynchronization with the master"
+                );
+                let rdb = link.read_rdb().await?;
+                info!("received an RDB payload of {} bytes", rdb.len());
+                self.load_rdb(&rdb)?;
+                // The append-only file must start over from the new data set.
+                if self.aof.is_some() && self.aof_rewrite.is_none() {
+                    self.start_aof_rewrite();
+                }
+                // A replica reports the replication ID and offset of its master.
+                self.master_replid = replication_id;
+                self.master_replid2 = NO_REPLICATION_ID.to_string();
+                self.master_reploffset = offset;
+                self.second_reploffset = -1;
+                // Replicas of this replica can't follow a new history either.
+                self.repl_backlog = None;
+                self.replicas.clear();
+            }
+            PsyncReply::Continue { replication_id } => {
+                info!("continuing from offset {}", self.master_reploffset);
+                if let Some(replication_id) =
+                    replication_id.filter(|replication_id| *replication_id != self.master_replid)
+                {
+                    self.shift_replication_id(replication_id);
+                }
+            }
+        }
+        // The master's stream is kept for replicas of this replica.
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(ReplicationBacklog::new(
+                self.repl_backlog_size as usize,
+                self.master_reploffset + 1,
+            ));
+        }
+        self.synced_with_master = true;
+        self.master_last_io = Some(Instant::now());
 
+        // A transaction the last link cut short is never executed.
+        self.discard_unattended_transaction();
+        self.master_connection = Some(MasterConnection::spawn(link, self.master_reploffset));
         Ok(())
     }
 
+    /// Retries the connection to the master, backing off exponentially
+    /// while it fails.
+    async fn reconnect_to_master(self: &mut DataCore) {
+        self.reconnect_at = None;
+        if !self.is_slave() {
+            return;
+        }
+        let Err(err) = self.initialize_slaves().await else {
+            self.reconnect_attempts = 0;
+            return;
+        };
+        let delay = RECONNECT_MAX_DELAY
+            .min(RECONNECT_BASE_DELAY * 2u32.pow(self.reconnect_attempts.min(16)));
+        warn!(
+            "unable to sync with master, retrying in {:?}: {}",
+            delay, err
+        );
+        self.reconnect_attempts += 1;
+        self.reconnect_at = Some(Instant::now() + delay);
+    }
+
     pub fn is_slave(self: &DataCore) -> bool {
         self.replication_role == ReplicationRole::Slave
     }
@@ -397,23 +1153,231 @@ impl DataCore {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
 
     use tokio::sync::{mpsc, oneshot};
 
-    use crate::data_core::{Command, DataCore, ReplicationRole};
+    use crate::data_core::{Client, Command, DataCore, ReplicationRole, Value};
     use crate::parser::ParserValue;
-    use crate::tokenizer::Token;
+    use crate::tokenizer::{self, Token};
 
-    #[test]
-    fn test_responds_to_ping_command() {
+    pub(crate) fn new_data_core() -> DataCore {
+        let (_command_tx, command_rx) = mpsc::channel::<Command>(32);
+        DataCore::new(command_rx, ReplicationRole::Master, None, None)
+    }
+
+    pub(crate) fn run(data_core: &mut DataCore, arguments: &[&str]) -> ParserValue {
+        let arguments = arguments
+            .iter()
+            .map(|argument| argument.as_bytes())
+            .collect::<Vec<_>>();
+        run_bytes(data_core, &arguments)
+    }
+
+    /// Like [`run`], with arguments that needn't be UTF-8.
+    pub(crate) fn run_bytes(data_core: &mut DataCore, arguments: &[&[u8]]) -> ParserValue {
+        let arguments = arguments
+            .iter()
+            .map(|argument| ParserValue::BulkString((*argument).into()))
+            .collect::<Vec<_>>();
+        data_core.execute(&arguments)
+    }
+
+    /// Like [`run`], but on behalf of a connected client.
+    pub(crate) fn run_as(
+        data_core: &mut DataCore,
+        client: &Client,
+        arguments: &[&str],
+    ) -> ParserValue {
+        data_core.client = Some(client.clone());
+        let response = run(data_core, arguments);
+        data_core.client = None;
+        response
+    }
+
+    #[tokio::test]
+    async fn test_responds_to_ping_command() {
         let (tx, rx) = oneshot::channel::<Vec<Token>>();
-        let command = Command::new(
-            Arc::new(vec![ParserValue::BulkString("PING".to_string())]),
-            tx,
-        );
+        let command = Command::new(vec!["PING".into()], tx);
 
         let (command_tx, command_rx) = mpsc::channel::<Command>(32);
-        let data_core = DataCore::new(command_rx, ReplicationRole::Master, None, None);
+        let mut data_core = DataCore::new(command_rx, ReplicationRole::Master, None, None);
+        tokio::spawn(async move {
+            data_core.process_command().await;
+        });
+
+        command_tx.send(command).await.unwrap();
+        let response = rx.await.unwrap();
+        assert_eq!(
+            ParserValue::SimpleString("PONG".to_string())
+                .to_tokens()
+                .len(),
+            response.len()
+        );
+        assert_eq!(Some("PONG".to_string()), response[1].to_string());
+    }
+
+    #[test]
+    fn test_get_missing_key_and_wrong_type() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "k"])
+        );
+        run(&mut data_core, &["SADD", "k", "a"]);
+        assert!(matches!(
+            run(&mut data_core, &["GET", "k"]),
+            ParserValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_set_refuses_expiry_out_of_range() {
+        let mut data_core = new_data_core();
+        let invalid = ParserValue::Error("ERR invalid expire time in 'set' command".to_string());
+        for milliseconds in ["99999999999999999", &i64::MAX.to_string(), "0", "-1"] {
+            assert_eq!(
+                invalid,
+                run(&mut data_core, &["SET", "k", "v", "PX", milliseconds])
+            );
+        }
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "k"])
+        );
+        run(&mut data_core, &["SET", "k", "v", "PX", "100000"]);
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut data_core, &["GET", "k"])
+        );
+    }
+
+    #[test]
+    fn test_stores_binary_values() {
+        let mut data_core = new_data_core();
+        let bytes = b"\x00nul\x00 \xff\xfe invalid \xc3";
+        run_bytes(&mut data_core, &[b"SET", b"k", bytes]);
+        run_bytes(&mut data_core, &[b"MSET", b"m", bytes]);
+        for key in ["k", "m"] {
+            assert!(
+                matches!(&data_core.data_set[key.as_bytes()].value, Value::String(stored) if *stored.to_bytes() == bytes[..])
+            );
+        }
+
+        let reply = run(&mut data_core, &["GET", "k"]);
+        let mut expected = format!("${}\r\n", bytes.len()).into_bytes();
+        expected.extend_from_slice(bytes);
+        expected.extend_from_slice(b"\r\n");
+        assert_eq!(
+            expected,
+            tokenizer::serialize_tokens_to_bytes(&reply.to_tokens()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_binary_keys_and_members_use_their_bytes() {
+        let mut data_core = new_data_core();
+        let (invalid, accented) = (&b"\x80"[..], "\u{e9}".as_bytes());
+        run_bytes(
+            &mut data_core,
+            &[b"ZADD", b"z", b"0", accented, b"0", invalid],
+        );
+        let bulk = |member: &[u8]| ParserValue::BulkString(member.into());
+        assert_eq!(
+            ParserValue::Array(vec![bulk(invalid), bulk(accented)]),
+            run(&mut data_core, &["ZRANGE", "z", "0", "-1"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![bulk(invalid)]),
+            run_bytes(
+                &mut data_core,
+                &[b"ZRANGEBYLEX", b"z", b"-", &[b"(", accented].concat()]
+            )
+        );
+
+        // The accented member is two bytes long.
+        assert_eq!(
+            ParserValue::Array(vec![
+                bulk(b"0"),
+                ParserValue::Array(vec![bulk(invalid), bulk(b"0")])
+            ]),
+            run(&mut data_core, &["ZSCAN", "z", "0", "MATCH", "?"])
+        );
+        assert_eq!(
+            ParserValue::Integer(crate::cluster::key_hash_slot(invalid) as i64),
+            run_bytes(&mut data_core, &[b"CLUSTER", b"KEYSLOT", invalid])
+        );
+    }
+
+    #[test]
+    fn test_expired_keys_on_every_access_path() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "string", "v"]);
+        run(&mut data_core, &["SADD", "set", "a", "b"]);
+        run(&mut data_core, &["ZADD", "zset", "1", "a"]);
+        run(&mut data_core, &["XADD", "stream", "*", "f", "v"]);
+        run(&mut data_core, &["SET", "other", "v"]);
+        for value in data_core.data_set.values_mut() {
+            value.expiry_in_nanoseconds = Some(1);
+        }
+
+        for (command, reply) in [
+            (vec!["GET", "string"], ParserValue::NullBulkString),
+            (vec!["SCARD", "set"], ParserValue::Integer(0)),
+            (vec!["ZCARD", "zset"], ParserValue::Integer(0)),
+            (vec!["XLEN", "stream"], ParserValue::Integer(0)),
+            (
+                vec!["OBJECT", "ENCODING", "other"],
+                ParserValue::NullBulkString,
+            ),
+        ] {
+            assert_eq!(reply, run(&mut data_core, &command), "{:?}", command);
+        }
+        assert!(data_core.data_set.is_empty());
+        assert_eq!(5, data_core.stats.expired_keys);
+
+        // Writes start over instead of adding to the expired value.
+        run(&mut data_core, &["SADD", "set", "c"]);
+        data_core
+            .data_set
+            .get_mut("set".as_bytes())
+            .unwrap()
+            .expiry_in_nanoseconds = Some(1);
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SADD", "set", "d"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SCARD", "set"])
+        );
+    }
+
+    #[test]
+    fn test_renamed_commands() {
+        let mut data_core = new_data_core()
+            .with_renamed_command("SET", "store")
+            .and_then(|data_core| data_core.with_renamed_command("sadd", ""))
+            .unwrap();
+        let (push_tx, _) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(1, push_tx);
+
+        assert_eq!(
+            ParserValue::Error("ERR unknown command 'SET'".to_string()),
+            run_as(&mut data_core, &client, &["SET", "k", "v"])
+        );
+        assert_eq!(
+            ParserValue::Error("ERR unknown command 'sadd'".to_string()),
+            run_as(&mut data_core, &client, &["sadd", "s", "a"])
+        );
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run_as(&mut data_core, &client, &["STORE", "k", "v"])
+        );
+        // The master and the AOF still use the commands' own names.
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SADD", "s", "a"])
+        );
+        assert!(new_data_core().with_renamed_command("nosuch", "x").is_err());
     }
 }
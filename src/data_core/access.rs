@@ -0,0 +1,126 @@
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+
+/// The LRU clock counts seconds in 24 bits, wrapping around every 194 days.
+const LRU_CLOCK_MAX: u32 = (1 << 24) - 1;
+/// The LFU clock counts minutes in 16 bits.
+const LFU_CLOCK_MAX: u32 = (1 << 16) - 1;
+/// The counter of new keys, so they aren't evicted before being accessed.
+const LFU_INIT_VAL: u8 = 5;
+
+fn lru_clock(now: i64) -> u32 {
+    now as u32 & LRU_CLOCK_MAX
+}
+
+fn lfu_clock(now: i64) -> u16 {
+    ((now / 60) as u32 & LFU_CLOCK_MAX) as u16
+}
+
+/// When a key was last accessed and how often it is, like the LRU and LFU
+/// fields of Redis objects. The frequency is a logarithmic counter that
+/// saturates at 255 and halves its growth as it gets higher, decaying by
+/// one every `lfu-decay-time` minutes without access.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AccessInfo {
+    lru_clock: u32,
+    lfu_clock: u16,
+    lfu_counter: u8,
+}
+
+impl AccessInfo {
+    pub(crate) fn new() -> AccessInfo {
+        let now = Utc::now().timestamp();
+        AccessInfo {
+            lru_clock: lru_clock(now),
+            lfu_clock: lfu_clock(now),
+            lfu_counter: LFU_INIT_VAL,
+        }
+    }
+
+    /// Records an access to the key.
+    pub(crate) fn touch(self: &mut AccessInfo, lfu_log_factor: u32, lfu_decay_time: u32) {
+        let now = Utc::now().timestamp();
+        let mut counter = self.frequency_at(now, lfu_decay_time);
+        if counter < u8::MAX {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if thread_rng().gen::<f64>() < 1.0 / (base * lfu_log_factor as f64 + 1.0) {
+                counter += 1;
+            }
+        }
+        self.lru_clock = lru_clock(now);
+        self.lfu_clock = lfu_clock(now);
+        self.lfu_counter = counter;
+    }
+
+    pub(crate) fn lru_clock(self: &AccessInfo) -> u32 {
+        self.lru_clock
+    }
+
+    /// The seconds since the key was last accessed, for OBJECT IDLETIME.
+    pub(crate) fn idle_seconds(self: &AccessInfo) -> u64 {
+        self.idle_seconds_at(Utc::now().timestamp())
+    }
+
+    fn idle_seconds_at(self: &AccessInfo, now: i64) -> u64 {
+        let clock = lru_clock(now);
+        if clock >= self.lru_clock {
+            (clock - self.lru_clock) as u64
+        } else {
+            (clock + (LRU_CLOCK_MAX - self.lru_clock)) as u64
+        }
+    }
+
+    /// The access frequency counter after its decay, for OBJECT FREQ.
+    pub(crate) fn frequency(self: &AccessInfo, lfu_decay_time: u32) -> u8 {
+        self.frequency_at(Utc::now().timestamp(), lfu_decay_time)
+    }
+
+    fn frequency_at(self: &AccessInfo, now: i64, lfu_decay_time: u32) -> u8 {
+        if lfu_decay_time == 0 {
+            return self.lfu_counter;
+        }
+        let clock = lfu_clock(now) as u32;
+        let last = self.lfu_clock as u32;
+        let elapsed = if clock >= last {
+            clock - last
+        } else {
+            clock + (LFU_CLOCK_MAX - last)
+        };
+        let periods = elapsed / lfu_decay_time;
+        self.lfu_counter
+            .saturating_sub(u8::try_from(periods).unwrap_or(u8::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::access::{AccessInfo, LFU_INIT_VAL, LRU_CLOCK_MAX};
+
+    #[test]
+    fn test_idle_time_and_frequency_decay() {
+        let access = AccessInfo {
+            lru_clock: 100,
+            lfu_clock: 10,
+            lfu_counter: 20,
+        };
+        assert_eq!(30, access.idle_seconds_at(130));
+        // The clock wrapped around since the access.
+        assert_eq!(
+            5 + (LRU_CLOCK_MAX - 100) as u64,
+            access.idle_seconds_at((LRU_CLOCK_MAX as i64 + 1) + 5)
+        );
+
+        assert_eq!(20, access.frequency_at(10 * 60, 1));
+        assert_eq!(17, access.frequency_at(13 * 60, 1));
+        assert_eq!(19, access.frequency_at(13 * 60, 2));
+        assert_eq!(0, access.frequency_at(1000 * 60, 1));
+        assert_eq!(20, access.frequency_at(1000 * 60, 0));
+
+        // With a log factor of 0 every access counts.
+        let mut access = AccessInfo::new();
+        for _ in 0..10 {
+            access.touch(0, 1);
+        }
+        assert_eq!(LFU_INIT_VAL + 10, access.frequency(1));
+    }
+}
@@ -0,0 +1,709 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::binary::ByteString;
+use crate::data_core::commands::{self, CommandSpec, COMMANDS};
+use crate::data_core::{check_arity, client_required, CommandError, DataCore};
+use crate::glob::glob_match;
+use crate::parser::ParserValue;
+
+/// Commands a client may send before it authenticated.
+const NO_AUTH_COMMANDS: &[&str] = &["auth", "hello", "quit"];
+
+/// The command categories of `spec`, as used in `+@category` rules. They
+/// follow from the command flags, plus a few named groups.
+pub(crate) fn categories(spec: &CommandSpec) -> Vec<&'static str> {
+    let mut categories = Vec::new();
+    for (flag, category) in [
+        ("write", "write"),
+        ("readonly", "read"),
+        ("admin", "admin"),
+        ("admin", "dangerous"),
+        ("pubsub", "pubsub"),
+        ("blocking", "blocking"),
+    ] {
+        if spec.has_flag(flag) {
+            categories.push(category);
+        }
+    }
+    categories.push(if spec.has_flag("fast") {
+        "fast"
+    } else {
+        "slow"
+    });
+    if !matches!(spec.keys, commands::KeySpec::None) {
+        categories.push("keyspace");
+    }
+    match spec.name {
+        "ping" | "echo" | "auth" | "client" | "quit" | "asking" | "wait" => {
+            categories.push("connection")
+        }
+        "multi" | "exec" | "discard" | "watch" | "unwatch" => categories.push("transaction"),
+        "eval" | "evalsha" | "script" | "function" | "fcall" | "fcall_ro" => {
+            categories.push("scripting")
+        }
+        _ => {}
+    }
+    categories
+}
+
+/// Passwords are only kept as SHA1 digests, like script bodies.
+fn hash_password(password: &[u8]) -> String {
+    sha1_smol::Sha1::from(password).digest().to_string()
+}
+
+/// An ACL user: how it authenticates and what it may run and access.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct User {
+    enabled: bool,
+    /// Authenticates with any password, or none.
+    nopass: bool,
+    password_hashes: BTreeSet<String>,
+    /// Set by `+@all`: every command is allowed except the denied ones.
+    /// Otherwise only the allowed ones are.
+    all_commands: bool,
+    allowed_commands: BTreeSet<String>,
+    denied_commands: BTreeSet<String>,
+    key_patterns: Vec<String>,
+}
+
+impl User {
+    /// The user clients are authenticated as when they connect, which
+    /// may do anything until it is given a password.
+    fn default_user() -> User {
+        User {
+            enabled: true,
+            nopass: true,
+            all_commands: true,
+            key_patterns: vec!["*".to_string()],
+            ..User::default()
+        }
+    }
+
+    fn allow_command(self: &mut User, name: &str) {
+        self.denied_commands.remove(name);
+        if !self.all_commands {
+            self.allowed_commands.insert(name.to_string());
+        }
+    }
+
+    fn deny_command(self: &mut User, name: &str) {
+        self.allowed_commands.remove(name);
+        if self.all_commands {
+            self.denied_commands.insert(name.to_string());
+        }
+    }
+
+    fn set_all_commands(self: &mut User, all_commands: bool) {
+        self.all_commands = all_commands;
+        self.allowed_commands.clear();
+        self.denied_commands.clear();
+    }
+
+    /// Applies one SETUSER rule.
+    fn apply_rule(self: &mut User, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.password_hashes.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.password_hashes.clear();
+            }
+            "allkeys" => self.key_patterns = vec!["*".to_string()],
+            "resetkeys" => self.key_patterns.clear(),
+            "allcommands" | "+@all" => self.set_all_commands(true),
+            "nocommands" | "-@all" => self.set_all_commands(false),
+            "reset" => *self = User::default(),
+            _ => return self.apply_argument_rule(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_argument_rule(self: &mut User, rule: &str) -> Result<(), String> {
+        let (prefix, value) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+        match prefix {
+            ">" => {
+                self.nopass = false;
+                self.password_hashes.insert(hash_password(value.as_bytes()));
+            }
+            "<" => {
+                if !self
+                    .password_hashes
+                    .remove(&hash_password(value.as_bytes()))
+                {
+                    return Err("no such password".to_string());
+                }
+            }
+            "#" | "!" => {
+                let hash = value.to_lowercase();
+                if hash.len() != 40 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err("The password hash must be exactly 40 characters and contain only lowercase hexadecimal characters".to_string());
+                }
+                if prefix == "#" {
+                    self.nopass = false;
+                    self.password_hashes.insert(hash);
+                } else if !self.password_hashes.remove(&hash) {
+                    return Err("no such password".to_string());
+                }
+            }
+            "~" => self.key_patterns.push(value.to_string()),
+            "+" | "-" => {
+                let names = match value.strip_prefix('@') {
+                    Some(category) => {
+                        let names = COMMANDS
+                            .iter()
+                            .filter(|spec| categories(spec).contains(&category))
+                            .map(|spec| spec.name)
+                            .collect::<Vec<_>>();
+                        if names.is_empty() {
+                            return Err("Unknown command or category name in ACL".to_string());
+                        }
+                        names
+                    }
+                    None => match commands::lookup(&value.to_lowercase()) {
+                        Some(spec) => vec![spec.name],
+                        None => return Err("Unknown command or category name in ACL".to_string()),
+                    },
+                };
+                for name in names {
+                    if prefix == "+" {
+                        self.allow_command(name);
+                    } else {
+                        self.deny_command(name);
+                    }
+                }
+            }
+            _ => return Err("Syntax error".to_string()),
+        }
+        Ok(())
+    }
+
+    fn can_run(self: &User, name: &str) -> bool {
+        if self.all_commands {
+            !self.denied_commands.contains(name)
+        } else {
+            self.allowed_commands.contains(name)
+        }
+    }
+
+    fn can_access(self: &User, key: &[u8]) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|pattern| glob_match(pattern, key))
+    }
+
+    fn authenticates_with(self: &User, password: &[u8]) -> bool {
+        self.enabled && (self.nopass || self.password_hashes.contains(&hash_password(password)))
+    }
+
+    fn flags(self: &User) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    /// The command rules that rebuild this user's permissions.
+    fn command_rules(self: &User) -> String {
+        let mut rules = if self.all_commands {
+            vec!["+@all".to_string()]
+        } else {
+            vec!["-@all".to_string()]
+        };
+        rules.extend(
+            self.allowed_commands
+                .iter()
+                .map(|name| format!("+{}", name)),
+        );
+        rules.extend(self.denied_commands.iter().map(|name| format!("-{}", name)));
+        rules.join(" ")
+    }
+
+    fn key_rules(self: &User) -> String {
+        self.key_patterns
+            .iter()
+            .map(|pattern| format!("~{}", pattern))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The user as an ACL LIST line, without its name.
+    fn describe(self: &User) -> String {
+        let mut rules = self
+            .flags()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        rules.extend(self.password_hashes.iter().map(|hash| format!("#{}", hash)));
+        let keys = self.key_rules();
+        if !keys.is_empty() {
+            rules.push(keys);
+        }
+        rules.push(self.command_rules());
+        rules.join(" ")
+    }
+}
+
+/// Users by name. There is always a `default` user.
+#[derive(Debug)]
+pub(crate) struct Acl {
+    users: BTreeMap<String, User>,
+    /// The password of the default user set with `requirepass`.
+    requirepass: String,
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        Acl {
+            users: BTreeMap::from([("default".to_string(), User::default_user())]),
+            requirepass: String::new(),
+        }
+    }
+}
+
+impl Acl {
+    pub(crate) fn requirepass(self: &Acl) -> &str {
+        &self.requirepass
+    }
+
+    /// Makes `password` the only password of the default user, or lets
+    /// it in without one when empty, like `requirepass` does in Redis.
+    pub(crate) fn set_requirepass(self: &mut Acl, password: &str) {
+        let user = self
+            .users
+            .entry("default".to_string())
+            .or_insert_with(User::default_user);
+        if password.is_empty() {
+            user.nopass = true;
+            user.password_hashes.clear();
+        } else {
+            user.nopass = false;
+            user.password_hashes = BTreeSet::from([hash_password(password.as_bytes())]);
+        }
+        self.requirepass = password.to_string();
+    }
+}
+
+impl DataCore {
+    /// Requires clients to authenticate with `password` as the default
+    /// user. Empty to need no password.
+    pub fn with_requirepass(mut self: DataCore, password: &str) -> DataCore {
+        self.acl.set_requirepass(password);
+        self
+    }
+
+    /// The name of the user the current client authenticated as, if any.
+    fn authenticated_user(self: &DataCore) -> Option<String> {
+        let client = self.client.as_ref()?;
+        self.connections
+            .get(client.id)
+            .and_then(|connection| connection.user)
+    }
+
+    /// The user the current client runs commands as. Until it
+    /// authenticates, that's the default user when it needs no password.
+    fn current_user(self: &DataCore) -> Option<&User> {
+        match self.authenticated_user() {
+            Some(name) => self.acl.users.get(&name),
+            None => self
+                .acl
+                .users
+                .get("default")
+                .filter(|user| user.enabled && user.nopass),
+        }
+    }
+
+    /// Checks that the current client may run the command of `arguments`
+    /// on its keys. Commands not sent by clients, like those of the master
+    /// or the AOF, are always allowed.
+    pub(crate) fn check_acl(
+        self: &mut DataCore,
+        name: &str,
+        arguments: &[ByteString],
+    ) -> Result<(), CommandError> {
+        if self.client.is_none() || NO_AUTH_COMMANDS.contains(&name) {
+            return Ok(());
+        }
+        let result = match self.current_user() {
+            None => Err(CommandError::Other(
+                "NOAUTH Authentication required.".to_string(),
+            )),
+            Some(user) if !user.can_run(name) => Err(CommandError::Other(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                self.authenticated_user()
+                    .unwrap_or_else(|| "default".to_string()),
+                name
+            ))),
+            Some(user) => {
+                let keys = commands::lookup(name)
+                    .map(|spec| spec.keys(arguments))
+                    .unwrap_or_default();
+                if keys.iter().all(|key| user.can_access(key)) {
+                    Ok(())
+                } else {
+                    Err(CommandError::Other(
+                        "NOPERM No permissions to access a key".to_string(),
+                    ))
+                }
+            }
+        };
+        if result.is_err() {
+            self.reject_in_transaction(name);
+        }
+        result
+    }
+
+    /// AUTH [username] password
+    pub(crate) fn auth(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 3)?;
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| client_required("auth"))?
+            .id;
+        let (name, password) = match arguments {
+            [_, password] => {
+                let default = &self.acl.users["default"];
+                if default.nopass {
+                    return Err(CommandError::Other("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()));
+                }
+                ("default", password)
+            }
+            [_, name, password] => (name.to_str().unwrap_or_default(), password),
+            _ => unreachable!("the arity was checked"),
+        };
+        let authenticated = self
+            .acl
+            .users
+            .get(name)
+            .is_some_and(|user| user.authenticates_with(password));
+        if !authenticated {
+            return Err(CommandError::Other(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+            ));
+        }
+        self.connections.update(client, |connection| {
+            connection.user = Some(name.to_string());
+        });
+        Ok(ParserValue::SimpleString("OK".to_string()))
+    }
+
+    /// ACL WHOAMI | LIST | GETUSER username | SETUSER username [rule ...]
+    /// | DELUSER username [username ...]
+    pub(crate) fn acl(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        match arguments[1].to_lowercase().as_str() {
+            "whoami" => {
+                check_arity(arguments, 2, 2)?;
+                if self.client.is_none() {
+                    return Err(client_required("acl|whoami"));
+                }
+                Ok(ParserValue::BulkString(
+                    self.authenticated_user()
+                        .unwrap_or_else(|| "default".to_string())
+                        .into(),
+                ))
+            }
+            "list" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Array(
+                    self.acl
+                        .users
+                        .iter()
+                        .map(|(name, user)| {
+                            ParserValue::BulkString(
+                                format!("user {} {}", name, user.describe()).into(),
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            "getuser" => {
+                check_arity(arguments, 3, 3)?;
+                let Some(user) = arguments[2]
+                    .to_str()
+                    .and_then(|name| self.acl.users.get(name))
+                else {
+                    return Ok(ParserValue::NullBulkString);
+                };
+                let strings = |values: Vec<String>| {
+                    ParserValue::Array(
+                        values
+                            .into_iter()
+                            .map(|value| ParserValue::BulkString(value.into()))
+                            .collect(),
+                    )
+                };
+                Ok(ParserValue::Array(vec![
+                    ParserValue::BulkString("flags".into()),
+                    strings(user.flags().into_iter().map(String::from).collect()),
+                    ParserValue::BulkString("passwords".into()),
+                    strings(user.password_hashes.iter().cloned().collect()),
+                    ParserValue::BulkString("commands".into()),
+                    ParserValue::BulkString(user.command_rules().into()),
+                    ParserValue::BulkString("keys".into()),
+                    ParserValue::BulkString(user.key_rules().into()),
+                ]))
+            }
+            "setuser" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                // Rules apply all or nothing.
+                let name = arguments[2].to_str().ok_or_else(|| {
+                    CommandError::Other(
+                        "ERR Usernames can't contain spaces or null characters".to_string(),
+                    )
+                })?;
+                let mut user = self.acl.users.get(name).cloned().unwrap_or_default();
+                for rule in &arguments[3..] {
+                    let applied = match rule.to_str() {
+                        Some(rule) => user.apply_rule(rule),
+                        None => Err("Syntax error".to_string()),
+                    };
+                    applied.map_err(|err| {
+                        CommandError::Other(format!(
+                            "ERR Error in ACL SETUSER modifier '{}': {}",
+                            rule, err
+                        ))
+                    })?;
+                }
+                self.acl.users.insert(name.to_string(), user);
+                Ok(ParserValue::SimpleString("OK".to_string()))
+            }
+            "deluser" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                if arguments[2..].iter().any(|name| name == "default") {
+                    return Err(CommandError::Other(
+                        "ERR The 'default' user cannot be removed".to_string(),
+                    ));
+                }
+                let mut deleted = 0;
+                for name in arguments[2..].iter().filter_map(|name| name.to_str()) {
+                    if self.acl.users.remove(name).is_none() {
+                        continue;
+                    }
+                    deleted += 1;
+                    // Clients authenticated as a deleted user are dropped.
+                    for connection in self.connections.list() {
+                        if connection.user.as_deref() == Some(name) {
+                            self.connections.kill(connection.id);
+                        }
+                    }
+                }
+                Ok(ParserValue::Integer(deleted))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "ACL".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    use crate::connections::Registration;
+    use crate::data_core::tests::{new_data_core, run_as};
+    use crate::data_core::{Client, DataCore};
+    use crate::parser::ParserValue;
+    use crate::tokenizer::Token;
+
+    fn connect(data_core: &DataCore) -> (Client, Registration) {
+        let (push_tx, _) = mpsc::unbounded_channel::<Vec<Token>>();
+        let registration = data_core.connections.register(None, push_tx.clone());
+        (Client::new(registration.id, push_tx), registration)
+    }
+
+    fn ok() -> ParserValue {
+        ParserValue::SimpleString("OK".to_string())
+    }
+
+    fn error(value: ParserValue) -> String {
+        match value {
+            ParserValue::Error(err) => err,
+            value => panic!("expected an error, got {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_users_are_restricted_to_their_commands_and_keys() {
+        let mut data_core = new_data_core();
+        let (admin, _admin) = connect(&data_core);
+        let (client, _client) = connect(&data_core);
+
+        assert_eq!(
+            ok(),
+            run_as(
+                &mut data_core,
+                &admin,
+                &[
+                    "ACL",
+                    "SETUSER",
+                    "reader",
+                    "on",
+                    ">secret",
+                    "~app:*",
+                    "+@read",
+                    "+@transaction",
+                    "+acl"
+                ]
+            )
+        );
+        assert!(error(run_as(
+            &mut data_core,
+            &client,
+            &["AUTH", "reader", "wrong"]
+        ))
+        .starts_with("WRONGPASS"));
+        assert_eq!(
+            ok(),
+            run_as(&mut data_core, &client, &["AUTH", "reader", "secret"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("reader".into()),
+            run_as(&mut data_core, &client, &["ACL", "WHOAMI"])
+        );
+
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run_as(&mut data_core, &client, &["GET", "app:1"])
+        );
+        assert_eq!(
+            "NOPERM No permissions to access a key",
+            error(run_as(&mut data_core, &client, &["GET", "other"]))
+        );
+        assert_eq!(
+            "NOPERM User reader has no permissions to run the 'set' command",
+            error(run_as(&mut data_core, &client, &["SET", "app:1", "v"]))
+        );
+
+        // A rejected command fails the transaction it was queued in.
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "app:1", "v"]);
+        assert!(error(run_as(&mut data_core, &client, &["EXEC"])).starts_with("EXECABORT"));
+    }
+
+    #[test]
+    fn test_default_user_password() {
+        let mut data_core = new_data_core();
+        let (client, _client) = connect(&data_core);
+        let (other, _other) = connect(&data_core);
+
+        assert!(error(run_as(&mut data_core, &client, &["AUTH", "pass"])).starts_with("ERR AUTH"));
+        assert_eq!(
+            ok(),
+            run_as(
+                &mut data_core,
+                &client,
+                &["ACL", "SETUSER", "default", "resetpass", ">pass"]
+            )
+        );
+        assert_eq!(
+            "NOAUTH Authentication required.",
+            error(run_as(&mut data_core, &other, &["PING"]))
+        );
+        assert_eq!(ok(), run_as(&mut data_core, &other, &["AUTH", "pass"]));
+        assert_eq!(
+            ParserValue::SimpleString("PONG".to_string()),
+            run_as(&mut data_core, &other, &["PING"])
+        );
+    }
+
+    #[test]
+    fn test_requirepass() {
+        let mut data_core = new_data_core().with_requirepass("secret");
+        let (client, _client) = connect(&data_core);
+
+        assert_eq!(
+            "NOAUTH Authentication required.",
+            error(run_as(&mut data_core, &client, &["PING"]))
+        );
+        assert_eq!(ok(), run_as(&mut data_core, &client, &["AUTH", "secret"]));
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("requirepass".into()),
+                ParserValue::BulkString("secret".into()),
+            ]),
+            run_as(&mut data_core, &client, &["CONFIG", "GET", "requirepass"])
+        );
+
+        run_as(
+            &mut data_core,
+            &client,
+            &["CONFIG", "SET", "requirepass", ""],
+        );
+        let (other, _other) = connect(&data_core);
+        assert_eq!(
+            ParserValue::SimpleString("PONG".to_string()),
+            run_as(&mut data_core, &other, &["PING"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acl_list_getuser_and_deluser() {
+        let mut data_core = new_data_core();
+        let (admin, _admin) = connect(&data_core);
+        let (client, registration) = connect(&data_core);
+
+        run_as(
+            &mut data_core,
+            &admin,
+            &[
+                "ACL", "SETUSER", "writer", "on", "nopass", "allkeys", "+@all", "-save",
+            ],
+        );
+        assert!(error(run_as(
+            &mut data_core,
+            &admin,
+            &["ACL", "SETUSER", "writer", "+nosuchcommand"]
+        ))
+        .contains("Unknown command"));
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("user default on nopass ~* +@all".into()),
+                ParserValue::BulkString("user writer on nopass ~* +@all -save".into()),
+            ]),
+            run_as(&mut data_core, &admin, &["ACL", "LIST"])
+        );
+        let ParserValue::Array(fields) =
+            run_as(&mut data_core, &admin, &["ACL", "GETUSER", "writer"])
+        else {
+            panic!("ACL GETUSER should reply with an array");
+        };
+        assert_eq!(ParserValue::BulkString("+@all -save".into()), fields[5]);
+
+        run_as(&mut data_core, &client, &["AUTH", "writer", "anything"]);
+        assert_eq!(
+            ParserValue::Integer(1),
+            run_as(
+                &mut data_core,
+                &admin,
+                &["ACL", "DELUSER", "writer", "missing"]
+            )
+        );
+        assert!(error(run_as(
+            &mut data_core,
+            &admin,
+            &["ACL", "DELUSER", "default"]
+        ))
+        .contains("cannot be removed"));
+        // The client authenticated as the deleted user is told to close.
+        timeout(Duration::from_secs(1), registration.killed())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,659 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::Utc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, Value};
+use crate::parser::ParserValue;
+use crate::replication::parse_command;
+use crate::sorted_set::format_score;
+use crate::stream::StreamId;
+use crate::tokenizer;
+
+/// When the append-only file is fsynced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write, before the command is answered.
+    Always,
+    /// At most once a second.
+    Everysec,
+    /// Never, the operating system flushes the file when it wants.
+    No,
+}
+
+impl AppendFsync {
+    pub fn as_str(self: &AppendFsync) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+impl FromStr for AppendFsync {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<AppendFsync, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::Everysec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("invalid appendfsync policy '{}'", value)),
+        }
+    }
+}
+
+const EVERYSEC_PERIOD: Duration = Duration::from_secs(1);
+
+enum AofRequest {
+    /// Bytes to append, with a channel to signal once they are written and
+    /// fsynced under the always policy.
+    Write(Vec<u8>, Option<mpsc::Sender<()>>),
+    SetFsync(AppendFsync),
+}
+
+/// What the writer thread reports back for INFO.
+#[derive(Debug)]
+struct AofStatus {
+    last_write_ok: AtomicBool,
+    size: AtomicU64,
+}
+
+/// The append-only file, written on a dedicated thread so the data core
+/// never waits on the disk unless appendfsync is always.
+#[derive(Debug)]
+pub(crate) struct AofWriter {
+    requests: mpsc::Sender<AofRequest>,
+    thread: thread::JoinHandle<()>,
+    status: Arc<AofStatus>,
+    fsync: AppendFsync,
+}
+
+impl AofWriter {
+    fn open(path: &Path, fsync: AppendFsync) -> io::Result<AofWriter> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let status = Arc::new(AofStatus {
+            last_write_ok: AtomicBool::new(true),
+            size: AtomicU64::new(file.metadata()?.len()),
+        });
+        let (requests, receiver) = mpsc::channel();
+        let writer_status = status.clone();
+        let thread = thread::Builder::new()
+            .name("aof-writer".to_string())
+            .spawn(move || write_aof(file, receiver, fsync, &writer_status))?;
+        Ok(AofWriter {
+            requests,
+            thread,
+            status,
+            fsync,
+        })
+    }
+
+    fn append(self: &AofWriter, bytes: Vec<u8>) {
+        if self.fsync != AppendFsync::Always {
+            let _ = self.requests.send(AofRequest::Write(bytes, None));
+            return;
+        }
+        let (done, written) = mpsc::channel();
+        if self
+            .requests
+            .send(AofRequest::Write(bytes, Some(done)))
+            .is_ok()
+        {
+            let _ = written.recv();
+        }
+    }
+
+    fn set_fsync(self: &mut AofWriter, fsync: AppendFsync) {
+        self.fsync = fsync;
+        let _ = self.requests.send(AofRequest::SetFsync(fsync));
+    }
+
+    /// Waits for the writer thread to write and fsync everything it was
+    /// sent.
+    fn close(self: AofWriter) {
+        drop(self.requests);
+        let _ = self.thread.join();
+    }
+}
+
+/// An append-only file rewrite running in the background.
+#[derive(Debug)]
+pub(crate) struct AofRewrite {
+    task: JoinHandle<io::Result<()>>,
+    started: Instant,
+    /// The writes made while the snapshot is written, appended to the new
+    /// file before it replaces the old one.
+    buffer: Vec<u8>,
+}
+
+/// Waits for the append-only file rewrite to finish, if there is one.
+pub(crate) async fn wait_for_aof_rewrite(
+    aof_rewrite: Option<&mut AofRewrite>,
+) -> Option<io::Result<()>> {
+    let task = &mut aof_rewrite?.task;
+    Some(task.await.unwrap_or_else(|err| Err(io::Error::other(err))))
+}
+
+fn serialize_command(arguments: &[impl AsRef<[u8]>]) -> Option<Vec<u8>> {
+    let command = ParserValue::Array(
+        arguments
+            .iter()
+            .map(|argument| ParserValue::BulkString(ByteString::from(argument.as_ref())))
+            .collect(),
+    );
+    tokenizer::serialize_tokens_to_bytes(&command.to_tokens()).ok()
+}
+
+/// The writer thread: appends what the data core sends and fsyncs according
+/// to the policy, until the data core drops its [`AofWriter`].
+fn write_aof(
+    mut file: File,
+    requests: mpsc::Receiver<AofRequest>,
+    mut fsync: AppendFsync,
+    status: &AofStatus,
+) {
+    let mut last_sync = Instant::now();
+    let mut unsynced = false;
+    loop {
+        let request = match requests.recv_timeout(EVERYSEC_PERIOD) {
+            Ok(request) => Some(request),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match request {
+            Some(AofRequest::Write(bytes, done)) => {
+                let result = file.write_all(&bytes);
+                if let Err(err) = &result {
+                    error!("cannot write to the AOF file: {}", err);
+                }
+                status
+                    .last_write_ok
+                    .store(result.is_ok(), Ordering::Relaxed);
+                status.size.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                unsynced = true;
+                if fsync == AppendFsync::Always {
+                    let _ = file.sync_data();
+                    last_sync = Instant::now();
+                    unsynced = false;
+                }
+                if let Some(done) = done {
+                    let _ = done.send(());
+                }
+            }
+            Some(AofRequest::SetFsync(policy)) => fsync = policy,
+            None => {}
+        }
+        if fsync == AppendFsync::Everysec && unsynced && last_sync.elapsed() >= EVERYSEC_PERIOD {
+            let _ = file.sync_data();
+            last_sync = Instant::now();
+            unsynced = false;
+        }
+    }
+    let _ = file.sync_data();
+}
+
+impl DataCore {
+    pub fn with_append_only(
+        self: DataCore,
+        appendonly: bool,
+        appendfsync: AppendFsync,
+        appendfilename: String,
+    ) -> DataCore {
+        DataCore {
+            appendonly,
+            appendfsync,
+            appendfilename,
+            ..self
+        }
+    }
+
+    /// The path of the append-only file, from the dir and appendfilename
+    /// settings.
+    fn aof_path(self: &DataCore) -> PathBuf {
+        Path::new(&self.dir).join(&self.appendfilename)
+    }
+
+    fn aof_rewrite_path(self: &DataCore) -> PathBuf {
+        Path::new(&self.dir).join(format!("temp-rewriteaof-bg-{}.aof", std::process::id()))
+    }
+
+    /// Replays the append-only file at startup, returning whether there was
+    /// one. A file starting with an RDB preamble has it loaded first. A
+    /// command cut short at the end of the file, as a crash while appending
+    /// leaves behind, is ignored.
+    fn load_aof_file(self: &mut DataCore) -> anyhow::Result<bool> {
+        let path = self.aof_path();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let mut position = 0;
+        if bytes.starts_with(b"REDIS") {
+            position = self
+                .load_rdb(&bytes)
+                .with_context(|| format!("cannot load the RDB preamble of {}", path.display()))?;
+        }
+        while position < bytes.len() {
+            let Some((arguments, length)) = parse_command(&bytes[position..])
+                .with_context(|| format!("cannot load {}", path.display()))?
+            else {
+                warn!(
+                    "{} ends with a truncated command, ignoring it",
+                    path.display()
+                );
+                break;
+            };
+            let arguments = arguments
+                .into_iter()
+                .map(ParserValue::BulkString)
+                .collect::<Vec<_>>();
+            if let ParserValue::Error(err) = self.execute(&arguments) {
+                warn!(
+                    ?arguments,
+                    "cannot replay a command of the AOF file: {}", err
+                );
+            }
+            position += length;
+        }
+        self.discard_unattended_transaction();
+        info!(
+            "loaded {} keys from {}",
+            self.data_set.len(),
+            path.display()
+        );
+        Ok(true)
+    }
+
+    /// Loads the data set at startup: from the append-only file when
+    /// appendonly is on and it exists, from the RDB file otherwise. The
+    /// append-only file is then opened for the writes to come.
+    pub fn load_data(self: &mut DataCore) -> anyhow::Result<()> {
+        if !(self.appendonly && self.load_aof_file()?) {
+            self.load_rdb_file()?;
+        }
+        // Loading isn't a change to save.
+        self.changes_since_last_save = 0;
+        if self.appendonly {
+            self.open_aof()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn open_aof(self: &mut DataCore) -> io::Result<()> {
+        self.aof = Some(AofWriter::open(&self.aof_path(), self.appendfsync)?);
+        Ok(())
+    }
+
+    pub(crate) fn close_aof(self: &mut DataCore) {
+        if let Some(aof) = self.aof.take() {
+            aof.close();
+        }
+    }
+
+    pub(crate) fn set_appendfsync(self: &mut DataCore, appendfsync: AppendFsync) {
+        self.appendfsync = appendfsync;
+        if let Some(aof) = self.aof.as_mut() {
+            aof.set_fsync(appendfsync);
+        }
+    }
+
+    /// Appends a write command to the append-only file, in RESP form.
+    pub(crate) fn feed_aof(self: &mut DataCore, arguments: &[impl AsRef<[u8]>]) {
+        let Some(aof) = self.aof.as_ref() else {
+            return;
+        };
+        if let Some(serialized) = serialize_command(arguments) {
+            if let Some(aof_rewrite) = self.aof_rewrite.as_mut() {
+                aof_rewrite.buffer.extend_from_slice(&serialized);
+            }
+            aof.append(serialized);
+        }
+    }
+
+    /// BGREWRITEAOF
+    pub(crate) fn bgrewriteaof(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        if self.aof_rewrite.is_some() {
+            return Err(CommandError::Other(
+                "ERR Background append only file rewriting already in progress".to_string(),
+            ));
+        }
+        self.start_aof_rewrite();
+        Ok(ParserValue::SimpleString(String::from(
+            "Background append only file rewriting started",
+        )))
+    }
+
+    /// Starts rewriting the append-only file from a snapshot of the keyspace:
+    /// an RDB file with aof-use-rdb-preamble, the commands that rebuild it
+    /// otherwise.
+    pub(crate) fn start_aof_rewrite(self: &mut DataCore) {
+        let started = Instant::now();
+        let snapshot = if self.aof_use_rdb_preamble {
+            self.to_rdb_bytes()
+        } else {
+            self.rewrite_commands()
+                .iter()
+                .filter_map(|command| serialize_command(command))
+                .flatten()
+                .collect()
+        };
+        self.latency.record("fork", started.elapsed());
+        let path = self.aof_rewrite_path();
+        self.aof_rewrite = Some(AofRewrite {
+            task: tokio::task::spawn_blocking(move || fs::write(&path, snapshot)),
+            started: Instant::now(),
+            buffer: Vec::new(),
+        });
+    }
+
+    /// Completes the rewrite once its snapshot is written: the writes made
+    /// meanwhile are appended and the new file replaces the old one.
+    pub(crate) fn finish_aof_rewrite(self: &mut DataCore, result: io::Result<()>) {
+        let Some(aof_rewrite) = self.aof_rewrite.take() else {
+            return;
+        };
+        let temporary = self.aof_rewrite_path();
+        let result = result.and_then(|()| {
+            let mut file = OpenOptions::new().append(true).open(&temporary)?;
+            file.write_all(&aof_rewrite.buffer)?;
+            file.sync_all()?;
+            fs::rename(&temporary, self.aof_path())
+        });
+        // Appends go to the new file from now on.
+        let result = match result {
+            Ok(()) if self.aof.is_some() => self.open_aof(),
+            result => result,
+        };
+        self.last_aof_rewrite_duration = Some(aof_rewrite.started.elapsed());
+        self.last_aof_rewrite_ok = result.is_ok();
+        match result {
+            Ok(()) => info!("background AOF rewrite finished successfully"),
+            Err(err) => {
+                let _ = fs::remove_file(&temporary);
+                error!("background AOF rewrite error: {}", err)
+            }
+        }
+    }
+
+    /// The commands that rebuild the keyspace. Consumer groups keep their
+    /// last delivered ID but not their pending entries, which only the RDB
+    /// preamble preserves.
+    fn rewrite_commands(self: &DataCore) -> Vec<Vec<ByteString>> {
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let mut commands = Vec::new();
+        for (key, data_value) in &self.data_set {
+            if data_value.has_expired() {
+                continue;
+            }
+            let key = ByteString::from(&key[..]);
+            match &data_value.value {
+                Value::String(string) => {
+                    let mut command = vec![
+                        "SET".into(),
+                        key.clone(),
+                        ByteString::new(string.to_bytes().into_owned()),
+                    ];
+                    if let Some(expiry_in_nanoseconds) = data_value.expiry_in_nanoseconds {
+                        let remaining = ((expiry_in_nanoseconds - now) / 1_000_000).max(1);
+                        command.extend(["PX".into(), remaining.to_string().into()]);
+                    }
+                    commands.push(command);
+                }
+                Value::Set(set) => {
+                    commands.push(
+                        ["SADD".into(), key.clone()]
+                            .into_iter()
+                            .chain(set.iter())
+                            .collect(),
+                    );
+                }
+                Value::SortedSet(sorted_set) => {
+                    let mut command = vec!["ZADD".into(), key.clone()];
+                    for (member, score) in sorted_set.iter() {
+                        command.extend([format_score(score).into(), member.into()]);
+                    }
+                    commands.push(command);
+                }
+                Value::Stream(stream) => {
+                    let entries =
+                        stream.range(StreamId::new(0, 0), StreamId::new(u64::MAX, u64::MAX));
+                    if stream.is_empty() {
+                        // Adding an entry trimmed right away creates an empty
+                        // stream, XSETID below then restores its last ID.
+                        let mut command = vec!["XADD".into(), key.clone()];
+                        command.extend(["MAXLEN", "0", "0-1", "x", "y"].map(ByteString::from));
+                        commands.push(command);
+                    }
+                    for (id, fields) in entries {
+                        let mut command = vec!["XADD".into(), key.clone(), id.to_string().into()];
+                        for (field, value) in fields {
+                            command.extend([field.clone(), value.clone()]);
+                        }
+                        commands.push(command);
+                    }
+                    commands.push(vec![
+                        "XSETID".into(),
+                        key.clone(),
+                        stream.last_id().to_string().into(),
+                        "ENTRIESADDED".into(),
+                        stream.entries_added().to_string().into(),
+                        "MAXDELETEDID".into(),
+                        stream.max_deleted_id().to_string().into(),
+                    ]);
+                    for (name, group) in stream.groups() {
+                        commands.push(vec![
+                            "XGROUP".into(),
+                            "CREATE".into(),
+                            key.clone(),
+                            name.clone(),
+                            group.last_delivered_id.to_string().into(),
+                        ]);
+                    }
+                }
+            }
+        }
+        commands
+    }
+
+    /// The append-only file fields of the persistence section of INFO.
+    pub(crate) fn aof_info(self: &DataCore) -> String {
+        let seconds =
+            |duration: Option<Duration>| duration.map_or(-1, |duration| duration.as_secs() as i64);
+        let mut info = format!(
+            "aof_enabled:{}\naof_rewrite_in_progress:{}\naof_last_rewrite_time_sec:{}\naof_current_rewrite_time_sec:{}\naof_last_bgrewrite_status:{}\naof_last_write_status:{}\n",
+            self.aof.is_some() as i64,
+            self.aof_rewrite.is_some() as i64,
+            seconds(self.last_aof_rewrite_duration),
+            seconds(self.aof_rewrite.as_ref().map(|aof_rewrite| aof_rewrite.started.elapsed())),
+            if self.last_aof_rewrite_ok { "ok" } else { "err" },
+            match &self.aof {
+                Some(aof) if !aof.status.last_write_ok.load(Ordering::Relaxed) => "err",
+                _ => "ok",
+            }
+        );
+        if let Some(aof) = &self.aof {
+            info.push_str(&format!(
+                "aof_current_size:{}\n",
+                aof.status.size.load(Ordering::Relaxed)
+            ));
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::aof::{wait_for_aof_rewrite, AppendFsync};
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::data_core::DataCore;
+    use crate::parser::ParserValue;
+    use crate::tokenizer;
+
+    #[test]
+    fn test_aof_is_replayed_at_startup() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let open = || {
+            new_data_core()
+                .with_rdb_file(dir.clone(), "dump.rdb".to_string())
+                .with_append_only(true, AppendFsync::Always, "appendonly.aof".to_string())
+        };
+
+        let mut data_core = open();
+        data_core.load_data().unwrap();
+        run(&mut data_core, &["SET", "k", "v"]);
+        run(&mut data_core, &["SADD", "s", "a", "b"]);
+        run(&mut data_core, &["GET", "k"]);
+        run(&mut data_core, &["SREM", "s", "a"]);
+        let ParserValue::BulkString(info) = run(&mut data_core, &["INFO"]) else {
+            panic!("INFO should reply with a bulk string");
+        };
+        let info = info.to_string();
+        assert!(info.contains("aof_enabled:1\n"));
+        assert!(info.contains("aof_last_write_status:ok\n"));
+        let aof = std::fs::read_to_string(format!("{}/appendonly.aof", dir)).unwrap();
+        assert!(aof.starts_with("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*4\r\n$4\r\nSADD\r\n"));
+        assert!(info.contains(&format!("aof_current_size:{}\n", aof.len())));
+
+        // A crash in the middle of an append leaves a truncated command.
+        std::fs::write(
+            format!("{}/appendonly.aof", dir),
+            format!("{}*3\r\n$3\r\nSET\r\n$1\r\nk", aof),
+        )
+        .unwrap();
+        let mut reloaded = open();
+        reloaded.load_data().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut reloaded, &["GET", "k"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![ParserValue::BulkString("b".into())]),
+            run(&mut reloaded, &["SMEMBERS", "s"])
+        );
+    }
+
+    async fn rewrite_and_reload(preamble: &str) -> (String, DataCore) {
+        let dir = std::env::temp_dir().join(format!(
+            "aof-rewrite-test-{}-{}",
+            preamble,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        let open = || {
+            new_data_core()
+                .with_rdb_file(dir.clone(), "dump.rdb".to_string())
+                .with_append_only(true, AppendFsync::Always, "appendonly.aof".to_string())
+        };
+
+        let mut data_core = open();
+        data_core.load_data().unwrap();
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "aof-use-rdb-preamble", preamble],
+        );
+        run(&mut data_core, &["SET", "k", "v"]);
+        run(&mut data_core, &["SET", "deleted", "v"]);
+        run(&mut data_core, &["ZADD", "z", "1.5", "a", "-2", "b"]);
+        run(&mut data_core, &["XADD", "s", "1-1", "f", "v"]);
+        run(&mut data_core, &["XADD", "s", "2-1", "f", "v"]);
+        run(&mut data_core, &["XDEL", "s", "2-1"]);
+        run(&mut data_core, &["XGROUP", "CREATE", "s", "g", "1-1"]);
+        run(
+            &mut data_core,
+            &["XGROUP", "CREATE", "empty", "g", "0", "MKSTREAM"],
+        );
+        assert_eq!(
+            ParserValue::SimpleString("Background append only file rewriting started".to_string()),
+            run(&mut data_core, &["BGREWRITEAOF"])
+        );
+        // Writes made during the rewrite end up in the new file.
+        run(&mut data_core, &["SADD", "later", "x"]);
+        run(&mut data_core, &["DEL", "deleted"]);
+        let result = wait_for_aof_rewrite(data_core.aof_rewrite.as_mut())
+            .await
+            .unwrap();
+        data_core.finish_aof_rewrite(result);
+        run(&mut data_core, &["SET", "after", "v"]);
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains("aof_last_bgrewrite_status:ok\n")
+        ));
+
+        let aof = std::fs::read(format!("{}/appendonly.aof", dir)).unwrap();
+        let mut reloaded = open();
+        reloaded.load_data().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        for (command, expected) in [
+            (vec!["GET", "k"], "$1\r\nv\r\n"),
+            (vec!["GET", "deleted"], "$-1\r\n"),
+            (vec!["GET", "after"], "$1\r\nv\r\n"),
+            (vec!["SMEMBERS", "later"], "*1\r\n$1\r\nx\r\n"),
+            (
+                vec!["ZRANGE", "z", "0", "-1", "WITHSCORES"],
+                "*4\r\n$1\r\nb\r\n$2\r\n-2\r\n$1\r\na\r\n$3\r\n1.5\r\n",
+            ),
+            (
+                vec!["XRANGE", "s", "-", "+"],
+                "*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n",
+            ),
+            (vec!["XLEN", "empty"], ":0\r\n"),
+            (
+                vec!["XADD", "s", "2-1", "f", "v"],
+                "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n",
+            ),
+        ] {
+            let reply = run(&mut reloaded, &command).to_tokens();
+            assert_eq!(
+                expected,
+                tokenizer::serialize_tokens(&reply).unwrap(),
+                "{:?}",
+                command
+            );
+        }
+        (String::from_utf8_lossy(&aof).into_owned(), reloaded)
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_with_rdb_preamble() {
+        let (aof, mut reloaded) = rewrite_and_reload("yes").await;
+        assert!(aof.starts_with("REDIS"));
+        assert!(aof.ends_with("*3\r\n$3\r\nSET\r\n$5\r\nafter\r\n$1\r\nv\r\n"));
+        assert!(matches!(
+            run(&mut reloaded, &["XINFO", "GROUPS", "s"]),
+            ParserValue::Array(groups) if groups.len() == 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_without_rdb_preamble() {
+        let (aof, mut reloaded) = rewrite_and_reload("no").await;
+        assert!(aof.starts_with("*"));
+        assert!(!aof.contains("$4\r\nXDEL\r\n"));
+        assert!(matches!(
+            run(&mut reloaded, &["XINFO", "GROUPS", "empty"]),
+            ParserValue::Array(groups) if groups.len() == 1
+        ));
+    }
+}
@@ -0,0 +1,580 @@
+use std::borrow::Cow;
+
+use crate::binary::ByteString;
+use crate::bitmap::{
+    bit_op, count_bits, count_ones, get_bit, position, set_bit, BitFieldType, BitOp, Overflow,
+    MAX_BIT_OFFSET,
+};
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Value};
+use crate::parser::ParserValue;
+use crate::string::RedisString;
+
+fn parse_bit_offset(offset: &ByteString) -> Result<u64, CommandError> {
+    match offset.parse::<u64>() {
+        Ok(offset) if offset <= MAX_BIT_OFFSET => Ok(offset),
+        _ => Err(CommandError::Other(
+            "ERR bit offset is not an integer or out of range".to_string(),
+        )),
+    }
+}
+
+/// A single GET, SET or INCRBY of a BITFIELD command.
+enum BitFieldOperation {
+    Get(BitFieldType, u64),
+    Set(BitFieldType, u64, i64, Overflow),
+    IncrBy(BitFieldType, u64, i64, Overflow),
+}
+
+/// Parses a BITFIELD offset: a bit offset, or `#n` for the n-th field of the
+/// given type's width.
+fn parse_field_offset(offset: &ByteString, field_type: BitFieldType) -> Result<u64, CommandError> {
+    let invalid =
+        || CommandError::Other("ERR bit offset is not an integer or out of range".to_string());
+    let offset = offset.to_str().ok_or_else(invalid)?;
+    let offset = match offset.strip_prefix('#') {
+        Some(index) => index
+            .parse::<u64>()
+            .ok()
+            .and_then(|index| index.checked_mul(field_type.bits as u64))
+            .ok_or_else(invalid)?,
+        None => offset.parse::<u64>().map_err(|_| invalid())?,
+    };
+    if offset + field_type.bits as u64 - 1 > MAX_BIT_OFFSET {
+        return Err(invalid());
+    }
+    Ok(offset)
+}
+
+fn parse_bitfield_operations(
+    arguments: &[ByteString],
+    read_only: bool,
+) -> Result<Vec<BitFieldOperation>, CommandError> {
+    let parse_type = |encoding: &ByteString| {
+        encoding.to_str().and_then(BitFieldType::parse).ok_or_else(|| {
+            CommandError::Other(
+                "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.".to_string(),
+            )
+        })
+    };
+    let parse_value =
+        |value: &ByteString| value.parse::<i64>().map_err(|_| CommandError::NotInteger);
+
+    let mut operations = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut index = 0;
+    while index < arguments.len() {
+        let operation = arguments[index].to_lowercase();
+        let operands = match operation.as_str() {
+            "get" | "overflow" => &arguments[index + 1..(index + 3).min(arguments.len())],
+            _ => &arguments[index + 1..(index + 4).min(arguments.len())],
+        };
+        match (operation.as_str(), operands) {
+            ("get", [encoding, offset]) => {
+                let field_type = parse_type(encoding)?;
+                let offset = parse_field_offset(offset, field_type)?;
+                operations.push(BitFieldOperation::Get(field_type, offset));
+                index += 3;
+            }
+            ("set" | "incrby", _) if read_only => {
+                return Err(CommandError::Other(
+                    "ERR BITFIELD_RO only supports the GET subcommand".to_string(),
+                ))
+            }
+            ("set", [encoding, offset, value]) => {
+                let field_type = parse_type(encoding)?;
+                let offset = parse_field_offset(offset, field_type)?;
+                let value = parse_value(value)?;
+                operations.push(BitFieldOperation::Set(field_type, offset, value, overflow));
+                index += 4;
+            }
+            ("incrby", [encoding, offset, increment]) => {
+                let field_type = parse_type(encoding)?;
+                let offset = parse_field_offset(offset, field_type)?;
+                let increment = parse_value(increment)?;
+                operations.push(BitFieldOperation::IncrBy(
+                    field_type, offset, increment, overflow,
+                ));
+                index += 4;
+            }
+            ("overflow", [mode, ..]) if !read_only => {
+                overflow = match mode.to_lowercase().as_str() {
+                    "wrap" => Overflow::Wrap,
+                    "sat" => Overflow::Sat,
+                    "fail" => Overflow::Fail,
+                    _ => {
+                        return Err(CommandError::Other(
+                            "ERR Invalid OVERFLOW type specified".to_string(),
+                        ))
+                    }
+                };
+                index += 2;
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+    Ok(operations)
+}
+
+/// Parses the optional `start end [BYTE | BIT]` range of BITCOUNT and BITPOS
+/// into inclusive bit offsets over a string of `len` bytes, or None when the
+/// range is empty. Negative indexes count from the end.
+fn parse_bit_range(range: &[ByteString], len: usize) -> Result<Option<(u64, u64)>, CommandError> {
+    let parse = |index: &ByteString| index.parse::<i64>().map_err(|_| CommandError::NotInteger);
+    let start = range.first().map(parse).transpose()?.unwrap_or(0);
+    let end = range.get(1).map(parse).transpose()?.unwrap_or(-1);
+    let unit_bits = match range.get(2).map(|unit| unit.to_lowercase()).as_deref() {
+        None | Some("byte") => 8,
+        Some("bit") => 1,
+        Some(_) => return Err(CommandError::Syntax),
+    };
+    if range.len() > 3 {
+        return Err(CommandError::Syntax);
+    }
+
+    let len = len as i64 * 8 / unit_bits;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 {
+        (len + end).max(0)
+    } else {
+        end.min(len - 1)
+    };
+    if start > end || start >= len {
+        return Ok(None);
+    }
+    let (start, end) = ((start * unit_bits) as u64, (end * unit_bits) as u64);
+    Ok(Some((start, end + unit_bits as u64 - 1)))
+}
+
+impl DataCore {
+    pub(crate) fn get_string(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<Option<Cow<'_, [u8]>>, CommandError> {
+        match self.lookup(key) {
+            Some(DataValue {
+                value: Value::String(string),
+                ..
+            }) => Ok(Some(string.to_bytes())),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the string at `key`, creating an empty one if it doesn't exist.
+    pub(crate) fn get_or_create_string(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<&mut Vec<u8>, CommandError> {
+        self.expire_if_needed(key);
+        let data_value = self
+            .data_set
+            .entry(key.into())
+            .or_insert_with(|| DataValue::new(Value::String(RedisString::Raw(Vec::new()))));
+        match &mut data_value.value {
+            Value::String(string) => Ok(string.make_raw()),
+            _ => Err(CommandError::WrongType),
+        }
+    }
+
+    /// SETBIT key offset value
+    pub(crate) fn setbit(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        let offset = parse_bit_offset(&arguments[2])?;
+        let value = match arguments[3].as_bytes() {
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                return Err(CommandError::Other(
+                    "ERR bit is not an integer or out of range".to_string(),
+                ))
+            }
+        };
+        let bytes = self.get_or_create_string(&arguments[1])?;
+        Ok(ParserValue::Integer(set_bit(bytes, offset, value) as i64))
+    }
+
+    /// GETBIT key offset
+    pub(crate) fn getbit(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        let offset = parse_bit_offset(&arguments[2])?;
+        let bit = self
+            .get_string(&arguments[1])?
+            .is_some_and(|bytes| get_bit(&bytes, offset));
+        Ok(ParserValue::Integer(bit as i64))
+    }
+
+    /// BITCOUNT key [start end [BYTE | BIT]]
+    pub(crate) fn bitcount(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 5)?;
+        if arguments.len() == 3 {
+            return Err(CommandError::Syntax);
+        }
+        let bytes = self.get_string(&arguments[1])?.unwrap_or_default();
+        let count = match arguments.len() {
+            2 => count_ones(&bytes),
+            _ => match parse_bit_range(&arguments[2..], bytes.len())? {
+                Some((start, end)) => count_bits(&bytes, start, end),
+                None => 0,
+            },
+        };
+        Ok(ParserValue::Integer(count as i64))
+    }
+
+    /// BITPOS key bit [start [end [BYTE | BIT]]]
+    pub(crate) fn bitpos(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 6)?;
+        let bit = match arguments[2].as_bytes() {
+            b"0" => false,
+            b"1" => true,
+            _ => {
+                return Err(CommandError::Other(
+                    "ERR The bit argument must be 1 or 0.".to_string(),
+                ))
+            }
+        };
+        let Some(bytes) = self.get_string(&arguments[1])? else {
+            // A missing key is an empty string: no set bits, all clear bits.
+            return Ok(ParserValue::Integer(if bit { -1 } else { 0 }));
+        };
+        let Some((start, end)) = parse_bit_range(&arguments[3..], bytes.len())? else {
+            return Ok(ParserValue::Integer(-1));
+        };
+        let found = match position(&bytes, bit, start, end) {
+            Some(offset) => offset as i64,
+            // Without an explicit end the string is treated as padded with
+            // zeros, so the first clear bit is right past its end.
+            None if !bit && arguments.len() < 5 => end as i64 + 1,
+            None => -1,
+        };
+        Ok(ParserValue::Integer(found))
+    }
+
+    /// BITOP AND | OR | XOR | NOT destkey key [key ...]
+    ///
+    /// Stores the result in `destkey`, deleting it when the result is empty,
+    /// and returns the length of the stored string.
+    pub(crate) fn bitop(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let op = match arguments[1].to_lowercase().as_str() {
+            "and" => BitOp::And,
+            "or" => BitOp::Or,
+            "xor" => BitOp::Xor,
+            "not" => BitOp::Not,
+            _ => return Err(CommandError::Syntax),
+        };
+        if op == BitOp::Not && arguments.len() != 4 {
+            return Err(CommandError::Other(
+                "ERR BITOP NOT must be called with a single source key.".to_string(),
+            ));
+        }
+
+        let mut sources = Vec::with_capacity(arguments.len() - 3);
+        for key in &arguments[3..] {
+            sources.push(self.get_string(key)?.unwrap_or_default().into_owned());
+        }
+        let sources = sources.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let result = bit_op(op, &sources);
+        let len = result.len();
+
+        let destination = &arguments[2];
+        if result.is_empty() {
+            self.remove_value(destination);
+        } else {
+            self.insert_value(
+                destination,
+                DataValue::new(Value::String(RedisString::Raw(result))),
+            );
+        }
+        Ok(ParserValue::Integer(len as i64))
+    }
+
+    /// BITFIELD key [GET encoding offset | [OVERFLOW WRAP | SAT | FAIL]
+    /// SET encoding offset value | INCRBY encoding offset increment ...]
+    ///
+    /// Replies with one element per GET, SET or INCRBY; SET returns the old
+    /// value and INCRBY the new one, or nil when OVERFLOW FAIL prevented it.
+    pub(crate) fn bitfield(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        read_only: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let operations = parse_bitfield_operations(&arguments[2..], read_only)?;
+        let key = &arguments[1];
+        let writes = operations
+            .iter()
+            .any(|operation| !matches!(operation, BitFieldOperation::Get(..)));
+        if !writes {
+            let bytes = self.get_string(key)?.unwrap_or_default();
+            let values = operations
+                .iter()
+                .map(|operation| match operation {
+                    BitFieldOperation::Get(field_type, offset) => {
+                        ParserValue::Integer(field_type.get(&bytes, *offset))
+                    }
+                    _ => unreachable!("only reads were requested"),
+                })
+                .collect();
+            return Ok(ParserValue::Array(values));
+        }
+
+        let bytes = self.get_or_create_string(key)?;
+        let mut values = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let value = match operation {
+                BitFieldOperation::Get(field_type, offset) => Some(field_type.get(bytes, offset)),
+                BitFieldOperation::Set(field_type, offset, value, overflow) => {
+                    let previous = field_type.get(bytes, offset);
+                    field_type.fit(value as i128, overflow).map(|value| {
+                        field_type.set(bytes, offset, value);
+                        previous
+                    })
+                }
+                BitFieldOperation::IncrBy(field_type, offset, increment, overflow) => {
+                    let current = field_type.get(bytes, offset) as i128;
+                    field_type
+                        .fit(current + increment as i128, overflow)
+                        .inspect(|&value| field_type.set(bytes, offset, value))
+                }
+            };
+            values.push(value.map_or(ParserValue::NullBulkString, ParserValue::Integer));
+        }
+        Ok(ParserValue::Array(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_setbit_and_getbit() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["SETBIT", "b", "1", "1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SETBIT", "b", "1", "1"])
+        );
+        // Turning on bits 1 and 6 spells "B" (0x42).
+        run(&mut data_core, &["SETBIT", "b", "6", "1"]);
+        assert_eq!(
+            ParserValue::BulkString("B".into()),
+            run(&mut data_core, &["GET", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["GETBIT", "b", "6"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["GETBIT", "b", "100"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["GETBIT", "missing", "0"])
+        );
+
+        run(&mut data_core, &["SET", "s", "a"]);
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["GETBIT", "s", "1"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["SETBIT", "b", "4294967296", "1"]),
+            ParserValue::Error(e) if e.contains("bit offset")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["SETBIT", "b", "0", "2"]),
+            ParserValue::Error(e) if e.contains("bit is not")
+        ));
+        run(&mut data_core, &["SADD", "set", "a"]);
+        assert!(matches!(
+            run(&mut data_core, &["SETBIT", "set", "0", "1"]),
+            ParserValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_bitcount_and_bitpos() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "s", "foobar"]);
+        assert_eq!(
+            ParserValue::Integer(26),
+            run(&mut data_core, &["BITCOUNT", "s"])
+        );
+        assert_eq!(
+            ParserValue::Integer(4),
+            run(&mut data_core, &["BITCOUNT", "s", "0", "0"])
+        );
+        assert_eq!(
+            ParserValue::Integer(6),
+            run(&mut data_core, &["BITCOUNT", "s", "1", "1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(17),
+            run(&mut data_core, &["BITCOUNT", "s", "5", "30", "BIT"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITCOUNT", "s", "3", "1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITCOUNT", "missing"])
+        );
+
+        for offset in ["0", "1", "2", "3", "4", "5", "6", "7", "12"] {
+            run(&mut data_core, &["SETBIT", "b", offset, "1"]);
+        }
+        assert_eq!(
+            ParserValue::Integer(8),
+            run(&mut data_core, &["BITPOS", "b", "0"])
+        );
+        assert_eq!(
+            ParserValue::Integer(12),
+            run(&mut data_core, &["BITPOS", "b", "1", "1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(-1),
+            run(&mut data_core, &["BITPOS", "b", "1", "13", "-1", "BIT"])
+        );
+        assert_eq!(
+            ParserValue::Integer(-1),
+            run(&mut data_core, &["BITPOS", "b", "0", "0", "0"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITPOS", "missing", "0"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["BITPOS", "b", "2"]),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_bitop() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "a", "abc"]);
+        run(&mut data_core, &["SET", "b", "a"]);
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(
+                &mut data_core,
+                &["BITOP", "AND", "dest", "a", "b", "missing"]
+            )
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITCOUNT", "dest"])
+        );
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(&mut data_core, &["BITOP", "OR", "dest", "a", "b"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("abc".into()),
+            run(&mut data_core, &["GET", "dest"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["BITOP", "XOR", "dest", "b", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITCOUNT", "dest"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["BITOP", "NOT", "dest", "missing"])
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "dest"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["BITOP", "NOT", "dest", "a", "b"]),
+            ParserValue::Error(e) if e.contains("single source key")
+        ));
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let mut data_core = new_data_core();
+        let integers = |values: &[i64]| {
+            ParserValue::Array(values.iter().copied().map(ParserValue::Integer).collect())
+        };
+        assert_eq!(
+            integers(&[0, 0]),
+            run(
+                &mut data_core,
+                &["BITFIELD", "f", "GET", "u8", "0", "GET", "i4", "#3"]
+            )
+        );
+        assert!(!data_core.data_set.contains_key("f".as_bytes()));
+
+        assert_eq!(
+            integers(&[0, 1]),
+            run(
+                &mut data_core,
+                &["BITFIELD", "f", "SET", "i8", "#0", "100", "INCRBY", "u2", "100", "1"]
+            )
+        );
+        assert_eq!(
+            integers(&[100, -56]),
+            run(
+                &mut data_core,
+                &["BITFIELD", "f", "GET", "i8", "0", "INCRBY", "i8", "0", "100"]
+            )
+        );
+        assert_eq!(
+            ParserValue::Array(vec![ParserValue::Integer(127), ParserValue::NullBulkString]),
+            run(
+                &mut data_core,
+                &[
+                    "BITFIELD", "f", "OVERFLOW", "SAT", "INCRBY", "i8", "0", "500", "OVERFLOW",
+                    "FAIL", "INCRBY", "i8", "0", "1"
+                ]
+            )
+        );
+        assert_eq!(
+            integers(&[127]),
+            run(&mut data_core, &["BITFIELD_RO", "f", "GET", "i8", "0"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["BITFIELD_RO", "f", "SET", "i8", "0", "1"]),
+            ParserValue::Error(e) if e.contains("BITFIELD_RO")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["BITFIELD", "f", "GET", "u64", "0"]),
+            ParserValue::Error(e) if e.contains("Invalid bitfield type")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["BITFIELD", "f", "GET", "u8"]),
+            ParserValue::Error(e) if e == "ERR syntax error"
+        ));
+    }
+}
@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::binary::ByteString;
+use crate::data_core::{Command, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// Set by a blocking command that found nothing to serve. The command loop
+/// parks the client instead of replying, while callers that must not block
+/// (tests, EXEC, scripts) just use the timeout reply.
+#[derive(Debug)]
+pub(crate) struct BlockRequest {
+    keys: Vec<ByteString>,
+    deadline: Option<Instant>,
+    timeout_reply: ParserValue,
+    /// Replaces the command's arguments while it is parked, for commands like
+    /// XREAD whose `$` must be resolved at the time they block.
+    retry_arguments: Option<Vec<ByteString>>,
+    /// For WAIT: the replication offset to be acknowledged and by how many
+    /// replicas. Acknowledgements wake the client instead of keys.
+    awaited_acks: Option<(i64, usize)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BlockedClient {
+    command: Command,
+    request: BlockRequest,
+}
+
+/// The clients parked by blocking commands, like BZPOPMIN and XREAD BLOCK.
+///
+/// Clients are numbered in the order they blocked. Each key keeps the
+/// clients waiting on it in that order, so a key that gets a value is
+/// offered to its oldest waiter first, and deadlines are indexed so the
+/// command loop only wakes up for the next one. Only the keys written to
+/// since clients were last served are retried.
+#[derive(Debug, Default)]
+pub(crate) struct BlockedClients {
+    clients: BTreeMap<u64, BlockedClient>,
+    waiters: HashMap<ByteString, BTreeSet<u64>>,
+    deadlines: BTreeSet<(Instant, u64)>,
+    ready_keys: HashSet<ByteString>,
+    waiting_for_acks: BTreeSet<u64>,
+    next_id: u64,
+}
+
+impl BlockedClients {
+    fn push(self: &mut BlockedClients, client: BlockedClient) {
+        let id = self.next_id;
+        self.next_id += 1;
+        for key in &client.request.keys {
+            self.waiters.entry(key.clone()).or_default().insert(id);
+        }
+        if let Some(deadline) = client.request.deadline {
+            self.deadlines.insert((deadline, id));
+        }
+        if client.request.awaited_acks.is_some() {
+            self.waiting_for_acks.insert(id);
+        }
+        self.clients.insert(id, client);
+    }
+
+    /// Takes a client out to retry its command, keeping its place in the
+    /// queues until it is [forgotten](BlockedClients::forget) or put back.
+    fn take(self: &mut BlockedClients, id: u64) -> Option<BlockedClient> {
+        self.clients.remove(&id)
+    }
+
+    fn put_back(self: &mut BlockedClients, id: u64, client: BlockedClient) {
+        self.clients.insert(id, client);
+    }
+
+    /// Drops a taken client from the queues of its keys and the deadlines.
+    fn forget(self: &mut BlockedClients, id: u64, client: &BlockedClient) {
+        for key in &client.request.keys {
+            if let Some(waiters) = self.waiters.get_mut(key) {
+                waiters.remove(&id);
+                if waiters.is_empty() {
+                    self.waiters.remove(key);
+                }
+            }
+        }
+        if let Some(deadline) = client.request.deadline {
+            self.deadlines.remove(&(deadline, id));
+        }
+        self.waiting_for_acks.remove(&id);
+    }
+
+    fn remove(self: &mut BlockedClients, id: u64) -> Option<BlockedClient> {
+        let client = self.take(id)?;
+        self.forget(id, &client);
+        Some(client)
+    }
+
+    /// Notes that `key` was written to, if any client waits on it.
+    pub(crate) fn signal_key_as_ready(self: &mut BlockedClients, key: &[u8]) {
+        if self.waiters.contains_key(key) && !self.ready_keys.contains(key) {
+            self.ready_keys.insert(ByteString::from(key));
+        }
+    }
+
+    /// The clients waiting on the keys signaled since the last call, oldest
+    /// first.
+    fn take_ready(self: &mut BlockedClients) -> Vec<u64> {
+        let mut ids = self
+            .ready_keys
+            .drain()
+            .filter_map(|key| self.waiters.get(&key))
+            .flat_map(|waiters| waiters.iter().copied())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// The clients whose timeout passed by `now`.
+    fn due(self: &BlockedClients, now: Instant) -> Vec<u64> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    fn next_deadline(self: &BlockedClients) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    /// Drops every client that disconnected while waiting.
+    fn remove_disconnected(self: &mut BlockedClients) {
+        let disconnected = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.command.response_channel.is_closed())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in disconnected {
+            self.remove(id);
+        }
+    }
+
+    /// How many clients are still waiting, for INFO.
+    pub(crate) fn len(self: &BlockedClients) -> usize {
+        self.clients
+            .values()
+            .filter(|client| !client.command.response_channel.is_closed())
+            .count()
+    }
+}
+
+/// Parses a blocking timeout in seconds, where zero means block forever.
+pub(crate) fn parse_timeout(timeout: &ByteString) -> Result<Option<Duration>, CommandError> {
+    let seconds = timeout.parse::<f64>().map_err(|_| {
+        CommandError::Other("ERR timeout is not a float or out of range".to_string())
+    })?;
+    if seconds.is_nan() || seconds.is_infinite() {
+        return Err(CommandError::Other(
+            "ERR timeout is not a float or out of range".to_string(),
+        ));
+    }
+    if seconds < 0.0 {
+        return Err(CommandError::Other("ERR timeout is negative".to_string()));
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    // The deadline has to fit in an Instant too.
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|timeout| Instant::now().checked_add(*timeout).is_some())
+        .map(Some)
+        .ok_or_else(|| CommandError::Other("ERR timeout is out of range".to_string()))
+}
+
+impl DataCore {
+    /// Asks the command loop to block the current client on `keys` and returns
+    /// the reply to use if it times out.
+    pub(crate) fn block(
+        self: &mut DataCore,
+        keys: &[ByteString],
+        timeout: Option<Duration>,
+        timeout_reply: ParserValue,
+    ) -> Result<ParserValue, CommandError> {
+        self.block_request = Some(BlockRequest {
+            keys: keys.to_vec(),
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            timeout_reply: timeout_reply.clone(),
+            retry_arguments: None,
+            awaited_acks: None,
+        });
+        Ok(timeout_reply)
+    }
+
+    /// Asks the command loop to block the current client until `replicas`
+    /// replicas acknowledged the replication stream up to `offset`, and
+    /// returns the reply to use if it can't block.
+    pub(crate) fn block_for_acks(
+        self: &mut DataCore,
+        offset: i64,
+        replicas: usize,
+        timeout: Option<Duration>,
+    ) -> Result<ParserValue, CommandError> {
+        let reply = self.block(&[], timeout, self.acked_replicas_reply(offset))?;
+        if let Some(request) = self.block_request.as_mut() {
+            request.awaited_acks = Some((offset, replicas));
+        }
+        Ok(reply)
+    }
+
+    /// Like [`DataCore::block`], but retries the command with `arguments`
+    /// instead of the ones it was called with.
+    pub(crate) fn block_with_arguments(
+        self: &mut DataCore,
+        keys: &[ByteString],
+        timeout: Option<Duration>,
+        timeout_reply: ParserValue,
+        arguments: Vec<ByteString>,
+    ) -> Result<ParserValue, CommandError> {
+        let reply = self.block(keys, timeout, timeout_reply)?;
+        if let Some(request) = self.block_request.as_mut() {
+            request.retry_arguments = Some(arguments);
+        }
+        Ok(reply)
+    }
+
+    pub(crate) fn park_blocked_client(
+        self: &mut DataCore,
+        mut command: Command,
+        mut request: BlockRequest,
+    ) {
+        if let Some(arguments) = request.retry_arguments.take() {
+            command.arguments = arguments;
+        }
+        self.blocked_clients
+            .push(BlockedClient { command, request });
+    }
+
+    pub(crate) fn next_blocked_deadline(self: &DataCore) -> Option<Instant> {
+        self.blocked_clients.next_deadline()
+    }
+
+    /// Retries the clients waiting on keys written to since the last call,
+    /// oldest first. Clients whose retry finds nothing, because an older one
+    /// took it, keep their place. Serving a client can write to more keys,
+    /// like the destination of BLMOVE, whose clients are served in turn.
+    /// Clients that disconnected while waiting are dropped.
+    pub(crate) fn serve_blocked_clients(self: &mut DataCore) {
+        if self.blocked_clients.ready_keys.is_empty() {
+            return;
+        }
+        self.blocked_clients.remove_disconnected();
+        loop {
+            let ready = self.blocked_clients.take_ready();
+            if ready.is_empty() {
+                break;
+            }
+            for id in ready {
+                let Some(mut client) = self.blocked_clients.take(id) else {
+                    continue;
+                };
+                if !client.request.keys.iter().any(|key| self.has_live_key(key)) {
+                    self.blocked_clients.put_back(id, client);
+                    continue;
+                }
+                let response = self.run_command(&mut client.command);
+                match self.block_request.take() {
+                    Some(_) => self.blocked_clients.put_back(id, client),
+                    None => {
+                        self.blocked_clients.forget(id, &client);
+                        let _ = client.command.response_channel.send(response);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replies to the clients blocked by WAIT once enough replicas
+    /// acknowledged the offset they wait for.
+    pub(crate) fn serve_clients_waiting_for_acks(self: &mut DataCore) {
+        let waiting = self
+            .blocked_clients
+            .waiting_for_acks
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        for id in waiting {
+            let Some(client) = self.blocked_clients.take(id) else {
+                continue;
+            };
+            let Some((offset, replicas)) = client.request.awaited_acks else {
+                continue;
+            };
+            if self.acked_replicas(offset) < replicas {
+                self.blocked_clients.put_back(id, client);
+                continue;
+            }
+            self.blocked_clients.forget(id, &client);
+            let _ = client
+                .command
+                .response_channel
+                .send(self.acked_replicas_reply(offset).to_tokens());
+        }
+    }
+
+    /// Replies to every blocked client whose timeout has passed. Clients
+    /// blocked by WAIT get how many replicas acknowledged their offset by
+    /// then.
+    pub(crate) fn time_out_blocked_clients(self: &mut DataCore) {
+        self.blocked_clients.remove_disconnected();
+        for id in self.blocked_clients.due(Instant::now()) {
+            if let Some(client) = self.blocked_clients.remove(id) {
+                let reply = match client.request.awaited_acks {
+                    Some((offset, _)) => self.acked_replicas_reply(offset),
+                    None => client.request.timeout_reply,
+                };
+                let _ = client.command.response_channel.send(reply.to_tokens());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot::{self, Receiver};
+
+    use crate::binary::ByteString;
+    use crate::data_core::blocking::parse_timeout;
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::data_core::{Command, DataCore};
+    use crate::parser::ParserValue;
+    use crate::tokenizer::{serialize_tokens, Token};
+
+    /// Runs a command like the command loop does, parking it if it blocks.
+    fn send(data_core: &mut DataCore, arguments: &[&str]) -> Receiver<Vec<Token>> {
+        let (tx, rx) = oneshot::channel();
+        let arguments = arguments.iter().map(|argument| ByteString::from(*argument));
+        let mut command = Command::new(arguments.collect(), tx);
+        let response = data_core.run_command(&mut command);
+        match data_core.block_request.take() {
+            Some(request) => data_core.park_blocked_client(command, request),
+            None => {
+                let _ = command.response_channel.send(response);
+            }
+        }
+        rx
+    }
+
+    fn reply(rx: &mut Receiver<Vec<Token>>) -> Option<String> {
+        rx.try_recv()
+            .ok()
+            .map(|tokens| serialize_tokens(&tokens).unwrap())
+    }
+
+    #[test]
+    fn test_serves_waiters_of_a_key_in_order() {
+        let mut data_core = new_data_core();
+        let mut first = send(&mut data_core, &["BZPOPMIN", "a", "0"]);
+        let mut both = send(&mut data_core, &["BZPOPMIN", "b", "a", "0"]);
+        let gone = send(&mut data_core, &["BZPOPMIN", "b", "0"]);
+        let mut last = send(&mut data_core, &["BZPOPMIN", "b", "0"]);
+        drop(gone);
+        assert_eq!(3, data_core.blocked_clients.len());
+
+        // One member for two waiters: the oldest gets it.
+        run(&mut data_core, &["ZADD", "a", "1", "x"]);
+        data_core.serve_blocked_clients();
+        assert_eq!(
+            Some("*3\r\n$1\r\na\r\n$1\r\nx\r\n$1\r\n1\r\n".to_string()),
+            reply(&mut first)
+        );
+        assert_eq!(None, reply(&mut both));
+
+        // The client that disconnected is skipped.
+        run(&mut data_core, &["ZADD", "b", "1", "y", "2", "z"]);
+        data_core.serve_blocked_clients();
+        assert_eq!(
+            Some("*3\r\n$1\r\nb\r\n$1\r\ny\r\n$1\r\n1\r\n".to_string()),
+            reply(&mut both)
+        );
+        assert_eq!(
+            Some("*3\r\n$1\r\nb\r\n$1\r\nz\r\n$1\r\n2\r\n".to_string()),
+            reply(&mut last)
+        );
+        assert_eq!(0, data_core.blocked_clients.len());
+        assert!(data_core.blocked_clients.waiters.is_empty());
+        assert_eq!(None, data_core.next_blocked_deadline());
+    }
+
+    #[test]
+    fn test_only_waiters_of_written_keys_are_retried() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["XADD", "s", "1-1", "f", "v"]);
+        let mut reader = send(
+            &mut data_core,
+            &["XREAD", "BLOCK", "0", "STREAMS", "s", "$"],
+        );
+
+        // Commands that don't write to the stream leave the reader waiting,
+        // without running its command again.
+        let processed = data_core.stats.total_commands_processed;
+        send(&mut data_core, &["PING"]);
+        send(&mut data_core, &["SET", "other", "v"]);
+        data_core.serve_blocked_clients();
+        assert_eq!(processed + 2, data_core.stats.total_commands_processed);
+        assert_eq!(None, reply(&mut reader));
+
+        run(&mut data_core, &["XADD", "s", "2-1", "f", "w"]);
+        data_core.serve_blocked_clients();
+        assert!(reply(&mut reader).is_some_and(|reply| reply.contains("2-1")));
+        assert_eq!(0, data_core.blocked_clients.len());
+    }
+
+    #[test]
+    fn test_timeouts_out_of_range_are_refused() {
+        assert_eq!(None, parse_timeout(&"0".into()).unwrap());
+        assert_eq!(
+            1500,
+            parse_timeout(&"1.5".into()).unwrap().unwrap().as_millis()
+        );
+        for timeout in ["nan", "inf", "-inf", "-1", "1e300", "1e20"] {
+            assert!(parse_timeout(&timeout.into()).is_err(), "{}", timeout);
+        }
+
+        let mut data_core = new_data_core();
+        for command in [&["BZPOPMIN", "z", "1e300"][..], &["BZPOPMAX", "z", "nan"]] {
+            assert!(matches!(
+                run(&mut data_core, command),
+                ParserValue::Error(error) if error.contains("out of range")
+            ));
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use tokio::time::Instant;
+
+use crate::binary::ByteString;
+use crate::connections::ConnectionHandle;
+use crate::data_core::{check_arity, client_required, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// Commands whose first argument is a subcommand, shown as `name|sub` in
+/// the `cmd` field of CLIENT INFO.
+const CONTAINER_COMMANDS: &[&str] = &[
+    "client", "cluster", "command", "config", "function", "latency", "memory", "object", "pubsub",
+    "script", "xinfo",
+];
+
+/// The kinds of clients CLIENT LIST can filter on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientType {
+    Normal,
+    Master,
+    Replica,
+    PubSub,
+}
+
+impl DataCore {
+    /// Records that the current client is running the command of
+    /// `arguments`.
+    pub(crate) fn touch_client(self: &DataCore, arguments: &[ByteString]) {
+        let Some(client) = &self.client else {
+            return;
+        };
+        let mut last_command = arguments
+            .first()
+            .map_or(String::new(), |name| name.to_lowercase());
+        if CONTAINER_COMMANDS.contains(&last_command.as_str()) && arguments.len() > 1 {
+            last_command = format!("{}|{}", last_command, arguments[1].to_lowercase());
+        }
+        self.connections.update(client.id, |connection| {
+            connection.last_command = last_command;
+            connection.last_interaction = Instant::now();
+        });
+    }
+}
+
+impl DataCore {
+    /// CLIENT ID | SETNAME name | GETNAME | INFO
+    /// | LIST [TYPE normal|master|replica|pubsub] [ID id [id ...]]
+    pub(crate) fn client_command(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        if subcommand == "list" {
+            return self.client_list(arguments);
+        }
+        let client = self
+            .client
+            .as_ref()
+            .map(|client| client.id)
+            .ok_or_else(|| client_required(&format!("client|{}", subcommand)))?;
+        match subcommand.as_str() {
+            "id" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Integer(client as i64))
+            }
+            "setname" => {
+                check_arity(arguments, 3, 3)?;
+                let name = &arguments[2];
+                if name.iter().any(|byte| !(b'!'..=b'~').contains(byte)) {
+                    return Err(CommandError::Other(
+                        "ERR Client names cannot contain spaces, newlines or special characters."
+                            .to_string(),
+                    ));
+                }
+                self.connections.update(client, |connection| {
+                    // Only printable ASCII gets here, so the name is text.
+                    connection.name = Some(name.to_string()).filter(|name| !name.is_empty());
+                });
+                Ok(ParserValue::SimpleString("OK".to_string()))
+            }
+            "getname" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(self
+                    .connections
+                    .get(client)
+                    .and_then(|connection| connection.name)
+                    .map_or(ParserValue::NullBulkString, |name| {
+                        ParserValue::BulkString(name.into())
+                    }))
+            }
+            "info" => {
+                check_arity(arguments, 2, 2)?;
+                let info = match self.connections.get(client) {
+                    Some(connection) => self.describe_client(&connection),
+                    None => format!("id={}", client),
+                };
+                Ok(ParserValue::BulkString(format!("{}\n", info).into()))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "CLIENT".to_string(),
+            )),
+        }
+    }
+
+    fn client_list(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        let mut client_type = None;
+        let mut ids = None;
+        let mut index = 2;
+        while index < arguments.len() {
+            match arguments[index].to_lowercase().as_str() {
+                "type" if index + 1 < arguments.len() => {
+                    client_type = Some(match arguments[index + 1].to_lowercase().as_str() {
+                        "normal" => ClientType::Normal,
+                        "master" => ClientType::Master,
+                        "replica" | "slave" => ClientType::Replica,
+                        "pubsub" => ClientType::PubSub,
+                        _ => {
+                            return Err(CommandError::Other(format!(
+                                "ERR Unknown client type '{}'",
+                                arguments[index + 1]
+                            )))
+                        }
+                    });
+                    index += 2;
+                }
+                "id" if index + 1 < arguments.len() => {
+                    let parsed = arguments[index + 1..]
+                        .iter()
+                        .map(|id| id.parse::<u64>().ok().filter(|id| *id > 0))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| CommandError::Other("ERR Invalid client ID".to_string()))?;
+                    ids = Some(parsed);
+                    index = arguments.len();
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let mut lines = String::new();
+        if let Some(master) = &self.master_connection {
+            let listed = ids.is_none() && client_type.is_none_or(|kind| kind == ClientType::Master);
+            if listed {
+                lines.push_str(&self.describe_master_link(master.connected_at));
+                lines.push('\n');
+            }
+        }
+        for connection in self.connections.list() {
+            if ids
+                .as_ref()
+                .is_some_and(|ids| !ids.contains(&connection.id))
+            {
+                continue;
+            }
+            if client_type.is_some_and(|kind| kind != self.client_type(connection.id)) {
+                continue;
+            }
+            lines.push_str(&self.describe_client(&connection));
+            lines.push('\n');
+        }
+        Ok(ParserValue::BulkString(lines.into()))
+    }
+
+    fn client_type(self: &DataCore, id: u64) -> ClientType {
+        if self.replicas.contains_key(&id) {
+            ClientType::Replica
+        } else if self.pubsub.is_subscribed(id) {
+            ClientType::PubSub
+        } else {
+            ClientType::Normal
+        }
+    }
+
+    /// The CLIENT LIST line of the link to this replica's master, which
+    /// has no client ID of its own.
+    fn describe_master_link(self: &DataCore, connected_at: Instant) -> String {
+        let now = Instant::now();
+        format!(
+            "id=0 addr={}:{} name= age={} idle={} flags=M db=0 sub=0 psub=0 multi=-1 watch=0 cmd=NULL user=default",
+            self.master_host.as_deref().unwrap_or(""),
+            self.master_port.unwrap_or(0),
+            (now - connected_at).as_secs(),
+            self.master_last_io
+                .map_or(0, |last_io| (now - last_io).as_secs()),
+        )
+    }
+
+    /// The `field=value` line describing a client, in the format of
+    /// CLIENT INFO and CLIENT LIST.
+    fn describe_client(self: &DataCore, connection: &ConnectionHandle) -> String {
+        let id = connection.id;
+        let now = Instant::now();
+        let mut flags = String::new();
+        if self.replicas.contains_key(&id) {
+            flags.push('S');
+        }
+        if self.pubsub.is_subscribed(id) {
+            flags.push('P');
+        }
+        if self.transactions.contains_key(&id) {
+            flags.push('x');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        let (sub, psub) = self.pubsub.subscription_counts(id);
+        let multi = self
+            .transaction_length(id)
+            .map_or(-1, |length| length as i64);
+        format!(
+            "id={} addr={} name={} age={} idle={} flags={} db=0 sub={} psub={} multi={} watch={} cmd={} user={}",
+            id,
+            connection
+                .address
+                .map_or(String::new(), |address| address.to_string()),
+            connection.name.as_deref().unwrap_or(""),
+            (now - connection.connected_at).as_secs(),
+            (now - connection.last_interaction).as_secs(),
+            flags,
+            sub,
+            psub,
+            multi,
+            self.watched_key_count(id),
+            connection.last_command,
+            connection.user.as_deref().unwrap_or("default"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::sync::mpsc;
+
+    use crate::connections::Registration;
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::{Client, DataCore};
+    use crate::parser::ParserValue;
+    use crate::tokenizer::Token;
+
+    /// Registers a connection like the server does on accept.
+    fn connect(data_core: &DataCore, address: Option<SocketAddr>) -> (Client, Registration) {
+        let (push_tx, _) = mpsc::unbounded_channel::<Vec<Token>>();
+        let registration = data_core.connections.register(address, push_tx.clone());
+        (Client::new(registration.id, push_tx), registration)
+    }
+
+    #[test]
+    fn test_client_id_and_names() {
+        let mut data_core = new_data_core();
+        connect(&data_core, None);
+        let (client, _registration) = connect(&data_core, None);
+
+        assert_eq!(
+            ParserValue::Integer(2),
+            run_as(&mut data_core, &client, &["CLIENT", "ID"])
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run_as(&mut data_core, &client, &["CLIENT", "GETNAME"])
+        );
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run_as(&mut data_core, &client, &["CLIENT", "SETNAME", "worker"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("worker".into()),
+            run_as(&mut data_core, &client, &["CLIENT", "GETNAME"])
+        );
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["CLIENT", "SETNAME", "two words"]),
+            ParserValue::Error(_)
+        ));
+        run_as(&mut data_core, &client, &["CLIENT", "SETNAME", ""]);
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run_as(&mut data_core, &client, &["CLIENT", "GETNAME"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["CLIENT", "ID"]),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_client_info() {
+        let mut data_core = new_data_core();
+        let (client, _registration) = connect(&data_core, "127.0.0.1:5000".parse().ok());
+
+        run_as(&mut data_core, &client, &["CLIENT", "SETNAME", "app"]);
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "k", "v"]);
+        run_as(&mut data_core, &client, &["EXEC"]);
+        let ParserValue::BulkString(info) = run_as(&mut data_core, &client, &["CLIENT", "INFO"])
+        else {
+            panic!("CLIENT INFO should reply with a bulk string");
+        };
+        assert_eq!(
+            "id=1 addr=127.0.0.1:5000 name=app age=0 idle=0 flags=N db=0 sub=0 psub=0 multi=-1 watch=0 cmd=client|info user=default\n",
+            info
+        );
+
+        run_as(&mut data_core, &client, &["SUBSCRIBE", "news"]);
+        let info = data_core.describe_client(&data_core.connections.get(1).unwrap());
+        assert!(info.contains(" flags=P "));
+        assert!(info.contains(" sub=1 "));
+    }
+
+    #[test]
+    fn test_client_list() {
+        let mut data_core = new_data_core();
+        let (normal, _normal) = connect(&data_core, None);
+        let (subscriber, _subscriber) = connect(&data_core, None);
+        let (replica, _replica) = connect(&data_core, None);
+        let (gone, registration) = connect(&data_core, None);
+
+        run_as(&mut data_core, &subscriber, &["SUBSCRIBE", "news"]);
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+        run_as(&mut data_core, &gone, &["PING"]);
+        drop(registration);
+
+        let list = |data_core: &mut _, arguments: &[&str]| {
+            let ParserValue::BulkString(list) = run_as(data_core, &normal, arguments) else {
+                panic!("CLIENT LIST should reply with a bulk string");
+            };
+            list.to_string()
+                .lines()
+                .map(|line| {
+                    let id = line.split(' ').next().unwrap().to_string();
+                    let flags = line.split(' ').find(|field| field.starts_with("flags="));
+                    format!("{} {}", id, flags.unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["id=1 flags=N", "id=2 flags=P", "id=3 flags=S"],
+            list(&mut data_core, &["CLIENT", "LIST"])
+        );
+        assert_eq!(
+            vec!["id=3 flags=S"],
+            list(&mut data_core, &["CLIENT", "LIST", "TYPE", "replica"])
+        );
+        assert_eq!(
+            vec!["id=1 flags=N"],
+            list(&mut data_core, &["CLIENT", "LIST", "TYPE", "normal"])
+        );
+        assert_eq!(
+            vec!["id=2 flags=P"],
+            list(
+                &mut data_core,
+                &["CLIENT", "LIST", "TYPE", "pubsub", "ID", "2", "3"]
+            )
+        );
+        assert!(list(&mut data_core, &["CLIENT", "LIST", "TYPE", "master"]).is_empty());
+        assert!(matches!(
+            run_as(
+                &mut data_core,
+                &normal,
+                &["CLIENT", "LIST", "TYPE", "other"]
+            ),
+            ParserValue::Error(_)
+        ));
+        assert!(matches!(
+            run_as(&mut data_core, &normal, &["CLIENT", "LIST", "ID", "x"]),
+            ParserValue::Error(_)
+        ));
+    }
+}
@@ -0,0 +1,902 @@
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+
+use crate::binary::ByteString;
+use crate::cluster::bus::{BusEnvelope, BusMessage, BusMessageType, ClusterBus, BUS_PORT_OFFSET};
+use crate::cluster::{key_hash_slot, ClusterNode, ClusterState, CLUSTER_SLOTS};
+use crate::data_core::{check_arity, commands, CommandError, DataCore, Key};
+use crate::parser::ParserValue;
+
+/// How often nodes send each other heartbeats over the cluster bus.
+const CLUSTER_PING_INTERVAL: Duration = Duration::from_secs(1);
+
+fn parse_slot(argument: &ByteString) -> Result<u16, CommandError> {
+    argument
+        .parse::<u16>()
+        .ok()
+        .filter(|slot| *slot < CLUSTER_SLOTS)
+        .ok_or_else(|| CommandError::Other("ERR Invalid or out of range slot".to_string()))
+}
+
+fn cluster_disabled() -> CommandError {
+    CommandError::Other("ERR This instance has cluster support disabled".to_string())
+}
+
+impl DataCore {
+    pub fn with_cluster_enabled(self: DataCore, cluster_enabled: bool) -> DataCore {
+        let cluster = cluster_enabled
+            .then(|| ClusterState::new(ClusterNode::new("127.0.0.1".to_string(), self.port)));
+        DataCore { cluster, ..self }
+    }
+
+    /// Talks to the other nodes over the cluster bus accepted by `listener`.
+    pub fn with_cluster_bus(self: DataCore, listener: TcpListener) -> DataCore {
+        DataCore {
+            cluster_bus: Some(ClusterBus::spawn(listener)),
+            ..self
+        }
+    }
+
+    fn cluster_state(self: &mut DataCore) -> Result<&mut ClusterState, CommandError> {
+        self.cluster.as_mut().ok_or_else(cluster_disabled)
+    }
+
+    /// Sends a client to the node serving the slot of its command's keys:
+    /// `-MOVED` when another node owns it, `-ASK` for keys this node already
+    /// handed over to the node the slot is migrating to. A client that sent
+    /// ASKING may use keys of a slot this node is importing. All the keys of
+    /// a command, or of the commands of a transaction at EXEC, must be in
+    /// the same slot, which `{hash tags}` ensure for related keys.
+    ///
+    /// Like queueing errors, a redirection fails the transaction it happens
+    /// in, and one at EXEC discards it.
+    pub(crate) fn check_cluster_redirect(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<(), CommandError> {
+        let name = arguments[0].to_lowercase();
+        let result = self.cluster_redirect(&name, arguments);
+        if result.is_err() {
+            self.reject_in_transaction(&name);
+        }
+        result
+    }
+
+    fn cluster_redirect(
+        self: &mut DataCore,
+        name: &str,
+        arguments: &[ByteString],
+    ) -> Result<(), CommandError> {
+        let (Some(cluster), Some(client)) = (self.cluster.as_mut(), self.client.as_ref()) else {
+            return Ok(());
+        };
+        let asking = name != "asking" && cluster.take_asking(client.id);
+        let keys = match (name, self.queued_commands()) {
+            ("exec", Some(queued)) => queued
+                .iter()
+                .filter_map(|command| {
+                    commands::lookup(&command[0].to_lowercase()).map(|spec| spec.keys(command))
+                })
+                .flatten()
+                .collect(),
+            _ => match commands::lookup(name) {
+                Some(spec) => spec.keys(arguments),
+                None => Vec::new(),
+            },
+        };
+        let Some(first) = keys.first() else {
+            return Ok(());
+        };
+        let slot = key_hash_slot(first);
+        if keys.iter().any(|key| key_hash_slot(key) != slot) {
+            return Err(CommandError::Other(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+            ));
+        }
+
+        let cluster = self.cluster.as_ref().expect("cluster mode is enabled");
+        if asking && cluster.importing_from(slot).is_some() {
+            return Ok(());
+        }
+        match cluster.slot_owner(slot) {
+            Some(owner) if cluster.is_myself(owner) => {
+                let Some(target) = cluster.migrating_to(slot) else {
+                    return Ok(());
+                };
+                let missing = keys.iter().filter(|key| !self.has_live_key(key)).count();
+                if missing == 0 {
+                    Ok(())
+                } else if missing == keys.len() {
+                    Err(CommandError::Other(format!(
+                        "ASK {} {}",
+                        slot,
+                        target.address()
+                    )))
+                } else {
+                    // Some keys moved already: neither node has them all.
+                    Err(CommandError::Other(
+                        "TRYAGAIN Multiple keys request during rehashing of slot".to_string(),
+                    ))
+                }
+            }
+            Some(owner) => Err(CommandError::Other(format!(
+                "MOVED {} {}",
+                slot,
+                owner.address()
+            ))),
+            None => Err(CommandError::Other(
+                "CLUSTERDOWN Hash slot not served".to_string(),
+            )),
+        }
+    }
+
+    /// This node's view of the cluster, as sent to the others.
+    fn bus_message(self: &DataCore, kind: BusMessageType) -> Option<BusMessage> {
+        let cluster = self.cluster.as_ref()?;
+        let myself = cluster.myself();
+        Some(BusMessage {
+            kind,
+            sender: myself.clone(),
+            current_epoch: cluster.current_epoch(),
+            slots: cluster.slot_ranges(&myself.id),
+            gossip: cluster
+                .nodes()
+                .filter(|node| !cluster.is_myself(node))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Sends a heartbeat with this node's view of the cluster to every
+    /// other node.
+    pub(crate) fn ping_cluster_nodes(self: &mut DataCore) {
+        self.next_cluster_ping = Instant::now() + CLUSTER_PING_INTERVAL;
+        let (Some(cluster), Some(bus)) = (self.cluster.as_ref(), self.cluster_bus.as_ref()) else {
+            return;
+        };
+        let Some(ping) = self.bus_message(BusMessageType::Ping) else {
+            return;
+        };
+        for node in cluster.nodes().filter(|node| !cluster.is_myself(node)) {
+            bus.send(node.bus_address(), ping.clone());
+        }
+    }
+
+    /// Learns from a message of another node and answers MEET and PING with
+    /// a PONG. PINGs from unknown nodes are answered but otherwise ignored:
+    /// nodes only join through MEET or gossip.
+    pub(crate) fn handle_bus_message(self: &mut DataCore, envelope: BusEnvelope) {
+        let message = envelope.message;
+        let Some(cluster) = self.cluster.as_mut() else {
+            return;
+        };
+        let known = cluster.node(&message.sender.id).is_some();
+        if known || message.kind != BusMessageType::Ping {
+            cluster.update_from(&message.sender, message.current_epoch, &message.slots);
+            for node in &message.gossip {
+                cluster.learn_node(node);
+            }
+        }
+        if let (Some(reply), Some(pong)) = (envelope.reply, self.bus_message(BusMessageType::Pong))
+        {
+            let _ = reply.send(pong);
+        }
+    }
+
+    /// ASKING
+    pub(crate) fn asking(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        let client = self.client.as_ref().map(|client| client.id);
+        let cluster = self.cluster_state()?;
+        if let Some(client) = client {
+            cluster.set_asking(client);
+        }
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// CLUSTER KEYSLOT key | ADDSLOTS slot [slot ...] |
+    /// ADDSLOTSRANGE start end [start end ...] | DELSLOTS slot [slot ...] |
+    /// SETSLOT slot IMPORTING|MIGRATING|NODE node-id | SETSLOT slot STABLE |
+    /// COUNTKEYSINSLOT slot | GETKEYSINSLOT slot count | INFO | MYID |
+    /// SLOTS | SHARDS | MEET ip port [bus-port]
+    pub(crate) fn cluster(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        if subcommand == "keyslot" {
+            check_arity(arguments, 3, 3)?;
+            return Ok(ParserValue::Integer(key_hash_slot(&arguments[2]) as i64));
+        }
+        match subcommand.as_str() {
+            "addslots" | "delslots" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                let slots = arguments[2..]
+                    .iter()
+                    .map(parse_slot)
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.update_slots(&slots, subcommand == "addslots")
+            }
+            "addslotsrange" => {
+                if arguments.len() < 4 || !arguments.len().is_multiple_of(2) {
+                    return Err(CommandError::WrongArity(
+                        "cluster|addslotsrange".to_string(),
+                    ));
+                }
+                let mut slots = Vec::new();
+                for range in arguments[2..].chunks(2) {
+                    let (start, end) = (parse_slot(&range[0])?, parse_slot(&range[1])?);
+                    if start > end {
+                        return Err(CommandError::Other(format!(
+                            "ERR start slot number {} is greater than end slot number {}",
+                            start, end
+                        )));
+                    }
+                    slots.extend(start..=end);
+                }
+                self.update_slots(&slots, true)
+            }
+            "setslot" => self.cluster_setslot(arguments),
+            "meet" => {
+                check_arity(arguments, 4, 5)?;
+                self.cluster_state()?;
+                let port = |argument: &ByteString| {
+                    argument
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|port| *port <= u16::MAX as u64)
+                        .ok_or_else(|| {
+                            CommandError::Other(format!(
+                                "ERR Invalid node address specified: {}:{}",
+                                arguments[2], arguments[3]
+                            ))
+                        })
+                };
+                let bus_port = match arguments.get(4) {
+                    Some(bus_port) => port(bus_port)?,
+                    None => port(&arguments[3])? + BUS_PORT_OFFSET,
+                };
+                if let (Some(bus), Some(meet)) = (
+                    self.cluster_bus.as_ref(),
+                    self.bus_message(BusMessageType::Meet),
+                ) {
+                    bus.send(format!("{}:{}", arguments[2], bus_port), meet);
+                }
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            "countkeysinslot" => {
+                check_arity(arguments, 3, 3)?;
+                self.cluster_state()?;
+                let slot = parse_slot(&arguments[2])?;
+                Ok(ParserValue::Integer(self.keys_in_slot(slot).count() as i64))
+            }
+            "getkeysinslot" => {
+                check_arity(arguments, 4, 4)?;
+                self.cluster_state()?;
+                let slot = parse_slot(&arguments[2])?;
+                let count = arguments[3]
+                    .parse::<usize>()
+                    .map_err(|_| CommandError::Other("ERR Invalid number of keys".to_string()))?;
+                let mut keys = self.keys_in_slot(slot).cloned().collect::<Vec<_>>();
+                keys.sort();
+                Ok(ParserValue::Array(
+                    keys.into_iter()
+                        .take(count)
+                        .map(|key| ParserValue::BulkString(key[..].into()))
+                        .collect(),
+                ))
+            }
+            "info" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::BulkString(self.cluster_info()?.into()))
+            }
+            "myid" => {
+                check_arity(arguments, 2, 2)?;
+                let cluster = self.cluster_state()?;
+                Ok(ParserValue::BulkString(cluster.myself().id.clone().into()))
+            }
+            "slots" => {
+                check_arity(arguments, 2, 2)?;
+                self.cluster_slots()
+            }
+            "shards" => {
+                check_arity(arguments, 2, 2)?;
+                self.cluster_shards()
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "CLUSTER".to_string(),
+            )),
+        }
+    }
+
+    /// CLUSTER INFO: `field:value` lines, the cluster being ok once every
+    /// slot is served.
+    fn cluster_info(self: &mut DataCore) -> Result<String, CommandError> {
+        let cluster = self.cluster_state()?;
+        let assigned = cluster.assigned_slots();
+        let size = cluster
+            .nodes()
+            .filter(|node| !cluster.slot_ranges(&node.id).is_empty())
+            .count();
+        Ok(format!(
+            "cluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_slots_ok:{}\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\ncluster_current_epoch:{}\r\ncluster_my_epoch:{}\r\n",
+            if assigned == CLUSTER_SLOTS as usize { "ok" } else { "fail" },
+            assigned,
+            assigned,
+            cluster.nodes().count(),
+            size,
+            cluster.current_epoch(),
+            cluster.myself().config_epoch
+        ))
+    }
+
+    /// CLUSTER SLOTS: each range of slots with the node serving it, as
+    /// `[start, end, [host, port, id, []]]`.
+    fn cluster_slots(self: &mut DataCore) -> Result<ParserValue, CommandError> {
+        let cluster = self.cluster_state()?;
+        let mut ranges = cluster
+            .nodes()
+            .flat_map(|node| {
+                cluster
+                    .slot_ranges(&node.id)
+                    .into_iter()
+                    .map(move |range| (range, node))
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|(range, _)| *range);
+        Ok(ParserValue::Array(
+            ranges
+                .into_iter()
+                .map(|((start, end), node)| {
+                    ParserValue::Array(vec![
+                        ParserValue::Integer(start as i64),
+                        ParserValue::Integer(end as i64),
+                        ParserValue::Array(vec![
+                            ParserValue::BulkString(node.host.clone().into()),
+                            ParserValue::Integer(node.port as i64),
+                            ParserValue::BulkString(node.id.clone().into()),
+                            ParserValue::Array(Vec::new()),
+                        ]),
+                    ])
+                })
+                .collect(),
+        ))
+    }
+
+    /// CLUSTER SHARDS: one shard per node, with the flattened bounds of its
+    /// slot ranges and the node's description as maps.
+    fn cluster_shards(self: &mut DataCore) -> Result<ParserValue, CommandError> {
+        let offset = self.master_reploffset;
+        let cluster = self.cluster_state()?;
+        let field = |name: &str| ParserValue::BulkString(name.into());
+        Ok(ParserValue::Array(
+            cluster
+                .nodes()
+                .map(|node| {
+                    let slots = cluster
+                        .slot_ranges(&node.id)
+                        .into_iter()
+                        .flat_map(|(start, end)| {
+                            [
+                                ParserValue::Integer(start as i64),
+                                ParserValue::Integer(end as i64),
+                            ]
+                        })
+                        .collect();
+                    let description = vec![
+                        field("id"),
+                        field(&node.id),
+                        field("port"),
+                        ParserValue::Integer(node.port as i64),
+                        field("ip"),
+                        field(&node.host),
+                        field("endpoint"),
+                        field(&node.host),
+                        field("role"),
+                        field("master"),
+                        field("replication-offset"),
+                        ParserValue::Integer(if cluster.is_myself(node) { offset } else { 0 }),
+                        field("health"),
+                        field("online"),
+                    ];
+                    ParserValue::Array(vec![
+                        field("slots"),
+                        ParserValue::Array(slots),
+                        field("nodes"),
+                        ParserValue::Array(vec![ParserValue::Array(description)]),
+                    ])
+                })
+                .collect(),
+        ))
+    }
+
+    fn keys_in_slot(self: &DataCore, slot: u16) -> impl Iterator<Item = &Key> {
+        self.data_set
+            .keys()
+            .filter(move |key| key_hash_slot(key) == slot)
+    }
+
+    /// Assigns unassigned slots to this node, or unassigns assigned ones.
+    /// Nothing changes unless every slot can be.
+    fn update_slots(
+        self: &mut DataCore,
+        slots: &[u16],
+        add: bool,
+    ) -> Result<ParserValue, CommandError> {
+        let cluster = self.cluster_state()?;
+        for (index, slot) in slots.iter().enumerate() {
+            if slots[..index].contains(slot) {
+                return Err(CommandError::Other(format!(
+                    "ERR Slot {} specified multiple times",
+                    slot
+                )));
+            }
+            match (add, cluster.slot_owner(*slot)) {
+                (true, Some(_)) => {
+                    return Err(CommandError::Other(format!(
+                        "ERR Slot {} is already busy",
+                        slot
+                    )))
+                }
+                (false, None) => {
+                    return Err(CommandError::Other(format!(
+                        "ERR Slot {} is already unassigned",
+                        slot
+                    )))
+                }
+                _ => {}
+            }
+        }
+        let myself = cluster.myself().id.clone();
+        for slot in slots {
+            if add {
+                cluster.assign_slot(*slot, &myself);
+            } else {
+                cluster.unassign_slot(*slot);
+            }
+        }
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id | SETSLOT slot STABLE
+    fn cluster_setslot(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 5)?;
+        let slot = parse_slot(&arguments[2])?;
+        let action = arguments[3].to_lowercase();
+        let cluster = self.cluster_state()?;
+        if action == "stable" {
+            check_arity(arguments, 4, 4)?;
+            cluster.set_stable(slot);
+            return Ok(ParserValue::SimpleString(String::from("OK")));
+        }
+        check_arity(arguments, 5, 5)?;
+        let node = cluster
+            .node(&arguments[4].to_string())
+            .ok_or_else(|| {
+                CommandError::Other(format!("ERR I don't know about node {}", arguments[4]))
+            })?
+            .clone();
+        let owned = cluster
+            .slot_owner(slot)
+            .is_some_and(|owner| cluster.is_myself(owner));
+        match action.as_str() {
+            "migrating" => {
+                if !owned {
+                    return Err(CommandError::Other(format!(
+                        "ERR I'm not the owner of hash slot {}",
+                        slot
+                    )));
+                }
+                if cluster.is_myself(&node) {
+                    return Err(CommandError::Other(
+                        "ERR Can't MIGRATE to myself".to_string(),
+                    ));
+                }
+                cluster.set_migrating(slot, &node.id);
+            }
+            "importing" => {
+                if owned {
+                    return Err(CommandError::Other(format!(
+                        "ERR I'm already the owner of hash slot {}",
+                        slot
+                    )));
+                }
+                if cluster.is_myself(&node) {
+                    return Err(CommandError::Other(
+                        "ERR Can't IMPORT from myself".to_string(),
+                    ));
+                }
+                cluster.set_importing(slot, &node.id);
+            }
+            "node" => {
+                // Taking over an imported slot needs a config epoch newer
+                // than its previous owner's for the cluster to agree.
+                if cluster.is_myself(&node) && cluster.importing_from(slot).is_some() {
+                    cluster.bump_epoch();
+                }
+                cluster.assign_slot(slot, &node.id);
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::cluster::bus::{BusEnvelope, BusMessage, BusMessageType};
+    use crate::cluster::ClusterNode;
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::Client;
+    use crate::parser::ParserValue;
+    use crate::tokenizer::Token;
+
+    #[test]
+    fn test_cluster_keyslot() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(12182),
+            run(&mut data_core, &["CLUSTER", "KEYSLOT", "foo"])
+        );
+        assert_eq!(
+            ParserValue::Integer(12182),
+            run(&mut data_core, &["CLUSTER", "KEYSLOT", "{foo}bar"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["CLUSTER", "KEYSLOT"]),
+            ParserValue::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["CLUSTER", "NOPE"]),
+            ParserValue::Error(err) if err.starts_with("ERR unknown subcommand 'NOPE'")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["CLUSTER", "ADDSLOTS", "1"]),
+            ParserValue::Error(err) if err.contains("cluster support disabled")
+        ));
+    }
+
+    #[test]
+    fn test_cluster_redirects() {
+        let mut data_core = new_data_core().with_cluster_enabled(true);
+        let other = ClusterNode::new("10.0.0.2".to_string(), 7001);
+        data_core.cluster.as_mut().unwrap().add_node(other.clone());
+        let client = Client::new(1, mpsc::unbounded_channel::<Vec<Token>>().0);
+        let error = |reply: ParserValue| match reply {
+            ParserValue::Error(err) => err,
+            reply => panic!("expected an error, got {:?}", reply),
+        };
+
+        // "foo" is in slot 12182, "bar" in slot 5061.
+        assert_eq!(
+            "CLUSTERDOWN Hash slot not served",
+            error(run_as(&mut data_core, &client, &["GET", "foo"]))
+        );
+        run(
+            &mut data_core,
+            &["CLUSTER", "ADDSLOTSRANGE", "10000", "16383"],
+        );
+        assert!(matches!(
+            run(&mut data_core, &["CLUSTER", "ADDSLOTS", "12182"]),
+            ParserValue::Error(err) if err == "ERR Slot 12182 is already busy"
+        ));
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "5061", "NODE", &other.id],
+        );
+        run_as(&mut data_core, &client, &["SET", "foo", "1"]);
+        assert_eq!(
+            ParserValue::BulkString("1".into()),
+            run_as(&mut data_core, &client, &["GET", "foo"])
+        );
+        assert_eq!(
+            "MOVED 5061 10.0.0.2:7001",
+            error(run_as(&mut data_core, &client, &["GET", "bar"]))
+        );
+
+        // While "foo"'s slot migrates, only keys still here are served.
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &other.id],
+        );
+        assert_eq!(
+            ParserValue::BulkString("1".into()),
+            run_as(&mut data_core, &client, &["GET", "foo"])
+        );
+        assert_eq!(
+            "ASK 12182 10.0.0.2:7001",
+            error(run_as(&mut data_core, &client, &["GET", "{foo}x"]))
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["CLUSTER", "COUNTKEYSINSLOT", "12182"])
+        );
+
+        // Clients that sent ASKING can use a slot being imported, once.
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "5061", "IMPORTING", &other.id],
+        );
+        run_as(&mut data_core, &client, &["ASKING"]);
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run_as(&mut data_core, &client, &["GET", "bar"])
+        );
+        assert!(error(run_as(&mut data_core, &client, &["GET", "bar"])).starts_with("MOVED"));
+
+        let myself = data_core.cluster.as_ref().unwrap().myself().id.clone();
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "5061", "NODE", &myself],
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run_as(&mut data_core, &client, &["GET", "bar"])
+        );
+    }
+
+    #[test]
+    fn test_cluster_topology() {
+        let mut data_core = new_data_core().with_cluster_enabled(true);
+        let other = ClusterNode::new("10.0.0.2".to_string(), 7001);
+        data_core.cluster.as_mut().unwrap().add_node(other.clone());
+        let myself = data_core.cluster.as_ref().unwrap().myself().clone();
+
+        let ParserValue::BulkString(id) = run(&mut data_core, &["CLUSTER", "MYID"]) else {
+            panic!("expected the node ID");
+        };
+        assert_eq!(40, id.len());
+        assert_eq!(myself.id, id.to_string());
+
+        run(&mut data_core, &["CLUSTER", "ADDSLOTSRANGE", "0", "8191"]);
+        let ParserValue::BulkString(info) = run(&mut data_core, &["CLUSTER", "INFO"]) else {
+            panic!("expected the cluster info");
+        };
+        let info = info.to_string();
+        assert!(info.starts_with("cluster_state:fail\r\ncluster_slots_assigned:8192\r\n"));
+        assert!(info.contains("cluster_known_nodes:2\r\ncluster_size:1\r\n"));
+
+        for slot in 8192..16384 {
+            let slot = slot.to_string();
+            run(
+                &mut data_core,
+                &["CLUSTER", "SETSLOT", &slot, "NODE", &other.id],
+            );
+        }
+        let ParserValue::BulkString(info) = run(&mut data_core, &["CLUSTER", "INFO"]) else {
+            panic!("expected the cluster info");
+        };
+        let info = info.to_string();
+        assert!(info.starts_with("cluster_state:ok\r\n"));
+
+        let node = |node: &ClusterNode| {
+            ParserValue::Array(vec![
+                ParserValue::BulkString(node.host.as_str().into()),
+                ParserValue::Integer(node.port as i64),
+                ParserValue::BulkString(node.id.as_str().into()),
+                ParserValue::Array(Vec::new()),
+            ])
+        };
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::Array(vec![
+                    ParserValue::Integer(0),
+                    ParserValue::Integer(8191),
+                    node(&myself),
+                ]),
+                ParserValue::Array(vec![
+                    ParserValue::Integer(8192),
+                    ParserValue::Integer(16383),
+                    node(&other),
+                ]),
+            ]),
+            run(&mut data_core, &["CLUSTER", "SLOTS"])
+        );
+
+        let ParserValue::Array(shards) = run(&mut data_core, &["CLUSTER", "SHARDS"]) else {
+            panic!("expected the shards");
+        };
+        assert_eq!(2, shards.len());
+        assert!(shards.contains(&ParserValue::Array(vec![
+            ParserValue::BulkString("slots".into()),
+            ParserValue::Array(vec![ParserValue::Integer(0), ParserValue::Integer(8191)]),
+            ParserValue::BulkString("nodes".into()),
+            ParserValue::Array(vec![ParserValue::Array(
+                [
+                    "id",
+                    &myself.id,
+                    "port",
+                    "",
+                    "ip",
+                    "127.0.0.1",
+                    "endpoint",
+                    "127.0.0.1",
+                    "role",
+                    "master",
+                    "replication-offset",
+                    "",
+                    "health",
+                    "online",
+                ]
+                .iter()
+                .enumerate()
+                .map(|(index, field)| match index {
+                    3 => ParserValue::Integer(myself.port as i64),
+                    11 => ParserValue::Integer(0),
+                    _ => ParserValue::BulkString((*field).into()),
+                })
+                .collect()
+            )]),
+        ])));
+    }
+
+    #[test]
+    fn test_bus_messages_propagate_slots_and_epochs() {
+        let mut data_core = new_data_core().with_cluster_enabled(true);
+        let myself = data_core.cluster.as_ref().unwrap().myself().clone();
+        run(&mut data_core, &["CLUSTER", "ADDSLOTSRANGE", "0", "99"]);
+        let mut other = ClusterNode::new("10.0.0.2".to_string(), 7001);
+        let stranger = ClusterNode::new("10.0.0.3".to_string(), 7002);
+        let message = |kind, other: &ClusterNode, slots: Vec<(u16, u16)>| BusMessage {
+            kind,
+            sender: other.clone(),
+            current_epoch: other.config_epoch,
+            slots,
+            gossip: vec![stranger.clone()],
+        };
+
+        // Pings from unknown nodes are answered, but only MEET joins them.
+        let (reply_tx, reply_rx) = oneshot::channel();
+        data_core.handle_bus_message(BusEnvelope {
+            message: message(BusMessageType::Ping, &other, vec![(100, 199)]),
+            reply: Some(reply_tx),
+        });
+        let pong = reply_rx.blocking_recv().unwrap();
+        assert_eq!(BusMessageType::Pong, pong.kind);
+        assert_eq!(
+            (myself.id.clone(), vec![(0, 99)]),
+            (pong.sender.id, pong.slots)
+        );
+        assert!(data_core
+            .cluster
+            .as_ref()
+            .unwrap()
+            .node(&other.id)
+            .is_none());
+
+        data_core.handle_bus_message(BusEnvelope {
+            message: message(BusMessageType::Meet, &other, vec![(50, 199)]),
+            reply: None,
+        });
+        let cluster = data_core.cluster.as_ref().unwrap();
+        assert!(cluster.node(&stranger.id).is_some());
+        assert_eq!(vec![(100, 199)], cluster.slot_ranges(&other.id));
+        assert_eq!(vec![(0, 99)], cluster.slot_ranges(&myself.id));
+
+        // Both config epochs were 0, so the node with the smaller ID bumped.
+        assert_eq!(myself.id < other.id, cluster.myself().config_epoch == 1);
+
+        // Claims with a newer config epoch win.
+        other.config_epoch = 10;
+        data_core.handle_bus_message(BusEnvelope {
+            message: message(BusMessageType::Ping, &other, vec![(50, 199)]),
+            reply: None,
+        });
+        let cluster = data_core.cluster.as_ref().unwrap();
+        assert_eq!(vec![(0, 49)], cluster.slot_ranges(&myself.id));
+        assert_eq!(vec![(50, 199)], cluster.slot_ranges(&other.id));
+        assert_eq!(10, cluster.current_epoch());
+    }
+
+    #[test]
+    fn test_cluster_multi_key_commands_need_one_slot() {
+        let mut data_core = new_data_core().with_cluster_enabled(true);
+        let other = ClusterNode::new("10.0.0.2".to_string(), 7001);
+        data_core.cluster.as_mut().unwrap().add_node(other.clone());
+        let client = Client::new(1, mpsc::unbounded_channel::<Vec<Token>>().0);
+        run(&mut data_core, &["CLUSTER", "ADDSLOTSRANGE", "0", "16383"]);
+        let crossslot = |reply: ParserValue| matches!(reply, ParserValue::Error(err) if err.starts_with("CROSSSLOT"));
+
+        assert!(crossslot(run_as(
+            &mut data_core,
+            &client,
+            &["MSET", "foo", "1", "bar", "2"]
+        )));
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run_as(
+                &mut data_core,
+                &client,
+                &["MSET", "{user1}a", "1", "{user1}b", "2"]
+            )
+        );
+        assert!(crossslot(run_as(
+            &mut data_core,
+            &client,
+            &["SINTERSTORE", "dst", "{user1}a"]
+        )));
+        run_as(&mut data_core, &client, &["SADD", "{s}1", "a", "b"]);
+        run_as(&mut data_core, &client, &["SADD", "{s}2", "b"]);
+        assert_eq!(
+            ParserValue::Integer(1),
+            run_as(
+                &mut data_core,
+                &client,
+                &["SINTERSTORE", "{s}dst", "{s}1", "{s}2"]
+            )
+        );
+
+        // Source keys count too, not only the destination.
+        assert!(crossslot(run_as(
+            &mut data_core,
+            &client,
+            &["ZUNIONSTORE", "{s}dst", "2", "{s}1", "other"]
+        )));
+        assert_eq!(
+            ParserValue::Integer(0),
+            run_as(
+                &mut data_core,
+                &client,
+                &["ZUNIONSTORE", "{z}dst", "2", "{z}1", "{z}2"]
+            )
+        );
+
+        // The keys of every queued command must share a slot.
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "foo", "1"]);
+        run_as(&mut data_core, &client, &["SET", "bar", "1"]);
+        assert!(crossslot(run_as(&mut data_core, &client, &["EXEC"])));
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["EXEC"]),
+            ParserValue::Error(err) if err == "ERR EXEC without MULTI"
+        ));
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "{t}a", "1"]);
+        run_as(&mut data_core, &client, &["DEL", "{t}b"]);
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::SimpleString("OK".to_string()),
+                ParserValue::Integer(0),
+            ]),
+            run_as(&mut data_core, &client, &["EXEC"])
+        );
+
+        // A redirection while queueing aborts the transaction.
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "5061", "NODE", &other.id],
+        );
+        run_as(&mut data_core, &client, &["MULTI"]);
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["GET", "bar"]),
+            ParserValue::Error(err) if err.starts_with("MOVED")
+        ));
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["EXEC"]),
+            ParserValue::Error(err) if err.starts_with("EXECABORT")
+        ));
+
+        // Mid-migration, keys split between nodes can't be served together.
+        run_as(&mut data_core, &client, &["SET", "{foo}a", "1"]);
+        run(
+            &mut data_core,
+            &["CLUSTER", "SETSLOT", "12182", "MIGRATING", &other.id],
+        );
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["DEL", "{foo}a", "{foo}b"]),
+            ParserValue::Error(err) if err.starts_with("TRYAGAIN")
+        ));
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::binary::ByteString;
+use crate::data_core::acl::categories;
+use crate::data_core::{check_arity, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// Where a command's key arguments are, so callers can find the keys of a
+/// command without running it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KeySpec {
+    None,
+    /// Keys from `first` to `last` every `step` arguments, where a negative
+    /// `last` counts back from the end.
+    Range {
+        first: usize,
+        last: i32,
+        step: usize,
+    },
+    /// A key count at `index`, followed by that many keys.
+    NumKeys {
+        index: usize,
+    },
+    /// A destination key, then a key count followed by that many keys, like
+    /// ZUNIONSTORE.
+    DestinationNumKeys,
+    /// The first half of the arguments after the STREAMS keyword.
+    Streams,
+}
+
+/// Metadata about a command, in the spirit of the Redis command table.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
+    /// Number of arguments including the name, or minus the minimum number
+    /// of arguments for variadic commands.
+    pub(crate) arity: i32,
+    pub(crate) flags: &'static [&'static str],
+    pub(crate) keys: KeySpec,
+}
+
+const fn command(
+    name: &'static str,
+    arity: i32,
+    flags: &'static [&'static str],
+    keys: KeySpec,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        keys,
+    }
+}
+
+const fn keys(first: usize, last: i32, step: usize) -> KeySpec {
+    KeySpec::Range { first, last, step }
+}
+
+const NO_KEYS: KeySpec = KeySpec::None;
+const FIRST_KEY: KeySpec = keys(1, 1, 1);
+const ALL_KEYS: KeySpec = keys(1, -1, 1);
+
+const WRITE: &[&str] = &["write"];
+const WRITE_FAST: &[&str] = &["write", "fast"];
+const WRITE_DENYOOM: &[&str] = &["write", "denyoom"];
+const WRITE_DENYOOM_FAST: &[&str] = &["write", "denyoom", "fast"];
+const WRITE_BLOCKING: &[&str] = &["write", "blocking"];
+const READONLY: &[&str] = &["readonly"];
+const READONLY_FAST: &[&str] = &["readonly", "fast"];
+const READONLY_BLOCKING: &[&str] = &["readonly", "blocking"];
+const PUBSUB: &[&str] = &["pubsub", "noscript", "loading", "stale"];
+const TRANSACTION: &[&str] = &["noscript", "loading", "stale", "fast"];
+const SCRIPT: &[&str] = &["noscript", "stale"];
+const ADMIN: &[&str] = &["admin", "noscript", "loading", "stale"];
+const SERVER: &[&str] = &["loading", "stale"];
+
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    command("ping", -1, &["fast", "stale"], NO_KEYS),
+    command("echo", 2, &["fast"], NO_KEYS),
+    command("quit", -1, TRANSACTION, NO_KEYS),
+    command("set", -3, WRITE_DENYOOM, FIRST_KEY),
+    command("get", 2, READONLY_FAST, FIRST_KEY),
+    command("mset", -3, WRITE_DENYOOM, keys(1, -1, 2)),
+    command("del", -2, WRITE, ALL_KEYS),
+    command("unlink", -2, WRITE_FAST, ALL_KEYS),
+    command("command", -1, SERVER, NO_KEYS),
+    command("info", -1, SERVER, NO_KEYS),
+    command("replconf", -1, ADMIN, NO_KEYS),
+    command("psync", -3, ADMIN, NO_KEYS),
+    command("replicaof", 3, ADMIN, NO_KEYS),
+    command("slaveof", 3, ADMIN, NO_KEYS),
+    command("wait", 3, &["noscript"], NO_KEYS),
+    command("config", -2, ADMIN, NO_KEYS),
+    command("object", -2, READONLY, keys(2, 2, 1)),
+    command("memory", -2, READONLY, keys(2, 2, 1)),
+    command("debug", -2, ADMIN, NO_KEYS),
+    command("cluster", -2, SERVER, NO_KEYS),
+    command("asking", 1, &["fast"], NO_KEYS),
+    command("client", -2, &["noscript", "loading", "stale"], NO_KEYS),
+    command("latency", -2, ADMIN, NO_KEYS),
+    command(
+        "auth",
+        -2,
+        &["noscript", "loading", "stale", "fast"],
+        NO_KEYS,
+    ),
+    command("acl", -2, ADMIN, NO_KEYS),
+    command("save", 1, ADMIN, NO_KEYS),
+    command("bgsave", 1, ADMIN, NO_KEYS),
+    command("bgrewriteaof", 1, ADMIN, NO_KEYS),
+    command("lastsave", 1, &["loading", "stale", "fast"], NO_KEYS),
+    command("sadd", -3, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("srem", -3, WRITE_FAST, FIRST_KEY),
+    command("smembers", 2, READONLY, FIRST_KEY),
+    command("sismember", 3, READONLY_FAST, FIRST_KEY),
+    command("scard", 2, READONLY_FAST, FIRST_KEY),
+    command("smove", 4, WRITE_FAST, keys(1, 2, 1)),
+    command("sinter", -2, READONLY, ALL_KEYS),
+    command("sunion", -2, READONLY, ALL_KEYS),
+    command("sdiff", -2, READONLY, ALL_KEYS),
+    command("sinterstore", -3, WRITE_DENYOOM, ALL_KEYS),
+    command("sunionstore", -3, WRITE_DENYOOM, ALL_KEYS),
+    command("sdiffstore", -3, WRITE_DENYOOM, ALL_KEYS),
+    command("zadd", -4, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("zrange", -4, READONLY, FIRST_KEY),
+    command("zrank", -3, READONLY_FAST, FIRST_KEY),
+    command("zrevrank", -3, READONLY_FAST, FIRST_KEY),
+    command("zscore", 3, READONLY_FAST, FIRST_KEY),
+    command("zmscore", -3, READONLY_FAST, FIRST_KEY),
+    command("zincrby", 4, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("zcard", 2, READONLY_FAST, FIRST_KEY),
+    command("zcount", 4, READONLY_FAST, FIRST_KEY),
+    command("zlexcount", 4, READONLY_FAST, FIRST_KEY),
+    command("zrandmember", -2, READONLY, FIRST_KEY),
+    command("zscan", -3, READONLY, FIRST_KEY),
+    command("zrangestore", -5, WRITE_DENYOOM, keys(1, 2, 1)),
+    command("zunion", -3, READONLY, KeySpec::NumKeys { index: 1 }),
+    command("zinter", -3, READONLY, KeySpec::NumKeys { index: 1 }),
+    command("zdiff", -3, READONLY, KeySpec::NumKeys { index: 1 }),
+    command(
+        "zunionstore",
+        -4,
+        WRITE_DENYOOM,
+        KeySpec::DestinationNumKeys,
+    ),
+    command(
+        "zinterstore",
+        -4,
+        WRITE_DENYOOM,
+        KeySpec::DestinationNumKeys,
+    ),
+    command("zdiffstore", -4, WRITE_DENYOOM, KeySpec::DestinationNumKeys),
+    command("zrem", -3, WRITE_FAST, FIRST_KEY),
+    command("zremrangebyrank", 4, WRITE, FIRST_KEY),
+    command("zremrangebyscore", 4, WRITE, FIRST_KEY),
+    command("zremrangebylex", 4, WRITE, FIRST_KEY),
+    command("zpopmin", -2, WRITE_FAST, FIRST_KEY),
+    command("zpopmax", -2, WRITE_FAST, FIRST_KEY),
+    command("zmpop", -4, WRITE, KeySpec::NumKeys { index: 1 }),
+    command("bzpopmin", -3, WRITE_BLOCKING, keys(1, -2, 1)),
+    command("bzpopmax", -3, WRITE_BLOCKING, keys(1, -2, 1)),
+    command("bzmpop", -5, WRITE_BLOCKING, KeySpec::NumKeys { index: 2 }),
+    command("zrevrange", -4, READONLY, FIRST_KEY),
+    command("zrangebyscore", -4, READONLY, FIRST_KEY),
+    command("zrevrangebyscore", -4, READONLY, FIRST_KEY),
+    command("zrangebylex", -4, READONLY, FIRST_KEY),
+    command("zrevrangebylex", -4, READONLY, FIRST_KEY),
+    command("xadd", -5, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("xrange", -4, READONLY, FIRST_KEY),
+    command("xrevrange", -4, READONLY, FIRST_KEY),
+    command("xlen", 2, READONLY_FAST, FIRST_KEY),
+    command("xread", -4, READONLY_BLOCKING, KeySpec::Streams),
+    command("xreadgroup", -7, WRITE_BLOCKING, KeySpec::Streams),
+    command("xgroup", -2, WRITE, keys(2, 2, 1)),
+    command("xack", -4, WRITE_FAST, FIRST_KEY),
+    command("xdel", -3, WRITE_FAST, FIRST_KEY),
+    command("xtrim", -4, WRITE, FIRST_KEY),
+    command("xsetid", -3, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("xinfo", -2, READONLY, keys(2, 2, 1)),
+    command("setbit", 4, WRITE_DENYOOM, FIRST_KEY),
+    command("getbit", 3, READONLY_FAST, FIRST_KEY),
+    command("bitcount", -2, READONLY, FIRST_KEY),
+    command("bitpos", -3, READONLY, FIRST_KEY),
+    command("bitop", -4, WRITE_DENYOOM, keys(2, -1, 1)),
+    command("bitfield", -2, WRITE_DENYOOM, FIRST_KEY),
+    command("bitfield_ro", -2, READONLY_FAST, FIRST_KEY),
+    command("pfadd", -2, WRITE_DENYOOM_FAST, FIRST_KEY),
+    command("pfcount", -2, READONLY, ALL_KEYS),
+    command("pfmerge", -2, WRITE_DENYOOM, ALL_KEYS),
+    command("geoadd", -5, WRITE_DENYOOM, FIRST_KEY),
+    command("geopos", -2, READONLY, FIRST_KEY),
+    command("geodist", -4, READONLY, FIRST_KEY),
+    command("subscribe", -2, PUBSUB, NO_KEYS),
+    command("psubscribe", -2, PUBSUB, NO_KEYS),
+    command("unsubscribe", -1, PUBSUB, NO_KEYS),
+    command("punsubscribe", -1, PUBSUB, NO_KEYS),
+    command(
+        "publish",
+        3,
+        &["pubsub", "loading", "stale", "fast"],
+        NO_KEYS,
+    ),
+    command("pubsub", -2, PUBSUB, NO_KEYS),
+    command("multi", 1, TRANSACTION, NO_KEYS),
+    command("exec", 1, &["noscript", "loading", "stale"], NO_KEYS),
+    command("discard", 1, TRANSACTION, NO_KEYS),
+    command("watch", -2, TRANSACTION, ALL_KEYS),
+    command("unwatch", 1, TRANSACTION, NO_KEYS),
+    command("eval", -3, SCRIPT, KeySpec::NumKeys { index: 2 }),
+    command("evalsha", -3, SCRIPT, KeySpec::NumKeys { index: 2 }),
+    command("script", -2, SCRIPT, NO_KEYS),
+    command("function", -2, SCRIPT, NO_KEYS),
+    command("fcall", -3, SCRIPT, KeySpec::NumKeys { index: 2 }),
+    command("fcall_ro", -3, SCRIPT, KeySpec::NumKeys { index: 2 }),
+];
+
+/// [`COMMANDS`] by name. Commands are looked up several times each, when
+/// they run, propagate and are checked, so not by scanning the table.
+static COMMANDS_BY_NAME: LazyLock<HashMap<&'static str, &'static CommandSpec>> =
+    LazyLock::new(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect());
+
+/// Looks up a command by its lowercase name.
+pub(crate) fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS_BY_NAME.get(name).copied()
+}
+
+/// The commands clients can call, by the name they call them with once
+/// the renames of `rename-command` are applied.
+#[derive(Debug, Clone)]
+pub(crate) struct CommandTable {
+    commands: HashMap<String, &'static CommandSpec>,
+}
+
+impl Default for CommandTable {
+    fn default() -> CommandTable {
+        CommandTable {
+            commands: COMMANDS
+                .iter()
+                .map(|spec| (spec.name.to_string(), spec))
+                .collect(),
+        }
+    }
+}
+
+impl CommandTable {
+    /// Looks up a command by the lowercase name clients call it with.
+    pub(crate) fn get(self: &CommandTable, name: &str) -> Option<&'static CommandSpec> {
+        self.commands.get(name).copied()
+    }
+
+    /// The commands with the names clients call them by, sorted by name.
+    pub(crate) fn sorted(self: &CommandTable) -> Vec<(&str, &'static CommandSpec)> {
+        let mut commands = self
+            .commands
+            .iter()
+            .map(|(name, spec)| (name.as_str(), *spec))
+            .collect::<Vec<_>>();
+        commands.sort_unstable_by_key(|(name, _)| *name);
+        commands
+    }
+
+    /// Makes the command called `name` answer to `new_name` instead, or to
+    /// nothing when `new_name` is empty.
+    pub(crate) fn rename(
+        self: &mut CommandTable,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), String> {
+        let new_name = new_name.to_lowercase();
+        if !new_name.is_empty() && self.commands.contains_key(&new_name) {
+            return Err(format!("Target command name already exists: {}", new_name));
+        }
+        let spec = self
+            .commands
+            .remove(&name.to_lowercase())
+            .ok_or_else(|| format!("No such command in rename-command: {}", name))?;
+        if !new_name.is_empty() {
+            self.commands.insert(new_name, spec);
+        }
+        Ok(())
+    }
+}
+
+impl CommandSpec {
+    /// Whether the keys can't be told from fixed positions, but only by
+    /// looking at the arguments.
+    fn has_movable_keys(self: &CommandSpec) -> bool {
+        matches!(
+            self.keys,
+            KeySpec::NumKeys { .. } | KeySpec::DestinationNumKeys | KeySpec::Streams
+        )
+    }
+
+    /// The reply of COMMAND INFO for this command, called `name` by
+    /// clients: name, arity, flags, first key, last key, key step, ACL
+    /// categories, tips, key specs and subcommands.
+    fn info(self: &CommandSpec, name: &str) -> ParserValue {
+        let mut flags = self
+            .flags
+            .iter()
+            .map(|flag| ParserValue::SimpleString(flag.to_string()))
+            .collect::<Vec<_>>();
+        if self.has_movable_keys() {
+            flags.push(ParserValue::SimpleString("movablekeys".to_string()));
+        }
+        let (first, last, step) = match self.keys {
+            KeySpec::Range { first, last, step } => (first as i64, last as i64, step as i64),
+            // Only the destination has a fixed position.
+            KeySpec::DestinationNumKeys => (1, 1, 1),
+            KeySpec::None | KeySpec::NumKeys { .. } | KeySpec::Streams => (0, 0, 0),
+        };
+        ParserValue::Array(vec![
+            ParserValue::BulkString(name.into()),
+            ParserValue::Integer(self.arity as i64),
+            ParserValue::Array(flags),
+            ParserValue::Integer(first),
+            ParserValue::Integer(last),
+            ParserValue::Integer(step),
+            ParserValue::Array(
+                categories(self)
+                    .into_iter()
+                    .map(|category| ParserValue::SimpleString(format!("@{}", category)))
+                    .collect(),
+            ),
+            ParserValue::Array(Vec::new()),
+            ParserValue::Array(Vec::new()),
+            ParserValue::Array(Vec::new()),
+        ])
+    }
+
+    pub(crate) fn has_flag(self: &CommandSpec, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    pub(crate) fn is_write(self: &CommandSpec) -> bool {
+        self.has_flag("write")
+    }
+
+    /// Whether `count` arguments, including the name, satisfy the arity.
+    pub(crate) fn accepts_arity(self: &CommandSpec, count: usize) -> bool {
+        match self.arity {
+            arity if arity >= 0 => count == arity as usize,
+            arity => count >= arity.unsigned_abs() as usize,
+        }
+    }
+
+    /// Whether the key count of a call to this command, for commands that
+    /// take one, is a number of the arguments that follow it.
+    pub(crate) fn has_valid_key_count(self: &CommandSpec, arguments: &[ByteString]) -> bool {
+        match self.keys {
+            KeySpec::NumKeys { index } => is_valid_key_count(arguments, index),
+            KeySpec::DestinationNumKeys => is_valid_key_count(arguments, 2),
+            _ => true,
+        }
+    }
+
+    /// The key arguments of a call to this command. Malformed calls yield
+    /// the keys that can be found.
+    pub(crate) fn keys<'a>(self: &CommandSpec, arguments: &'a [ByteString]) -> Vec<&'a ByteString> {
+        match self.keys {
+            KeySpec::None => Vec::new(),
+            KeySpec::Range { first, last, step } => {
+                let last = match last {
+                    last if last >= 0 => last as usize,
+                    last => match arguments.len().checked_sub(last.unsigned_abs() as usize) {
+                        Some(last) => last,
+                        None => return Vec::new(),
+                    },
+                };
+                (first..=last.min(arguments.len().saturating_sub(1)))
+                    .step_by(step)
+                    .map(|index| &arguments[index])
+                    .collect()
+            }
+            KeySpec::NumKeys { index } => counted_keys(arguments, index).collect(),
+            KeySpec::DestinationNumKeys => arguments
+                .get(1)
+                .into_iter()
+                .chain(counted_keys(arguments, 2))
+                .collect(),
+            KeySpec::Streams => {
+                let Some(position) = arguments
+                    .iter()
+                    .position(|argument| argument.eq_ignore_ascii_case("streams"))
+                else {
+                    return Vec::new();
+                };
+                let rest = &arguments[position + 1..];
+                rest[..rest.len() / 2].iter().collect()
+            }
+        }
+    }
+}
+
+/// Whether the key count at `index` is a number of the arguments after it.
+fn is_valid_key_count(arguments: &[ByteString], index: usize) -> bool {
+    arguments
+        .get(index)
+        .and_then(|count| count.parse::<i64>().ok())
+        .is_some_and(|count| {
+            count >= 0 && count as usize <= arguments.len().saturating_sub(index + 1)
+        })
+}
+
+/// The keys after the key count at `index`.
+fn counted_keys(arguments: &[ByteString], index: usize) -> impl Iterator<Item = &ByteString> {
+    let count = arguments
+        .get(index)
+        .and_then(|count| count.parse::<usize>().ok())
+        .unwrap_or(0);
+    arguments.iter().skip(index + 1).take(count)
+}
+
+impl DataCore {
+    /// COMMAND [COUNT | LIST | INFO [name ...] | DOCS [name ...]
+    /// | GETKEYS name [argument ...]]
+    pub(crate) fn command_command(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        let table = self.command_table.sorted();
+        let Some(subcommand) = arguments.get(1) else {
+            return Ok(ParserValue::Array(
+                table.iter().map(|(name, spec)| spec.info(name)).collect(),
+            ));
+        };
+        match subcommand.to_lowercase().as_str() {
+            "count" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Integer(table.len() as i64))
+            }
+            "list" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Array(
+                    table
+                        .iter()
+                        .map(|(name, _)| ParserValue::BulkString(ByteString::from(*name)))
+                        .collect(),
+                ))
+            }
+            "info" => {
+                if arguments.len() == 2 {
+                    return Ok(ParserValue::Array(
+                        table.iter().map(|(name, spec)| spec.info(name)).collect(),
+                    ));
+                }
+                Ok(ParserValue::Array(
+                    arguments[2..]
+                        .iter()
+                        .map(|name| {
+                            let name = name.to_lowercase();
+                            self.command_table
+                                .get(&name)
+                                .map_or(ParserValue::NullArray, |spec| spec.info(&name))
+                        })
+                        .collect(),
+                ))
+            }
+            // There is no documentation to give, but clients that ask for
+            // it at connect time get every command with an empty entry.
+            "docs" => {
+                let names = if arguments.len() == 2 {
+                    table.iter().map(|(name, _)| name.to_string()).collect()
+                } else {
+                    arguments[2..]
+                        .iter()
+                        .map(|name| name.to_lowercase())
+                        .filter(|name| self.command_table.get(name).is_some())
+                        .collect::<Vec<_>>()
+                };
+                Ok(ParserValue::Array(
+                    names
+                        .into_iter()
+                        .flat_map(|name| {
+                            [
+                                ParserValue::BulkString(name.into()),
+                                ParserValue::Array(Vec::new()),
+                            ]
+                        })
+                        .collect(),
+                ))
+            }
+            "getkeys" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                let command = &arguments[2..];
+                let spec = self
+                    .command_table
+                    .get(&command[0].to_lowercase())
+                    .ok_or_else(|| {
+                        CommandError::Other("ERR Invalid command specified".to_string())
+                    })?;
+                if !spec.accepts_arity(command.len()) {
+                    return Err(CommandError::Other(
+                        "ERR Invalid number of arguments specified for command".to_string(),
+                    ));
+                }
+                if !spec.has_valid_key_count(command) {
+                    return Err(CommandError::Other(
+                        "ERR Invalid arguments specified for command".to_string(),
+                    ));
+                }
+                let keys = spec.keys(command);
+                if keys.is_empty() {
+                    return Err(CommandError::Other(
+                        "ERR The command has no key arguments".to_string(),
+                    ));
+                }
+                Ok(ParserValue::Array(
+                    keys.into_iter()
+                        .map(|key| ParserValue::BulkString(key.clone()))
+                        .collect(),
+                ))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                subcommand.clone(),
+                "COMMAND".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_core::tests::{new_data_core, run};
+
+    fn arguments(values: &[&str]) -> Vec<ByteString> {
+        values
+            .iter()
+            .map(|value| ByteString::from(*value))
+            .collect()
+    }
+
+    #[test]
+    fn test_finds_command_keys() {
+        let keys = |values: &[&str]| {
+            let arguments = arguments(values);
+            let spec = lookup(values[0]).unwrap();
+            spec.keys(&arguments)
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        // No command name is used twice.
+        assert_eq!(COMMANDS.len(), COMMANDS_BY_NAME.len());
+        assert_eq!(vec!["k"], keys(&["set", "k", "v"]));
+        assert_eq!(vec!["a", "b"], keys(&["bzpopmin", "a", "b", "0"]));
+        assert_eq!(vec!["d", "a", "b"], keys(&["bitop", "and", "d", "a", "b"]));
+        assert_eq!(
+            vec!["a", "b"],
+            keys(&["zunion", "2", "a", "b", "weights", "1", "2"])
+        );
+        assert_eq!(
+            vec!["a", "b"],
+            keys(&["xread", "count", "2", "streams", "a", "b", "0", "0"])
+        );
+        assert_eq!(
+            vec!["d", "a", "b"],
+            keys(&["zunionstore", "d", "2", "a", "b", "weights", "1", "2"])
+        );
+        assert!(keys(&["ping"]).is_empty());
+    }
+
+    #[test]
+    fn test_renames_commands() {
+        let mut table = CommandTable::default();
+        table.rename("SAVE", "").unwrap();
+        table.rename("config", "secret-config").unwrap();
+        assert!(table.get("save").is_none());
+        assert!(table.get("config").is_none());
+        assert_eq!("config", table.get("secret-config").unwrap().name);
+        assert!(table.rename("save", "x").is_err());
+        assert!(table.rename("get", "set").is_err());
+    }
+
+    #[test]
+    fn test_command_introspection() {
+        let mut data_core = new_data_core().with_renamed_command("del", "").unwrap();
+        let count = COMMANDS.len() as i64 - 1;
+        assert_eq!(
+            ParserValue::Integer(count),
+            run(&mut data_core, &["COMMAND", "COUNT"])
+        );
+        let ParserValue::Array(all) = run(&mut data_core, &["COMMAND"]) else {
+            panic!("COMMAND should reply with an array");
+        };
+        assert_eq!(count as usize, all.len());
+
+        let ParserValue::Array(info) = run(&mut data_core, &["COMMAND", "INFO", "get", "del"])
+        else {
+            panic!("COMMAND INFO should reply with an array");
+        };
+        assert_eq!(ParserValue::NullArray, info[1]);
+        let ParserValue::Array(get) = &info[0] else {
+            panic!("command infos should be arrays");
+        };
+        assert_eq!(
+            vec![
+                ParserValue::BulkString("get".into()),
+                ParserValue::Integer(2),
+                ParserValue::Array(vec![
+                    ParserValue::SimpleString("readonly".to_string()),
+                    ParserValue::SimpleString("fast".to_string()),
+                ]),
+                ParserValue::Integer(1),
+                ParserValue::Integer(1),
+                ParserValue::Integer(1),
+            ],
+            get[..6]
+        );
+
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("a".into()),
+                ParserValue::BulkString("c".into()),
+            ]),
+            run(
+                &mut data_core,
+                &["COMMAND", "GETKEYS", "MSET", "a", "b", "c", "d"]
+            )
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("d".into()),
+                ParserValue::BulkString("a".into()),
+                ParserValue::BulkString("b".into()),
+            ]),
+            run(
+                &mut data_core,
+                &[
+                    "COMMAND",
+                    "GETKEYS",
+                    "ZINTERSTORE",
+                    "d",
+                    "2",
+                    "a",
+                    "b",
+                    "WEIGHTS",
+                    "1",
+                    "2"
+                ]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["COMMAND", "GETKEYS", "PING"]),
+            ParserValue::Error(e) if e.contains("no key arguments")
+        ));
+        for numkeys in ["-1", "2", "x"] {
+            assert_eq!(
+                ParserValue::Error("ERR Invalid arguments specified for command".to_string()),
+                run(
+                    &mut data_core,
+                    &["COMMAND", "GETKEYS", "ZUNIONSTORE", "d", numkeys, "a"]
+                )
+            );
+        }
+        assert_eq!(
+            ParserValue::Error("ERR Invalid arguments specified for command".to_string()),
+            run(
+                &mut data_core,
+                &["COMMAND", "GETKEYS", "EVAL", "return 1", "-1"]
+            )
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("get".into()),
+                ParserValue::Array(Vec::new()),
+            ]),
+            run(&mut data_core, &["COMMAND", "DOCS", "get", "nosuch"])
+        );
+    }
+
+    #[test]
+    fn test_checks_arity() {
+        assert!(lookup("get").unwrap().accepts_arity(2));
+        assert!(!lookup("get").unwrap().accepts_arity(3));
+        assert!(lookup("set").unwrap().accepts_arity(5));
+        assert!(!lookup("set").unwrap().accepts_arity(2));
+    }
+}
@@ -0,0 +1,372 @@
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use anyhow::anyhow;
+
+use crate::binary::ByteString;
+use crate::data_core::persistence::{format_save_rules, parse_save_rules};
+use crate::data_core::{check_arity, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// Parameters understood by CONFIG GET and CONFIG SET, which config files
+/// may set too.
+pub const CONFIG_PARAMETERS: &[&str] = &[
+    "appendfilename",
+    "appendfsync",
+    "appendonly",
+    "aof-use-rdb-preamble",
+    "busy-reply-threshold",
+    "cluster-enabled",
+    "dbfilename",
+    "dir",
+    "latency-monitor-threshold",
+    "lfu-decay-time",
+    "lfu-log-factor",
+    "lua-time-limit",
+    "min-replicas-max-lag",
+    "min-replicas-to-write",
+    "min-slaves-max-lag",
+    "min-slaves-to-write",
+    "proto-max-bulk-len",
+    "repl-diskless-sync",
+    "repl-ping-replica-period",
+    "replica-announce-ip",
+    "replica-announce-port",
+    "requirepass",
+    "save",
+    "set-max-intset-entries",
+    "slave-announce-ip",
+    "slave-announce-port",
+    "tcp-backlog",
+    "tcp-keepalive",
+    "tcp-nodelay",
+    "timeout",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+];
+
+fn yes_or_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_yes_or_no(name: &str, value: &str) -> Result<bool, CommandError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(CommandError::Other(format!(
+            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be 'yes' or 'no'",
+            name
+        ))),
+    }
+}
+
+/// Parses a memory size like Redis configuration files write them: bytes,
+/// optionally with a `k`, `m` or `g` unit for powers of 1000, or `kb`, `mb`
+/// or `gb` for powers of 1024.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let multiplier = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    value[..digits].parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+impl DataCore {
+    /// Sets a parameter read from a config file, like CONFIG SET does.
+    pub fn with_config_parameter(
+        mut self: DataCore,
+        name: &str,
+        value: &str,
+    ) -> anyhow::Result<DataCore> {
+        self.config_set_value(name, value)
+            .map_err(|err| anyhow!("{}", err))?;
+        Ok(self)
+    }
+
+    fn config_get_value(self: &DataCore, name: &str) -> Option<String> {
+        let value = match name {
+            "appendfilename" => return Some(self.appendfilename.clone()),
+            "appendfsync" => return Some(self.appendfsync.as_str().to_string()),
+            "appendonly" => return Some(yes_or_no(self.appendonly)),
+            "aof-use-rdb-preamble" => return Some(yes_or_no(self.aof_use_rdb_preamble)),
+            "cluster-enabled" => return Some(yes_or_no(self.cluster.is_some())),
+            "dbfilename" => return Some(self.dbfilename.clone()),
+            "dir" => return Some(self.dir.clone()),
+            "latency-monitor-threshold" => return Some(self.latency.threshold_ms.to_string()),
+            "lfu-decay-time" => return Some(self.lfu_decay_time.to_string()),
+            "lfu-log-factor" => return Some(self.lfu_log_factor.to_string()),
+            "busy-reply-threshold" | "lua-time-limit" => {
+                return Some(self.lua_time_limit_ms.to_string())
+            }
+            "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                return Some(self.min_replicas_max_lag.to_string())
+            }
+            "min-replicas-to-write" | "min-slaves-to-write" => {
+                return Some(self.min_replicas_to_write.to_string())
+            }
+            "proto-max-bulk-len" => {
+                return Some(
+                    self.connection_config
+                        .proto_max_bulk_len
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                )
+            }
+            "repl-diskless-sync" => return Some(yes_or_no(self.repl_diskless_sync)),
+            "repl-ping-replica-period" => return Some(self.repl_ping_replica_period.to_string()),
+            "replica-announce-ip" | "slave-announce-ip" => {
+                return Some(self.replica_announce_ip.clone().unwrap_or_default())
+            }
+            "replica-announce-port" | "slave-announce-port" => {
+                return Some(self.replica_announce_port.unwrap_or_default().to_string())
+            }
+            "requirepass" => return Some(self.acl.requirepass().to_string()),
+            "save" => return Some(format_save_rules(&self.save_rules)),
+            "tcp-backlog" => {
+                return Some(
+                    self.connection_config
+                        .tcp_backlog
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                )
+            }
+            "tcp-keepalive" => {
+                return Some(
+                    self.connection_config
+                        .tcp_keepalive
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                )
+            }
+            "tcp-nodelay" => {
+                return Some(yes_or_no(
+                    self.connection_config.tcp_nodelay.load(Ordering::Relaxed),
+                ))
+            }
+            "timeout" => {
+                return Some(
+                    self.connection_config
+                        .timeout
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                )
+            }
+            "set-max-intset-entries" => self.set_limits.max_intset_entries,
+            "set-max-listpack-entries" => self.set_limits.max_listpack_entries,
+            "set-max-listpack-value" => self.set_limits.max_listpack_value,
+            "zset-max-listpack-entries" => self.sorted_set_limits.max_listpack_entries,
+            "zset-max-listpack-value" => self.sorted_set_limits.max_listpack_value,
+            _ => return None,
+        };
+        Some(value.to_string())
+    }
+
+    fn config_set_value(self: &mut DataCore, name: &str, value: &str) -> Result<(), CommandError> {
+        let invalid = || {
+            CommandError::Other(format!(
+                "ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+                name
+            ))
+        };
+        match name {
+            "appendfilename" | "cluster-enabled" | "tcp-backlog" => {
+                return Err(CommandError::Other(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                    name
+                )))
+            }
+            "appendfsync" => {
+                let appendfsync = value.parse().map_err(|_| {
+                    CommandError::Other(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - argument(s) must be one of the following: always, everysec, no",
+                        name
+                    ))
+                })?;
+                self.set_appendfsync(appendfsync)
+            }
+            "appendonly" => {
+                let appendonly = parse_yes_or_no(name, value)?;
+                if appendonly && self.aof.is_none() {
+                    self.open_aof().map_err(|err| {
+                        CommandError::Other(format!("ERR Failed to open the AOF file: {}", err))
+                    })?;
+                    // The file starts with the data set as it is now.
+                    if self.aof_rewrite.is_none() {
+                        self.start_aof_rewrite();
+                    }
+                } else if !appendonly {
+                    self.close_aof();
+                }
+                self.appendonly = appendonly
+            }
+            "aof-use-rdb-preamble" => self.aof_use_rdb_preamble = parse_yes_or_no(name, value)?,
+            "busy-reply-threshold" | "lua-time-limit" => {
+                self.lua_time_limit_ms = value.parse().map_err(|_| invalid())?
+            }
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err(CommandError::Other(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - dbfilename can't be a path, just a filename",
+                        name
+                    )));
+                }
+                self.dbfilename = value.to_string()
+            }
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    return Err(CommandError::Other(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - No such file or directory",
+                        name
+                    )));
+                }
+                self.dir = value.to_string()
+            }
+            "latency-monitor-threshold" => {
+                self.latency.threshold_ms = value.parse().map_err(|_| invalid())?
+            }
+            "lfu-decay-time" => self.lfu_decay_time = value.parse().map_err(|_| invalid())?,
+            "lfu-log-factor" => self.lfu_log_factor = value.parse().map_err(|_| invalid())?,
+            "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                self.min_replicas_max_lag = value.parse().map_err(|_| invalid())?
+            }
+            "min-replicas-to-write" | "min-slaves-to-write" => {
+                self.min_replicas_to_write = value.parse().map_err(|_| invalid())?
+            }
+            "proto-max-bulk-len" => {
+                let proto_max_bulk_len = parse_memory(value)
+                    .filter(|length| *length >= 1024 * 1024)
+                    .and_then(|length| usize::try_from(length).ok())
+                    .ok_or_else(|| {
+                        CommandError::Other(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be a memory value of at least 1mb",
+                            name
+                        ))
+                    })?;
+                self.connection_config
+                    .proto_max_bulk_len
+                    .store(proto_max_bulk_len, Ordering::Relaxed)
+            }
+            "repl-diskless-sync" => self.repl_diskless_sync = parse_yes_or_no(name, value)?,
+            "repl-ping-replica-period" => {
+                self.repl_ping_replica_period = value
+                    .parse()
+                    .ok()
+                    .filter(|period| *period > 0)
+                    .ok_or_else(invalid)?
+            }
+            "replica-announce-ip" | "slave-announce-ip" => {
+                self.replica_announce_ip = Some(value.to_string()).filter(|ip| !ip.is_empty())
+            }
+            "replica-announce-port" | "slave-announce-port" => {
+                self.replica_announce_port = value
+                    .parse()
+                    .ok()
+                    .filter(|port| *port <= 65535)
+                    .ok_or_else(invalid)
+                    .map(|port| Some(port).filter(|port| *port > 0))?
+            }
+            "requirepass" => self.acl.set_requirepass(value),
+            "save" => {
+                self.save_rules = parse_save_rules(value).ok_or_else(|| {
+                    CommandError::Other(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - Invalid save parameters",
+                        name
+                    ))
+                })?
+            }
+            "tcp-keepalive" => self
+                .connection_config
+                .tcp_keepalive
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "tcp-nodelay" => self
+                .connection_config
+                .tcp_nodelay
+                .store(parse_yes_or_no(name, value)?, Ordering::Relaxed),
+            "timeout" => self
+                .connection_config
+                .timeout
+                .store(value.parse().map_err(|_| invalid())?, Ordering::Relaxed),
+            "set-max-intset-entries" => {
+                self.set_limits.max_intset_entries = value.parse().map_err(|_| invalid())?
+            }
+            "set-max-listpack-entries" => {
+                self.set_limits.max_listpack_entries = value.parse().map_err(|_| invalid())?
+            }
+            "set-max-listpack-value" => {
+                self.set_limits.max_listpack_value = value.parse().map_err(|_| invalid())?
+            }
+            "zset-max-listpack-entries" => {
+                self.sorted_set_limits.max_listpack_entries =
+                    value.parse().map_err(|_| invalid())?
+            }
+            "zset-max-listpack-value" => {
+                self.sorted_set_limits.max_listpack_value = value.parse().map_err(|_| invalid())?
+            }
+            _ => {
+                return Err(CommandError::Other(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// CONFIG GET parameter [parameter ...] | CONFIG SET parameter value [parameter value ...]
+    pub(crate) fn config(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        match arguments[1].to_lowercase().as_str() {
+            "get" => {
+                check_arity(arguments, 3, usize::MAX)?;
+                let mut values = Vec::new();
+                for name in CONFIG_PARAMETERS {
+                    if arguments[2..]
+                        .iter()
+                        .any(|requested| requested.eq_ignore_ascii_case(name))
+                    {
+                        values.push(ParserValue::BulkString((*name).into()));
+                        values.push(ParserValue::BulkString(
+                            self.config_get_value(name).unwrap().into(),
+                        ));
+                    }
+                }
+                Ok(ParserValue::Array(values))
+            }
+            "set" => {
+                if arguments.len() < 4 || arguments.len() % 2 == 1 {
+                    return Err(CommandError::WrongArity("config|set".to_string()));
+                }
+                for pair in arguments[2..].chunks(2) {
+                    let name = pair[0].to_lowercase();
+                    let value = pair[1].to_str().ok_or_else(|| {
+                        CommandError::Other(format!(
+                            "ERR CONFIG SET failed (possibly related to argument '{}') - argument isn't valid UTF-8",
+                            name
+                        ))
+                    })?;
+                    self.config_set_value(&name, value)?;
+                }
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "CONFIG".to_string(),
+            )),
+        }
+    }
+}
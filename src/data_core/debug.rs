@@ -0,0 +1,126 @@
+use std::thread;
+use std::time::Duration;
+
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, Value};
+use crate::parser::ParserValue;
+
+impl Value {
+    /// Rough size of the value, standing in for the length of its RDB
+    /// serialization in DEBUG OBJECT.
+    fn serialized_length(self: &Value) -> usize {
+        match self {
+            Value::String(string) => string.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(sorted_set) => sorted_set.len(),
+            Value::Stream(stream) => stream.len(),
+        }
+    }
+}
+
+impl DataCore {
+    /// DEBUG SLEEP seconds | DEBUG OBJECT key | DEBUG SET-ACTIVE-EXPIRE 0|1 |
+    /// DEBUG PANIC
+    pub(crate) fn debug(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        match arguments[1].to_lowercase().as_str() {
+            "sleep" => {
+                check_arity(arguments, 3, 3)?;
+                let seconds = arguments[2]
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                    .ok_or(CommandError::NotFloat)?;
+                let duration = Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| CommandError::Other("ERR value is out of range".to_string()))?;
+                // Sleeping on the data core thread holds up every other
+                // client, which is what tests use this for. The runtime is
+                // told first, so connection tasks queued on this worker move
+                // to another one.
+                let sleep = || thread::sleep(duration);
+                match Handle::try_current() {
+                    Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                        task::block_in_place(sleep)
+                    }
+                    _ => sleep(),
+                }
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            "object" => {
+                check_arity(arguments, 3, 3)?;
+                let data_value = self
+                    .peek(&arguments[2])
+                    .ok_or_else(|| CommandError::Other("ERR no such key".to_string()))?;
+                Ok(ParserValue::SimpleString(format!(
+                    "Value at:{:p} refcount:1 encoding:{} serializedlength:{} lru:{} lru_seconds_idle:{}",
+                    data_value,
+                    data_value.value.encoding(),
+                    data_value.value.serialized_length(),
+                    data_value.access.lru_clock(),
+                    data_value.access.idle_seconds()
+                )))
+            }
+            // Unlike Redis, the data core survives this: the panic is turned
+            // into an error reply like any other failing command.
+            "panic" => panic!("DEBUG PANIC"),
+            "set-active-expire" => {
+                check_arity(arguments, 3, 3)?;
+                self.active_expire = match arguments[2].as_bytes() {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => return Err(CommandError::Syntax),
+                };
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "DEBUG".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_debug_object_and_active_expire() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SADD", "s", "1", "2", "3"]);
+        assert!(matches!(
+            run(&mut data_core, &["DEBUG", "OBJECT", "s"]),
+            ParserValue::SimpleString(s)
+                if s.contains(" encoding:intset serializedlength:3 ")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["DEBUG", "OBJECT", "missing"]),
+            ParserValue::Error(e) if e == "ERR no such key"
+        ));
+
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["DEBUG", "SET-ACTIVE-EXPIRE", "0"])
+        );
+        assert!(!data_core.active_expire);
+        assert!(matches!(
+            run(&mut data_core, &["DEBUG", "SET-ACTIVE-EXPIRE", "2"]),
+            ParserValue::Error(e) if e == "ERR syntax error"
+        ));
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["DEBUG", "SLEEP", "0"])
+        );
+        assert_eq!(
+            ParserValue::Error("ERR value is out of range".to_string()),
+            run(&mut data_core, &["DEBUG", "SLEEP", "1e300"])
+        );
+    }
+}
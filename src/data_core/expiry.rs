@@ -0,0 +1,162 @@
+use std::collections::BTreeSet;
+
+use chrono::Utc;
+use tokio::time::Instant;
+
+use crate::data_core::{DataCore, DataValue, Key};
+
+/// The keys with an expiry, ordered by when they expire, so the expiry
+/// cycle only visits the keys that are due instead of the whole data set.
+///
+/// Keys are added to and removed from the data set through
+/// [`DataCore::insert_value`] and [`DataCore::remove_value`], which keep
+/// one entry per key with an expiry. Replicas keep the entries of the keys
+/// they hide until their master deletes them.
+#[derive(Debug, Default)]
+pub(crate) struct ExpiryIndex {
+    deadlines: BTreeSet<(i64, Key)>,
+}
+
+impl ExpiryIndex {
+    fn insert(self: &mut ExpiryIndex, key: &Key, expiry_in_nanoseconds: i64) {
+        self.deadlines.insert((expiry_in_nanoseconds, key.clone()));
+    }
+
+    fn remove(self: &mut ExpiryIndex, entry: &(i64, Key)) {
+        self.deadlines.remove(entry);
+    }
+
+    /// Drops the entry of `key`, if its value had an expiry.
+    fn remove_key(self: &mut ExpiryIndex, key: &Key, value: &DataValue) {
+        if let Some(expiry_in_nanoseconds) = value.expiry_in_nanoseconds {
+            self.remove(&(expiry_in_nanoseconds, key.clone()));
+        }
+    }
+
+    /// The entries whose expiry passed by `now`, soonest first.
+    fn due(self: &ExpiryIndex, now: i64) -> Vec<(i64, Key)> {
+        self.deadlines
+            .range(..(now, Key::from([])))
+            .cloned()
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn len(self: &ExpiryIndex) -> usize {
+        self.deadlines.len()
+    }
+}
+
+/// Whether an entry of the index still stands for the expiry of `value`.
+fn is_current(entry: &(i64, Key), value: Option<&DataValue>) -> bool {
+    value.is_some_and(|value| value.expiry_in_nanoseconds == Some(entry.0))
+}
+
+impl DataCore {
+    /// Adds `value` to the data set, replacing the expiry of the value it
+    /// overwrites in the index with its own.
+    pub(super) fn insert_value(self: &mut DataCore, key: &[u8], value: DataValue) {
+        let key = self.intern_key(key);
+        if let Some(previous) = self.data_set.get(&key) {
+            self.expires.remove_key(&key, previous);
+        }
+        if let Some(expiry_in_nanoseconds) = value.expiry_in_nanoseconds {
+            self.expires.insert(&key, expiry_in_nanoseconds);
+        }
+        self.data_set.insert(key, value);
+    }
+
+    /// Removes `key` from the data set and its expiry from the index.
+    pub(super) fn remove_value(self: &mut DataCore, key: &[u8]) -> Option<DataValue> {
+        let (key, value) = self.data_set.remove_entry(key)?;
+        self.expires.remove_key(&key, &value);
+        Some(value)
+    }
+
+    /// Indexes the expiry of every key, after the data set was replaced.
+    pub(crate) fn reindex_expires(self: &mut DataCore) {
+        self.expires = ExpiryIndex::default();
+        for (key, value) in &self.data_set {
+            if let Some(expiry_in_nanoseconds) = value.expiry_in_nanoseconds {
+                self.expires.insert(key, expiry_in_nanoseconds);
+            }
+        }
+    }
+
+    /// Removes every key whose expiry passed, telling replicas to delete
+    /// them too. Replicas leave that to their master.
+    pub fn remove_expired_values(self: &mut DataCore) {
+        if self.is_slave() {
+            return;
+        }
+        let started = Instant::now();
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        for entry in self.expires.due(now) {
+            self.expires.remove(&entry);
+            if is_current(&entry, self.data_set.get(&entry.1)) {
+                self.expire_key(&entry.1);
+            }
+        }
+        self.latency.record("expire-cycle", started.elapsed());
+    }
+
+    /// Takes the keys whose expiry has passed out of the data set while a
+    /// replica serves its own clients.
+    pub(super) fn hide_expired_keys(self: &mut DataCore) -> Vec<(Key, DataValue)> {
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let mut hidden = Vec::new();
+        for entry in self.expires.due(now) {
+            // The entry stays until the master deletes the key.
+            if !is_current(&entry, self.data_set.get(&entry.1)) {
+                self.expires.remove(&entry);
+            } else if let Some(value) = self.data_set.remove_entry(&entry.1) {
+                hidden.push(value);
+            }
+        }
+        hidden
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_expires_only_due_keys() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "soon", "v", "PX", "1"]);
+        run(&mut data_core, &["SET", "later", "v", "PX", "60000"]);
+        run(&mut data_core, &["SET", "persistent", "v"]);
+        // Overwriting a key drops its expiry.
+        run(&mut data_core, &["SET", "overwritten", "v", "PX", "1"]);
+        run(&mut data_core, &["SET", "overwritten", "v"]);
+        assert_eq!(2, data_core.expires.len());
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        data_core.remove_expired_values();
+        assert!(!data_core.data_set.contains_key("soon".as_bytes()));
+        for key in ["later", "persistent", "overwritten"] {
+            assert_eq!(
+                ParserValue::BulkString("v".into()),
+                run(&mut data_core, &["GET", key])
+            );
+        }
+        assert_eq!(1, data_core.expires.len());
+        assert_eq!(1, data_core.stats.expired_keys);
+    }
+
+    #[test]
+    fn test_index_keeps_one_entry_per_key() {
+        let mut data_core = new_data_core();
+        for _ in 0..100 {
+            run(&mut data_core, &["SET", "refreshed", "v", "PX", "60000"]);
+        }
+        run(&mut data_core, &["SET", "deleted", "v", "PX", "60000"]);
+        assert_eq!(2, data_core.expires.len());
+
+        run(&mut data_core, &["DEL", "deleted"]);
+        run(&mut data_core, &["SET", "refreshed", "v"]);
+        assert_eq!(0, data_core.expires.len());
+    }
+}
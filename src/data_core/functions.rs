@@ -0,0 +1,573 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use mlua::Lua;
+
+use crate::binary::ByteString;
+use crate::data_core::scripting::{lua_to_reply, new_lua, parse_keys_and_arguments, script_error};
+use crate::data_core::{check_arity, CommandError, DataCore};
+use crate::glob::glob_match;
+use crate::parser::ParserValue;
+
+/// Registry key of the table mapping function names to their callbacks.
+const CALLBACKS: &str = "registered_functions";
+const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+#[derive(Debug, Clone)]
+pub(crate) struct FunctionInfo {
+    name: String,
+    description: Option<String>,
+    flags: Vec<String>,
+}
+
+/// A library loaded with FUNCTION LOAD, kept as source and re-evaluated in a
+/// fresh Lua state for every call.
+#[derive(Debug, Clone)]
+pub(crate) struct Library {
+    name: String,
+    code: ByteString,
+    functions: Vec<FunctionInfo>,
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+fn other(message: &str) -> CommandError {
+    CommandError::Other(format!("ERR {}", message))
+}
+
+/// Reads the `#!lua name=<library>` line, returning the library name and the
+/// code after it.
+fn parse_shebang(code: &[u8]) -> Result<(String, &[u8]), CommandError> {
+    let (shebang, body) = match code.iter().position(|&byte| byte == b'\n') {
+        Some(end) => (&code[..end], &code[end + 1..]),
+        None => (code, &b""[..]),
+    };
+    let shebang = String::from_utf8_lossy(shebang);
+    let Some(shebang) = shebang.strip_prefix("#!") else {
+        return Err(other("Missing library metadata"));
+    };
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(CommandError::Other(format!(
+            "ERR Engine '{}' not found",
+            engine
+        )));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => {
+                return Err(CommandError::Other(format!(
+                    "ERR Invalid metadata value given: {}",
+                    part
+                )))
+            }
+        }
+    }
+    let name = name.ok_or_else(|| other("Library name was not given"))?;
+    if !is_valid_name(&name) {
+        return Err(other(
+            "Library names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ));
+    }
+    Ok((name, body))
+}
+
+fn registration_error(message: &str) -> mlua::Error {
+    mlua::Error::RuntimeError(message.to_string())
+}
+
+/// Reads the arguments of `redis.register_function`, either a name and a
+/// callback or a table with `function_name`, `callback`, `flags` and
+/// `description`.
+fn parse_registration<'lua>(
+    arguments: mlua::MultiValue<'lua>,
+) -> mlua::Result<(FunctionInfo, mlua::Function<'lua>)> {
+    let arguments = arguments.into_vec();
+    let (name, callback, flags, description) = match arguments.as_slice() {
+        [mlua::Value::String(name), mlua::Value::Function(callback)] => (
+            name.to_str()?.to_string(),
+            callback.clone(),
+            Vec::new(),
+            None,
+        ),
+        [mlua::Value::Table(table)] => {
+            let (mut name, mut callback, mut flags, mut description) =
+                (None, None, Vec::new(), None);
+            for pair in table.clone().pairs::<String, mlua::Value>() {
+                match pair? {
+                    (key, mlua::Value::String(value)) if key == "function_name" => {
+                        name = Some(value.to_str()?.to_string())
+                    }
+                    (key, mlua::Value::Function(value)) if key == "callback" => {
+                        callback = Some(value)
+                    }
+                    (key, mlua::Value::Table(value)) if key == "flags" => {
+                        flags = value
+                            .sequence_values::<String>()
+                            .collect::<mlua::Result<_>>()?
+                    }
+                    (key, mlua::Value::String(value)) if key == "description" => {
+                        description = Some(value.to_str()?.to_string())
+                    }
+                    _ => {
+                        return Err(registration_error(
+                            "unknown argument given to redis.register_function",
+                        ))
+                    }
+                }
+            }
+            let name = name.ok_or_else(|| {
+                registration_error("redis.register_function must get a function name argument")
+            })?;
+            let callback = callback.ok_or_else(|| {
+                registration_error("redis.register_function must get a callback argument")
+            })?;
+            (name, callback, flags, description)
+        }
+        _ => {
+            return Err(registration_error(
+                "wrong number of arguments to redis.register_function",
+            ))
+        }
+    };
+    if !is_valid_name(&name) {
+        return Err(registration_error(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ));
+    }
+    if flags
+        .iter()
+        .any(|flag| !FUNCTION_FLAGS.contains(&flag.as_str()))
+    {
+        return Err(registration_error("unknown flag given"));
+    }
+    Ok((
+        FunctionInfo {
+            name,
+            description,
+            flags,
+        },
+        callback,
+    ))
+}
+
+/// Runs a library's code in `lua`, recording the functions it registers
+/// and their callbacks.
+fn load_library(lua: &Lua, body: &[u8]) -> mlua::Result<Vec<FunctionInfo>> {
+    let functions = Rc::new(RefCell::new(Vec::<FunctionInfo>::new()));
+    lua.set_named_registry_value(CALLBACKS, lua.create_table()?)?;
+    let registered = functions.clone();
+    let register = lua.create_function(move |lua, arguments: mlua::MultiValue| {
+        let (info, callback) = parse_registration(arguments)?;
+        if registered
+            .borrow()
+            .iter()
+            .any(|function| function.name == info.name)
+        {
+            return Err(registration_error("Function already exists in the library"));
+        }
+        let callbacks = lua.named_registry_value::<mlua::Table>(CALLBACKS)?;
+        callbacks.set(info.name.as_str(), callback)?;
+        registered.borrow_mut().push(info);
+        Ok(())
+    })?;
+
+    let redis = match lua.globals().get::<_, mlua::Value>("redis")? {
+        mlua::Value::Table(redis) => redis,
+        _ => {
+            let redis = lua.create_table()?;
+            lua.globals().set("redis", redis.clone())?;
+            redis
+        }
+    };
+    redis.set("register_function", register)?;
+    // The shebang line is replaced by an empty one to keep line numbers.
+    lua.load([&b"\n"[..], body].concat())
+        .set_name("@user_function")
+        .exec()?;
+    redis.set("register_function", mlua::Value::Nil)?;
+
+    let functions = functions.take();
+    if functions.is_empty() {
+        return Err(registration_error("No functions registered"));
+    }
+    Ok(functions)
+}
+
+fn load_error(err: &mlua::Error) -> CommandError {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => load_error(cause),
+        mlua::Error::RuntimeError(message) => {
+            CommandError::Other(format!("ERR Error registering functions: {}", message))
+        }
+        mlua::Error::SyntaxError { message, .. } => {
+            CommandError::Other(format!("ERR Error compiling function: {}", message))
+        }
+        err => CommandError::Other(format!("ERR {}", err)),
+    }
+}
+
+/// Parses and evaluates a library to find the functions it registers.
+fn compile_library(code: &[u8]) -> Result<Library, CommandError> {
+    let (name, body) = parse_shebang(code)?;
+    let functions = load_library(&new_lua()?, body).map_err(|err| load_error(&err))?;
+    Ok(Library {
+        name,
+        code: code.into(),
+        functions,
+    })
+}
+
+/// Adds `library` to `libraries`, checking that neither it nor its functions
+/// clash with other libraries.
+fn install_library(
+    libraries: &mut BTreeMap<String, Library>,
+    library: Library,
+    replace: bool,
+) -> Result<(), CommandError> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(CommandError::Other(format!(
+            "ERR Library '{}' already exists",
+            library.name
+        )));
+    }
+    for other_library in libraries.values() {
+        if other_library.name == library.name {
+            continue;
+        }
+        if let Some(function) = library.functions.iter().find(|function| {
+            other_library
+                .functions
+                .iter()
+                .any(|other| other.name == function.name)
+        }) {
+            return Err(CommandError::Other(format!(
+                "ERR Function {} already exists",
+                function.name
+            )));
+        }
+    }
+    libraries.insert(library.name.clone(), library);
+    Ok(())
+}
+
+fn library_reply(library: &Library, with_code: bool) -> ParserValue {
+    let functions = library
+        .functions
+        .iter()
+        .map(|function| {
+            ParserValue::Array(vec![
+                ParserValue::BulkString("name".into()),
+                ParserValue::BulkString(function.name.clone().into()),
+                ParserValue::BulkString("description".into()),
+                function
+                    .description
+                    .clone()
+                    .map_or(ParserValue::NullBulkString, |description| {
+                        ParserValue::BulkString(description.into())
+                    }),
+                ParserValue::BulkString("flags".into()),
+                ParserValue::Array(
+                    function
+                        .flags
+                        .iter()
+                        .map(|flag| ParserValue::BulkString(flag.as_str().into()))
+                        .collect(),
+                ),
+            ])
+        })
+        .collect();
+    let mut reply = vec![
+        ParserValue::BulkString("library_name".into()),
+        ParserValue::BulkString(library.name.clone().into()),
+        ParserValue::BulkString("engine".into()),
+        ParserValue::BulkString("LUA".into()),
+        ParserValue::BulkString("functions".into()),
+        ParserValue::Array(functions),
+    ];
+    if with_code {
+        reply.push(ParserValue::BulkString("library_code".into()));
+        reply.push(ParserValue::BulkString(library.code.clone()));
+    }
+    ParserValue::Array(reply)
+}
+
+/// Serializes libraries for FUNCTION DUMP as their length-prefixed code.
+fn dump_libraries(libraries: &BTreeMap<String, Library>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for library in libraries.values() {
+        payload.extend_from_slice(format!("{}\n", library.code.len()).as_bytes());
+        payload.extend_from_slice(&library.code);
+    }
+    payload
+}
+
+fn parse_dump(mut payload: &[u8]) -> Result<Vec<&[u8]>, CommandError> {
+    let invalid = || other("payload version or checksum are wrong");
+    let mut codes = Vec::new();
+    while !payload.is_empty() {
+        let end = payload
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .ok_or_else(invalid)?;
+        let (length, rest) = (&payload[..end], &payload[end + 1..]);
+        let length = std::str::from_utf8(length)
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or_else(invalid)?;
+        let code = rest.get(..length).ok_or_else(invalid)?;
+        codes.push(code);
+        payload = &rest[length..];
+    }
+    Ok(codes)
+}
+
+impl DataCore {
+    /// FCALL function numkeys [key ...] [arg ...] and its FCALL_RO variant.
+    pub(crate) fn fcall(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        read_only: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let (keys, function_arguments) = parse_keys_and_arguments(&arguments[2..])?;
+        let name = &arguments[1];
+        let (library, function) = self
+            .libraries
+            .values()
+            .find_map(|library| {
+                library
+                    .functions
+                    .iter()
+                    .find(|function| *name == function.name.as_str())
+                    .map(|function| (library, function))
+            })
+            .ok_or_else(|| other("Function not found"))?;
+        let no_writes = function.flags.iter().any(|flag| flag == "no-writes");
+        if read_only && !no_writes {
+            return Err(other(
+                "Can not execute a script with write flag using *_ro command.",
+            ));
+        }
+        let (_, body) = parse_shebang(&library.code)?;
+        let body = body.to_vec();
+
+        let lua = new_lua()?;
+        self.with_redis_api(&lua, no_writes, |lua| {
+            load_library(lua, &body)?;
+            let callback = lua
+                .named_registry_value::<mlua::Table>(CALLBACKS)?
+                .get::<_, mlua::Function>(lua.create_string(name)?)?;
+            let value = callback.call::<_, mlua::Value>((
+                lua.create_sequence_from(keys.iter().cloned())?,
+                lua.create_sequence_from(function_arguments.iter().cloned())?,
+            ))?;
+            Ok(lua_to_reply(&value))
+        })
+        .map_err(|err| script_error(&err, &format!("fcall to {}", name)))
+    }
+
+    /// FUNCTION LOAD | LIST | DELETE | FLUSH | DUMP | RESTORE
+    pub(crate) fn function(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        let wrong_arity = || CommandError::WrongArity(format!("function|{}", subcommand));
+        match subcommand.as_str() {
+            "load" => {
+                let (replace, code) = match &arguments[2..] {
+                    [code] => (false, code),
+                    [option, code] if option.eq_ignore_ascii_case("replace") => (true, code),
+                    [_, _] => {
+                        return Err(CommandError::Other(format!(
+                            "ERR Unknown option given: {}",
+                            arguments[2]
+                        )))
+                    }
+                    _ => return Err(wrong_arity()),
+                };
+                let library = compile_library(code)?;
+                let name = library.name.clone();
+                install_library(&mut self.libraries, library, replace)?;
+                Ok(ParserValue::BulkString(name.into()))
+            }
+            "list" => {
+                let mut pattern = &b"*"[..];
+                let mut with_code = false;
+                let mut index = 2;
+                while index < arguments.len() {
+                    match arguments[index].to_lowercase().as_str() {
+                        "withcode" => with_code = true,
+                        "libraryname" if index + 1 < arguments.len() => {
+                            index += 1;
+                            pattern = &arguments[index];
+                        }
+                        _ => return Err(CommandError::Syntax),
+                    }
+                    index += 1;
+                }
+                Ok(ParserValue::Array(
+                    self.libraries
+                        .values()
+                        .filter(|library| glob_match(pattern, &library.name))
+                        .map(|library| library_reply(library, with_code))
+                        .collect(),
+                ))
+            }
+            "delete" => {
+                if arguments.len() != 3 {
+                    return Err(wrong_arity());
+                }
+                self.libraries
+                    .remove(arguments[2].to_str().unwrap_or_default())
+                    .ok_or_else(|| other("Library not found"))?;
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            "flush" => {
+                match arguments.get(2).map(|mode| mode.to_lowercase()) {
+                    None => {}
+                    Some(mode) if arguments.len() == 3 && (mode == "async" || mode == "sync") => {}
+                    Some(_) => return Err(CommandError::Syntax),
+                }
+                self.libraries.clear();
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            "kill" => Err(CommandError::Other(
+                "NOTBUSY No scripts in execution right now.".to_string(),
+            )),
+            "dump" => {
+                if arguments.len() != 2 {
+                    return Err(wrong_arity());
+                }
+                Ok(ParserValue::BulkString(
+                    dump_libraries(&self.libraries).into(),
+                ))
+            }
+            "restore" => {
+                let policy = match arguments.len() {
+                    3 => "append".to_string(),
+                    4 => arguments[3].to_lowercase(),
+                    _ => return Err(wrong_arity()),
+                };
+                let mut libraries = match policy.as_str() {
+                    "append" | "replace" => self.libraries.clone(),
+                    "flush" => BTreeMap::new(),
+                    _ => return Err(other("Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.")),
+                };
+                for code in parse_dump(&arguments[2])? {
+                    install_library(&mut libraries, compile_library(code)?, policy == "replace")?;
+                }
+                self.libraries = libraries;
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "FUNCTION".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    const LIBRARY: &str = "#!lua name=mylib
+redis.register_function('store', function(keys, args)
+  return redis.call('SET', keys[1], args[1])
+end)
+redis.register_function{
+  function_name = 'fetch',
+  callback = function(keys) return redis.call('GET', keys[1]) end,
+  flags = {'no-writes'},
+}";
+
+    #[test]
+    fn test_load_and_call_functions() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::BulkString("mylib".into()),
+            run(&mut data_core, &["FUNCTION", "LOAD", LIBRARY])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["FUNCTION", "LOAD", LIBRARY]),
+            ParserValue::Error(e) if e == "ERR Library 'mylib' already exists"
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["FUNCTION", "LOAD", "#!lua name=other\nredis.register_function('fetch', function() end)"]),
+            ParserValue::Error(e) if e == "ERR Function fetch already exists"
+        ));
+
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["FCALL", "store", "1", "k", "v"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut data_core, &["FCALL_RO", "fetch", "1", "k"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["FCALL_RO", "store", "1", "k", "v"]),
+            ParserValue::Error(e) if e.starts_with("ERR Can not execute a script with write flag")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["FCALL", "missing", "0"]),
+            ParserValue::Error(e) if e == "ERR Function not found"
+        ));
+    }
+
+    #[test]
+    fn test_list_delete_dump_and_restore() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["FUNCTION", "LOAD", LIBRARY]);
+        let list = run(&mut data_core, &["FUNCTION", "LIST", "LIBRARYNAME", "my*"]);
+        let library = list.to_vec().unwrap()[0].clone();
+        let library = library.to_vec().unwrap();
+        assert_eq!(ParserValue::BulkString("mylib".into()), library[1]);
+        assert_eq!(2, library[5].to_vec().unwrap().len());
+        assert_eq!(
+            ParserValue::Array(vec![]),
+            run(&mut data_core, &["FUNCTION", "LIST", "LIBRARYNAME", "x*"])
+        );
+
+        let dump = run(&mut data_core, &["FUNCTION", "DUMP"])
+            .to_string()
+            .unwrap();
+        run(&mut data_core, &["FUNCTION", "DELETE", "mylib"]);
+        assert!(matches!(
+            run(&mut data_core, &["FUNCTION", "DELETE", "mylib"]),
+            ParserValue::Error(e) if e == "ERR Library not found"
+        ));
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["FUNCTION", "RESTORE", &dump])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["FUNCTION", "RESTORE", &dump]),
+            ParserValue::Error(e) if e == "ERR Library 'mylib' already exists"
+        ));
+        run(&mut data_core, &["FUNCTION", "RESTORE", &dump, "REPLACE"]);
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["FCALL", "fetch", "1", "k"])
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore};
+use crate::geohash;
+use crate::parser::ParserValue;
+use crate::sorted_set::format_score;
+
+/// Meters per unit accepted by GEODIST and the other geo commands.
+fn parse_unit(unit: &ByteString) -> Result<f64, CommandError> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(CommandError::Other(
+            "ERR unsupported unit provided. please use M, KM, FT, MI".to_string(),
+        )),
+    }
+}
+
+impl DataCore {
+    /// Position of `member` decoded from its geohash score.
+    fn geo_position(
+        self: &mut DataCore,
+        key: &[u8],
+        member: &[u8],
+    ) -> Result<Option<(f64, f64)>, CommandError> {
+        Ok(self
+            .get_sorted_set(key)?
+            .and_then(|sorted_set| sorted_set.score(member))
+            .map(|score| geohash::decode(score as u64)))
+    }
+
+    /// GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude member ...]
+    ///
+    /// Like Redis, this is a ZADD of the members with their geohashes as scores.
+    pub(crate) fn geoadd(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 5, usize::MAX)?;
+        let mut zadd = vec!["zadd".into(), arguments[1].clone()];
+        let mut index = 2;
+        let (mut nx, mut xx) = (false, false);
+        while index < arguments.len() {
+            match arguments[index].to_lowercase().as_str() {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                "ch" => {}
+                _ => break,
+            }
+            zadd.push(arguments[index].clone());
+            index += 1;
+        }
+        if nx && xx {
+            return Err(CommandError::Other(
+                "ERR XX and NX options at the same time are not compatible".to_string(),
+            ));
+        }
+        let positions = arguments[index..].chunks_exact(3);
+        if index == arguments.len() || !positions.remainder().is_empty() {
+            return Err(CommandError::Syntax);
+        }
+
+        for position in positions {
+            let parse = |coordinate: &ByteString| {
+                coordinate
+                    .parse::<f64>()
+                    .map_err(|_| CommandError::NotFloat)
+            };
+            let (longitude, latitude) = (parse(&position[0])?, parse(&position[1])?);
+            if !geohash::is_valid(longitude, latitude) {
+                return Err(CommandError::Other(format!(
+                    "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                    longitude, latitude
+                )));
+            }
+            zadd.push(geohash::encode(longitude, latitude).to_string().into());
+            zadd.push(position[2].clone());
+        }
+        self.zadd(&zadd)
+    }
+
+    /// GEOPOS key [member [member ...]]
+    pub(crate) fn geopos(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let mut positions = Vec::with_capacity(arguments.len() - 2);
+        for member in &arguments[2..] {
+            positions.push(match self.geo_position(&arguments[1], member)? {
+                Some((longitude, latitude)) => ParserValue::Array(vec![
+                    ParserValue::BulkString(format_score(longitude).into()),
+                    ParserValue::BulkString(format_score(latitude).into()),
+                ]),
+                None => ParserValue::NullArray,
+            });
+        }
+        Ok(ParserValue::Array(positions))
+    }
+
+    /// GEODIST key member1 member2 [M | KM | FT | MI]
+    pub(crate) fn geodist(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 5)?;
+        let unit = arguments.get(4).map_or(Ok(1.0), parse_unit)?;
+        let from = self.geo_position(&arguments[1], &arguments[2])?;
+        let to = self.geo_position(&arguments[1], &arguments[3])?;
+        Ok(match (from, to) {
+            (Some(from), Some(to)) => {
+                ParserValue::BulkString(format!("{:.4}", geohash::distance(from, to) / unit).into())
+            }
+            _ => ParserValue::NullBulkString,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_geoadd_geopos_and_geodist() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(
+                &mut data_core,
+                &[
+                    "GEOADD",
+                    "Sicily",
+                    "13.361389",
+                    "38.115556",
+                    "Palermo",
+                    "15.087269",
+                    "37.502669",
+                    "Catania"
+                ]
+            )
+        );
+        assert_eq!(
+            ParserValue::BulkString("3479099956230698".into()),
+            run(&mut data_core, &["ZSCORE", "Sicily", "Palermo"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("166274.1516".into()),
+            run(&mut data_core, &["GEODIST", "Sicily", "Palermo", "Catania"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("166.2742".into()),
+            run(
+                &mut data_core,
+                &["GEODIST", "Sicily", "Palermo", "Catania", "km"]
+            )
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GEODIST", "Sicily", "Palermo", "Rome"])
+        );
+
+        let positions = run(&mut data_core, &["GEOPOS", "Sicily", "Palermo", "Rome"]);
+        let positions = positions.to_vec().unwrap();
+        let palermo = positions[0].to_vec().unwrap();
+        let longitude = palermo[0].to_string().unwrap().parse::<f64>().unwrap();
+        assert!((longitude - 13.361389).abs() < 1e-5);
+        assert_eq!(ParserValue::NullArray, positions[1]);
+
+        assert!(matches!(
+            run(&mut data_core, &["GEOADD", "Sicily", "200", "10", "Nowhere"]),
+            ParserValue::Error(e) if e.contains("invalid longitude,latitude pair")
+        ));
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(
+                &mut data_core,
+                &["GEOADD", "Sicily", "NX", "13", "38", "Palermo"]
+            )
+        );
+    }
+}
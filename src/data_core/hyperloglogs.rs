@@ -0,0 +1,151 @@
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Value};
+use crate::hyperloglog;
+use crate::parser::ParserValue;
+use crate::string::RedisString;
+
+fn invalid_hyperloglog() -> CommandError {
+    CommandError::Other("WRONGTYPE Key is not a valid HyperLogLog string value.".to_string())
+}
+
+impl DataCore {
+    /// Returns the HyperLogLog at `key`, checking that the string holds one.
+    fn get_hyperloglog_mut(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<Option<&mut Vec<u8>>, CommandError> {
+        match self.lookup_mut(key) {
+            Some(DataValue {
+                value: Value::String(string),
+                ..
+            }) if hyperloglog::is_valid(&string.to_bytes()) => Ok(Some(string.make_raw())),
+            Some(DataValue {
+                value: Value::String(_),
+                ..
+            }) => Err(invalid_hyperloglog()),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Merges the registers of every existing HyperLogLog in `keys`.
+    fn merge_hyperloglogs(
+        self: &mut DataCore,
+        keys: &[ByteString],
+    ) -> Result<Vec<u8>, CommandError> {
+        let mut registers = vec![0; hyperloglog::REGISTERS];
+        for key in keys {
+            if let Some(hll) = self.get_hyperloglog_mut(key)? {
+                hyperloglog::merge_into(&mut registers, hll);
+            }
+        }
+        Ok(registers)
+    }
+
+    /// PFADD key [element [element ...]]
+    pub(crate) fn pfadd(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let key = &arguments[1];
+        let created = self.get_hyperloglog_mut(key)?.is_none();
+        if created {
+            self.insert_value(
+                key,
+                DataValue::new(Value::String(RedisString::new(hyperloglog::new()))),
+            );
+        }
+        let hll = self.get_hyperloglog_mut(key)?.unwrap();
+        let mut changed = created;
+        for element in &arguments[2..] {
+            changed |= hyperloglog::add(hll, element);
+        }
+        Ok(ParserValue::Integer(changed as i64))
+    }
+
+    /// PFCOUNT key [key ...]
+    ///
+    /// With several keys, estimates the cardinality of their union.
+    pub(crate) fn pfcount(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let count = match &arguments[1..] {
+            [key] => self
+                .get_hyperloglog_mut(key)?
+                .map_or(0, |hll| hyperloglog::cardinality(hll)),
+            keys => hyperloglog::estimate(&self.merge_hyperloglogs(keys)?),
+        };
+        Ok(ParserValue::Integer(count as i64))
+    }
+
+    /// PFMERGE destkey [sourcekey [sourcekey ...]]
+    pub(crate) fn pfmerge(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        // The destination takes part in the union when it already exists.
+        let registers = self.merge_hyperloglogs(&arguments[1..])?;
+        self.insert_value(
+            &arguments[1],
+            DataValue::new(Value::String(RedisString::new(
+                hyperloglog::from_registers(&registers),
+            ))),
+        );
+        Ok(ParserValue::SimpleString("OK".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_pfadd_pfcount_and_pfmerge() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["PFADD", "a", "x", "y", "z"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["PFADD", "a", "x"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["PFADD", "empty"])
+        );
+        run(&mut data_core, &["PFADD", "b", "z", "w"]);
+
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(&mut data_core, &["PFCOUNT", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["PFCOUNT", "empty"])
+        );
+        assert_eq!(
+            ParserValue::Integer(4),
+            run(&mut data_core, &["PFCOUNT", "a", "b", "missing"])
+        );
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["PFMERGE", "b", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(4),
+            run(&mut data_core, &["PFCOUNT", "b"])
+        );
+
+        run(&mut data_core, &["SET", "s", "not a hll"]);
+        assert!(matches!(
+            run(&mut data_core, &["PFADD", "s", "x"]),
+            ParserValue::Error(e) if e.contains("not a valid HyperLogLog")
+        ));
+    }
+}
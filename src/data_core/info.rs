@@ -0,0 +1,228 @@
+use std::fmt::Write;
+
+use chrono::Utc;
+use tokio::time::Instant;
+
+use crate::binary::ByteString;
+use crate::data_core::{CommandError, DataCore};
+use crate::memory::{cpu_time, human_bytes, resident_memory, used_memory, ALLOCATOR_NAME};
+use crate::parser::ParserValue;
+
+/// The sections of INFO, in the order they are shown.
+const SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cpu",
+    "cluster",
+    "keyspace",
+];
+
+/// Counters of what the server did since it started, for INFO.
+#[derive(Debug)]
+pub(crate) struct ServerStats {
+    pub(crate) started: Instant,
+    pub(crate) total_commands_processed: u64,
+    pub(crate) total_error_replies: u64,
+    pub(crate) expired_keys: u64,
+}
+
+impl Default for ServerStats {
+    fn default() -> ServerStats {
+        ServerStats {
+            started: Instant::now(),
+            total_commands_processed: 0,
+            total_error_replies: 0,
+            expired_keys: 0,
+        }
+    }
+}
+
+impl DataCore {
+    fn server_info(self: &DataCore) -> String {
+        let uptime = self.stats.started.elapsed().as_secs();
+        format!(
+            "# Server\nredis_version:7.2.0\nredis_mode:{}\nos:{} {}\narch_bits:{}\nprocess_id:{}\ntcp_port:{}\nserver_time_usec:{}\nuptime_in_seconds:{}\nuptime_in_days:{}\n",
+            if self.cluster.is_some() { "cluster" } else { "standalone" },
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            usize::BITS,
+            std::process::id(),
+            self.port,
+            Utc::now().timestamp_micros(),
+            uptime,
+            uptime / (24 * 60 * 60)
+        )
+    }
+
+    fn clients_info(self: &DataCore) -> String {
+        let connections = self.connections.list();
+        let pubsub_clients = connections
+            .iter()
+            .filter(|connection| self.pubsub.is_subscribed(connection.id))
+            .count();
+        format!(
+            "# Clients\nconnected_clients:{}\nblocked_clients:{}\npubsub_clients:{}\n",
+            connections.len(),
+            self.blocked_clients.len(),
+            pubsub_clients
+        )
+    }
+
+    fn memory_info(self: &DataCore) -> String {
+        let rss = resident_memory().unwrap_or(0);
+        // Without the counting allocator, the resident set size is the best
+        // there is.
+        let (used, peak) = match used_memory() {
+            (0, _) => (rss, rss),
+            counted => counted,
+        };
+        format!(
+            "# Memory\nused_memory:{}\nused_memory_human:{}\nused_memory_rss:{}\nused_memory_rss_human:{}\nused_memory_peak:{}\nused_memory_peak_human:{}\nmem_allocator:{}\n",
+            used,
+            human_bytes(used),
+            rss,
+            human_bytes(rss),
+            peak,
+            human_bytes(peak),
+            ALLOCATOR_NAME
+        )
+    }
+
+    fn stats_info(self: &DataCore) -> String {
+        format!(
+            "# Stats\ntotal_connections_received:{}\ntotal_commands_processed:{}\ntotal_error_replies:{}\nexpired_keys:{}\npubsub_channels:{}\npubsub_patterns:{}\n",
+            self.connections.total_registered(),
+            self.stats.total_commands_processed,
+            self.stats.total_error_replies,
+            self.stats.expired_keys,
+            self.pubsub.channel_count(),
+            self.pubsub.pattern_count()
+        )
+    }
+
+    fn cpu_info(self: &DataCore) -> String {
+        let (system, user) = cpu_time().unwrap_or_default();
+        format!(
+            "# CPU\nused_cpu_sys:{:.6}\nused_cpu_user:{:.6}\n",
+            system, user
+        )
+    }
+
+    /// Lists the database with its key counts and the average time to
+    /// live of its keys with an expiry, unless it is empty.
+    fn keyspace_info(self: &DataCore) -> String {
+        let mut info = "# Keyspace\n".to_string();
+        if self.data_set.is_empty() {
+            return info;
+        }
+        let now = Utc::now().timestamp_nanos_opt().unwrap_or_default();
+        let ttls = self
+            .data_set
+            .values()
+            .filter_map(|value| value.expiry_in_nanoseconds)
+            .map(|expiry| (expiry - now).max(0) / 1_000_000)
+            .collect::<Vec<_>>();
+        let avg_ttl = ttls.iter().sum::<i64>() / (ttls.len().max(1) as i64);
+        let _ = writeln!(
+            info,
+            "db0:keys={},expires={},avg_ttl={}",
+            self.data_set.len(),
+            ttls.len(),
+            avg_ttl
+        );
+        info
+    }
+
+    fn section_info(self: &DataCore, section: &str) -> String {
+        match section {
+            "server" => self.server_info(),
+            "clients" => self.clients_info(),
+            "memory" => self.memory_info(),
+            "persistence" => self.persistence_info(),
+            "stats" => self.stats_info(),
+            "replication" => self.replication_info(),
+            "cpu" => self.cpu_info(),
+            "cluster" => format!(
+                "# Cluster\ncluster_enabled:{}\n",
+                self.cluster.is_some() as i64
+            ),
+            "keyspace" => self.keyspace_info(),
+            _ => String::new(),
+        }
+    }
+
+    /// INFO [section ...], where `default`, `all` and `everything` stand
+    /// for every section.
+    pub(crate) fn info(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        let requested = arguments[1..]
+            .iter()
+            .map(|section| section.to_lowercase())
+            .collect::<Vec<_>>();
+        let every = requested.is_empty()
+            || requested
+                .iter()
+                .any(|section| ["default", "all", "everything"].contains(&section.as_str()));
+        let info = SECTIONS
+            .iter()
+            .filter(|section| every || requested.iter().any(|name| name == *section))
+            .map(|section| self.section_info(section).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        Ok(ParserValue::BulkString(info.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::memory::ALLOCATOR_NAME;
+    use crate::parser::ParserValue;
+
+    fn info(data_core: &mut crate::data_core::DataCore, sections: &[&str]) -> String {
+        let arguments = [&["INFO"], sections].concat();
+        match run(data_core, &arguments) {
+            ParserValue::BulkString(info) => info.to_string(),
+            reply => panic!("INFO should reply with a bulk string, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "a", "1"]);
+        run(&mut data_core, &["SET", "b", "2", "PX", "60000"]);
+        run(&mut data_core, &["GET"]);
+
+        let all = info(&mut data_core, &[]);
+        for section in [
+            "# Server",
+            "# Clients",
+            "# Memory",
+            "# Persistence",
+            "# Stats",
+            "# Replication",
+            "# CPU",
+            "# Cluster",
+            "# Keyspace",
+        ] {
+            assert!(all.contains(section), "{} is missing", section);
+        }
+
+        let stats = info(&mut data_core, &["STATS", "keyspace"]);
+        assert!(stats.starts_with("# Stats\n"));
+        assert!(!stats.contains("# Server"));
+        assert!(stats.contains("total_commands_processed:4\n"));
+        assert!(stats.contains("total_error_replies:1\n"));
+        assert!(stats.contains("db0:keys=2,expires=1,avg_ttl="));
+
+        let memory = info(&mut data_core, &["memory"]);
+        assert!(memory.ends_with(&format!("\nmem_allocator:{}", ALLOCATOR_NAME)));
+    }
+}
@@ -0,0 +1,287 @@
+use std::mem::size_of;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Key, Value};
+use crate::parser::ParserValue;
+use crate::stream::StreamId;
+use crate::string::RedisString;
+
+/// Elements of aggregates MEMORY USAGE looks at by default.
+const MEMORY_USAGE_SAMPLES: usize = 5;
+
+/// Redis' reference count for objects that are never freed.
+const SHARED_REFCOUNT: i64 = i32::MAX as i64;
+
+fn string_size(string: &[u8]) -> usize {
+    size_of::<ByteString>() + string.len()
+}
+
+/// The allocation of a key: its reference counts and bytes. The keyspace,
+/// the expiry index and WATCH all point to it, so it is counted once.
+fn key_size(key: &[u8]) -> usize {
+    2 * size_of::<usize>() + key.len()
+}
+
+/// Extrapolates the size of `len` elements from the first `samples` of
+/// `sizes`, or all of them for 0.
+fn sampled_size(len: usize, sizes: impl Iterator<Item = usize>, samples: usize) -> usize {
+    let count = match samples {
+        0 => len,
+        samples => samples.min(len),
+    };
+    if count == 0 {
+        return 0;
+    }
+    let total = sizes.take(count).sum::<usize>();
+    (total as f64 / count as f64 * len as f64) as usize
+}
+
+impl Value {
+    pub(crate) fn encoding(self: &Value) -> &'static str {
+        match self {
+            Value::String(string) => string.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::SortedSet(sorted_set) => sorted_set.encoding(),
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// The bytes the value takes outside of its [`DataValue`]. Int and
+    /// embstr strings live inside it and shared integers share their digits,
+    /// so they take none.
+    fn memory_usage(self: &Value, samples: usize) -> usize {
+        match self {
+            Value::String(RedisString::Raw(bytes)) => bytes.capacity(),
+            Value::String(_) => 0,
+            Value::Set(set) if set.encoding() == "intset" => set.len() * size_of::<i64>(),
+            Value::Set(set) => sampled_size(
+                set.len(),
+                set.iter().map(|member| string_size(&member)),
+                samples,
+            ),
+            Value::SortedSet(sorted_set) => sampled_size(
+                sorted_set.len(),
+                sorted_set
+                    .iter()
+                    .map(|(member, _)| string_size(member) + size_of::<f64>()),
+                samples,
+            ),
+            Value::Stream(stream) => sampled_size(
+                stream.len(),
+                stream
+                    .range(StreamId::new(0, 0), StreamId::new(u64::MAX, u64::MAX))
+                    .map(|(_, fields)| {
+                        size_of::<StreamId>()
+                            + fields
+                                .iter()
+                                .map(|(field, value)| string_size(field) + string_size(value))
+                                .sum::<usize>()
+                    }),
+                samples,
+            ),
+        }
+    }
+}
+
+impl DataCore {
+    /// DEL key [key ...] | UNLINK key [key ...]
+    pub(crate) fn del(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let mut deleted = 0;
+        for key in &arguments[1..] {
+            if self.lookup(key).is_some() {
+                self.remove_value(key);
+                deleted += 1;
+            }
+        }
+        Ok(ParserValue::Integer(deleted))
+    }
+
+    /// MEMORY USAGE key [SAMPLES count]
+    ///
+    /// Estimates the bytes the key and its value take, sampling `count`
+    /// elements of aggregates, or all of them for 0.
+    pub(crate) fn memory(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        if !arguments[1].eq_ignore_ascii_case("usage") {
+            return Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "MEMORY".to_string(),
+            ));
+        }
+        let samples = match &arguments[2..] {
+            [_] => MEMORY_USAGE_SAMPLES,
+            [_, option, count] if option.eq_ignore_ascii_case("samples") => {
+                count.parse().map_err(|_| CommandError::NotInteger)?
+            }
+            [] => return Err(CommandError::WrongArity("memory|usage".to_string())),
+            _ => return Err(CommandError::Syntax),
+        };
+        let key = &arguments[2];
+        let Some(data_value) = self.peek(key) else {
+            return Ok(ParserValue::NullBulkString);
+        };
+        let mut bytes =
+            size_of::<(Key, DataValue)>() + key_size(key) + data_value.value.memory_usage(samples);
+        // The expiry index holds another pointer to the key, not a copy.
+        if data_value.expiry_in_nanoseconds.is_some() {
+            bytes += size_of::<(i64, Key)>();
+        }
+        Ok(ParserValue::Integer(bytes as i64))
+    }
+
+    /// OBJECT ENCODING | IDLETIME | FREQ | REFCOUNT key, which don't count
+    /// as an access to the key.
+    pub(crate) fn object(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        if !["encoding", "idletime", "freq", "refcount"].contains(&subcommand.as_str()) {
+            return Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "OBJECT".to_string(),
+            ));
+        }
+        check_arity(arguments, 3, 3)?;
+        let lfu_decay_time = self.lfu_decay_time;
+        let Some(data_value) = self.peek(&arguments[2]) else {
+            return Ok(ParserValue::NullBulkString);
+        };
+        Ok(match subcommand.as_str() {
+            "encoding" => ParserValue::BulkString(data_value.value.encoding().into()),
+            "idletime" => ParserValue::Integer(data_value.access.idle_seconds() as i64),
+            "refcount" => ParserValue::Integer(match &data_value.value {
+                Value::String(string) if string.is_shared() => SHARED_REFCOUNT,
+                _ => 1,
+            }),
+            _ => ParserValue::Integer(data_value.access.frequency(lfu_decay_time) as i64),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::{Client, DataCore, Key};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_object_subcommands() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["CONFIG", "SET", "lfu-log-factor", "0"]);
+        run(&mut data_core, &["SET", "k", "v"]);
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["OBJECT", "IDLETIME", "k"])
+        );
+        for _ in 0..3 {
+            run(&mut data_core, &["GET", "k"]);
+        }
+        // OBJECT itself doesn't count as an access.
+        for _ in 0..2 {
+            assert_eq!(
+                ParserValue::Integer(8),
+                run(&mut data_core, &["OBJECT", "FREQ", "k"])
+            );
+        }
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["OBJECT", "IDLETIME", "missing"])
+        );
+        run(&mut data_core, &["SET", "n", "123"]);
+        assert_eq!(
+            ParserValue::BulkString("int".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "n"])
+        );
+        assert_eq!(
+            ParserValue::Integer(i32::MAX as i64),
+            run(&mut data_core, &["OBJECT", "REFCOUNT", "n"])
+        );
+        run(&mut data_core, &["SETBIT", "n", "0", "1"]);
+        assert_eq!(
+            ParserValue::BulkString("raw".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "n"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["OBJECT", "REFCOUNT", "n"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["OBJECT", "NOSUCH", "k"]),
+            ParserValue::Error(err) if err.starts_with("ERR unknown subcommand")
+        ));
+    }
+
+    fn memory_usage(data_core: &mut DataCore, arguments: &[&str]) -> i64 {
+        match run(data_core, &[&["MEMORY", "USAGE"], arguments].concat()) {
+            ParserValue::Integer(bytes) => bytes,
+            reply => panic!("MEMORY USAGE should reply with an integer, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "int", "1000"]);
+        run(&mut data_core, &["SET", "emb", "abcd"]);
+        run(&mut data_core, &["SET", "raw", &"x".repeat(1000)]);
+        // Small values live inside the key's entry.
+        let int = memory_usage(&mut data_core, &["int"]);
+        assert_eq!(int, memory_usage(&mut data_core, &["emb"]));
+        assert!(memory_usage(&mut data_core, &["raw"]) >= int + 1000);
+
+        // Members of the same length extrapolate exactly.
+        for member in 100..200 {
+            let member = format!("member{}", member);
+            run(&mut data_core, &["SADD", "set", &member]);
+        }
+        assert_eq!(
+            memory_usage(&mut data_core, &["set", "SAMPLES", "0"]),
+            memory_usage(&mut data_core, &["set"])
+        );
+
+        // An expiry adds an index entry, which shares the key.
+        run(&mut data_core, &["SET", "ttl", "1000", "EX", "100"]);
+        run(&mut data_core, &["SET", "tmp", "1000"]);
+        assert_eq!(
+            size_of::<(i64, Key)>() as i64,
+            memory_usage(&mut data_core, &["ttl"]) - memory_usage(&mut data_core, &["tmp"])
+        );
+
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["MEMORY", "USAGE", "missing"])
+        );
+        assert_eq!(
+            ParserValue::Error("ERR syntax error".to_string()),
+            run(&mut data_core, &["MEMORY", "USAGE", "int", "SAMPLES"])
+        );
+    }
+
+    #[test]
+    fn test_keys_are_shared() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel();
+        let client = Client::new(1, push_tx);
+        run_as(&mut data_core, &client, &["WATCH", "k"]);
+        run(&mut data_core, &["SET", "k", "v", "EX", "100"]);
+        run(&mut data_core, &["SET", "k", "w", "EX", "200"]);
+        // The keyspace, the expiry index, the key's WATCH version and the
+        // client's watched keys hold the same key.
+        let (key, _) = data_core.data_set.get_key_value(&b"k"[..]).unwrap();
+        assert_eq!(4, Arc::strong_count(key));
+    }
+}
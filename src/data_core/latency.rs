@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// Samples kept per event, like Redis.
+const HISTORY_LENGTH: usize = 160;
+
+/// A latency spike, in the second it happened.
+#[derive(Debug, Clone, Copy)]
+struct LatencySample {
+    time: i64,
+    latency_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct LatencyHistory {
+    samples: VecDeque<LatencySample>,
+    max_ms: u64,
+}
+
+/// Latency spikes by the event that caused them: `command` and
+/// `fast-command` for commands, `fork` for taking the snapshot of a
+/// background save or AOF rewrite and `expire-cycle` for removing expired
+/// keys.
+#[derive(Debug, Default)]
+pub(crate) struct LatencyMonitor {
+    /// Only events that take at least this many milliseconds are
+    /// recorded. 0 disables monitoring.
+    pub(crate) threshold_ms: u64,
+    events: BTreeMap<&'static str, LatencyHistory>,
+}
+
+impl LatencyMonitor {
+    /// Records that `event` took `duration`, if it reaches the threshold.
+    /// Spikes within the same second count as the worst of them.
+    pub(crate) fn record(self: &mut LatencyMonitor, event: &'static str, duration: Duration) {
+        let latency_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        if self.threshold_ms == 0 || latency_ms < self.threshold_ms {
+            return;
+        }
+        let time = Utc::now().timestamp();
+        let history = self.events.entry(event).or_default();
+        history.max_ms = history.max_ms.max(latency_ms);
+        match history.samples.back_mut() {
+            Some(last) if last.time == time => last.latency_ms = last.latency_ms.max(latency_ms),
+            _ => {
+                history
+                    .samples
+                    .push_back(LatencySample { time, latency_ms });
+                if history.samples.len() > HISTORY_LENGTH {
+                    history.samples.pop_front();
+                }
+            }
+        }
+    }
+
+    /// The report of LATENCY DOCTOR.
+    fn doctor(self: &LatencyMonitor) -> String {
+        if self.threshold_ms == 0 && self.events.is_empty() {
+            return "I'm sorry, Dave, I can't do that. Latency monitoring is disabled in this Redis instance. You may use \"CONFIG SET latency-monitor-threshold <milliseconds>.\" in order to enable it.\n".to_string();
+        }
+        if self.events.is_empty() {
+            return "Dave, no latency spike was observed during the lifetime of this Redis instance, not in the slightest bit. I honestly think you ought to sleep on it.\n".to_string();
+        }
+        let mut report = "Dave, I have observed latency spikes in this Redis instance. You don't mind talking about it, do you Dave?\n\n".to_string();
+        for (number, (event, history)) in self.events.iter().enumerate() {
+            let count = history.samples.len() as u64;
+            let total: u64 = history.samples.iter().map(|s| s.latency_ms).sum();
+            let average = total / count.max(1);
+            let deviation = history
+                .samples
+                .iter()
+                .map(|sample| sample.latency_ms.abs_diff(average))
+                .sum::<u64>()
+                / count.max(1);
+            let _ = write!(
+                report,
+                "{}. {}: {} latency spikes (average {}ms, mean deviation {}ms",
+                number + 1,
+                event,
+                count,
+                average,
+                deviation
+            );
+            if let (Some(first), Some(last)) = (history.samples.front(), history.samples.back()) {
+                if count > 1 {
+                    let period = (last.time - first.time) as f64 / (count - 1) as f64;
+                    let _ = write!(report, ", period {:.2} sec", period);
+                }
+            }
+            let _ = writeln!(report, "). Worst all time event {}ms.", history.max_ms);
+        }
+        report.push_str("\nI have a few advices for you:\n\n");
+        for event in self.events.keys() {
+            let advice = match *event {
+                "command" | "fast-command" => {
+                    "- Some commands take long to run. Look for commands with a high time complexity run on large values, like KEYS or SMEMBERS, and break them up or use SCAN and its variants."
+                }
+                "fork" => {
+                    "- Snapshotting the data set for a background save or AOF rewrite blocks the server for as long as it takes to copy it. Save less often or keep the data set smaller."
+                }
+                "expire-cycle" => {
+                    "- Many keys expire at the same time. Spread their expiry times with some randomness."
+                }
+                _ => continue,
+            };
+            report.push_str(advice);
+            report.push('\n');
+        }
+        report
+    }
+}
+
+impl DataCore {
+    /// LATENCY LATEST | HISTORY event | RESET [event ...] | DOCTOR
+    pub(crate) fn latency(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        match arguments[1].to_lowercase().as_str() {
+            "latest" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Array(
+                    self.latency
+                        .events
+                        .iter()
+                        .filter_map(|(event, history)| {
+                            let last = history.samples.back()?;
+                            Some(ParserValue::Array(vec![
+                                ParserValue::BulkString(event.to_string().into()),
+                                ParserValue::Integer(last.time),
+                                ParserValue::Integer(last.latency_ms as i64),
+                                ParserValue::Integer(history.max_ms as i64),
+                            ]))
+                        })
+                        .collect(),
+                ))
+            }
+            "history" => {
+                check_arity(arguments, 3, 3)?;
+                let samples = self
+                    .latency
+                    .events
+                    .get(arguments[2].to_str().unwrap_or_default())
+                    .map(|history| history.samples.iter().copied().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                Ok(ParserValue::Array(
+                    samples
+                        .into_iter()
+                        .map(|sample| {
+                            ParserValue::Array(vec![
+                                ParserValue::Integer(sample.time),
+                                ParserValue::Integer(sample.latency_ms as i64),
+                            ])
+                        })
+                        .collect(),
+                ))
+            }
+            "reset" => {
+                let reset = if arguments.len() == 2 {
+                    std::mem::take(&mut self.latency.events).len()
+                } else {
+                    arguments[2..]
+                        .iter()
+                        .filter(|event| {
+                            self.latency
+                                .events
+                                .remove(event.to_str().unwrap_or_default())
+                                .is_some()
+                        })
+                        .count()
+                };
+                Ok(ParserValue::Integer(reset as i64))
+            }
+            "doctor" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::BulkString(self.latency.doctor().into()))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "LATENCY".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_records_spikes_above_the_threshold() {
+        let mut data_core = new_data_core();
+        data_core
+            .latency
+            .record("command", Duration::from_millis(500));
+        assert_eq!(
+            ParserValue::Array(Vec::new()),
+            run(&mut data_core, &["LATENCY", "LATEST"])
+        );
+
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "latency-monitor-threshold", "100"],
+        );
+        data_core
+            .latency
+            .record("command", Duration::from_millis(50));
+        data_core
+            .latency
+            .record("command", Duration::from_millis(300));
+        data_core
+            .latency
+            .record("command", Duration::from_millis(200));
+        data_core
+            .latency
+            .record("expire-cycle", Duration::from_millis(100));
+
+        let ParserValue::Array(latest) = run(&mut data_core, &["LATENCY", "LATEST"]) else {
+            panic!("LATENCY LATEST should reply with an array");
+        };
+        assert_eq!(2, latest.len());
+        let ParserValue::Array(command) = &latest[0] else {
+            panic!("events should be arrays");
+        };
+        assert_eq!(ParserValue::BulkString("command".into()), command[0]);
+        // Both spikes happened in the same second, so only the worst stays.
+        assert_eq!(ParserValue::Integer(300), command[2]);
+        assert_eq!(ParserValue::Integer(300), command[3]);
+        let ParserValue::Array(history) = run(&mut data_core, &["LATENCY", "HISTORY", "command"])
+        else {
+            panic!("LATENCY HISTORY should reply with an array");
+        };
+        assert_eq!(1, history.len());
+
+        let ParserValue::BulkString(report) = run(&mut data_core, &["LATENCY", "DOCTOR"]) else {
+            panic!("LATENCY DOCTOR should reply with a bulk string");
+        };
+        let report = report.to_string();
+        assert!(report.contains("1. command: 1 latency spikes"));
+        assert!(report.contains("2. expire-cycle"));
+
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["LATENCY", "RESET", "command", "fork"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["LATENCY", "RESET"])
+        );
+        assert_eq!(
+            ParserValue::Array(Vec::new()),
+            run(&mut data_core, &["LATENCY", "HISTORY", "command"])
+        );
+    }
+}
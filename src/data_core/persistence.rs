@@ -0,0 +1,370 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::binary::ByteString;
+use crate::data_core::aof::wait_for_aof_rewrite;
+use crate::data_core::{check_arity, commands, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// How long to wait before a save rule retries a failed background save.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How often the save rules are checked.
+const SAVE_RULES_PERIOD: Duration = Duration::from_secs(1);
+
+/// A `save <seconds> <changes>` rule: snapshot once at least `changes`
+/// changes happened and `seconds` passed since the last save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SaveRule {
+    seconds: u64,
+    changes: u64,
+}
+
+/// The save rules Redis uses without a config file.
+pub(crate) fn default_save_rules() -> Vec<SaveRule> {
+    [(3600, 1), (300, 100), (60, 10000)]
+        .into_iter()
+        .map(|(seconds, changes)| SaveRule { seconds, changes })
+        .collect()
+}
+
+/// Parses the `save` parameter: pairs of seconds and changes, or nothing
+/// to disable snapshotting.
+pub(crate) fn parse_save_rules(value: &str) -> Option<Vec<SaveRule>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|number| number.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(
+        numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn format_save_rules(rules: &[SaveRule]) -> String {
+    rules
+        .iter()
+        .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An RDB file being written in the background by BGSAVE.
+#[derive(Debug)]
+pub(crate) struct BackgroundSave {
+    task: JoinHandle<io::Result<()>>,
+    started: Instant,
+    /// Changes since the last save when the snapshot was taken.
+    changes: u64,
+}
+
+/// Waits for the background save to finish, if there is one.
+pub(crate) async fn wait_for_background_save(
+    background_save: Option<&mut BackgroundSave>,
+) -> Option<io::Result<()>> {
+    let task = &mut background_save?.task;
+    Some(task.await.unwrap_or_else(|err| Err(io::Error::other(err))))
+}
+
+/// Writes an RDB file through a temporary file, so a failed save never
+/// leaves a truncated one behind.
+fn write_rdb_file(path: &Path, rdb: &[u8]) -> io::Result<()> {
+    let temporary = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = fs::write(&temporary, rdb).and_then(|_| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+impl DataCore {
+    pub(crate) fn save_rdb_file(self: &DataCore) -> io::Result<()> {
+        write_rdb_file(&self.rdb_path(), &self.to_rdb_bytes())
+    }
+
+    fn check_no_background_save(self: &DataCore) -> Result<(), CommandError> {
+        if self.background_save.is_some() {
+            return Err(CommandError::Other(
+                "ERR Background save already in progress".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// SAVE
+    pub(crate) fn save(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        self.check_no_background_save()?;
+        self.save_rdb_file()
+            .map_err(|err| CommandError::Other(format!("ERR {}", err)))?;
+        self.changes_since_last_save = 0;
+        self.last_save = Utc::now().timestamp();
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// BGSAVE
+    ///
+    /// Serializing the keyspace right away is the snapshot; only writing
+    /// the file happens in the background.
+    pub(crate) fn bgsave(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        self.check_no_background_save()?;
+        self.start_background_save();
+        Ok(ParserValue::SimpleString(String::from(
+            "Background saving started",
+        )))
+    }
+
+    pub(crate) fn start_background_save(self: &mut DataCore) {
+        let path = self.rdb_path();
+        let started = Instant::now();
+        let rdb = self.to_rdb_bytes();
+        self.latency.record("fork", started.elapsed());
+        self.background_save = Some(BackgroundSave {
+            task: tokio::task::spawn_blocking(move || write_rdb_file(&path, &rdb)),
+            started: Instant::now(),
+            changes: self.changes_since_last_save,
+        });
+        self.last_bgsave_try = Some(Instant::now());
+    }
+
+    /// Records how the background save went, once its task finished.
+    pub(crate) fn finish_background_save(self: &mut DataCore, result: io::Result<()>) {
+        let Some(background_save) = self.background_save.take() else {
+            return;
+        };
+        self.last_bgsave_duration = Some(background_save.started.elapsed());
+        self.last_bgsave_ok = result.is_ok();
+        match result {
+            Ok(()) => {
+                // Changes made while saving are left for the next snapshot.
+                self.changes_since_last_save -= background_save.changes;
+                self.last_save = Utc::now().timestamp();
+                info!("background saving terminated with success")
+            }
+            Err(err) => error!("background saving error: {}", err),
+        }
+    }
+
+    /// LASTSAVE
+    pub(crate) fn lastsave(
+        self: &DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        Ok(ParserValue::Integer(self.last_save))
+    }
+
+    /// Counts the keys a write command that ran successfully changed.
+    pub(crate) fn count_changes(self: &mut DataCore, name: &str, arguments: &[ByteString]) {
+        if let Some(spec) = commands::lookup(name).filter(|spec| spec.is_write()) {
+            self.changes_since_last_save += spec.keys(arguments).len().max(1) as u64;
+        }
+    }
+
+    /// Starts a background save if a save rule matches, like the Redis
+    /// server cron does once a second.
+    pub(crate) fn run_save_rules(self: &mut DataCore) {
+        self.next_save_rules_check = Instant::now() + SAVE_RULES_PERIOD;
+        if self.background_save.is_some() {
+            return;
+        }
+        // A failed save is only retried after a delay.
+        if !self.last_bgsave_ok
+            && self
+                .last_bgsave_try
+                .is_some_and(|last_try| last_try.elapsed() < BGSAVE_RETRY_DELAY)
+        {
+            return;
+        }
+        let since_last_save = (Utc::now().timestamp() - self.last_save).max(0) as u64;
+        if let Some(rule) = self.save_rules.iter().find(|rule| {
+            self.changes_since_last_save >= rule.changes && since_last_save >= rule.seconds
+        }) {
+            info!(
+                "{} changes in {} seconds, saving",
+                rule.changes, rule.seconds
+            );
+            self.start_background_save();
+        }
+    }
+
+    /// Runs once the server stopped serving clients: background jobs are
+    /// completed, the append-only file is flushed and the RDB file is saved
+    /// when there are save rules, as Redis does on SHUTDOWN.
+    pub async fn shutdown(self: &mut DataCore) {
+        if let Some(result) = wait_for_background_save(self.background_save.as_mut()).await {
+            self.finish_background_save(result);
+        }
+        if let Some(result) = wait_for_aof_rewrite(self.aof_rewrite.as_mut()).await {
+            self.finish_aof_rewrite(result);
+        }
+        self.close_aof();
+        if !self.save_rules.is_empty() {
+            info!("saving the final RDB snapshot before exiting");
+            match self.save_rdb_file() {
+                Ok(()) => info!("DB saved on disk"),
+                Err(err) => error!("cannot save the DB: {}", err),
+            }
+        }
+    }
+
+    /// The persistence section of INFO.
+    pub(crate) fn persistence_info(self: &DataCore) -> String {
+        let seconds =
+            |duration: Option<Duration>| duration.map_or(-1, |duration| duration.as_secs() as i64);
+        let mut info = format!(
+            "# Persistence\nloading:0\nrdb_changes_since_last_save:{}\nrdb_bgsave_in_progress:{}\nrdb_last_save_time:{}\nrdb_last_bgsave_status:{}\nrdb_last_bgsave_time_sec:{}\nrdb_current_bgsave_time_sec:{}\n",
+            self.changes_since_last_save,
+            self.background_save.is_some() as i64,
+            self.last_save,
+            if self.last_bgsave_ok { "ok" } else { "err" },
+            seconds(self.last_bgsave_duration),
+            seconds(self.background_save.as_ref().map(|background_save| background_save.started.elapsed())),
+        );
+        info.push_str(&self.aof_info());
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::persistence::wait_for_background_save;
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_save_writes_the_rdb_file() {
+        let dir = std::env::temp_dir().join(format!("save-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+
+        let mut data_core = new_data_core().with_rdb_file(dir.clone(), "dump.rdb".to_string());
+        run(&mut data_core, &["SET", "k", "v"]);
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["SAVE"])
+        );
+        let mut loaded = new_data_core().with_rdb_file(dir.clone(), "dump.rdb".to_string());
+        loaded.load_rdb_file().unwrap();
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut loaded, &["GET", "k"])
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            run(&mut data_core, &["SAVE"]),
+            ParserValue::Error(err) if err.starts_with("ERR ")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bgsave_writes_in_the_background() {
+        let dir = std::env::temp_dir().join(format!("bgsave-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+
+        let mut data_core = new_data_core().with_rdb_file(dir.clone(), "dump.rdb".to_string());
+        run(&mut data_core, &["SET", "k", "v"]);
+        assert_eq!(
+            ParserValue::SimpleString("Background saving started".to_string()),
+            run(&mut data_core, &["BGSAVE"])
+        );
+        // Changes after BGSAVE aren't part of the snapshot.
+        run(&mut data_core, &["SET", "k", "changed"]);
+        assert!(matches!(
+            run(&mut data_core, &["BGSAVE"]),
+            ParserValue::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains("rdb_bgsave_in_progress:1\n")
+        ));
+
+        let result = wait_for_background_save(data_core.background_save.as_mut())
+            .await
+            .unwrap();
+        data_core.finish_background_save(result);
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains("rdb_bgsave_in_progress:0\n") && info.to_string().contains("rdb_last_bgsave_status:ok\n")
+        ));
+
+        let mut loaded = new_data_core().with_rdb_file(dir.clone(), "dump.rdb".to_string());
+        loaded.load_rdb_file().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut loaded, &["GET", "k"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save_rules_trigger_background_saves() {
+        let dir = std::env::temp_dir().join(format!("save-rules-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+
+        let mut data_core = new_data_core().with_rdb_file(dir.clone(), "dump.rdb".to_string());
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["CONFIG", "SET", "save", "0 4"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["CONFIG", "SET", "save", "10"]),
+            ParserValue::Error(_)
+        ));
+        let ParserValue::Integer(started) = run(&mut data_core, &["LASTSAVE"]) else {
+            panic!("LASTSAVE should reply with an integer");
+        };
+
+        run(&mut data_core, &["SET", "a", "1"]);
+        run(&mut data_core, &["GET", "a"]);
+        run(&mut data_core, &["DEL", "a", "b"]);
+        data_core.run_save_rules();
+        assert!(data_core.background_save.is_none());
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains("rdb_changes_since_last_save:3\n")
+        ));
+
+        run(&mut data_core, &["SET", "c", "1"]);
+        data_core.run_save_rules();
+        run(&mut data_core, &["SET", "d", "1"]);
+        let result = wait_for_background_save(data_core.background_save.as_mut())
+            .await
+            .unwrap();
+        data_core.finish_background_save(result);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains("rdb_changes_since_last_save:1\n")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["LASTSAVE"]),
+            ParserValue::Integer(last_save) if last_save >= started
+        ));
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::{BTreeSet, HashMap};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, Client, CommandError, DataCore};
+use crate::glob::glob_match;
+use crate::parser::ParserValue;
+use crate::tokenizer::Token;
+
+/// Commands a client may still send once it has subscribed to something.
+const SUBSCRIBED_COMMANDS: &[&str] = &[
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "ping",
+    "quit",
+    "reset",
+];
+
+#[derive(Debug)]
+struct Subscriber {
+    push_channel: UnboundedSender<Vec<Token>>,
+    channels: BTreeSet<ByteString>,
+    patterns: BTreeSet<ByteString>,
+}
+
+impl Subscriber {
+    fn count(self: &Subscriber) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+/// Which of the two subscription namespaces a command works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SubscriptionKind {
+    Channel,
+    Pattern,
+}
+
+/// Channel and pattern subscriptions of every connected client.
+#[derive(Debug, Default)]
+pub(crate) struct PubSub {
+    channels: HashMap<ByteString, BTreeSet<u64>>,
+    patterns: HashMap<ByteString, BTreeSet<u64>>,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+impl PubSub {
+    fn registry(
+        self: &mut PubSub,
+        kind: SubscriptionKind,
+    ) -> &mut HashMap<ByteString, BTreeSet<u64>> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+        }
+    }
+
+    /// Whether `client` has at least one subscription.
+    pub(crate) fn is_subscribed(self: &PubSub, client: u64) -> bool {
+        self.subscribers.contains_key(&client)
+    }
+
+    /// How many channels and patterns `client` is subscribed to.
+    pub(crate) fn subscription_counts(self: &PubSub, client: u64) -> (usize, usize) {
+        self.subscribers.get(&client).map_or((0, 0), |subscriber| {
+            (subscriber.channels.len(), subscriber.patterns.len())
+        })
+    }
+
+    /// How many channels and patterns have subscribers.
+    pub(crate) fn channel_count(self: &PubSub) -> usize {
+        self.channels.len()
+    }
+
+    pub(crate) fn pattern_count(self: &PubSub) -> usize {
+        self.patterns.len()
+    }
+
+    /// Subscribes `client`, returning its subscription count afterwards.
+    fn subscribe(self: &mut PubSub, client: &Client, kind: SubscriptionKind, name: &[u8]) -> usize {
+        self.registry(kind)
+            .entry(name.into())
+            .or_default()
+            .insert(client.id);
+        let subscriber = self
+            .subscribers
+            .entry(client.id)
+            .or_insert_with(|| Subscriber {
+                push_channel: client.push_channel.clone(),
+                channels: BTreeSet::new(),
+                patterns: BTreeSet::new(),
+            });
+        match kind {
+            SubscriptionKind::Channel => subscriber.channels.insert(name.into()),
+            SubscriptionKind::Pattern => subscriber.patterns.insert(name.into()),
+        };
+        subscriber.count()
+    }
+
+    /// Unsubscribes `client`, returning its subscription count afterwards.
+    fn unsubscribe(self: &mut PubSub, client: u64, kind: SubscriptionKind, name: &[u8]) -> usize {
+        let registry = self.registry(kind);
+        if let Some(clients) = registry.get_mut(name) {
+            clients.remove(&client);
+            if clients.is_empty() {
+                registry.remove(name);
+            }
+        }
+        let Some(subscriber) = self.subscribers.get_mut(&client) else {
+            return 0;
+        };
+        match kind {
+            SubscriptionKind::Channel => subscriber.channels.remove(name),
+            SubscriptionKind::Pattern => subscriber.patterns.remove(name),
+        };
+        let count = subscriber.count();
+        if count == 0 {
+            self.subscribers.remove(&client);
+        }
+        count
+    }
+
+    fn subscriptions(self: &PubSub, client: u64, kind: SubscriptionKind) -> Vec<ByteString> {
+        self.subscribers
+            .get(&client)
+            .map(|subscriber| match kind {
+                SubscriptionKind::Channel => subscriber.channels.iter().cloned().collect(),
+                SubscriptionKind::Pattern => subscriber.patterns.iter().cloned().collect(),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Drops every subscription of clients that have disconnected.
+    fn remove_disconnected(self: &mut PubSub) {
+        let disconnected = self
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.push_channel.is_closed())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in disconnected {
+            for channel in self.subscriptions(id, SubscriptionKind::Channel) {
+                self.unsubscribe(id, SubscriptionKind::Channel, &channel);
+            }
+            for pattern in self.subscriptions(id, SubscriptionKind::Pattern) {
+                self.unsubscribe(id, SubscriptionKind::Pattern, &pattern);
+            }
+        }
+    }
+
+    /// Delivers `message` to subscribers of `channel` and of matching
+    /// patterns, returning how many received it.
+    fn publish(self: &PubSub, channel: &[u8], message: &[u8]) -> usize {
+        let mut receivers = 0;
+        let mut send = |client: &u64, reply: ParserValue| {
+            if let Some(subscriber) = self.subscribers.get(client) {
+                if subscriber.push_channel.send(reply.to_tokens()).is_ok() {
+                    receivers += 1;
+                }
+            }
+        };
+        for client in self.channels.get(channel).into_iter().flatten() {
+            send(client, push_message(&[b"message", channel, message]));
+        }
+        for (pattern, clients) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for client in clients {
+                send(
+                    client,
+                    push_message(&[b"pmessage", pattern, channel, message]),
+                );
+            }
+        }
+        receivers
+    }
+}
+
+fn push_message(parts: &[&[u8]]) -> ParserValue {
+    ParserValue::Array(
+        parts
+            .iter()
+            .map(|part| ParserValue::BulkString((*part).into()))
+            .collect(),
+    )
+}
+
+fn confirmation(kind: &str, name: Option<&[u8]>, count: usize) -> ParserValue {
+    ParserValue::Array(vec![
+        ParserValue::BulkString(kind.into()),
+        name.map_or(ParserValue::NullBulkString, |name| {
+            ParserValue::BulkString(name.into())
+        }),
+        ParserValue::Integer(count as i64),
+    ])
+}
+
+impl DataCore {
+    fn current_client(self: &DataCore, command: &str) -> Result<Client, CommandError> {
+        self.client.clone().ok_or_else(|| {
+            CommandError::Other(format!(
+                "ERR '{}' is only available to connected clients",
+                command
+            ))
+        })
+    }
+
+    /// Rejects commands a subscribed client is not allowed to send.
+    pub(crate) fn check_subscribed_context(
+        self: &DataCore,
+        name: &str,
+    ) -> Result<(), CommandError> {
+        let subscribed = self
+            .client
+            .as_ref()
+            .is_some_and(|client| self.pubsub.is_subscribed(client.id));
+        if subscribed && !SUBSCRIBED_COMMANDS.contains(&name) {
+            return Err(CommandError::Other(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// PING [message] as answered to a subscribed client.
+    pub(crate) fn subscribed_ping(
+        self: &DataCore,
+        arguments: &[ByteString],
+    ) -> Option<ParserValue> {
+        let client = self.client.as_ref()?;
+        if !self.pubsub.is_subscribed(client.id) {
+            return None;
+        }
+        let message = arguments.get(1).map_or(&b""[..], ByteString::as_bytes);
+        Some(push_message(&[b"pong", message]))
+    }
+
+    /// SUBSCRIBE channel [channel ...] and PSUBSCRIBE pattern [pattern ...]
+    pub(crate) fn subscribe(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        kind: SubscriptionKind,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let client = self.current_client(&arguments[0].to_lowercase())?;
+        let reply_kind = match kind {
+            SubscriptionKind::Channel => "subscribe",
+            SubscriptionKind::Pattern => "psubscribe",
+        };
+        let mut replies = arguments[1..]
+            .iter()
+            .map(|name| {
+                let count = self.pubsub.subscribe(&client, kind, name);
+                confirmation(reply_kind, Some(name), count)
+            })
+            .collect::<Vec<_>>();
+        let last = replies.pop().unwrap();
+        self.pending_replies.extend(replies);
+        Ok(last)
+    }
+
+    /// UNSUBSCRIBE [channel ...] and PUNSUBSCRIBE [pattern ...]
+    pub(crate) fn unsubscribe(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        kind: SubscriptionKind,
+    ) -> Result<ParserValue, CommandError> {
+        let client = self.current_client(&arguments[0].to_lowercase())?;
+        let reply_kind = match kind {
+            SubscriptionKind::Channel => "unsubscribe",
+            SubscriptionKind::Pattern => "punsubscribe",
+        };
+        let names = match arguments.len() {
+            1 => self.pubsub.subscriptions(client.id, kind),
+            _ => arguments[1..].to_vec(),
+        };
+        if names.is_empty() {
+            let count = self
+                .pubsub
+                .subscribers
+                .get(&client.id)
+                .map_or(0, Subscriber::count);
+            return Ok(confirmation(reply_kind, None, count));
+        }
+        let mut replies = names
+            .iter()
+            .map(|name| {
+                let count = self.pubsub.unsubscribe(client.id, kind, name);
+                confirmation(reply_kind, Some(name), count)
+            })
+            .collect::<Vec<_>>();
+        let last = replies.pop().unwrap();
+        self.pending_replies.extend(replies);
+        Ok(last)
+    }
+
+    /// PUBLISH channel message
+    pub(crate) fn publish(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        self.pubsub.remove_disconnected();
+        let receivers = self.pubsub.publish(&arguments[1], &arguments[2]);
+        Ok(ParserValue::Integer(receivers as i64))
+    }
+
+    /// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+    pub(crate) fn pubsub(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        self.pubsub.remove_disconnected();
+        match arguments[1].to_lowercase().as_str() {
+            "channels" => {
+                if arguments.len() > 3 {
+                    return Err(CommandError::WrongArity("pubsub|channels".to_string()));
+                }
+                let pattern = arguments.get(2).map_or(&b"*"[..], ByteString::as_bytes);
+                let mut channels = self
+                    .pubsub
+                    .channels
+                    .keys()
+                    .filter(|channel| glob_match(pattern, channel))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                channels.sort();
+                Ok(ParserValue::Array(
+                    channels.into_iter().map(ParserValue::BulkString).collect(),
+                ))
+            }
+            "numsub" => {
+                let mut counts = Vec::new();
+                for channel in &arguments[2..] {
+                    let count = self
+                        .pubsub
+                        .channels
+                        .get(&channel[..])
+                        .map_or(0, BTreeSet::len);
+                    counts.push(ParserValue::BulkString(channel.clone()));
+                    counts.push(ParserValue::Integer(count as i64));
+                }
+                Ok(ParserValue::Array(counts))
+            }
+            "numpat" => {
+                check_arity(arguments, 2, 2)?;
+                Ok(ParserValue::Integer(self.pubsub.patterns.len() as i64))
+            }
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "PUBSUB".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::Client;
+    use crate::parser::ParserValue;
+    use crate::tokenizer::{serialize_tokens, Token};
+
+    fn bulk_strings(values: &[&str]) -> ParserValue {
+        ParserValue::Array(
+            values
+                .iter()
+                .map(|value| ParserValue::BulkString((*value).into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_publish_reaches_channel_and_pattern_subscribers() {
+        let mut data_core = new_data_core();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(1, push_tx);
+
+        run_as(
+            &mut data_core,
+            &client,
+            &["SUBSCRIBE", "news.art", "news.tech"],
+        );
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["GET", "k"]),
+            ParserValue::Error(e) if e.starts_with("ERR Can't execute 'get'")
+        ));
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("psubscribe".into()),
+                ParserValue::BulkString("news.*".into()),
+                ParserValue::Integer(3),
+            ]),
+            run_as(&mut data_core, &client, &["PSUBSCRIBE", "news.*"])
+        );
+
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["PUBLISH", "news.art", "hello"])
+        );
+        let message = |values: &[&str]| serialize_tokens(&bulk_strings(values).to_tokens()).ok();
+        assert_eq!(
+            message(&["message", "news.art", "hello"]),
+            serialize_tokens(&push_rx.try_recv().unwrap()).ok()
+        );
+        assert_eq!(
+            message(&["pmessage", "news.*", "news.art", "hello"]),
+            serialize_tokens(&push_rx.try_recv().unwrap()).ok()
+        );
+
+        run_as(&mut data_core, &client, &["UNSUBSCRIBE"]);
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["PUBLISH", "news.art", "again"])
+        );
+    }
+
+    #[test]
+    fn test_pubsub_introspection() {
+        let mut data_core = new_data_core();
+        let (first_tx, _first_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let (second_tx, second_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let first = Client::new(1, first_tx);
+        let second = Client::new(2, second_tx);
+
+        run_as(&mut data_core, &first, &["SUBSCRIBE", "a", "b"]);
+        run_as(&mut data_core, &second, &["SUBSCRIBE", "a", "c"]);
+        run_as(&mut data_core, &second, &["PSUBSCRIBE", "x*", "y*"]);
+        run_as(&mut data_core, &first, &["PSUBSCRIBE", "x*"]);
+
+        assert_eq!(
+            bulk_strings(&["a", "b", "c"]),
+            run(&mut data_core, &["PUBSUB", "CHANNELS"])
+        );
+        assert_eq!(
+            bulk_strings(&["a", "b"]),
+            run(&mut data_core, &["PUBSUB", "CHANNELS", "[ab]"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("a".into()),
+                ParserValue::Integer(2),
+                ParserValue::BulkString("d".into()),
+                ParserValue::Integer(0),
+            ]),
+            run(&mut data_core, &["PUBSUB", "NUMSUB", "a", "d"])
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["PUBSUB", "NUMPAT"])
+        );
+
+        drop(second_rx);
+        assert_eq!(
+            bulk_strings(&["a", "b"]),
+            run(&mut data_core, &["PUBSUB", "CHANNELS"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["PUBSUB", "NUMPAT"])
+        );
+    }
+}
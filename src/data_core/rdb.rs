@@ -0,0 +1,1016 @@
+use anyhow::{anyhow, bail};
+use chrono::Utc;
+use tracing::info;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::binary::ByteString;
+use crate::data_core::{DataCore, DataValue, Keyspace, Value};
+use crate::listpack::{self, ListpackEntry};
+use crate::set::{RedisSet, SetLimits};
+use crate::sorted_set::{SortedSet, SortedSetLimits};
+use crate::stream::{Consumer, ConsumerGroup, Fields, PendingEntry, Stream, StreamId};
+use crate::string::RedisString;
+
+const MAGIC: &[u8] = b"REDIS0011";
+
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// Flags of stream entries in their listpack.
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+/// Entries per listpack node, as with the default stream-node-max-entries.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+/// How much of an RDB file is buffered before it is handed out while
+/// writing it in chunks.
+const RDB_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A length field, or the marker of a specially encoded string.
+enum Length {
+    Length(usize),
+    Encoded(u8),
+}
+
+/// Reads the parts of an RDB file in order.
+struct RdbReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn read_bytes(self: &mut RdbReader<'a>, count: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| anyhow!("unexpected end of RDB file"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn read_u8(self: &mut RdbReader<'a>) -> anyhow::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_length_or_encoding(self: &mut RdbReader<'a>) -> anyhow::Result<Length> {
+        let first = self.read_u8()?;
+        Ok(match first >> 6 {
+            0b00 => Length::Length((first & 0x3f) as usize),
+            0b01 => Length::Length((((first & 0x3f) as usize) << 8) | self.read_u8()? as usize),
+            0b10 if first == 0x80 => {
+                Length::Length(u32::from_be_bytes(self.read_bytes(4)?.try_into()?) as usize)
+            }
+            0b10 if first == 0x81 => {
+                Length::Length(u64::from_be_bytes(self.read_bytes(8)?.try_into()?) as usize)
+            }
+            0b10 => bail!("invalid RDB length encoding {:#04x}", first),
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn read_millisecond_time(self: &mut RdbReader<'a>) -> anyhow::Result<i64> {
+        Ok(i64::from_le_bytes(self.read_bytes(8)?.try_into()?))
+    }
+
+    /// A stream ID stored as two lengths.
+    fn read_stream_id(self: &mut RdbReader<'a>) -> anyhow::Result<StreamId> {
+        Ok(StreamId::new(
+            self.read_length()? as u64,
+            self.read_length()? as u64,
+        ))
+    }
+
+    /// A stream ID stored as 16 raw big-endian bytes.
+    fn read_raw_stream_id(self: &mut RdbReader<'a>) -> anyhow::Result<StreamId> {
+        raw_stream_id(self.read_bytes(16)?)
+    }
+
+    fn read_length(self: &mut RdbReader<'a>) -> anyhow::Result<usize> {
+        match self.read_length_or_encoding()? {
+            Length::Length(length) => Ok(length),
+            Length::Encoded(_) => bail!("expected an RDB length, found an encoded string"),
+        }
+    }
+
+    fn read_string(self: &mut RdbReader<'a>) -> anyhow::Result<Vec<u8>> {
+        Ok(match self.read_length_or_encoding()? {
+            Length::Length(length) => self.read_bytes(length)?.to_vec(),
+            Length::Encoded(ENCODING_INT8) => (self.read_u8()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENCODING_INT16) => i16::from_le_bytes(self.read_bytes(2)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(ENCODING_INT32) => i32::from_le_bytes(self.read_bytes(4)?.try_into()?)
+                .to_string()
+                .into_bytes(),
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed_length = self.read_length()?;
+                let length = self.read_length()?;
+                lzf_decompress(self.read_bytes(compressed_length)?, length)?
+            }
+            Length::Encoded(encoding) => bail!("unknown RDB string encoding {}", encoding),
+        })
+    }
+}
+
+/// The CRC-64/Jones checksum Redis puts at the end of RDB files, computed
+/// bit by bit with the reflected polynomial.
+fn crc64(bytes: &[u8]) -> u64 {
+    crc64_update(0, bytes)
+}
+
+/// Continues `crc` over more bytes, for checksums of data written in chunks.
+fn crc64_update(mut crc: u64, bytes: &[u8]) -> u64 {
+    const POLYNOMIAL: u64 = 0x95ac_9329_ac4b_c9b5;
+    for byte in bytes {
+        crc ^= *byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn write_length(output: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        output.push(length as u8);
+    } else if length < 1 << 14 {
+        output.extend_from_slice(&[0x40 | (length >> 8) as u8, length as u8]);
+    } else if let Ok(length) = u32::try_from(length) {
+        output.push(0x80);
+        output.extend_from_slice(&length.to_be_bytes());
+    } else {
+        output.push(0x81);
+        output.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+}
+
+/// Writes a string, as a number when it is the canonical form of one that
+/// fits in 32 bits, like Redis does.
+fn write_string(output: &mut Vec<u8>, bytes: &[u8]) {
+    let integer = std::str::from_utf8(bytes)
+        .ok()
+        .filter(|s| s.len() <= 11)
+        .and_then(|s| s.parse::<i64>().ok().filter(|n| n.to_string() == s));
+    match integer {
+        Some(n) if i8::try_from(n).is_ok() => {
+            output.extend_from_slice(&[0xc0 | ENCODING_INT8, n as i8 as u8])
+        }
+        Some(n) if i16::try_from(n).is_ok() => {
+            output.push(0xc0 | ENCODING_INT16);
+            output.extend_from_slice(&(n as i16).to_le_bytes());
+        }
+        Some(n) if i32::try_from(n).is_ok() => {
+            output.push(0xc0 | ENCODING_INT32);
+            output.extend_from_slice(&(n as i32).to_le_bytes());
+        }
+        _ => {
+            write_length(output, bytes.len());
+            output.extend_from_slice(bytes);
+        }
+    }
+}
+
+fn write_stream_id(output: &mut Vec<u8>, id: StreamId) {
+    write_length(output, id.ms as usize);
+    write_length(output, id.seq as usize);
+}
+
+fn write_raw_stream_id(output: &mut Vec<u8>, id: StreamId) {
+    output.extend_from_slice(&id.ms.to_be_bytes());
+    output.extend_from_slice(&id.seq.to_be_bytes());
+}
+
+fn raw_stream_id(bytes: &[u8]) -> anyhow::Result<StreamId> {
+    if bytes.len() != 16 {
+        bail!("invalid stream ID in RDB file");
+    }
+    Ok(StreamId::new(
+        u64::from_be_bytes(bytes[..8].try_into()?),
+        u64::from_be_bytes(bytes[8..].try_into()?),
+    ))
+}
+
+/// Writes a stream in the RDB_TYPE_STREAM_LISTPACKS_3 layout: its entries
+/// in listpacks keyed by their first ID, then its metadata and consumer
+/// groups.
+fn write_stream(output: &mut Vec<u8>, stream: &Stream) {
+    let entries = stream
+        .range(StreamId::MIN, StreamId::MAX)
+        .collect::<Vec<_>>();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect::<Vec<_>>();
+    write_length(output, nodes.len());
+    for node in nodes {
+        let (master_id, master_fields) = node[0];
+        let mut key = Vec::with_capacity(16);
+        write_raw_stream_id(&mut key, *master_id);
+        write_string(output, &key);
+        write_string(output, &stream_listpack(*master_id, master_fields, node));
+    }
+
+    write_length(output, stream.len());
+    write_stream_id(output, stream.last_id());
+    write_stream_id(
+        output,
+        stream.first_entry().map_or(StreamId::MIN, |(id, _)| *id),
+    );
+    write_stream_id(output, stream.max_deleted_id());
+    write_length(output, stream.entries_added() as usize);
+
+    write_length(output, stream.groups().len());
+    for (name, group) in stream.groups() {
+        write_string(output, name);
+        write_stream_id(output, group.last_delivered_id);
+        // An unknown read counter is stored as -1.
+        write_length(
+            output,
+            group.entries_read.map_or(usize::MAX, |read| read as usize),
+        );
+        write_length(output, group.pending.len());
+        for (id, pending) in &group.pending {
+            write_raw_stream_id(output, *id);
+            output.extend_from_slice(&(pending.delivery_time_ms as i64).to_le_bytes());
+            write_length(output, pending.delivery_count as usize);
+        }
+        write_length(output, group.consumers.len());
+        for (name, consumer) in &group.consumers {
+            write_string(output, name);
+            output.extend_from_slice(&(consumer.seen_time_ms as i64).to_le_bytes());
+            output.extend_from_slice(
+                &consumer
+                    .active_time_ms
+                    .map_or(-1, |time| time as i64)
+                    .to_le_bytes(),
+            );
+            write_length(output, consumer.pending.len());
+            for id in &consumer.pending {
+                write_raw_stream_id(output, *id);
+            }
+        }
+    }
+}
+
+/// The listpack of a stream node: a master entry with the entry count and
+/// the first entry's field names, then each entry as flags, ID deltas from
+/// the master ID, its fields (only the values when they match the master
+/// fields) and its element count.
+fn stream_listpack(
+    master_id: StreamId,
+    master_fields: &Fields,
+    node: &[(&StreamId, &Fields)],
+) -> Vec<u8> {
+    let mut elements = vec![
+        ListpackEntry::Integer(node.len() as i64),
+        ListpackEntry::Integer(0),
+        ListpackEntry::Integer(master_fields.len() as i64),
+    ];
+    elements.extend(
+        master_fields
+            .iter()
+            .map(|(field, _)| ListpackEntry::string(field)),
+    );
+    elements.push(ListpackEntry::Integer(0));
+
+    for (id, fields) in node {
+        let same_fields = fields.len() == master_fields.len()
+            && fields
+                .iter()
+                .zip(master_fields)
+                .all(|((field, _), (master_field, _))| field == master_field);
+        elements.push(ListpackEntry::Integer(if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        }));
+        elements.push(ListpackEntry::Integer(
+            id.ms.wrapping_sub(master_id.ms) as i64
+        ));
+        elements.push(ListpackEntry::Integer(
+            id.seq.wrapping_sub(master_id.seq) as i64
+        ));
+        if same_fields {
+            elements.extend(fields.iter().map(|(_, value)| ListpackEntry::string(value)));
+            elements.push(ListpackEntry::Integer(fields.len() as i64 + 3));
+        } else {
+            elements.push(ListpackEntry::Integer(fields.len() as i64));
+            for (field, value) in fields.iter() {
+                elements.push(ListpackEntry::string(field));
+                elements.push(ListpackEntry::string(value));
+            }
+            elements.push(ListpackEntry::Integer(2 * fields.len() as i64 + 4));
+        }
+    }
+    listpack::encode(&elements)
+}
+
+/// Reads a stream in any of the listpack layouts, older ones lacking some
+/// of the metadata.
+fn read_stream(reader: &mut RdbReader, value_type: u8) -> anyhow::Result<Stream> {
+    let mut entries = BTreeMap::new();
+    for _ in 0..reader.read_length()? {
+        let master_id = raw_stream_id(&reader.read_string()?)?;
+        read_stream_listpack(master_id, &reader.read_string()?, &mut entries)?;
+    }
+
+    let length = reader.read_length()?;
+    let last_id = reader.read_stream_id()?;
+    let (max_deleted_id, entries_added) = if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        reader.read_stream_id()?;
+        (reader.read_stream_id()?, reader.read_length()? as u64)
+    } else {
+        (StreamId::MIN, length as u64)
+    };
+
+    let mut groups = BTreeMap::new();
+    for _ in 0..reader.read_length()? {
+        let name = ByteString::from(reader.read_string()?);
+        let last_delivered_id = reader.read_stream_id()?;
+        let entries_read = match value_type >= TYPE_STREAM_LISTPACKS_2 {
+            true => Some(reader.read_length()?)
+                .filter(|read| *read != usize::MAX)
+                .map(|read| read as u64),
+            false => None,
+        };
+        let mut group = ConsumerGroup::new(last_delivered_id, entries_read);
+        let mut delivery_times = Vec::new();
+        for _ in 0..reader.read_length()? {
+            let id = reader.read_raw_stream_id()?;
+            let delivery_time_ms = reader.read_millisecond_time()? as u64;
+            let delivery_count = reader.read_length()? as u64;
+            delivery_times.push((id, delivery_time_ms, delivery_count));
+        }
+        for _ in 0..reader.read_length()? {
+            let name = ByteString::from(reader.read_string()?);
+            let seen_time_ms = reader.read_millisecond_time()? as u64;
+            let active_time_ms = match value_type >= TYPE_STREAM_LISTPACKS_3 {
+                true => Some(reader.read_millisecond_time()?)
+                    .filter(|time| *time >= 0)
+                    .map(|time| time as u64),
+                false => None,
+            };
+            let mut consumer = Consumer {
+                seen_time_ms,
+                active_time_ms,
+                ..Consumer::default()
+            };
+            for _ in 0..reader.read_length()? {
+                consumer.pending.insert(reader.read_raw_stream_id()?);
+            }
+            for (id, delivery_time_ms, delivery_count) in &delivery_times {
+                if consumer.pending.contains(id) {
+                    group.pending.insert(
+                        *id,
+                        PendingEntry {
+                            consumer: name.clone(),
+                            delivery_time_ms: *delivery_time_ms,
+                            delivery_count: *delivery_count,
+                        },
+                    );
+                }
+            }
+            group.consumers.insert(name, consumer);
+        }
+        groups.insert(name, group);
+    }
+    Ok(Stream::from_parts(
+        entries,
+        last_id,
+        entries_added,
+        max_deleted_id,
+        groups,
+    ))
+}
+
+fn next_element(
+    elements: &mut impl Iterator<Item = ListpackEntry>,
+) -> anyhow::Result<ListpackEntry> {
+    elements
+        .next()
+        .ok_or_else(|| anyhow!("truncated stream listpack"))
+}
+
+fn next_integer(elements: &mut impl Iterator<Item = ListpackEntry>) -> anyhow::Result<i64> {
+    next_element(elements)?
+        .as_integer()
+        .ok_or_else(|| anyhow!("expected an integer in stream listpack"))
+}
+
+/// Reads the entries of a stream node back from its listpack.
+fn read_stream_listpack(
+    master_id: StreamId,
+    listpack: &[u8],
+    entries: &mut BTreeMap<StreamId, Fields>,
+) -> anyhow::Result<()> {
+    let elements = &mut listpack::decode(listpack)?.into_iter();
+    let count = next_integer(elements)?;
+    let deleted = next_integer(elements)?;
+    let master_fields = (0..next_integer(elements)?)
+        .map(|_| next_element(elements).map(ListpackEntry::into_string))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    next_element(elements)?;
+
+    for _ in 0..count + deleted {
+        let flags = next_integer(elements)?;
+        let id = StreamId::new(
+            master_id.ms.wrapping_add(next_integer(elements)? as u64),
+            master_id.seq.wrapping_add(next_integer(elements)? as u64),
+        );
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next_element(elements)?.into_string())))
+                .collect::<anyhow::Result<Fields>>()?
+        } else {
+            (0..next_integer(elements)?)
+                .map(|_| {
+                    Ok((
+                        next_element(elements)?.into_string(),
+                        next_element(elements)?.into_string(),
+                    ))
+                })
+                .collect::<anyhow::Result<Fields>>()?
+        };
+        // The element count, only needed to walk the listpack backwards.
+        next_element(elements)?;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Most bytes a byte of LZF input can expand to: a three byte back reference
+/// copies up to 264 bytes.
+const LZF_MAX_EXPANSION: usize = 88;
+
+/// Expands an LZF compressed string, which RDB files use for long values.
+/// `length` comes from the file, so no more is reserved for the output than
+/// `input` can expand to.
+fn lzf_decompress(input: &[u8], length: usize) -> anyhow::Result<Vec<u8>> {
+    let truncated = || anyhow!("truncated LZF string in RDB file");
+    let wrong_length = || anyhow!("LZF string in RDB file has the wrong length");
+    let mut output = Vec::with_capacity(length.min(input.len().saturating_mul(LZF_MAX_EXPANSION)));
+    let mut position = 0;
+    while position < input.len() {
+        let control = input[position] as usize;
+        position += 1;
+        if control < 32 {
+            // A run of control + 1 literal bytes.
+            let literal = input
+                .get(position..position + control + 1)
+                .ok_or_else(truncated)?;
+            output.extend_from_slice(literal);
+            position += control + 1;
+        } else {
+            // A back reference of length (control >> 5) + 2, extended by the
+            // next byte when the 3 bit length is all ones.
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(position).ok_or_else(truncated)? as usize;
+                position += 1;
+            }
+            let offset =
+                ((control & 0x1f) << 8) + *input.get(position).ok_or_else(truncated)? as usize + 1;
+            position += 1;
+            let start = output
+                .len()
+                .checked_sub(offset)
+                .ok_or_else(|| anyhow!("invalid LZF back reference in RDB file"))?;
+            for i in 0..run + 2 {
+                output.push(output[start + i]);
+            }
+        }
+        if output.len() > length {
+            return Err(wrong_length());
+        }
+    }
+    if output.len() != length {
+        return Err(wrong_length());
+    }
+    Ok(output)
+}
+
+impl DataCore {
+    /// The keyspace as an RDB file, sent to replicas on a full resync.
+    pub(crate) fn to_rdb_bytes(self: &DataCore) -> Vec<u8> {
+        let mut rdb = Vec::new();
+        self.write_rdb(|chunk| rdb.extend_from_slice(&chunk));
+        rdb
+    }
+
+    /// Writes the keyspace as an RDB file, handing it to `sink` in chunks of
+    /// about [`RDB_CHUNK_SIZE`] bytes as the keys are serialized.
+    pub(crate) fn write_rdb(self: &DataCore, mut sink: impl FnMut(Vec<u8>)) {
+        let mut checksum = 0;
+        let mut flush = |output: &mut Vec<u8>| {
+            checksum = crc64_update(checksum, output);
+            sink(std::mem::take(output));
+        };
+        let mut output = MAGIC.to_vec();
+        let ctime = Utc::now().timestamp().to_string();
+        for (name, value) in [
+            ("redis-ver", "7.2.0"),
+            ("redis-bits", "64"),
+            ("ctime", ctime.as_str()),
+            ("aof-base", "0"),
+        ] {
+            output.push(OPCODE_AUX);
+            write_string(&mut output, name.as_bytes());
+            write_string(&mut output, value.as_bytes());
+        }
+
+        let entries = self
+            .data_set
+            .iter()
+            .filter(|(_, data_value)| !data_value.has_expired())
+            .collect::<Vec<_>>();
+        if !entries.is_empty() {
+            output.push(OPCODE_SELECTDB);
+            write_length(&mut output, 0);
+            output.push(OPCODE_RESIZEDB);
+            write_length(&mut output, entries.len());
+            write_length(
+                &mut output,
+                entries
+                    .iter()
+                    .filter(|(_, data_value)| data_value.expiry_in_nanoseconds.is_some())
+                    .count(),
+            );
+        }
+        for (key, data_value) in entries {
+            if let Some(expiry_in_nanoseconds) = data_value.expiry_in_nanoseconds {
+                output.push(OPCODE_EXPIRETIME_MS);
+                output.extend_from_slice(&(expiry_in_nanoseconds / 1_000_000).to_le_bytes());
+            }
+            match &data_value.value {
+                Value::String(string) => {
+                    output.push(TYPE_STRING);
+                    write_string(&mut output, key);
+                    write_string(&mut output, &string.to_bytes());
+                }
+                Value::Set(set) => {
+                    output.push(TYPE_SET);
+                    write_string(&mut output, key);
+                    write_length(&mut output, set.len());
+                    for member in set.iter() {
+                        write_string(&mut output, &member);
+                    }
+                }
+                Value::SortedSet(sorted_set) => {
+                    output.push(TYPE_ZSET_2);
+                    write_string(&mut output, key);
+                    write_length(&mut output, sorted_set.len());
+                    for (member, score) in sorted_set.iter() {
+                        write_string(&mut output, member);
+                        output.extend_from_slice(&score.to_le_bytes());
+                    }
+                }
+                Value::Stream(stream) => {
+                    output.push(TYPE_STREAM_LISTPACKS_3);
+                    write_string(&mut output, key);
+                    write_stream(&mut output, stream);
+                }
+            }
+            if output.len() >= RDB_CHUNK_SIZE {
+                flush(&mut output);
+            }
+        }
+
+        output.push(OPCODE_EOF);
+        flush(&mut output);
+        sink(checksum.to_le_bytes().to_vec());
+    }
+
+    /// The path of the RDB file, from the dir and dbfilename settings.
+    pub(crate) fn rdb_path(self: &DataCore) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    /// Loads the RDB file at startup, if there is one.
+    pub fn load_rdb_file(self: &mut DataCore) -> anyhow::Result<()> {
+        let path = self.rdb_path();
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        self.load_rdb(&bytes)
+            .map_err(|err| err.context(format!("cannot load {}", path.display())))?;
+        info!(
+            "loaded {} keys from {}",
+            self.data_set.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Replaces the keyspace with the keys of an RDB file, like the one a
+    /// replica receives on a full resync. Strings, sets, sorted sets and
+    /// streams are supported. Keys that already expired are skipped, except
+    /// on replicas, which leave that to their master. Returns the length
+    /// of the RDB file, which may be followed by more data in an AOF.
+    pub(crate) fn load_rdb(self: &mut DataCore, bytes: &[u8]) -> anyhow::Result<usize> {
+        let is_slave = self.is_slave();
+        let mut data_set = Keyspace::default();
+        let length = read_rdb(
+            bytes,
+            &self.set_limits,
+            &self.sorted_set_limits,
+            |item| match item {
+                RdbItem::Aux(..) => {}
+                // Sizes the keyspace up front instead of growing it key by key,
+                // trusting the count only as far as the file could hold the keys.
+                RdbItem::ResizeDb(keys) => data_set.reserve(keys.min(bytes.len())),
+                RdbItem::Key(key, data_value) => {
+                    if !data_value.has_expired() || is_slave {
+                        data_set.insert(key.into_bytes().into(), data_value);
+                    }
+                }
+            },
+        )?;
+        self.data_set = data_set;
+        self.reindex_expires();
+        Ok(length.0)
+    }
+}
+
+/// An auxiliary field or a key of an RDB file, in the order they appear.
+enum RdbItem {
+    Aux(String, String),
+    /// The number of keys of the database that follows.
+    ResizeDb(usize),
+    Key(ByteString, DataValue),
+}
+
+/// Reads an RDB file, handing its items to `visit`. Returns the length of
+/// the file and its checksum, zero when the writer had checksums disabled.
+fn read_rdb(
+    bytes: &[u8],
+    set_limits: &SetLimits,
+    sorted_set_limits: &SortedSetLimits,
+    mut visit: impl FnMut(RdbItem),
+) -> anyhow::Result<(usize, u64)> {
+    let mut reader = RdbReader { bytes, position: 0 };
+    let magic = reader.read_bytes(MAGIC.len())?;
+    if !magic.starts_with(b"REDIS") {
+        bail!("not an RDB file");
+    }
+
+    let mut expiry_in_nanoseconds = None;
+    loop {
+        match reader.read_u8()? {
+            OPCODE_EOF => {
+                // A zero checksum means the writer had checksums disabled.
+                let content = &bytes[..reader.position];
+                let checksum = u64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+                if checksum != 0 && checksum != crc64(content) {
+                    bail!("wrong RDB checksum");
+                }
+                return Ok((reader.position, checksum));
+            }
+            OPCODE_AUX => {
+                let name = String::from_utf8_lossy(&reader.read_string()?).into_owned();
+                let value = String::from_utf8_lossy(&reader.read_string()?).into_owned();
+                visit(RdbItem::Aux(name, value));
+            }
+            OPCODE_SELECTDB => {
+                reader.read_length()?;
+            }
+            OPCODE_RESIZEDB => {
+                let keys = reader.read_length()?;
+                reader.read_length()?;
+                visit(RdbItem::ResizeDb(keys));
+            }
+            OPCODE_EXPIRETIME_MS => {
+                let milliseconds = i64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+                expiry_in_nanoseconds = Some(milliseconds * 1_000_000);
+            }
+            OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(reader.read_bytes(4)?.try_into()?);
+                expiry_in_nanoseconds = Some(seconds as i64 * 1_000_000_000);
+            }
+            value_type => {
+                let key = ByteString::from(reader.read_string()?);
+                let value = read_rdb_value(&mut reader, value_type, set_limits, sorted_set_limits)?;
+                let mut data_value = DataValue::new(value);
+                data_value.expiry_in_nanoseconds = expiry_in_nanoseconds.take();
+                visit(RdbItem::Key(key, data_value));
+            }
+        }
+    }
+}
+
+fn read_rdb_value(
+    reader: &mut RdbReader,
+    value_type: u8,
+    set_limits: &SetLimits,
+    sorted_set_limits: &SortedSetLimits,
+) -> anyhow::Result<Value> {
+    let read_member = |reader: &mut RdbReader| -> anyhow::Result<ByteString> {
+        Ok(ByteString::from(reader.read_string()?))
+    };
+    Ok(match value_type {
+        TYPE_STRING => Value::String(RedisString::new(reader.read_string()?)),
+        TYPE_SET => {
+            let length = reader.read_length()?;
+            let members = (0..length)
+                .map(|_| read_member(reader))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Value::Set(RedisSet::from_members(members, set_limits))
+        }
+        TYPE_ZSET_2 => {
+            let mut sorted_set = SortedSet::new();
+            for _ in 0..reader.read_length()? {
+                let member = read_member(reader)?;
+                let score = f64::from_le_bytes(reader.read_bytes(8)?.try_into()?);
+                sorted_set.insert(member, score, sorted_set_limits);
+            }
+            Value::SortedSet(sorted_set)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            Value::Stream(read_stream(reader, value_type)?)
+        }
+        value_type => bail!("unsupported RDB value type {}", value_type),
+    })
+}
+
+/// A key of an RDB file, as listed by `rdb-inspect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RdbKey {
+    pub key: ByteString,
+    pub value_type: &'static str,
+    /// Bytes of a string, members of a set or sorted set, entries of a
+    /// stream.
+    pub size: usize,
+    /// Unix time in milliseconds the key expires at.
+    pub expiry_ms: Option<i64>,
+}
+
+/// The contents of an RDB file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RdbSummary {
+    pub aux: Vec<(String, String)>,
+    pub keys: Vec<RdbKey>,
+    /// The length of the RDB file, without whatever follows it.
+    pub length: usize,
+    /// The verified checksum, zero when the writer had checksums disabled.
+    pub checksum: u64,
+}
+
+/// Parses an RDB file and verifies its checksum, keeping expired keys.
+pub fn inspect_rdb(bytes: &[u8]) -> anyhow::Result<RdbSummary> {
+    let mut aux = Vec::new();
+    let mut keys = Vec::new();
+    let (length, checksum) = read_rdb(
+        bytes,
+        &SetLimits::default(),
+        &SortedSetLimits::default(),
+        |item| match item {
+            RdbItem::Aux(name, value) => aux.push((name, value)),
+            RdbItem::ResizeDb(_) => {}
+            RdbItem::Key(key, data_value) => {
+                let (value_type, size) = match &data_value.value {
+                    Value::String(string) => ("string", string.len()),
+                    Value::Set(set) => ("set", set.len()),
+                    Value::SortedSet(sorted_set) => ("zset", sorted_set.len()),
+                    Value::Stream(stream) => ("stream", stream.len()),
+                };
+                keys.push(RdbKey {
+                    key,
+                    value_type,
+                    size,
+                    expiry_ms: data_value
+                        .expiry_in_nanoseconds
+                        .map(|expiry| expiry / 1_000_000),
+                });
+            }
+        },
+    )?;
+    Ok(RdbSummary {
+        aux,
+        keys,
+        length,
+        checksum,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::rdb::{crc64, inspect_rdb, lzf_decompress};
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::data_core::Value;
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_load_rdb_with_strings_and_expiry() {
+        let mut data_core = new_data_core();
+        let mut rdb = b"REDIS0011\xfa\x09redis-ver\x057.2.0\xfe\x00\xfb\x03\x01".to_vec();
+        rdb.extend_from_slice(b"\x00\x03foo\x03bar");
+        rdb.extend_from_slice(b"\x00\x03num\xc1\x39\x30");
+        rdb.extend_from_slice(b"\x00\x03lzf\xc3\x05\x0a\x01ab\xc0\x01");
+        rdb.extend_from_slice(b"\xfc\x00\x9c\xef\x12\x7e\x01\x00\x00\x00\x03old\x01x");
+        rdb.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+        data_core.load_rdb(&rdb).unwrap();
+
+        assert_eq!(
+            ParserValue::BulkString("bar".into()),
+            run(&mut data_core, &["GET", "foo"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("12345".into()),
+            run(&mut data_core, &["GET", "num"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("ababababab".into()),
+            run(&mut data_core, &["GET", "lzf"])
+        );
+        assert!(!data_core.data_set.contains_key("old".as_bytes()));
+
+        // A RESIZEDB count larger than the file can hold isn't trusted.
+        let mut rdb = b"REDIS0011\xfe\x00\xfb\x80\xff\xff\xff\xff\x00".to_vec();
+        rdb.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+        data_core.load_rdb(&rdb).unwrap();
+        assert!(data_core.data_set.capacity() < 1000);
+    }
+
+    #[test]
+    fn test_lzf_lengths_are_not_trusted() {
+        assert_eq!(
+            b"ababababab".to_vec(),
+            lzf_decompress(b"\x01ab\xc0\x01", 10).unwrap()
+        );
+        // Neither a length far beyond what the input expands to nor one
+        // shorter than it is allocated for.
+        assert!(lzf_decompress(b"\x01ab\xc0\x01", 1 << 60).is_err());
+        assert!(lzf_decompress(b"\x01ab\xc0\x01", 4).is_err());
+    }
+
+    #[test]
+    fn test_load_rdb_file_from_dir() {
+        let dir = std::env::temp_dir().join(format!("rdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+
+        let mut data_core = new_data_core().with_rdb_file(dir.clone(), "test.rdb".to_string());
+        data_core.load_rdb_file().unwrap();
+        run(&mut data_core, &["SET", "k", "v"]);
+        std::fs::write(data_core.rdb_path(), data_core.to_rdb_bytes()).unwrap();
+
+        let mut loaded = new_data_core();
+        run(
+            &mut loaded,
+            &["CONFIG", "SET", "dir", &dir, "dbfilename", "test.rdb"],
+        );
+        loaded.load_rdb_file().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut loaded, &["GET", "k"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("dbfilename".into()),
+                ParserValue::BulkString("test.rdb".into()),
+            ]),
+            run(&mut loaded, &["CONFIG", "GET", "dbfilename"])
+        );
+    }
+
+    #[test]
+    fn test_rdb_round_trip() {
+        assert_eq!(0xe9c6_d914_c4b8_d9ca, crc64(b"123456789"));
+
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "s", "v", "PX", "100000"]);
+        run(&mut data_core, &["SADD", "set", "a", "b"]);
+        run(&mut data_core, &["ZADD", "z", "1.5", "m", "-2", "n"]);
+        let rdb = data_core.to_rdb_bytes();
+
+        let mut replica = new_data_core();
+        replica.load_rdb(&rdb).unwrap();
+        // The keyspace was sized from the RESIZEDB count.
+        assert!(replica.data_set.capacity() >= 3);
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut replica, &["GET", "s"])
+        );
+        assert!(replica.data_set["s".as_bytes()]
+            .expiry_in_nanoseconds
+            .is_some());
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut replica, &["SCARD", "set"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("1.5".into()),
+            run(&mut replica, &["ZSCORE", "z", "m"])
+        );
+
+        let mut corrupted = rdb.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(replica.load_rdb(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_rdb_round_trip_of_streams() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "n", "12345"]);
+        for (id, fields) in [
+            ("1-1", &["a", "1", "b", "2"][..]),
+            ("1-2", &["a", "3", "b", "4"]),
+            ("2-0", &["c", "-70000"]),
+        ] {
+            let mut command = vec!["XADD", "st", id];
+            command.extend_from_slice(fields);
+            run(&mut data_core, &command);
+        }
+        run(&mut data_core, &["XDEL", "st", "1-2"]);
+        run(&mut data_core, &["XGROUP", "CREATE", "st", "g", "0"]);
+        run(
+            &mut data_core,
+            &[
+                "XREADGROUP",
+                "GROUP",
+                "g",
+                "alice",
+                "COUNT",
+                "1",
+                "STREAMS",
+                "st",
+                ">",
+            ],
+        );
+        let rdb = data_core.to_rdb_bytes();
+        assert!(rdb.windows(5).any(|window| window == b"\x01n\xc1\x39\x30"));
+
+        let mut replica = new_data_core();
+        replica.load_rdb(&rdb).unwrap();
+        let (Value::Stream(original), Value::Stream(loaded)) = (
+            &data_core.data_set["st".as_bytes()].value,
+            &replica.data_set["st".as_bytes()].value,
+        ) else {
+            panic!("expected streams");
+        };
+        assert_eq!(original, loaded);
+        assert_eq!(1, loaded.groups()["g".as_bytes()].pending.len());
+        assert_eq!(
+            ParserValue::BulkString("12345".into()),
+            run(&mut replica, &["GET", "n"])
+        );
+    }
+
+    #[test]
+    fn test_inspect_rdb() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SET", "s", "value", "PX", "100000"]);
+        run(&mut data_core, &["ZADD", "z", "1", "m", "2", "n"]);
+        let mut rdb = data_core.to_rdb_bytes();
+        let length = rdb.len();
+        rdb.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        let mut summary = inspect_rdb(&rdb).unwrap();
+        assert_eq!(length, summary.length);
+        assert_eq!(crc64(&rdb[..length - 8]), summary.checksum);
+        assert!(summary
+            .aux
+            .contains(&("redis-ver".to_string(), "7.2.0".to_string())));
+        summary.keys.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            vec![("s", "string", 5, true), ("z", "zset", 2, false)],
+            summary
+                .keys
+                .iter()
+                .map(|key| (
+                    key.key.to_str().unwrap(),
+                    key.value_type,
+                    key.size,
+                    key.expiry_ms.is_some()
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert!(inspect_rdb(&rdb[..length - 1]).is_err());
+    }
+}
@@ -0,0 +1,944 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+
+use crate::backlog::ReplicationBacklog;
+use crate::binary::ByteString;
+use crate::data_core::commands;
+use crate::data_core::{
+    check_arity, client_required, new_replication_id, CommandError, DataCore, ReplicationRole,
+};
+use crate::parser::ParserValue;
+use crate::tokenizer;
+use crate::tokenizer::Token;
+
+/// A connection that completed PSYNC, which write commands are propagated to.
+#[derive(Debug)]
+pub(crate) struct Replica {
+    push_channel: UnboundedSender<Vec<Token>>,
+    /// Where the replica can be reached, as announced or else as seen on
+    /// its connection.
+    ip: Option<String>,
+    listening_port: Option<u64>,
+    /// The replication offset the replica last acknowledged with
+    /// REPLCONF ACK, and when.
+    ack_offset: i64,
+    ack_time: Option<Instant>,
+}
+
+/// What a client told with REPLCONF before sending PSYNC.
+#[derive(Debug, Default)]
+pub(crate) struct PendingReplica {
+    listening_port: Option<u64>,
+    ip_address: Option<String>,
+    /// Whether it can load an RDB file of unknown length, sent with `$EOF:`.
+    capa_eof: bool,
+}
+
+/// Connected replicas by client ID.
+pub(crate) type Replicas = HashMap<u64, Replica>;
+
+impl DataCore {
+    /// PSYNC replicationid offset
+    ///
+    /// Continues from the replica's offset when it is still in the backlog,
+    /// sending only the missing part of the stream. Otherwise the reply is
+    /// followed by the keyspace as an RDB file. Every write command the
+    /// master runs afterwards is propagated.
+    pub(crate) fn psync(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        let client = self
+            .client
+            .clone()
+            .ok_or_else(|| client_required("psync"))?;
+
+        if self.repl_backlog.is_none() {
+            self.repl_backlog = Some(ReplicationBacklog::new(
+                self.repl_backlog_size as usize,
+                self.master_reploffset + 1,
+            ));
+        }
+
+        // Pushed data is written by the connection right after the reply.
+        let replication_id = arguments[1].to_str().unwrap_or_default();
+        let (reply, data) = match self.backlog_since(replication_id, &arguments[2]) {
+            Some(missing) => (
+                format!("CONTINUE {}", self.master_replid),
+                (!missing.is_empty()).then(|| vec![Token::Bytes(missing)]),
+            ),
+            None => (
+                format!(
+                    "FULLRESYNC {} {}",
+                    self.master_replid, self.master_reploffset
+                ),
+                None,
+            ),
+        };
+        let pending = self.pending_replicas.remove(&client.id).unwrap_or_default();
+        if let Some(data) = data {
+            let _ = client.push_channel.send(data);
+        } else if self.repl_diskless_sync && pending.capa_eof {
+            self.stream_rdb(&client.push_channel);
+        } else {
+            let rdb = self.to_rdb_bytes();
+            let _ = client.push_channel.send(vec![
+                Token::Dollar,
+                Token::Number(rdb.len() as i64),
+                Token::Separator,
+                Token::Bytes(rdb),
+            ]);
+        }
+
+        self.replicas.insert(
+            client.id,
+            Replica {
+                push_channel: client.push_channel,
+                ip: pending
+                    .ip_address
+                    .or_else(|| client.address.map(|address| address.ip().to_string())),
+                listening_port: pending.listening_port,
+                ack_offset: 0,
+                ack_time: None,
+            },
+        );
+        // Give the replica a full period to load the RDB file before the
+        // first heartbeat.
+        self.next_replica_ping =
+            Instant::now() + Duration::from_secs(self.repl_ping_replica_period);
+
+        Ok(ParserValue::SimpleString(reply))
+    }
+
+    /// Sends the RDB file to a replica as it is written, in the
+    /// `$EOF:<delimiter>` format used when its length isn't known up front:
+    /// the payload ends with the same random 40 byte delimiter.
+    fn stream_rdb(self: &DataCore, push_channel: &UnboundedSender<Vec<Token>>) {
+        let delimiter = new_replication_id();
+        let _ = push_channel.send(vec![Token::Bytes(
+            format!("$EOF:{}\r\n", delimiter).into_bytes(),
+        )]);
+        self.write_rdb(|chunk| {
+            let _ = push_channel.send(vec![Token::Bytes(chunk)]);
+        });
+        let _ = push_channel.send(vec![Token::Bytes(delimiter.into_bytes())]);
+    }
+
+    /// The part of the replication stream from `offset` on, if a replica of
+    /// `replication_id` can continue from there. The previous ID is only
+    /// good for the part of the history it was used for.
+    fn backlog_since(
+        self: &DataCore,
+        replication_id: &str,
+        offset: &ByteString,
+    ) -> Option<Vec<u8>> {
+        let offset = offset.parse().ok()?;
+        if replication_id != self.master_replid
+            && (replication_id != self.master_replid2 || offset > self.second_reploffset)
+        {
+            return None;
+        }
+        self.repl_backlog.as_ref()?.since(offset)
+    }
+
+    /// Switches to a new replication ID from the current offset on,
+    /// remembering the current one as the previous ID.
+    pub(crate) fn shift_replication_id(self: &mut DataCore, replication_id: String) {
+        self.master_replid2 = std::mem::replace(&mut self.master_replid, replication_id);
+        self.second_reploffset = self.master_reploffset + 1;
+    }
+
+    /// What the current client told with REPLCONF so far.
+    fn pending_replica(self: &mut DataCore) -> Option<&mut PendingReplica> {
+        let client_id = self.client.as_ref()?.id;
+        Some(self.pending_replicas.entry(client_id).or_default())
+    }
+
+    /// REPLCONF listening-port port | REPLCONF ip-address ip |
+    /// REPLCONF capa capability | REPLCONF ACK offset
+    ///
+    /// REPLCONF GETACK is answered by the replication link itself and only
+    /// reaches here to count towards the replica's offset.
+    pub(crate) fn replconf(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let client_id = self.client.as_ref().map(|client| client.id);
+        match arguments[1].to_lowercase().as_str() {
+            "capa" => {
+                // Capabilities come in pairs: REPLCONF capa eof capa psync2.
+                let eof = arguments[1..].chunks(2).any(|pair| {
+                    pair.len() == 2
+                        && pair[0].eq_ignore_ascii_case("capa")
+                        && pair[1].eq_ignore_ascii_case("eof")
+                });
+                if let Some(pending) = self.pending_replica() {
+                    pending.capa_eof |= eof;
+                }
+            }
+            "listening-port" => {
+                let port = arguments[2]
+                    .parse::<u64>()
+                    .map_err(|_| CommandError::NotInteger)?;
+                if let Some(pending) = self.pending_replica() {
+                    pending.listening_port = Some(port);
+                }
+            }
+            "ip-address" => {
+                if let Some(pending) = self.pending_replica() {
+                    pending.ip_address = Some(arguments[2].to_string());
+                }
+            }
+            "ack" => {
+                let offset = arguments[2]
+                    .parse::<i64>()
+                    .map_err(|_| CommandError::NotInteger)?;
+                if let Some(replica) = client_id.and_then(|id| self.replicas.get_mut(&id)) {
+                    replica.ack_offset = offset;
+                    replica.ack_time = Some(Instant::now());
+                }
+                self.serve_clients_waiting_for_acks();
+                self.no_reply = true;
+            }
+            _ => {}
+        }
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// WAIT numreplicas timeout
+    ///
+    /// Blocks until `numreplicas` replicas acknowledged the replication
+    /// stream sent so far, or for at most `timeout` milliseconds when it
+    /// isn't 0, and replies with how many did.
+    pub(crate) fn wait(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        if self.is_slave() {
+            return Err(CommandError::Other(
+                "ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.".to_string(),
+            ));
+        }
+        let numreplicas = arguments[1]
+            .parse::<i64>()
+            .map_err(|_| CommandError::NotInteger)?;
+        let timeout = arguments[2].parse::<i64>().map_err(|_| {
+            CommandError::Other("ERR timeout is not an integer or out of range".to_string())
+        })?;
+        if timeout < 0 {
+            return Err(CommandError::Other("ERR timeout is negative".to_string()));
+        }
+        let timeout =
+            Some(Duration::from_millis(timeout as u64)).filter(|timeout| !timeout.is_zero());
+        if timeout.is_some_and(|timeout| Instant::now().checked_add(timeout).is_none()) {
+            return Err(CommandError::Other(
+                "ERR timeout is out of range".to_string(),
+            ));
+        }
+
+        let offset = self.master_reploffset;
+        let acked = self.acked_replicas(offset);
+        if numreplicas <= acked as i64 {
+            return Ok(ParserValue::Integer(acked as i64));
+        }
+        // Replicas acknowledge on the heartbeat otherwise, so ask them now.
+        self.feed_replicas(&["REPLCONF", "GETACK", "*"]);
+        self.block_for_acks(offset, numreplicas as usize, timeout)
+    }
+
+    /// How many replicas acknowledged the replication stream up to `offset`.
+    pub(crate) fn acked_replicas(self: &DataCore, offset: i64) -> usize {
+        self.replicas
+            .values()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count()
+    }
+
+    pub(crate) fn acked_replicas_reply(self: &DataCore, offset: i64) -> ParserValue {
+        ParserValue::Integer(self.acked_replicas(offset) as i64)
+    }
+
+    /// REPLICAOF host port | REPLICAOF NO ONE
+    ///
+    /// Switching to a new master drops the current data and replicas; the
+    /// handshake itself runs after the reply.
+    pub(crate) fn replicaof(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        if arguments[1].eq_ignore_ascii_case("no") && arguments[2].eq_ignore_ascii_case("one") {
+            if self.is_slave() {
+                self.master_connection = None;
+                self.reconnect_at = None;
+                self.master_host = None;
+                self.master_port = None;
+                self.replication_role = ReplicationRole::Master;
+                // A promoted replica starts a new replication history, but
+                // replicas of its old master can still continue from it.
+                self.shift_replication_id(new_replication_id());
+            }
+            return Ok(ParserValue::SimpleString(String::from("OK")));
+        }
+
+        let port = arguments[2]
+            .parse::<u16>()
+            .map_err(|_| CommandError::Other("ERR Invalid master port".to_string()))?
+            as u64;
+        let host = arguments[1].to_string();
+        if self.is_slave()
+            && self.master_host.as_ref() == Some(&host)
+            && self.master_port == Some(port)
+        {
+            return Ok(ParserValue::SimpleString(String::from(
+                "OK Already connected to specified master",
+            )));
+        }
+        self.replication_role = ReplicationRole::Slave;
+        self.master_host = Some(host);
+        self.master_port = Some(port);
+        self.master_connection = None;
+        self.replicas.clear();
+        self.synced_with_master = false;
+        self.reconnect_attempts = 0;
+        self.reconnect_at = Some(Instant::now());
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// The replication section of INFO.
+    pub(crate) fn replication_info(self: &DataCore) -> String {
+        let mut info = format!("# Replication\nrole:{}\n", self.replication_role);
+        if self.is_slave() {
+            let link_up = self.master_connection.is_some();
+            info.push_str(&format!(
+                "master_host:{}\nmaster_port:{}\nmaster_link_status:{}\nmaster_last_io_seconds_ago:{}\nmaster_sync_in_progress:0\nslave_repl_offset:{}\n",
+                self.master_host.as_deref().unwrap_or_default(),
+                self.master_port.unwrap_or_default(),
+                if link_up { "up" } else { "down" },
+                self.master_last_io
+                    .filter(|_| link_up)
+                    .map_or(-1, |last_io| last_io.elapsed().as_secs() as i64),
+                self.master_reploffset
+            ));
+        }
+        info.push_str(&format!("connected_slaves:{}\n", self.replicas.len()));
+
+        let mut replicas = self.replicas.iter().collect::<Vec<_>>();
+        replicas.sort_by_key(|(id, _)| **id);
+        for (index, (_, replica)) in replicas.iter().enumerate() {
+            info.push_str(&format!(
+                "slave{}:ip={},port={},state=online,offset={},lag={}\n",
+                index,
+                replica.ip.as_deref().unwrap_or_default(),
+                replica
+                    .listening_port
+                    .map_or_else(String::new, |port| port.to_string()),
+                replica.ack_offset,
+                replica
+                    .ack_time
+                    .map_or(0, |ack_time| ack_time.elapsed().as_secs())
+            ));
+        }
+
+        info.push_str(&format!(
+            "master_replid:{}\nmaster_replid2:{}\nmaster_repl_offset:{}\nsecond_repl_offset:{}\nrepl_backlog_active:{}\nrepl_backlog_size:{}\nrepl_backlog_first_byte_offset:{}\nrepl_backlog_histlen:{}",
+            self.master_replid,
+            self.master_replid2,
+            self.master_reploffset,
+            self.second_reploffset,
+            self.repl_backlog.is_some() as i64,
+            self.repl_backlog_size,
+            self.repl_backlog
+                .as_ref()
+                .map_or(0, |backlog| backlog.first_byte_offset()),
+            self.repl_backlog
+                .as_ref()
+                .map_or(0, |backlog| backlog.histlen())
+        ));
+        info
+    }
+
+    /// Forwards a successful write command to the append-only file and
+    /// every replica. Replicas pass on their master's stream instead, see
+    /// [`DataCore::proxy_to_replicas`].
+    pub(crate) fn propagate(self: &mut DataCore, name: &str, arguments: &[ByteString]) {
+        if commands::lookup(name).is_some_and(|spec| spec.is_write()) {
+            self.propagate_command(arguments);
+        }
+    }
+
+    /// Propagates a command whatever its flags, like the MULTI and EXEC
+    /// around the writes of a transaction.
+    pub(crate) fn propagate_command(self: &mut DataCore, arguments: &[impl AsRef<[u8]>]) {
+        self.feed_aof(arguments);
+        if !self.is_slave() {
+            self.feed_replicas(arguments);
+        }
+    }
+
+    /// Asks every replica for its offset, on the repl-ping-replica-period
+    /// timer. This keeps acknowledged offsets fresh and notices replicas
+    /// that went away.
+    pub(crate) fn ping_replicas(self: &mut DataCore) {
+        // A replica's replication stream must stay its master's.
+        if !self.is_slave() {
+            self.feed_replicas(&["REPLCONF", "GETACK", "*"]);
+        }
+        self.next_replica_ping =
+            Instant::now() + Duration::from_secs(self.repl_ping_replica_period);
+    }
+
+    /// Passes a command of the master's stream on to this replica's own
+    /// backlog and replicas, so its offsets stay the master's.
+    pub(crate) fn proxy_to_replicas(self: &mut DataCore, arguments: &[ByteString]) {
+        self.feed_replicas(arguments);
+    }
+
+    /// Refuses write commands while fewer than min-replicas-to-write
+    /// replicas acknowledged the stream within min-replicas-max-lag seconds.
+    pub(crate) fn check_min_replicas(self: &DataCore, name: &str) -> Result<(), CommandError> {
+        if self.min_replicas_to_write == 0
+            || self.is_slave()
+            || !commands::lookup(name).is_some_and(|spec| spec.is_write())
+        {
+            return Ok(());
+        }
+        let max_lag = Duration::from_secs(self.min_replicas_max_lag);
+        let good_replicas = self
+            .replicas
+            .values()
+            .filter(|replica| {
+                replica
+                    .ack_time
+                    .is_some_and(|ack_time| ack_time.elapsed() <= max_lag)
+            })
+            .count() as u64;
+        if good_replicas < self.min_replicas_to_write {
+            return Err(CommandError::Other(
+                "NOREPLICAS Not enough good replicas to write.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Appends a command to the replication stream, dropping the replicas
+    /// that disconnected.
+    pub(crate) fn feed_replicas(self: &mut DataCore, arguments: &[impl AsRef<[u8]>]) {
+        // Nothing is recorded until the first replica attaches.
+        if self.repl_backlog.is_none() {
+            return;
+        }
+        let command = ParserValue::Array(
+            arguments
+                .iter()
+                .map(|argument| ParserValue::BulkString(ByteString::from(argument.as_ref())))
+                .collect(),
+        )
+        .to_tokens();
+        if let Ok(serialized) = tokenizer::serialize_tokens_to_bytes(&command) {
+            // A replica's offset follows its master's stream instead.
+            if !self.is_slave() {
+                self.master_reploffset += serialized.len() as i64;
+            }
+            if let Some(backlog) = self.repl_backlog.as_mut() {
+                backlog.feed(&serialized);
+            }
+        }
+        self.replicas
+            .retain(|_, replica| replica.push_channel.send(command.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::Instant;
+
+    use crate::backlog::ReplicationBacklog;
+    use crate::binary::ByteString;
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::{Client, Command, DataCore, ReplicationRole};
+    use crate::parser::ParserValue;
+    use crate::tokenizer::{self, Token};
+
+    #[test]
+    fn test_psync_registers_replica_and_propagates_writes() {
+        let mut data_core = new_data_core();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+
+        assert!(matches!(
+            run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]),
+            ParserValue::SimpleString(s) if s.starts_with("FULLRESYNC ") && s.ends_with(" 0")
+        ));
+        let rdb = tokenizer::serialize_tokens_to_bytes(&push_rx.try_recv().unwrap()).unwrap();
+        let header = format!("${}\r\n", data_core.to_rdb_bytes().len());
+        assert!(rdb.starts_with(header.as_bytes()));
+        assert_eq!(b"REDIS0011", &rdb[header.len()..header.len() + 9]);
+
+        run(&mut data_core, &["GET", "k"]);
+        run(&mut data_core, &["SET", "k", "v"]);
+        let propagated = tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap();
+        assert_eq!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n", propagated);
+        assert!(push_rx.try_recv().is_err());
+        assert_eq!(propagated.len() as i64, data_core.master_reploffset);
+    }
+
+    #[test]
+    fn test_psync_streams_rdb_to_eof_capable_replicas() {
+        let mut data_core = new_data_core();
+        let value = "x".repeat(20_000);
+        run(&mut data_core, &["SET", "a", &value]);
+        run(&mut data_core, &["SET", "b", &value]);
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+        run_as(
+            &mut data_core,
+            &replica,
+            &["REPLCONF", "capa", "eof", "capa", "psync2"],
+        );
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+
+        let mut chunks = Vec::new();
+        while let Ok(tokens) = push_rx.try_recv() {
+            chunks.push(tokenizer::serialize_tokens_to_bytes(&tokens).unwrap());
+        }
+        assert!(chunks.len() > 3);
+        let header = String::from_utf8(chunks.remove(0)).unwrap();
+        let delimiter = header.strip_prefix("$EOF:").unwrap().trim_end();
+        assert_eq!(delimiter.as_bytes(), chunks.pop().unwrap());
+
+        let mut loaded = new_data_core();
+        loaded.load_rdb(&chunks.concat()).unwrap();
+        assert_eq!(
+            ParserValue::BulkString(value.into()),
+            run(&mut loaded, &["GET", "b"])
+        );
+    }
+
+    #[test]
+    fn test_ping_replicas_drops_disconnected_ones() {
+        let mut data_core = new_data_core();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let (closed_tx, _) = mpsc::unbounded_channel::<Vec<Token>>();
+        run_as(
+            &mut data_core,
+            &Client::new(1, push_tx),
+            &["PSYNC", "?", "-1"],
+        );
+        run_as(
+            &mut data_core,
+            &Client::new(2, closed_tx),
+            &["PSYNC", "?", "-1"],
+        );
+        push_rx.try_recv().unwrap();
+
+        data_core.ping_replicas();
+        let getack = "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+        assert_eq!(
+            getack,
+            tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap()
+        );
+        assert_eq!(getack.len() as i64, data_core.master_reploffset);
+        assert_eq!(1, data_core.replicas.len());
+    }
+
+    #[tokio::test]
+    async fn test_replicaof_switches_roles() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+
+        let mut master = new_data_core();
+        run(&mut master, &["SET", "k", "v"]);
+        let rdb = master.to_rdb_bytes();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut replies = format!(
+                "+PONG\r\n+OK\r\n+OK\r\n+FULLRESYNC {} 100\r\n${}\r\n",
+                master.master_replid,
+                rdb.len()
+            )
+            .into_bytes();
+            replies.extend_from_slice(&rdb);
+            socket.write_all(&replies).await.unwrap();
+            let mut buffer = [0; 1024];
+            while socket.read(&mut buffer).await.is_ok_and(|read| read > 0) {}
+        });
+
+        let mut data_core = new_data_core();
+        for invalid in ["99999", "-1", "port"] {
+            assert_eq!(
+                ParserValue::Error("ERR Invalid master port".to_string()),
+                run(&mut data_core, &["REPLICAOF", "127.0.0.1", invalid])
+            );
+        }
+        assert!(!data_core.is_slave());
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["REPLICAOF", "127.0.0.1", &port])
+        );
+        assert!(data_core.is_slave() && data_core.reconnect_at.is_some());
+        data_core.initialize_slaves().await.unwrap();
+        assert_eq!(100, data_core.master_reploffset);
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info) if info.to_string().contains(&format!(
+                "role:slave\nmaster_host:127.0.0.1\nmaster_port:{}\nmaster_link_status:up\nmaster_last_io_seconds_ago:0\nmaster_sync_in_progress:0\nslave_repl_offset:100\nconnected_slaves:0\n",
+                port
+            ))
+        ));
+        assert!(data_core.master_connection.is_some());
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut data_core, &["GET", "k"])
+        );
+
+        let replid = data_core.master_replid.clone();
+        run(&mut data_core, &["REPLICAOF", "NO", "ONE"]);
+        assert!(!data_core.is_slave() && data_core.master_connection.is_none());
+        assert_ne!(replid, data_core.master_replid);
+        assert_eq!(101, data_core.second_reploffset);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_with_backoff_and_partial_resync() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        drop(listener);
+
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["REPLICAOF", "127.0.0.1", &port]);
+        data_core.synced_with_master = true;
+        data_core.master_replid = "a".repeat(40);
+        data_core.master_reploffset = 41;
+        data_core.reconnect_to_master().await;
+        data_core.reconnect_to_master().await;
+        assert_eq!(2, data_core.reconnect_attempts);
+        let delay = data_core.reconnect_at.unwrap() - Instant::now();
+        assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
+            .await
+            .unwrap();
+        let master = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket
+                .write_all(b"+PONG\r\n+OK\r\n+OK\r\n+CONTINUE\r\n")
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 1024];
+            while let Ok(read @ 1..) = socket.read(&mut buffer).await {
+                received.extend_from_slice(&buffer[..read]);
+                if received.ends_with(b"$2\r\n42\r\n") {
+                    break;
+                }
+            }
+            String::from_utf8(received).unwrap()
+        });
+        data_core.reconnect_to_master().await;
+        assert_eq!(0, data_core.reconnect_attempts);
+        assert!(data_core.reconnect_at.is_none() && data_core.master_connection.is_some());
+        assert!(master.await.unwrap().ends_with(&format!(
+            "*3\r\n$5\r\nPSYNC\r\n$40\r\n{}\r\n$2\r\n42\r\n",
+            "a".repeat(40)
+        )));
+        assert_eq!(41, data_core.master_reploffset);
+    }
+
+    #[test]
+    fn test_commands_from_master_advance_offset() {
+        let mut data_core = new_data_core();
+        let arguments = ["SET", "k", "v"]
+            .iter()
+            .map(|argument| ByteString::from(*argument))
+            .collect();
+        let (tx, _rx) = oneshot::channel::<Vec<Token>>();
+        let mut command = Command::new(arguments, tx).from_master(29);
+        data_core.run_command(&mut command);
+        data_core.run_command(&mut command);
+        assert_eq!(58, data_core.master_reploffset);
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(&mut data_core, &["GET", "k"])
+        );
+    }
+
+    #[test]
+    fn test_only_the_master_expires_keys() {
+        let mut master = new_data_core();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        run_as(&mut master, &Client::new(1, push_tx), &["PSYNC", "?", "-1"]);
+        push_rx.try_recv().unwrap();
+        run(&mut master, &["SET", "k", "v", "PX", "1"]);
+        push_rx.try_recv().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(ParserValue::NullBulkString, run(&mut master, &["GET", "k"]));
+        assert_eq!(
+            "*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n",
+            tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap()
+        );
+
+        let mut replica = new_data_core();
+        replica.replication_role = ReplicationRole::Slave;
+        let run_from = |replica: &mut DataCore, arguments: &[&str], master: bool| {
+            let arguments = arguments
+                .iter()
+                .map(|argument| ByteString::from(*argument))
+                .collect();
+            let command = Command::new(arguments, oneshot::channel().0);
+            let mut command = if master {
+                command.from_master(0)
+            } else {
+                command
+            };
+            tokenizer::serialize_tokens(&replica.run_command(&mut command)).unwrap()
+        };
+        run_from(&mut replica, &["SET", "k", "v", "PX", "1"], true);
+        std::thread::sleep(Duration::from_millis(5));
+        replica.remove_expired_values();
+        assert_eq!("$-1\r\n", run_from(&mut replica, &["GET", "k"], false));
+        assert_eq!(
+            "$-1\r\n",
+            run_from(&mut replica, &["OBJECT", "ENCODING", "k"], false)
+        );
+        assert!(replica.data_set.contains_key("k".as_bytes()));
+        run_from(&mut replica, &["DEL", "k"], true);
+        assert!(replica.data_set.is_empty());
+    }
+
+    #[test]
+    fn test_psync_continues_from_backlog() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        run_as(
+            &mut data_core,
+            &Client::new(1, push_tx),
+            &["PSYNC", "?", "-1"],
+        );
+        run(&mut data_core, &["SET", "a", "1"]);
+        run(&mut data_core, &["SET", "b", "2"]);
+        let replid = data_core.master_replid.clone();
+        assert!(matches!(
+            run(&mut data_core, &["INFO", "replication"]),
+            ParserValue::BulkString(info) if info.to_string().ends_with(
+                "repl_backlog_active:1\nrepl_backlog_size:1048576\nrepl_backlog_first_byte_offset:1\nrepl_backlog_histlen:54"
+            )
+        ));
+
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(2, push_tx);
+        assert_eq!(
+            ParserValue::SimpleString(format!("CONTINUE {}", replid)),
+            run_as(&mut data_core, &replica, &["PSYNC", &replid, "28"])
+        );
+        assert_eq!(
+            "*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n",
+            tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap()
+        );
+
+        for (replid, offset) in [(replid.as_str(), "100"), ("other", "28")] {
+            assert!(matches!(
+                run_as(&mut data_core, &replica, &["PSYNC", replid, offset]),
+                ParserValue::SimpleString(s) if s.starts_with("FULLRESYNC ")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_promoted_replica_continues_previous_history() {
+        let mut data_core = new_data_core();
+        let previous = "a".repeat(40);
+        data_core.replication_role = ReplicationRole::Slave;
+        data_core.master_replid = previous.clone();
+        data_core.repl_backlog = Some(ReplicationBacklog::new(1024, 1));
+        let set = ["SET", "a", "1"]
+            .iter()
+            .map(|argument| ByteString::from(*argument))
+            .collect();
+        data_core.run_command(&mut Command::new(set, oneshot::channel().0).from_master(27));
+
+        run(&mut data_core, &["REPLICAOF", "NO", "ONE"]);
+        assert_eq!(previous, data_core.master_replid2);
+        assert_eq!(28, data_core.second_reploffset);
+
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+        assert_eq!(
+            ParserValue::SimpleString(format!("CONTINUE {}", data_core.master_replid)),
+            run_as(&mut data_core, &replica, &["PSYNC", &previous, "1"])
+        );
+        assert_eq!(
+            "*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n",
+            tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap()
+        );
+
+        run(&mut data_core, &["SET", "b", "2"]);
+        assert!(matches!(
+            run_as(&mut data_core, &replica, &["PSYNC", &previous, "29"]),
+            ParserValue::SimpleString(reply) if reply.starts_with("FULLRESYNC ")
+        ));
+    }
+
+    #[test]
+    fn test_replconf_ack_is_recorded_without_reply() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx).with_address("10.0.0.2:50000".parse().unwrap());
+        run_as(
+            &mut data_core,
+            &replica,
+            &["REPLCONF", "listening-port", "6380"],
+        );
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+
+        let arguments = ["REPLCONF", "ACK", "42"]
+            .iter()
+            .map(|argument| ByteString::from(*argument))
+            .collect();
+        let (tx, _rx) = oneshot::channel::<Vec<Token>>();
+        let mut command = Command::new(arguments, tx).with_client(replica);
+        assert!(data_core.run_command(&mut command).is_empty());
+
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info)
+                if info.to_string().contains("connected_slaves:1\nslave0:ip=10.0.0.2,port=6380,state=online,offset=42,lag=0\n")
+        ));
+    }
+
+    #[test]
+    fn test_replicas_announce_their_address() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx).with_address("10.0.0.2:50000".parse().unwrap());
+        for command in [
+            ["REPLCONF", "listening-port", "7000"],
+            ["REPLCONF", "ip-address", "203.0.113.5"],
+            ["PSYNC", "?", "-1"],
+        ] {
+            run_as(&mut data_core, &replica, &command);
+        }
+        assert!(matches!(
+            run(&mut data_core, &["INFO"]),
+            ParserValue::BulkString(info)
+                if info.to_string().contains("slave0:ip=203.0.113.5,port=7000,state=online")
+        ));
+        assert!(data_core.pending_replicas.is_empty());
+
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "replica-announce-port", "7001"],
+        );
+        assert_eq!(Some(7001), data_core.replica_announce_port);
+        assert!(matches!(
+            run(
+                &mut data_core,
+                &["CONFIG", "SET", "replica-announce-port", "70000"]
+            ),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_wait_for_replica_acks() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["WAIT", "1", "0"])
+        );
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+        push_rx.try_recv().unwrap();
+        run(&mut data_core, &["SET", "k", "v"]);
+        push_rx.try_recv().unwrap();
+
+        // Blocks like the command loop does, asking the replica for its
+        // offset.
+        let mut wait = |arguments: &[&str]| {
+            let (tx, rx) = oneshot::channel::<Vec<Token>>();
+            let arguments = arguments.iter().map(|argument| ByteString::from(*argument));
+            let mut command = Command::new(arguments.collect(), tx);
+            data_core.run_command(&mut command);
+            let request = data_core.block_request.take().unwrap();
+            data_core.park_blocked_client(command, request);
+            rx
+        };
+        let mut waiting = wait(&["WAIT", "1", "0"]);
+        let mut timing_out = wait(&["WAIT", "2", "10"]);
+        let getack = tokenizer::serialize_tokens(&push_rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n",
+            getack
+        );
+        assert!(waiting.try_recv().is_err());
+
+        let offset = data_core.master_reploffset.to_string();
+        run_as(&mut data_core, &replica, &["REPLCONF", "ACK", &offset]);
+        assert_eq!(
+            ":1\r\n",
+            tokenizer::serialize_tokens(&waiting.try_recv().unwrap()).unwrap()
+        );
+        assert!(timing_out.try_recv().is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        data_core.time_out_blocked_clients();
+        assert_eq!(
+            ":1\r\n",
+            tokenizer::serialize_tokens(&timing_out.try_recv().unwrap()).unwrap()
+        );
+
+        // Replicas that already acknowledged count right away.
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["WAIT", "1", "0"])
+        );
+        assert_eq!(
+            ParserValue::Error("ERR timeout is negative".to_string()),
+            run(&mut data_core, &["WAIT", "1", "-1"])
+        );
+    }
+
+    #[test]
+    fn test_min_replicas_to_write() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "min-replicas-to-write", "1"],
+        );
+        let no_replicas =
+            ParserValue::Error("NOREPLICAS Not enough good replicas to write.".to_string());
+        assert_eq!(no_replicas, run(&mut data_core, &["SET", "k", "v"]));
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "k"])
+        );
+
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+        assert_eq!(no_replicas, run(&mut data_core, &["SET", "k", "v"]));
+        run_as(&mut data_core, &replica, &["REPLCONF", "ACK", "0"]);
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(&mut data_core, &["SET", "k", "v"])
+        );
+
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "min-slaves-max-lag", "0"],
+        );
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(no_replicas, run(&mut data_core, &["SET", "k", "v"]));
+    }
+}
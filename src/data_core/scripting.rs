@@ -0,0 +1,569 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Variadic};
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, Command, CommandError, DataCore};
+use crate::parser::ParserValue;
+
+/// How many Lua instructions run between checks of the script's run time.
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+/// An error reply from `redis.call`, raised in Lua and handed back to the
+/// client unchanged if the script doesn't catch it.
+#[derive(Debug)]
+struct CallError(String);
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+impl<'lua> mlua::IntoLua<'lua> for ByteString {
+    fn into_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+        lua.create_string(self.as_bytes()).map(mlua::Value::String)
+    }
+}
+
+pub(crate) fn sha1_hex(bytes: &[u8]) -> String {
+    sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+/// A Lua state with the libraries Redis exposes to scripts.
+pub(crate) fn new_lua() -> Result<Lua, CommandError> {
+    Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )
+    .map_err(|err| CommandError::Other(format!("ERR {}", err)))
+}
+
+/// Converts a Lua error into the reply sent to the client.
+pub(crate) fn script_error(err: &mlua::Error, context: &str) -> CommandError {
+    match err {
+        mlua::Error::CallbackError { cause, .. } => script_error(cause, context),
+        mlua::Error::ExternalError(external) => match external.downcast_ref::<CallError>() {
+            Some(CallError(reply)) => CommandError::Other(reply.clone()),
+            None => CommandError::Other(format!("ERR Error running script ({}): {}", context, err)),
+        },
+        mlua::Error::SyntaxError { message, .. } => CommandError::Other(format!(
+            "ERR Error compiling script ({}): {}",
+            context, message
+        )),
+        err => CommandError::Other(format!("ERR Error running script ({}): {}", context, err)),
+    }
+}
+
+fn reply_to_lua<'lua>(lua: &'lua Lua, reply: &ParserValue) -> mlua::Result<mlua::Value<'lua>> {
+    Ok(match reply {
+        ParserValue::Integer(n) => mlua::Value::Integer(*n),
+        ParserValue::BulkString(s) => mlua::Value::String(lua.create_string(s)?),
+        ParserValue::NullBulkString | ParserValue::NullArray => mlua::Value::Boolean(false),
+        ParserValue::SimpleString(s) => {
+            let table = lua.create_table()?;
+            table.set("ok", s.as_str())?;
+            mlua::Value::Table(table)
+        }
+        ParserValue::Error(s) => {
+            let table = lua.create_table()?;
+            table.set("err", s.as_str())?;
+            mlua::Value::Table(table)
+        }
+        ParserValue::Array(values) => {
+            let table = lua.create_table()?;
+            for (index, value) in values.iter().enumerate() {
+                table.raw_set(index + 1, reply_to_lua(lua, value)?)?;
+            }
+            mlua::Value::Table(table)
+        }
+    })
+}
+
+/// Converts a script's return value into a reply the way Redis does: numbers
+/// are truncated to integers, `false` becomes nil and arrays stop at the
+/// first nil.
+pub(crate) fn lua_to_reply(value: &mlua::Value) -> ParserValue {
+    match value {
+        mlua::Value::Boolean(true) => ParserValue::Integer(1),
+        mlua::Value::Integer(n) => ParserValue::Integer(*n),
+        mlua::Value::Number(n) => ParserValue::Integer(*n as i64),
+        mlua::Value::String(s) => ParserValue::BulkString(s.as_bytes().into()),
+        mlua::Value::Table(table) => {
+            if let Ok(mlua::Value::String(err)) = table.raw_get::<_, mlua::Value>("err") {
+                return ParserValue::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(mlua::Value::String(ok)) = table.raw_get::<_, mlua::Value>("ok") {
+                return ParserValue::SimpleString(ok.to_string_lossy().into_owned());
+            }
+            let mut values = Vec::new();
+            for index in 1.. {
+                match table.raw_get::<_, mlua::Value>(index) {
+                    Ok(mlua::Value::Nil) | Err(_) => break,
+                    Ok(value) => values.push(lua_to_reply(&value)),
+                }
+            }
+            ParserValue::Array(values)
+        }
+        _ => ParserValue::NullBulkString,
+    }
+}
+
+fn lua_error(message: &str) -> mlua::Error {
+    mlua::Error::external(CallError(format!("ERR {}", message)))
+}
+
+/// State of the script being run, shared with the Lua hook that watches it.
+struct RunningScript {
+    read_only: bool,
+    started: Instant,
+    time_limit: Duration,
+    /// The data core's command channel, lent to the hook for the duration of
+    /// the script.
+    receiver: RefCell<Option<Receiver<Command>>>,
+    wrote: Cell<bool>,
+    killed: Cell<bool>,
+}
+
+impl RunningScript {
+    /// Called from the Lua hook. Once the script has run for longer than
+    /// lua-time-limit, other clients get `-BUSY` replies and may kill it.
+    fn check(self: &RunningScript) -> mlua::Result<()> {
+        if !self.killed.get() && self.started.elapsed() >= self.time_limit {
+            self.serve_busy_commands();
+        }
+        if self.killed.get() {
+            return Err(lua_error("Script killed by user with SCRIPT KILL..."));
+        }
+        Ok(())
+    }
+
+    fn serve_busy_commands(self: &RunningScript) {
+        let mut receiver = self.receiver.borrow_mut();
+        let Some(receiver) = receiver.as_mut() else {
+            return;
+        };
+        while let Ok(command) = receiver.try_recv() {
+            let arguments = command
+                .arguments
+                .iter()
+                .map(|argument| argument.to_lowercase())
+                .collect::<Vec<_>>();
+            let arguments = arguments.iter().map(String::as_str).collect::<Vec<_>>();
+            let reply = match arguments.as_slice() {
+                ["script" | "function", "kill"] if self.wrote.get() => ParserValue::Error(
+                    "UNKILLABLE Sorry the script already executed write commands against the dataset. You can only wait for the script termination.".to_string(),
+                ),
+                ["script" | "function", "kill"] => {
+                    self.killed.set(true);
+                    ParserValue::SimpleString(String::from("OK"))
+                }
+                _ => ParserValue::Error(
+                    "BUSY Redis is busy running a script. You can only call SCRIPT KILL or FUNCTION KILL.".to_string(),
+                ),
+            };
+            let _ = command.response_channel.send(reply.to_tokens());
+        }
+    }
+}
+
+/// Splits `numkeys key [key ...] arg [arg ...]` into keys and arguments.
+pub(crate) fn parse_keys_and_arguments(
+    arguments: &[ByteString],
+) -> Result<(&[ByteString], &[ByteString]), CommandError> {
+    let count = arguments[0]
+        .parse::<i64>()
+        .map_err(|_| CommandError::NotInteger)?;
+    if count < 0 {
+        return Err(CommandError::Other(
+            "ERR Number of keys can't be negative".to_string(),
+        ));
+    }
+    let count = count as usize;
+    if count > arguments.len() - 1 {
+        return Err(CommandError::Other(
+            "ERR Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+    Ok(arguments[1..].split_at(count))
+}
+
+impl DataCore {
+    /// Runs a command on behalf of a script through `redis.call` or
+    /// `redis.pcall`. Read-only scripts can't run write commands.
+    fn call_from_script(
+        self: &mut DataCore,
+        arguments: &[mlua::Value],
+        script: &RunningScript,
+    ) -> mlua::Result<ParserValue> {
+        if arguments.is_empty() {
+            return Err(lua_error(
+                "Please specify at least one argument for this redis lib call",
+            ));
+        }
+        let mut arguments = arguments
+            .iter()
+            .map(|argument| match argument {
+                mlua::Value::String(s) => Ok(ByteString::from(s.as_bytes())),
+                mlua::Value::Integer(n) => Ok(n.to_string().into()),
+                mlua::Value::Number(n) => Ok(n.to_string().into()),
+                _ => Err(lua_error(
+                    "Lua redis lib command arguments must be strings or integers",
+                )),
+            })
+            .collect::<mlua::Result<Vec<_>>>()?;
+        // Scripts call commands by the names clients do.
+        match self.command_table.get(&arguments[0].to_lowercase()) {
+            None => return Err(lua_error("Unknown Redis command called from script")),
+            Some(spec) if spec.has_flag("noscript") => {
+                return Err(lua_error("This Redis command is not allowed from script"))
+            }
+            Some(spec) if script.read_only && spec.is_write() => {
+                return Err(lua_error(
+                    "Write commands are not allowed from read-only scripts.",
+                ))
+            }
+            Some(spec) => {
+                script.wrote.set(script.wrote.get() || spec.is_write());
+                arguments[0] = spec.name.into();
+            }
+        }
+        let reply = self
+            .dispatch(&arguments)
+            .unwrap_or_else(|err| ParserValue::Error(err.to_string()));
+        // Scripts can't wait, so blocking commands behave as if they timed out.
+        self.block_request = None;
+        Ok(reply)
+    }
+
+    /// Installs the `redis` table into `lua` and runs `run` while its
+    /// functions can reach this data core, watching for scripts that run
+    /// longer than lua-time-limit.
+    pub(crate) fn with_redis_api<R>(
+        self: &mut DataCore,
+        lua: &Lua,
+        read_only: bool,
+        run: impl FnOnce(&Lua) -> mlua::Result<R>,
+    ) -> mlua::Result<R> {
+        let (_, placeholder) = mpsc::channel::<Command>(1);
+        let script = Rc::new(RunningScript {
+            read_only,
+            started: Instant::now(),
+            time_limit: Duration::from_millis(self.lua_time_limit_ms),
+            receiver: RefCell::new(Some(std::mem::replace(&mut self.rx, placeholder))),
+            wrote: Cell::new(false),
+            killed: Cell::new(false),
+        });
+        let watched = script.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| watched.check(),
+        );
+
+        let core = RefCell::new(self);
+        let result = lua.scope(|scope| {
+            let redis = lua.create_table()?;
+            redis.set(
+                "call",
+                scope.create_function(|lua, arguments: Variadic<mlua::Value>| {
+                    match core.borrow_mut().call_from_script(&arguments, &script)? {
+                        ParserValue::Error(reply) => Err(mlua::Error::external(CallError(reply))),
+                        reply => reply_to_lua(lua, &reply),
+                    }
+                })?,
+            )?;
+            redis.set(
+                "pcall",
+                scope.create_function(|lua, arguments: Variadic<mlua::Value>| {
+                    match core.borrow_mut().call_from_script(&arguments, &script) {
+                        Ok(reply) => reply_to_lua(lua, &reply),
+                        Err(err) => reply_to_lua(lua, &ParserValue::Error(err.to_string())),
+                    }
+                })?,
+            )?;
+            redis.set(
+                "status_reply",
+                lua.create_function(|lua, status: String| {
+                    reply_to_lua(lua, &ParserValue::SimpleString(status))
+                })?,
+            )?;
+            redis.set(
+                "error_reply",
+                lua.create_function(|lua, error: String| {
+                    reply_to_lua(lua, &ParserValue::Error(error))
+                })?,
+            )?;
+            redis.set(
+                "sha1hex",
+                lua.create_function(|_, value: mlua::String| Ok(sha1_hex(value.as_bytes())))?,
+            )?;
+            redis.set(
+                "log",
+                lua.create_function(|_, _: Variadic<mlua::Value>| Ok(()))?,
+            )?;
+            lua.globals().set("redis", redis)?;
+            run(lua)
+        });
+
+        lua.remove_hook();
+        if let Some(receiver) = script.receiver.take() {
+            core.into_inner().rx = receiver;
+        }
+        result
+    }
+
+    /// Runs the script `body` with the given KEYS and ARGV.
+    fn run_script(
+        self: &mut DataCore,
+        sha: &str,
+        body: &[u8],
+        keys: &[ByteString],
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        let lua = new_lua()?;
+        let context = format!("call to f_{}", sha);
+        self.with_redis_api(&lua, false, |lua| {
+            lua.globals().set("KEYS", keys)?;
+            lua.globals().set("ARGV", arguments)?;
+            let value = lua
+                .load(body)
+                .set_name("@user_script")
+                .call::<_, mlua::Value>(())?;
+            Ok(lua_to_reply(&value))
+        })
+        .map_err(|err| script_error(&err, &context))
+    }
+
+    /// EVAL script numkeys [key ...] [arg ...]
+    pub(crate) fn eval(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let (keys, script_arguments) = parse_keys_and_arguments(&arguments[2..])?;
+        let body = &arguments[1];
+        let sha = sha1_hex(body);
+        self.scripts.insert(sha.clone(), body.clone());
+        self.run_script(&sha, body, keys, script_arguments)
+    }
+
+    /// EVALSHA sha1 numkeys [key ...] [arg ...]
+    pub(crate) fn evalsha(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let (keys, script_arguments) = parse_keys_and_arguments(&arguments[2..])?;
+        let sha = arguments[1].to_lowercase();
+        let body = self.scripts.get(&sha).cloned().ok_or_else(|| {
+            CommandError::Other("NOSCRIPT No matching script. Please use EVAL.".to_string())
+        })?;
+        self.run_script(&sha, &body, keys, script_arguments)
+    }
+
+    /// SCRIPT LOAD script | EXISTS sha1 [sha1 ...] | FLUSH [ASYNC|SYNC]
+    pub(crate) fn script(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        match arguments[1].to_lowercase().as_str() {
+            "load" => {
+                if arguments.len() != 3 {
+                    return Err(CommandError::WrongArity("script|load".to_string()));
+                }
+                let body = &arguments[2];
+                // Compile the script so syntax errors surface at load time.
+                new_lua()?
+                    .load(&body[..])
+                    .set_name("@user_script")
+                    .into_function()
+                    .map_err(|err| script_error(&err, "new function"))?;
+                let sha = sha1_hex(body);
+                self.scripts.insert(sha.clone(), body.clone());
+                Ok(ParserValue::BulkString(sha.into()))
+            }
+            "exists" => {
+                if arguments.len() < 3 {
+                    return Err(CommandError::WrongArity("script|exists".to_string()));
+                }
+                Ok(ParserValue::Array(
+                    arguments[2..]
+                        .iter()
+                        .map(|sha| {
+                            ParserValue::Integer(
+                                self.scripts.contains_key(&sha.to_lowercase()) as i64
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            "flush" => {
+                match arguments.get(2).map(|mode| mode.to_lowercase()) {
+                    None => {}
+                    Some(mode) if arguments.len() == 3 && (mode == "async" || mode == "sync") => {}
+                    Some(_) => return Err(CommandError::Syntax),
+                }
+                self.scripts.clear();
+                Ok(ParserValue::SimpleString(String::from("OK")))
+            }
+            // A running script is killed from its hook, so getting here means
+            // nothing is running.
+            "kill" => Err(CommandError::Other(
+                "NOTBUSY No scripts in execution right now.".to_string(),
+            )),
+            _ => Err(CommandError::UnknownSubcommand(
+                arguments[1].clone(),
+                "SCRIPT".to_string(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::binary::ByteString;
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::data_core::{Command, DataCore, ReplicationRole};
+    use crate::parser::ParserValue;
+    use crate::tokenizer::Token;
+
+    #[test]
+    fn test_eval_converts_replies_and_calls_commands() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::Integer(1),
+                ParserValue::Integer(2),
+                ParserValue::BulkString("k".into()),
+                ParserValue::BulkString("a".into()),
+            ]),
+            run(
+                &mut data_core,
+                &[
+                    "EVAL",
+                    "return {1, 2.9, KEYS[1], ARGV[1], nil, 5}",
+                    "1",
+                    "k",
+                    "a"
+                ]
+            )
+        );
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(
+                &mut data_core,
+                &[
+                    "EVAL",
+                    "return redis.call('SET', KEYS[1], ARGV[1])",
+                    "1",
+                    "k",
+                    "v"
+                ]
+            )
+        );
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            run(
+                &mut data_core,
+                &["EVAL", "return redis.call('GET', 'k')", "0"]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["EVAL", "return redis.call('SADD', 'k', 'a')", "0"]),
+            ParserValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(
+            ParserValue::BulkString("WRONGTYPE".into()),
+            run(
+                &mut data_core,
+                &[
+                    "EVAL",
+                    "return string.sub(redis.pcall('SADD', 'k', 'a').err, 1, 9)",
+                    "0"
+                ]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["EVAL", "return redis.call('MULTI')", "0"]),
+            ParserValue::Error(e) if e == "ERR This Redis command is not allowed from script"
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["EVAL", "return (", "0"]),
+            ParserValue::Error(e) if e.starts_with("ERR Error compiling script")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["EVAL", "return 1", "2", "k"]),
+            ParserValue::Error(e) if e == "ERR Number of keys can't be greater than number of args"
+        ));
+    }
+
+    #[test]
+    fn test_script_cache() {
+        let mut data_core = new_data_core();
+        let script = "return ARGV[1]";
+        let sha = "098e0f0d1448c0a81dafe820f66d460eb09263da";
+        assert_eq!(
+            ParserValue::BulkString(sha.into()),
+            run(&mut data_core, &["SCRIPT", "LOAD", script])
+        );
+        assert_eq!(
+            ParserValue::BulkString("x".into()),
+            run(&mut data_core, &["EVALSHA", sha, "0", "x"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![ParserValue::Integer(1), ParserValue::Integer(0)]),
+            run(&mut data_core, &["SCRIPT", "EXISTS", sha, "ffff"])
+        );
+        run(&mut data_core, &["SCRIPT", "FLUSH"]);
+        assert!(matches!(
+            run(&mut data_core, &["EVALSHA", sha, "0", "x"]),
+            ParserValue::Error(e) if e.starts_with("NOSCRIPT")
+        ));
+    }
+
+    #[test]
+    fn test_script_kill_after_time_limit() {
+        let (command_tx, command_rx) = mpsc::channel::<Command>(32);
+        let mut data_core = DataCore::new(command_rx, ReplicationRole::Master, None, None);
+        assert!(matches!(
+            run(&mut data_core, &["SCRIPT", "KILL"]),
+            ParserValue::Error(e) if e.starts_with("NOTBUSY")
+        ));
+        run(&mut data_core, &["CONFIG", "SET", "lua-time-limit", "0"]);
+
+        let mut replies = Vec::new();
+        for arguments in [
+            vec!["GET", "k"],
+            vec!["SHUTDOWN", "NOSAVE"],
+            vec!["SCRIPT", "KILL"],
+        ] {
+            let (tx, rx) = oneshot::channel::<Vec<Token>>();
+            let arguments = arguments
+                .iter()
+                .map(|argument| ByteString::from(*argument))
+                .collect();
+            command_tx.try_send(Command::new(arguments, tx)).unwrap();
+            replies.push(rx);
+        }
+        assert!(matches!(
+            run(&mut data_core, &["EVAL", "while true do end", "0"]),
+            ParserValue::Error(e) if e.starts_with("ERR Script killed by user")
+        ));
+        for busy in &mut replies[..2] {
+            let busy = busy.try_recv().unwrap();
+            assert!(busy[1].to_string().unwrap().starts_with("BUSY"));
+        }
+        let killed = replies[2].try_recv().unwrap();
+        assert_eq!(Some("OK".to_string()), killed[1].to_string());
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Value};
+use crate::parser::ParserValue;
+use crate::set::RedisSet;
+
+impl DataCore {
+    fn get_set(self: &mut DataCore, key: &[u8]) -> Result<Option<&RedisSet>, CommandError> {
+        match self.lookup(key) {
+            Some(DataValue {
+                value: Value::Set(set),
+                ..
+            }) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_set_mut(self: &mut DataCore, key: &[u8]) -> Result<Option<&mut RedisSet>, CommandError> {
+        match self.lookup_mut(key) {
+            Some(DataValue {
+                value: Value::Set(set),
+                ..
+            }) => Ok(Some(set)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the key if it holds an empty set, as Redis never keeps empty aggregates around.
+    fn remove_if_empty_set(self: &mut DataCore, key: &[u8]) {
+        if let Some(DataValue {
+            value: Value::Set(set),
+            ..
+        }) = self.data_set.get(key)
+        {
+            if set.is_empty() {
+                self.remove_value(key);
+            }
+        }
+    }
+
+    pub(crate) fn sadd(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let key = &arguments[1];
+        let limits = self.set_limits;
+        if self.get_set(key)?.is_none() {
+            let set = RedisSet::new(&arguments[2], &limits);
+            self.insert_value(key, DataValue::new(Value::Set(set)));
+        }
+        let set = self.get_set_mut(key)?.unwrap();
+        let added = arguments[2..]
+            .iter()
+            .filter(|member| set.insert((*member).clone(), &limits))
+            .count();
+        Ok(ParserValue::Integer(added as i64))
+    }
+
+    pub(crate) fn srem(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let key = &arguments[1];
+        let removed = match self.get_set_mut(key)? {
+            Some(set) => arguments[2..]
+                .iter()
+                .filter(|member| set.remove(member))
+                .count(),
+            None => 0,
+        };
+        self.remove_if_empty_set(key);
+        Ok(ParserValue::Integer(removed as i64))
+    }
+
+    pub(crate) fn smembers(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 2)?;
+        let members = match self.get_set(&arguments[1])? {
+            Some(set) => set.iter().map(ParserValue::BulkString).collect(),
+            None => Vec::new(),
+        };
+        Ok(ParserValue::Array(members))
+    }
+
+    pub(crate) fn sismember(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        let is_member = self
+            .get_set(&arguments[1])?
+            .is_some_and(|set| set.contains(&arguments[2]));
+        Ok(ParserValue::Integer(is_member as i64))
+    }
+
+    pub(crate) fn scard(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 2)?;
+        let cardinality = self.get_set(&arguments[1])?.map_or(0, |set| set.len());
+        Ok(ParserValue::Integer(cardinality as i64))
+    }
+
+    /// SMOVE source destination member
+    ///
+    /// Both keys are type checked before anything is modified so a failed move
+    /// never leaves the member removed from the source.
+    pub(crate) fn smove(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        let (source, destination, member) = (&arguments[1], &arguments[2], &arguments[3]);
+
+        let in_source = self
+            .get_set(source)?
+            .is_some_and(|set| set.contains(member));
+        self.get_set(destination)?;
+        if !in_source {
+            return Ok(ParserValue::Integer(0));
+        }
+        if source == destination {
+            return Ok(ParserValue::Integer(1));
+        }
+
+        let limits = self.set_limits;
+        self.get_set_mut(source)?.unwrap().remove(member);
+        self.remove_if_empty_set(source);
+        if !self.data_set.contains_key(&destination[..]) {
+            let set = RedisSet::new(member, &limits);
+            self.insert_value(destination, DataValue::new(Value::Set(set)));
+        }
+        self.get_set_mut(destination)?
+            .unwrap()
+            .insert(member.clone(), &limits);
+
+        Ok(ParserValue::Integer(1))
+    }
+
+    /// Loads every set named in `keys`, treating missing keys as empty sets. All
+    /// keys are type checked up front so a single wrong-typed key fails the
+    /// whole command.
+    fn collect_sets(
+        self: &mut DataCore,
+        keys: &[ByteString],
+    ) -> Result<Vec<HashSet<ByteString>>, CommandError> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            let members = self.get_set(key)?.map(|set| set.iter().collect());
+            sets.push(members.unwrap_or_default());
+        }
+        Ok(sets)
+    }
+
+    fn set_operation(
+        self: &mut DataCore,
+        operation: SetOperation,
+        keys: &[ByteString],
+    ) -> Result<HashSet<ByteString>, CommandError> {
+        let mut sets = self.collect_sets(keys)?.into_iter();
+        let first = sets.next().unwrap_or_default();
+        let result = match operation {
+            SetOperation::Intersection => sets.fold(first, |acc, set| {
+                acc.into_iter()
+                    .filter(|member| set.contains(member))
+                    .collect()
+            }),
+            SetOperation::Union => sets.fold(first, |mut acc, set| {
+                acc.extend(set);
+                acc
+            }),
+            SetOperation::Difference => sets.fold(first, |acc, set| {
+                acc.into_iter()
+                    .filter(|member| !set.contains(member))
+                    .collect()
+            }),
+        };
+        Ok(result)
+    }
+
+    /// SINTER, SUNION and SDIFF
+    pub(crate) fn set_operation_command(
+        self: &mut DataCore,
+        operation: SetOperation,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let result = self.set_operation(operation, &arguments[1..])?;
+        Ok(ParserValue::Array(
+            result.into_iter().map(ParserValue::BulkString).collect(),
+        ))
+    }
+
+    /// SINTERSTORE, SUNIONSTORE and SDIFFSTORE
+    ///
+    /// The destination is overwritten regardless of its previous type, and
+    /// deleted when the result is empty.
+    pub(crate) fn set_operation_store_command(
+        self: &mut DataCore,
+        operation: SetOperation,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let destination = &arguments[1];
+        let result = self.set_operation(operation, &arguments[2..])?;
+        let cardinality = result.len();
+        if result.is_empty() {
+            self.remove_value(destination);
+        } else {
+            let set = RedisSet::from_members(result, &self.set_limits);
+            self.insert_value(destination, DataValue::new(Value::Set(set)));
+        }
+        Ok(ParserValue::Integer(cardinality as i64))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SetOperation {
+    Intersection,
+    Union,
+    Difference,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_smove_moves_member_between_sets() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SADD", "src", "a", "b"]);
+        run(&mut data_core, &["SADD", "dst", "c"]);
+
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SMOVE", "src", "dst", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["SMOVE", "src", "dst", "missing"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SISMEMBER", "dst", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SCARD", "src"])
+        );
+
+        run(&mut data_core, &["SMOVE", "src", "new", "b"]);
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["SCARD", "src"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SCARD", "new"])
+        );
+    }
+
+    #[test]
+    fn test_smove_rejects_wrong_destination_type() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SADD", "src", "a"]);
+        run(&mut data_core, &["SET", "str", "value"]);
+
+        assert!(matches!(
+            run(&mut data_core, &["SMOVE", "src", "str", "a"]),
+            ParserValue::Error(_)
+        ));
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SISMEMBER", "src", "a"])
+        );
+    }
+
+    #[test]
+    fn test_set_algebra_and_store_variants() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["SADD", "a", "1", "2", "3"]);
+        run(&mut data_core, &["SADD", "b", "2", "3", "4"]);
+
+        let sorted = |value: ParserValue| {
+            let mut members = value
+                .to_vec()
+                .unwrap()
+                .iter()
+                .map(|member| member.to_string().unwrap())
+                .collect::<Vec<_>>();
+            members.sort();
+            members
+        };
+
+        assert_eq!(
+            vec!["2", "3"],
+            sorted(run(&mut data_core, &["SINTER", "a", "b"]))
+        );
+        assert_eq!(
+            vec!["1", "2", "3", "4"],
+            sorted(run(&mut data_core, &["SUNION", "a", "b"]))
+        );
+        assert_eq!(vec!["1"], sorted(run(&mut data_core, &["SDIFF", "a", "b"])));
+        assert!(sorted(run(&mut data_core, &["SINTER", "a", "missing"])).is_empty());
+
+        run(&mut data_core, &["SET", "dst", "string"]);
+        assert_eq!(
+            ParserValue::Integer(4),
+            run(&mut data_core, &["SUNIONSTORE", "dst", "a", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(4),
+            run(&mut data_core, &["SCARD", "dst"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["SDIFFSTORE", "dst", "a", "a"])
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "dst"])
+        );
+
+        run(&mut data_core, &["SET", "str", "value"]);
+        assert!(matches!(
+            run(&mut data_core, &["SINTERSTORE", "dst", "a", "str"]),
+            ParserValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[test]
+    fn test_small_sets_use_compact_encodings() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "set-max-intset-entries", "2"],
+        );
+        run(&mut data_core, &["SADD", "ints", "1", "2"]);
+        assert_eq!(
+            ParserValue::BulkString("intset".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "ints"])
+        );
+        run(&mut data_core, &["SADD", "ints", "3"]);
+        assert_eq!(
+            ParserValue::BulkString("hashtable".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "ints"])
+        );
+
+        run(&mut data_core, &["SADD", "strings", "a", "b"]);
+        assert_eq!(
+            ParserValue::BulkString("listpack".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "strings"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SREM", "strings", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["SCARD", "strings"])
+        );
+    }
+}
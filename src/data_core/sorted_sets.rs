@@ -0,0 +1,1576 @@
+use std::collections::HashMap;
+
+use rand::seq::index::sample;
+use rand::{thread_rng, Rng};
+
+use crate::binary::ByteString;
+use crate::data_core::blocking::parse_timeout;
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Value};
+use crate::glob::glob_match;
+use crate::parser::ParserValue;
+use crate::sorted_set::{format_score, LexBound, LexRange, ScoreBound, ScoreRange, SortedSet};
+
+/// Parses a score argument, accepting `inf`/`+inf`/`-inf` but rejecting NaN.
+pub(crate) fn parse_score(score: &[u8]) -> Result<f64, CommandError> {
+    match std::str::from_utf8(score).map(str::parse::<f64>) {
+        Ok(Ok(score)) if !score.is_nan() => Ok(score),
+        _ => Err(CommandError::NotFloat),
+    }
+}
+
+/// Parses a ZRANGEBYSCORE style bound such as `1.5`, `(1.5` or `-inf`.
+fn parse_score_bound(bound: &[u8]) -> Result<ScoreBound, CommandError> {
+    let not_float = || CommandError::Other("ERR min or max is not a float".to_string());
+    match bound.strip_prefix(b"(") {
+        Some(score) => Ok(ScoreBound::Exclusive(
+            parse_score(score).map_err(|_| not_float())?,
+        )),
+        None => Ok(ScoreBound::Inclusive(
+            parse_score(bound).map_err(|_| not_float())?,
+        )),
+    }
+}
+
+pub(crate) fn parse_score_range(min: &[u8], max: &[u8]) -> Result<ScoreRange, CommandError> {
+    Ok(ScoreRange {
+        min: parse_score_bound(min)?,
+        max: parse_score_bound(max)?,
+    })
+}
+
+/// Parses a ZRANGEBYLEX style bound: `[member`, `(member`, `-` or `+`.
+fn parse_lex_bound(bound: &[u8]) -> Result<LexBound, CommandError> {
+    match bound {
+        b"-" => Ok(LexBound::NegativeInfinity),
+        b"+" => Ok(LexBound::PositiveInfinity),
+        _ => match (bound.strip_prefix(b"["), bound.strip_prefix(b"(")) {
+            (Some(member), _) => Ok(LexBound::Inclusive(member.into())),
+            (_, Some(member)) => Ok(LexBound::Exclusive(member.into())),
+            _ => Err(CommandError::Other(
+                "ERR min or max not valid string range item".to_string(),
+            )),
+        },
+    }
+}
+
+pub(crate) fn parse_lex_range(min: &[u8], max: &[u8]) -> Result<LexRange, CommandError> {
+    Ok(LexRange {
+        min: parse_lex_bound(min)?,
+        max: parse_lex_bound(max)?,
+    })
+}
+
+fn parse_integer(value: &ByteString) -> Result<i64, CommandError> {
+    value.parse::<i64>().map_err(|_| CommandError::NotInteger)
+}
+
+/// Builds the flat `member [score]` array returned by the range commands.
+pub(crate) fn range_reply(members: Vec<(ByteString, f64)>, with_scores: bool) -> ParserValue {
+    let mut values = Vec::with_capacity(members.len() * if with_scores { 2 } else { 1 });
+    for (member, score) in members {
+        values.push(ParserValue::BulkString(member));
+        if with_scores {
+            values.push(ParserValue::BulkString(format_score(score).into()));
+        }
+    }
+    ParserValue::Array(values)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+/// The options shared by ZRANGE and its legacy variants.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RangeSpec {
+    pub(crate) kind: RangeKind,
+    pub(crate) rev: bool,
+    pub(crate) limit: Option<(i64, i64)>,
+    pub(crate) with_scores: bool,
+}
+
+impl RangeSpec {
+    pub(crate) fn new(kind: RangeKind, rev: bool) -> RangeSpec {
+        RangeSpec {
+            kind,
+            rev,
+            limit: None,
+            with_scores: false,
+        }
+    }
+
+    /// Applies the trailing options of a range command. BYSCORE, BYLEX and REV
+    /// are only accepted by the unified ZRANGE syntax.
+    pub(crate) fn parse_options(
+        mut self,
+        options: &[ByteString],
+        unified: bool,
+    ) -> Result<RangeSpec, CommandError> {
+        let mut index = 0;
+        while index < options.len() {
+            match options[index].to_lowercase().as_str() {
+                "withscores" => self.with_scores = true,
+                "limit" if index + 2 < options.len() => {
+                    let offset = parse_integer(&options[index + 1])?;
+                    let count = parse_integer(&options[index + 2])?;
+                    self.limit = Some((offset, count));
+                    index += 2;
+                }
+                "byscore" if unified => self.kind = RangeKind::Score,
+                "bylex" if unified => self.kind = RangeKind::Lex,
+                "rev" if unified => self.rev = true,
+                _ => return Err(CommandError::Syntax),
+            }
+            index += 1;
+        }
+
+        if self.limit.is_some() && self.kind == RangeKind::Rank {
+            return Err(CommandError::Other(
+                "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                    .to_string(),
+            ));
+        }
+        if self.with_scores && self.kind == RangeKind::Lex {
+            return Err(CommandError::Other(
+                "ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+type ScoredMembers = Vec<(ByteString, f64)>;
+
+/// Parses the `numkeys key [key ...] MIN | MAX [COUNT count]` tail shared by
+/// ZMPOP and BZMPOP.
+fn parse_mpop_arguments(
+    arguments: &[ByteString],
+) -> Result<(&[ByteString], bool, usize), CommandError> {
+    let numkeys = parse_integer(&arguments[0])?;
+    if numkeys <= 0 {
+        return Err(CommandError::Other(
+            "ERR numkeys should be greater than 0".to_string(),
+        ));
+    }
+    let numkeys = numkeys as usize;
+    if arguments.len() < numkeys + 2 {
+        return Err(CommandError::Syntax);
+    }
+    let keys = &arguments[1..=numkeys];
+    let max = match arguments[numkeys + 1].to_lowercase().as_str() {
+        "min" => false,
+        "max" => true,
+        _ => return Err(CommandError::Syntax),
+    };
+    let count = match &arguments[numkeys + 2..] {
+        [] => 1,
+        [option, count] if option.eq_ignore_ascii_case("count") => match parse_integer(count)? {
+            count if count > 0 => count as usize,
+            _ => {
+                return Err(CommandError::Other(
+                    "ERR count should be greater than 0".to_string(),
+                ))
+            }
+        },
+        _ => return Err(CommandError::Syntax),
+    };
+    Ok((keys, max, count))
+}
+
+/// The `[key, [[member, score], ...]]` reply of ZMPOP and BZMPOP.
+fn mpop_reply(key: &[u8], popped: Vec<(ByteString, f64)>) -> ParserValue {
+    let popped = popped
+        .into_iter()
+        .map(|(member, score)| {
+            ParserValue::Array(vec![
+                ParserValue::BulkString(member),
+                ParserValue::BulkString(format_score(score).into()),
+            ])
+        })
+        .collect();
+    ParserValue::Array(vec![
+        ParserValue::BulkString(key.into()),
+        ParserValue::Array(popped),
+    ])
+}
+
+/// ZUNION, ZINTER and ZDIFF, with or without STORE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ZSetOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, current: f64, score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which Redis turns into zero.
+            Aggregate::Sum => match current + score {
+                sum if sum.is_nan() => 0.0,
+                sum => sum,
+            },
+            Aggregate::Min => current.min(score),
+            Aggregate::Max => current.max(score),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ZAddOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+impl DataCore {
+    pub(crate) fn get_sorted_set(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<Option<&SortedSet>, CommandError> {
+        match self.lookup(key) {
+            Some(DataValue {
+                value: Value::SortedSet(sorted_set),
+                ..
+            }) => Ok(Some(sorted_set)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_sorted_set_mut(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<Option<&mut SortedSet>, CommandError> {
+        match self.lookup_mut(key) {
+            Some(DataValue {
+                value: Value::SortedSet(sorted_set),
+                ..
+            }) => Ok(Some(sorted_set)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Collects the members selected by a range command. For reversed score
+    /// and lex ranges `start` is the maximum and `stop` the minimum, as in
+    /// `ZRANGE key max min BYSCORE REV`.
+    pub(crate) fn zrange_members(
+        self: &mut DataCore,
+        key: &[u8],
+        start: &ByteString,
+        stop: &ByteString,
+        spec: RangeSpec,
+    ) -> Result<Vec<(ByteString, f64)>, CommandError> {
+        let (min, max) = match spec.rev {
+            true => (stop, start),
+            false => (start, stop),
+        };
+        let (offset, count) = spec.limit.unwrap_or((0, -1));
+        if offset < 0 {
+            return Ok(Vec::new());
+        }
+        let count = if count < 0 {
+            usize::MAX
+        } else {
+            count as usize
+        };
+
+        let to_owned = |(member, score): (&[u8], f64)| (ByteString::from(member), score);
+        match spec.kind {
+            RangeKind::Rank => {
+                let (start, stop) = (parse_integer(start)?, parse_integer(stop)?);
+                Ok(match self.get_sorted_set(key)? {
+                    Some(sorted_set) => sorted_set
+                        .range_by_rank(start, stop, spec.rev)
+                        .map(to_owned)
+                        .collect(),
+                    None => Vec::new(),
+                })
+            }
+            RangeKind::Score => {
+                let range = parse_score_range(min, max)?;
+                Ok(match self.get_sorted_set(key)? {
+                    Some(sorted_set) => sorted_set
+                        .range_by_score(&range, spec.rev)
+                        .skip(offset as usize)
+                        .take(count)
+                        .map(to_owned)
+                        .collect(),
+                    None => Vec::new(),
+                })
+            }
+            RangeKind::Lex => {
+                let range = parse_lex_range(min, max)?;
+                Ok(match self.get_sorted_set(key)? {
+                    Some(sorted_set) => sorted_set
+                        .range_by_lex(&range, spec.rev)
+                        .skip(offset as usize)
+                        .take(count)
+                        .map(to_owned)
+                        .collect(),
+                    None => Vec::new(),
+                })
+            }
+        }
+    }
+
+    /// ZRANGE key start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+    pub(crate) fn zrange(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let spec = RangeSpec::new(RangeKind::Rank, false).parse_options(&arguments[4..], true)?;
+        let members = self.zrange_members(&arguments[1], &arguments[2], &arguments[3], spec)?;
+        Ok(range_reply(members, spec.with_scores))
+    }
+
+    /// ZRANGEBYSCORE, ZREVRANGEBYSCORE, ZRANGEBYLEX, ZREVRANGEBYLEX and ZREVRANGE,
+    /// expressed in terms of the unified ZRANGE.
+    pub(crate) fn zrange_legacy(
+        self: &mut DataCore,
+        kind: RangeKind,
+        rev: bool,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let spec = RangeSpec::new(kind, rev).parse_options(&arguments[4..], false)?;
+        let members = self.zrange_members(&arguments[1], &arguments[2], &arguments[3], spec)?;
+        Ok(range_reply(members, spec.with_scores))
+    }
+
+    /// ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+    pub(crate) fn zadd(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let key = &arguments[1];
+
+        let mut options = ZAddOptions::default();
+        let mut index = 2;
+        while index < arguments.len() {
+            match arguments[index].to_lowercase().as_str() {
+                "nx" => options.nx = true,
+                "xx" => options.xx = true,
+                "gt" => options.gt = true,
+                "lt" => options.lt = true,
+                "ch" => options.ch = true,
+                "incr" => options.incr = true,
+                _ => break,
+            }
+            index += 1;
+        }
+
+        let pairs = &arguments[index..];
+        if pairs.is_empty() || pairs.len() % 2 == 1 {
+            return Err(CommandError::Syntax);
+        }
+        if options.nx && options.xx {
+            return Err(CommandError::Other(
+                "ERR XX and NX options at the same time are not compatible".to_string(),
+            ));
+        }
+        if (options.gt && options.lt) || (options.nx && (options.gt || options.lt)) {
+            return Err(CommandError::Other(
+                "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+            ));
+        }
+        if options.incr && pairs.len() > 2 {
+            return Err(CommandError::Other(
+                "ERR INCR option supports a single increment-element pair".to_string(),
+            ));
+        }
+        let pairs = pairs
+            .chunks(2)
+            .map(|pair| Ok((parse_score(&pair[0])?, &pair[1])))
+            .collect::<Result<Vec<_>, CommandError>>()?;
+
+        if self.get_sorted_set(key)?.is_none() {
+            if options.xx {
+                return Ok(match options.incr {
+                    true => ParserValue::NullBulkString,
+                    false => ParserValue::Integer(0),
+                });
+            }
+            self.insert_value(key, DataValue::new(Value::SortedSet(SortedSet::new())));
+        }
+        let limits = self.sorted_set_limits;
+        let sorted_set = self.get_sorted_set_mut(key)?.unwrap();
+
+        let mut added = 0;
+        let mut changed = 0;
+        let mut incremented = None;
+        for (score, member) in pairs {
+            let current = sorted_set.score(member);
+            let score = match (options.incr, current) {
+                (true, Some(current)) => current + score,
+                _ => score,
+            };
+            if score.is_nan() {
+                return Err(CommandError::Other(
+                    "ERR resulting score is not a number (NaN)".to_string(),
+                ));
+            }
+            let skip = match current {
+                Some(current) => {
+                    options.nx
+                        || (options.gt && score <= current)
+                        || (options.lt && score >= current)
+                }
+                None => options.xx,
+            };
+            if skip {
+                continue;
+            }
+            if current.is_none() {
+                added += 1;
+            } else if current != Some(score) {
+                changed += 1;
+            }
+            sorted_set.insert(member.clone(), score, &limits);
+            incremented = Some(score);
+        }
+
+        if sorted_set.is_empty() {
+            self.remove_value(key);
+        }
+
+        if options.incr {
+            return Ok(match incremented {
+                Some(score) => ParserValue::BulkString(format_score(score).into()),
+                None => ParserValue::NullBulkString,
+            });
+        }
+        match options.ch {
+            true => Ok(ParserValue::Integer(added + changed)),
+            false => Ok(ParserValue::Integer(added)),
+        }
+    }
+
+    /// ZRANK key member [WITHSCORE] and ZREVRANK key member [WITHSCORE]
+    pub(crate) fn zrank(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        rev: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 4)?;
+        let with_score = match arguments.get(3) {
+            Some(option) if option.eq_ignore_ascii_case("withscore") => true,
+            Some(_) => return Err(CommandError::Syntax),
+            None => false,
+        };
+        let ranked = self.get_sorted_set(&arguments[1])?.and_then(|sorted_set| {
+            let rank = sorted_set.rank(&arguments[2])?;
+            let rank = match rev {
+                true => sorted_set.len() - 1 - rank,
+                false => rank,
+            };
+            Some((rank, sorted_set.score(&arguments[2])?))
+        });
+
+        Ok(match (ranked, with_score) {
+            (Some((rank, score)), true) => ParserValue::Array(vec![
+                ParserValue::Integer(rank as i64),
+                ParserValue::BulkString(format_score(score).into()),
+            ]),
+            (Some((rank, _)), false) => ParserValue::Integer(rank as i64),
+            (None, true) => ParserValue::NullArray,
+            (None, false) => ParserValue::NullBulkString,
+        })
+    }
+
+    /// ZSCORE key member
+    pub(crate) fn zscore(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 3)?;
+        let score = self
+            .get_sorted_set(&arguments[1])?
+            .and_then(|sorted_set| sorted_set.score(&arguments[2]));
+        Ok(match score {
+            Some(score) => ParserValue::BulkString(format_score(score).into()),
+            None => ParserValue::NullBulkString,
+        })
+    }
+
+    /// ZMSCORE key member [member ...]
+    pub(crate) fn zmscore(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let sorted_set = self.get_sorted_set(&arguments[1])?;
+        let scores = arguments[2..]
+            .iter()
+            .map(
+                |member| match sorted_set.and_then(|sorted_set| sorted_set.score(member)) {
+                    Some(score) => ParserValue::BulkString(format_score(score).into()),
+                    None => ParserValue::NullBulkString,
+                },
+            )
+            .collect();
+        Ok(ParserValue::Array(scores))
+    }
+
+    /// ZINCRBY key increment member, which behaves exactly like ZADD key INCR.
+    pub(crate) fn zincrby(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        self.zadd(&[
+            "zadd".into(),
+            arguments[1].clone(),
+            "incr".into(),
+            arguments[2].clone(),
+            arguments[3].clone(),
+        ])
+    }
+
+    /// ZCARD key
+    pub(crate) fn zcard(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 2)?;
+        let cardinality = self
+            .get_sorted_set(&arguments[1])?
+            .map_or(0, |sorted_set| sorted_set.len());
+        Ok(ParserValue::Integer(cardinality as i64))
+    }
+
+    /// ZCOUNT key min max
+    pub(crate) fn zcount(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        let range = parse_score_range(&arguments[2], &arguments[3])?;
+        let count = self.get_sorted_set(&arguments[1])?.map_or(0, |sorted_set| {
+            sorted_set.range_by_score(&range, false).count()
+        });
+        Ok(ParserValue::Integer(count as i64))
+    }
+
+    /// ZLEXCOUNT key min max
+    pub(crate) fn zlexcount(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        let range = parse_lex_range(&arguments[2], &arguments[3])?;
+        let count = self.get_sorted_set(&arguments[1])?.map_or(0, |sorted_set| {
+            sorted_set.range_by_lex(&range, false).count()
+        });
+        Ok(ParserValue::Integer(count as i64))
+    }
+
+    /// Pops up to `count` members from the first non-empty sorted set in
+    /// `keys`, deleting the key once it is empty.
+    fn pop_first_non_empty(
+        self: &mut DataCore,
+        keys: &[ByteString],
+        count: usize,
+        max: bool,
+    ) -> Result<Option<(ByteString, ScoredMembers)>, CommandError> {
+        for key in keys {
+            if let Some(sorted_set) = self.get_sorted_set_mut(key)? {
+                let popped = sorted_set.pop(count, max);
+                if sorted_set.is_empty() {
+                    self.remove_value(key);
+                }
+                return Ok(Some((key.clone(), popped)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// ZPOPMIN key [count] and ZPOPMAX key [count]
+    pub(crate) fn zpop(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        max: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 3)?;
+        let count = match arguments.get(2) {
+            Some(count) => match parse_integer(count)? {
+                count if count >= 0 => count as usize,
+                _ => {
+                    return Err(CommandError::Other(
+                        "ERR value is out of range, must be positive".to_string(),
+                    ))
+                }
+            },
+            None => 1,
+        };
+        let popped = self
+            .pop_first_non_empty(&arguments[1..2], count, max)?
+            .map(|(_, popped)| popped)
+            .unwrap_or_default();
+        Ok(range_reply(popped, true))
+    }
+
+    /// ZMPOP numkeys key [key ...] MIN | MAX [COUNT count]
+    pub(crate) fn zmpop(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let (keys, max, count) = parse_mpop_arguments(&arguments[1..])?;
+        Ok(match self.pop_first_non_empty(keys, count, max)? {
+            Some((key, popped)) => mpop_reply(&key, popped),
+            None => ParserValue::NullArray,
+        })
+    }
+
+    /// BZPOPMIN key [key ...] timeout and BZPOPMAX key [key ...] timeout
+    pub(crate) fn bzpop(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        max: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let timeout = parse_timeout(arguments.last().unwrap())?;
+        let keys = &arguments[1..arguments.len() - 1];
+        match self.pop_first_non_empty(keys, 1, max)? {
+            Some((key, mut popped)) => {
+                let (member, score) = popped.remove(0);
+                Ok(ParserValue::Array(vec![
+                    ParserValue::BulkString(key),
+                    ParserValue::BulkString(member),
+                    ParserValue::BulkString(format_score(score).into()),
+                ]))
+            }
+            None => self.block(keys, timeout, ParserValue::NullArray),
+        }
+    }
+
+    /// BZMPOP timeout numkeys key [key ...] MIN | MAX [COUNT count]
+    pub(crate) fn bzmpop(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 5, usize::MAX)?;
+        let timeout = parse_timeout(&arguments[1])?;
+        let (keys, max, count) = parse_mpop_arguments(&arguments[2..])?;
+        match self.pop_first_non_empty(keys, count, max)? {
+            Some((key, popped)) => Ok(mpop_reply(&key, popped)),
+            None => self.block(keys, timeout, ParserValue::NullArray),
+        }
+    }
+
+    /// Deletes `key` once the sorted set it holds is empty.
+    fn remove_if_empty_sorted_set(self: &mut DataCore, key: &[u8]) {
+        if let Some(DataValue {
+            value: Value::SortedSet(sorted_set),
+            ..
+        }) = self.data_set.get(key)
+        {
+            if sorted_set.is_empty() {
+                self.remove_value(key);
+            }
+        }
+    }
+
+    /// ZREM key member [member ...]
+    pub(crate) fn zrem(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let removed = match self.get_sorted_set_mut(&arguments[1])? {
+            Some(sorted_set) => arguments[2..]
+                .iter()
+                .filter(|member| sorted_set.remove(member))
+                .count(),
+            None => 0,
+        };
+        self.remove_if_empty_sorted_set(&arguments[1]);
+        Ok(ParserValue::Integer(removed as i64))
+    }
+
+    /// ZREMRANGEBYRANK key start stop, ZREMRANGEBYSCORE key min max and
+    /// ZREMRANGEBYLEX key min max
+    pub(crate) fn zremrange(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        kind: RangeKind,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 4)?;
+        let key = &arguments[1];
+        let members = self.zrange_members(
+            key,
+            &arguments[2],
+            &arguments[3],
+            RangeSpec::new(kind, false),
+        )?;
+        if let Some(sorted_set) = self.get_sorted_set_mut(key)? {
+            for (member, _) in &members {
+                sorted_set.remove(member);
+            }
+        }
+        self.remove_if_empty_sorted_set(key);
+        Ok(ParserValue::Integer(members.len() as i64))
+    }
+
+    /// ZRANDMEMBER key [count [WITHSCORES]]
+    ///
+    /// A positive count returns distinct members, a negative count allows the
+    /// same member to be returned several times.
+    pub(crate) fn zrandmember(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 4)?;
+        let count = arguments.get(2).map(parse_integer).transpose()?;
+        let with_scores = match arguments.get(3) {
+            Some(option) if option.eq_ignore_ascii_case("withscores") => true,
+            Some(_) => return Err(CommandError::Syntax),
+            None => false,
+        };
+
+        // Like Redis, refuse counts whose reply length can't be represented.
+        let out_of_range = || CommandError::Other("ERR value is out of range".to_string());
+        let limit = if with_scores { i64::MAX / 2 } else { i64::MAX };
+        if count.is_some_and(|count| count < -limit || count > limit) {
+            return Err(out_of_range());
+        }
+
+        let Some(sorted_set) = self.get_sorted_set(&arguments[1])? else {
+            return Ok(match count {
+                Some(_) => ParserValue::Array(Vec::new()),
+                None => ParserValue::NullBulkString,
+            });
+        };
+        let len = sorted_set.len();
+        let at = |index: usize| {
+            let (member, score) = sorted_set.iter_range(index, index + 1).next().unwrap();
+            (ByteString::from(member), score)
+        };
+        let mut rng = thread_rng();
+
+        let count = match count {
+            Some(count) => count,
+            None => return Ok(ParserValue::BulkString(at(rng.gen_range(0..len)).0)),
+        };
+        let chosen = if count >= 0 {
+            let amount = (count as usize).min(len);
+            sample(&mut rng, len, amount).into_iter().map(at).collect()
+        } else {
+            let amount = count.unsigned_abs() as usize;
+            let mut chosen = Vec::new();
+            chosen
+                .try_reserve_exact(amount)
+                .map_err(|_| out_of_range())?;
+            chosen.extend((0..amount).map(|_| at(rng.gen_range(0..len))));
+            chosen
+        };
+        Ok(range_reply(chosen, with_scores))
+    }
+
+    /// The members of a sorted set, or of a plain set with every score at 1,
+    /// as ZUNION and friends accept both.
+    fn get_scored_members(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<HashMap<ByteString, f64>, CommandError> {
+        match self.lookup(key) {
+            Some(DataValue {
+                value: Value::SortedSet(sorted_set),
+                ..
+            }) => Ok(sorted_set
+                .iter()
+                .map(|(member, score)| (ByteString::from(member), score))
+                .collect()),
+            Some(DataValue {
+                value: Value::Set(set),
+                ..
+            }) => Ok(set.iter().map(|member| (member, 1.0)).collect()),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    /// Evaluates `numkeys key [key ...] [WEIGHTS weight ...] [AGGREGATE SUM | MIN | MAX]
+    /// [WITHSCORES]`, returning the resulting sorted set and whether
+    /// WITHSCORES was given.
+    fn zset_operation(
+        self: &mut DataCore,
+        operation: ZSetOperation,
+        command: &str,
+        arguments: &[ByteString],
+        allow_with_scores: bool,
+    ) -> Result<(SortedSet, bool), CommandError> {
+        let numkeys = parse_integer(&arguments[0])?;
+        if numkeys <= 0 {
+            return Err(CommandError::Other(format!(
+                "ERR at least 1 input key is needed for '{}' command",
+                command
+            )));
+        }
+        let numkeys = numkeys as usize;
+        if arguments.len() < numkeys + 1 {
+            return Err(CommandError::Syntax);
+        }
+        let keys = &arguments[1..=numkeys];
+
+        let mut weights = vec![1.0; numkeys];
+        let mut aggregate = Aggregate::Sum;
+        let mut with_scores = false;
+        let mut index = numkeys + 1;
+        while index < arguments.len() {
+            let remaining = arguments.len() - index - 1;
+            match arguments[index].to_lowercase().as_str() {
+                "weights" if operation != ZSetOperation::Difference && remaining >= numkeys => {
+                    for (weight, argument) in weights.iter_mut().zip(&arguments[index + 1..]) {
+                        *weight = parse_score(argument).map_err(|_| {
+                            CommandError::Other("ERR weight value is not a float".to_string())
+                        })?;
+                    }
+                    index += numkeys;
+                }
+                "aggregate" if operation != ZSetOperation::Difference && remaining >= 1 => {
+                    aggregate = match arguments[index + 1].to_lowercase().as_str() {
+                        "sum" => Aggregate::Sum,
+                        "min" => Aggregate::Min,
+                        "max" => Aggregate::Max,
+                        _ => return Err(CommandError::Syntax),
+                    };
+                    index += 1;
+                }
+                "withscores" if allow_with_scores => with_scores = true,
+                _ => return Err(CommandError::Syntax),
+            }
+            index += 1;
+        }
+
+        let mut sources = Vec::with_capacity(numkeys);
+        for key in keys {
+            sources.push(self.get_scored_members(key)?);
+        }
+        let weighted = |score: f64, weight: f64| match score * weight {
+            // 0 * inf is NaN, which Redis turns into zero.
+            product if product.is_nan() => 0.0,
+            product => product,
+        };
+
+        let mut result: HashMap<ByteString, f64> = HashMap::new();
+        match operation {
+            ZSetOperation::Union => {
+                for (source, weight) in sources.iter().zip(&weights) {
+                    for (member, score) in source {
+                        let score = weighted(*score, *weight);
+                        result
+                            .entry(member.clone())
+                            .and_modify(|current| *current = aggregate.apply(*current, score))
+                            .or_insert(score);
+                    }
+                }
+            }
+            ZSetOperation::Intersection => {
+                for (member, score) in &sources[0] {
+                    let mut total = weighted(*score, weights[0]);
+                    let mut in_all = true;
+                    for (source, weight) in sources.iter().zip(&weights).skip(1) {
+                        match source.get(member) {
+                            Some(score) => {
+                                total = aggregate.apply(total, weighted(*score, *weight))
+                            }
+                            None => {
+                                in_all = false;
+                                break;
+                            }
+                        }
+                    }
+                    if in_all {
+                        result.insert(member.clone(), total);
+                    }
+                }
+            }
+            ZSetOperation::Difference => {
+                for (member, score) in &sources[0] {
+                    if !sources[1..]
+                        .iter()
+                        .any(|source| source.contains_key(member))
+                    {
+                        result.insert(member.clone(), *score);
+                    }
+                }
+            }
+        }
+
+        let mut sorted_set = SortedSet::new();
+        for (member, score) in result {
+            sorted_set.insert(member, score, &self.sorted_set_limits);
+        }
+        Ok((sorted_set, with_scores))
+    }
+
+    /// Replaces `destination` with `sorted_set`, deleting it when the result is
+    /// empty, and returns the resulting cardinality.
+    fn store_sorted_set(
+        self: &mut DataCore,
+        destination: &[u8],
+        sorted_set: SortedSet,
+    ) -> ParserValue {
+        let cardinality = sorted_set.len();
+        if sorted_set.is_empty() {
+            self.remove_value(destination);
+        } else {
+            self.insert_value(destination, DataValue::new(Value::SortedSet(sorted_set)));
+        }
+        ParserValue::Integer(cardinality as i64)
+    }
+
+    /// ZUNION, ZINTER and ZDIFF: numkeys key [key ...] [...] [WITHSCORES]
+    pub(crate) fn zset_operation_command(
+        self: &mut DataCore,
+        operation: ZSetOperation,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let command = arguments[0].to_lowercase();
+        let (sorted_set, with_scores) =
+            self.zset_operation(operation, &command, &arguments[1..], true)?;
+        let members = sorted_set
+            .iter()
+            .map(|(member, score)| (ByteString::from(member), score))
+            .collect();
+        Ok(range_reply(members, with_scores))
+    }
+
+    /// ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE: destination numkeys key [key ...] [...]
+    pub(crate) fn zset_operation_store_command(
+        self: &mut DataCore,
+        operation: ZSetOperation,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let command = arguments[0].to_lowercase();
+        let (sorted_set, _) = self.zset_operation(operation, &command, &arguments[2..], false)?;
+        Ok(self.store_sorted_set(&arguments[1], sorted_set))
+    }
+
+    /// ZRANGESTORE dst src min max [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+    pub(crate) fn zrangestore(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 5, usize::MAX)?;
+        let spec = RangeSpec::new(RangeKind::Rank, false).parse_options(&arguments[5..], true)?;
+        if spec.with_scores {
+            return Err(CommandError::Syntax);
+        }
+        let members = self.zrange_members(&arguments[2], &arguments[3], &arguments[4], spec)?;
+        let mut sorted_set = SortedSet::new();
+        for (member, score) in members {
+            sorted_set.insert(member, score, &self.sorted_set_limits);
+        }
+        Ok(self.store_sorted_set(&arguments[1], sorted_set))
+    }
+
+    /// ZSCAN key cursor [MATCH pattern] [COUNT count]
+    ///
+    /// The cursor is the rank to resume from, so members present for the
+    /// whole iteration are returned unless members ranked before the cursor are
+    /// removed in between.
+    pub(crate) fn zscan(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let cursor = arguments[2]
+            .parse::<u64>()
+            .map_err(|_| CommandError::Other("ERR invalid cursor".to_string()))?;
+        let mut pattern = None;
+        let mut count = 10;
+        for option in arguments[3..].chunks(2) {
+            match (option[0].to_lowercase().as_str(), option.get(1)) {
+                ("match", Some(value)) => pattern = Some(value.clone()),
+                ("count", Some(value)) => match parse_integer(value)? {
+                    value if value >= 1 => count = value as usize,
+                    _ => return Err(CommandError::Syntax),
+                },
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let (next_cursor, members) = match self.get_sorted_set(&arguments[1])? {
+            Some(sorted_set) => {
+                let start = cursor.min(sorted_set.len() as u64) as usize;
+                let members = sorted_set
+                    .iter_range(start, start + count)
+                    .filter(|(member, _)| match &pattern {
+                        Some(pattern) => glob_match(pattern, member),
+                        None => true,
+                    })
+                    .map(|(member, score)| (ByteString::from(member), score))
+                    .collect::<Vec<_>>();
+                let end = start + count;
+                match end >= sorted_set.len() {
+                    true => (0, members),
+                    false => (end, members),
+                }
+            }
+            None => (0, Vec::new()),
+        };
+        Ok(ParserValue::Array(vec![
+            ParserValue::BulkString(next_cursor.to_string().into()),
+            range_reply(members, true),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::ByteString;
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_zadd_option_matrix() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZADD", "z", "1", "a", "2", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZADD", "z", "NX", "5", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(
+                &mut data_core,
+                &["ZADD", "z", "XX", "CH", "5", "a", "1", "c"]
+            )
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZADD", "z", "GT", "CH", "3", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZADD", "z", "LT", "CH", "3", "a"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("5.5".into()),
+            run(&mut data_core, &["ZADD", "z", "INCR", "2.5", "a"])
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["ZADD", "z", "NX", "INCR", "1", "a"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZADD", "missing", "XX", "1", "a"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["ZADD", "z", "NX", "XX", "1", "a"]),
+            ParserValue::Error(_)
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["ZADD", "z", "nan", "a"]),
+            ParserValue::Error(e) if e == "ERR value is not a valid float"
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["ZADD", "z", "1", "a", "2"]),
+            ParserValue::Error(e) if e == "ERR syntax error"
+        ));
+    }
+
+    fn members(value: ParserValue) -> Vec<String> {
+        value
+            .to_vec()
+            .unwrap()
+            .iter()
+            .map(|member| member.to_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_zrange_variants() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &["ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d"],
+        );
+
+        assert_eq!(
+            vec!["a", "1", "b", "2"],
+            members(run(
+                &mut data_core,
+                &["ZRANGE", "z", "0", "1", "WITHSCORES"]
+            ))
+        );
+        assert_eq!(
+            vec!["d", "c"],
+            members(run(&mut data_core, &["ZRANGE", "z", "0", "1", "REV"]))
+        );
+        assert_eq!(
+            vec!["c", "d"],
+            members(run(&mut data_core, &["ZRANGE", "z", "-2", "-1"]))
+        );
+        assert_eq!(
+            vec!["b", "c"],
+            members(run(
+                &mut data_core,
+                &["ZRANGE", "z", "(1", "+inf", "BYSCORE", "LIMIT", "0", "2"]
+            ))
+        );
+        assert_eq!(
+            vec!["c", "b"],
+            members(run(
+                &mut data_core,
+                &["ZRANGE", "z", "(4", "2", "BYSCORE", "REV"]
+            ))
+        );
+        assert_eq!(
+            vec!["d"],
+            members(run(&mut data_core, &["ZREVRANGE", "z", "0", "0"]))
+        );
+        assert_eq!(
+            vec!["a", "b"],
+            members(run(&mut data_core, &["ZRANGEBYSCORE", "z", "-inf", "2"]))
+        );
+        assert!(matches!(
+            run(
+                &mut data_core,
+                &["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"]
+            ),
+            ParserValue::Error(_)
+        ));
+
+        run(
+            &mut data_core,
+            &["ZADD", "lex", "0", "a", "0", "b", "0", "c"],
+        );
+        assert_eq!(
+            vec!["b", "c"],
+            members(run(&mut data_core, &["ZRANGEBYLEX", "lex", "(a", "+"]))
+        );
+        assert_eq!(
+            vec!["b", "a"],
+            members(run(
+                &mut data_core,
+                &["ZRANGE", "lex", "[b", "-", "BYLEX", "REV"]
+            ))
+        );
+        assert!(matches!(
+            run(&mut data_core, &["ZRANGEBYLEX", "lex", "a", "+"]),
+            ParserValue::Error(e) if e == "ERR min or max not valid string range item"
+        ));
+    }
+
+    #[test]
+    fn test_rank_and_score_lookups() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &["ZADD", "z", "1", "a", "2.5", "b", "3", "c"],
+        );
+
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZRANK", "z", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZREVRANK", "z", "c"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::Integer(1),
+                ParserValue::BulkString("2.5".into())
+            ]),
+            run(&mut data_core, &["ZREVRANK", "z", "b", "WITHSCORE"])
+        );
+        assert_eq!(
+            ParserValue::NullArray,
+            run(&mut data_core, &["ZRANK", "z", "x", "WITHSCORE"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("3".into()),
+            run(&mut data_core, &["ZSCORE", "z", "c"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("1".into()),
+                ParserValue::NullBulkString
+            ]),
+            run(&mut data_core, &["ZMSCORE", "z", "a", "x"])
+        );
+    }
+
+    #[test]
+    fn test_zincrby_and_counting() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::BulkString("2".into()),
+            run(&mut data_core, &["ZINCRBY", "z", "2", "a"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("1.5".into()),
+            run(&mut data_core, &["ZINCRBY", "z", "-0.5", "a"])
+        );
+        run(&mut data_core, &["ZADD", "z", "0", "b", "3", "c"]);
+
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(&mut data_core, &["ZCARD", "z"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZCARD", "missing"])
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZCOUNT", "z", "(0", "+inf"])
+        );
+        run(
+            &mut data_core,
+            &["ZADD", "lex", "0", "a", "0", "b", "0", "c"],
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZLEXCOUNT", "lex", "[b", "+"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["ZCOUNT", "z", "x", "1"]),
+            ParserValue::Error(e) if e == "ERR min or max is not a float"
+        ));
+    }
+
+    #[test]
+    fn test_pop_commands() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["ZADD", "z", "1", "a", "2", "b", "3", "c"]);
+
+        assert_eq!(
+            vec!["a", "1"],
+            members(run(&mut data_core, &["ZPOPMIN", "z"]))
+        );
+        assert_eq!(
+            vec!["c", "3", "b", "2"],
+            members(run(&mut data_core, &["ZPOPMAX", "z", "5"]))
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZCARD", "z"])
+        );
+        assert_eq!(
+            ParserValue::NullArray,
+            run(&mut data_core, &["ZMPOP", "1", "z", "MIN"])
+        );
+
+        run(&mut data_core, &["ZADD", "y", "1", "a", "2", "b"]);
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("y".into()),
+                ParserValue::Array(vec![ParserValue::Array(vec![
+                    ParserValue::BulkString("b".into()),
+                    ParserValue::BulkString("2".into()),
+                ])]),
+            ]),
+            run(
+                &mut data_core,
+                &["ZMPOP", "2", "z", "y", "MAX", "COUNT", "1"]
+            )
+        );
+        assert_eq!(
+            vec!["y", "a", "1"],
+            members(run(&mut data_core, &["BZPOPMIN", "z", "y", "0"]))
+        );
+        assert_eq!(
+            ParserValue::NullArray,
+            run(&mut data_core, &["BZPOPMAX", "z", "y", "0.1"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bzpopmin_blocks_until_zadd() {
+        use tokio::sync::{mpsc, oneshot};
+
+        use crate::data_core::{Command, DataCore, ReplicationRole};
+        use crate::tokenizer::{serialize_tokens, Token};
+
+        let (command_tx, command_rx) = mpsc::channel::<Command>(32);
+        let mut data_core = DataCore::new(command_rx, ReplicationRole::Master, None, None);
+        tokio::spawn(async move {
+            data_core.process_command().await;
+        });
+        let send = |arguments: &[&str]| {
+            let (tx, rx) = oneshot::channel();
+            let arguments = arguments
+                .iter()
+                .map(|argument| ByteString::from(*argument))
+                .collect();
+            (Command::new(arguments, tx), rx)
+        };
+
+        let (blocked, blocked_rx) = send(&["BZPOPMIN", "z", "0"]);
+        command_tx.send(blocked).await.unwrap();
+        let (timed_out, timed_out_rx) = send(&["BZPOPMAX", "other", "0.05"]);
+        command_tx.send(timed_out).await.unwrap();
+        let (zadd, zadd_rx) = send(&["ZADD", "z", "1", "a"]);
+        command_tx.send(zadd).await.unwrap();
+
+        let serialize = |tokens: Vec<Token>| serialize_tokens(&tokens).unwrap();
+        assert_eq!(":1\r\n", serialize(zadd_rx.await.unwrap()));
+        assert_eq!(
+            "*3\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\n1\r\n",
+            serialize(blocked_rx.await.unwrap())
+        );
+        assert_eq!("*-1\r\n", serialize(timed_out_rx.await.unwrap()));
+    }
+
+    #[test]
+    fn test_removal_commands() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &[
+                "ZADD", "z", "1", "a", "2", "b", "3", "c", "4", "d", "5", "e",
+            ],
+        );
+
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZREM", "z", "a", "missing"])
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZREMRANGEBYRANK", "z", "-2", "-1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZREMRANGEBYSCORE", "z", "(2", "3"])
+        );
+        assert_eq!(
+            vec!["b"],
+            members(run(&mut data_core, &["ZRANGE", "z", "0", "-1"]))
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZREMRANGEBYLEX", "z", "-", "+"])
+        );
+        assert!(!data_core.data_set.contains_key("z".as_bytes()));
+    }
+
+    #[test]
+    fn test_zrandmember_counts() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["ZRANDMEMBER", "z"])
+        );
+        run(&mut data_core, &["ZADD", "z", "1", "a", "2", "b", "3", "c"]);
+
+        let mut distinct = members(run(&mut data_core, &["ZRANDMEMBER", "z", "10"]));
+        distinct.sort();
+        assert_eq!(vec!["a", "b", "c"], distinct);
+        assert_eq!(
+            10,
+            members(run(&mut data_core, &["ZRANDMEMBER", "z", "-10"])).len()
+        );
+        let with_scores = members(run(
+            &mut data_core,
+            &["ZRANDMEMBER", "z", "1", "WITHSCORES"],
+        ));
+        assert_eq!(2, with_scores.len());
+        assert_eq!(
+            ParserValue::BulkString(with_scores[1].as_str().into()),
+            run(&mut data_core, &["ZSCORE", "z", &with_scores[0]])
+        );
+        assert_eq!(
+            ParserValue::Array(Vec::new()),
+            run(&mut data_core, &["ZRANDMEMBER", "z", "0"])
+        );
+
+        let out_of_range = ParserValue::Error("ERR value is out of range".to_string());
+        for count in [i64::MIN.to_string(), (i64::MIN + 1).to_string()] {
+            assert_eq!(
+                out_of_range,
+                run(&mut data_core, &["ZRANDMEMBER", "z", &count])
+            );
+        }
+        assert_eq!(
+            out_of_range,
+            run(
+                &mut data_core,
+                &[
+                    "ZRANDMEMBER",
+                    "z",
+                    &(i64::MAX / 2 + 1).to_string(),
+                    "WITHSCORES"
+                ]
+            )
+        );
+        assert_eq!(
+            3,
+            members(run(
+                &mut data_core,
+                &["ZRANDMEMBER", "z", &i64::MAX.to_string()]
+            ))
+            .len()
+        );
+    }
+
+    #[test]
+    fn test_zset_operations() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["ZADD", "a", "1", "x", "2", "y"]);
+        run(&mut data_core, &["ZADD", "b", "10", "y", "20", "z"]);
+        run(&mut data_core, &["SADD", "s", "x"]);
+
+        assert_eq!(
+            vec!["x", "1", "y", "12", "z", "20"],
+            members(run(
+                &mut data_core,
+                &["ZUNION", "2", "a", "b", "WITHSCORES"]
+            ))
+        );
+        assert_eq!(
+            vec!["y", "20"],
+            members(run(
+                &mut data_core,
+                &[
+                    "ZINTER",
+                    "2",
+                    "a",
+                    "b",
+                    "WEIGHTS",
+                    "10",
+                    "1",
+                    "AGGREGATE",
+                    "MAX",
+                    "WITHSCORES"
+                ]
+            ))
+        );
+        assert_eq!(
+            vec!["x", "2"],
+            members(run(
+                &mut data_core,
+                &["ZINTER", "2", "a", "s", "WITHSCORES"]
+            ))
+        );
+        assert_eq!(
+            vec!["x"],
+            members(run(&mut data_core, &["ZDIFF", "2", "a", "b"]))
+        );
+
+        run(&mut data_core, &["SET", "dst", "string"]);
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(
+                &mut data_core,
+                &["ZUNIONSTORE", "dst", "2", "a", "b", "AGGREGATE", "MIN"]
+            )
+        );
+        assert_eq!(
+            ParserValue::BulkString("1".into()),
+            run(&mut data_core, &["ZSCORE", "dst", "x"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["ZINTERSTORE", "dst", "2", "a", "b"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["ZDIFFSTORE", "dst", "2", "a", "a"])
+        );
+        assert!(!data_core.data_set.contains_key("dst".as_bytes()));
+
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZRANGESTORE", "dst", "b", "0", "-1"])
+        );
+        assert_eq!(
+            vec!["y", "10", "z", "20"],
+            members(run(
+                &mut data_core,
+                &["ZRANGE", "dst", "0", "-1", "WITHSCORES"]
+            ))
+        );
+        assert!(matches!(
+            run(&mut data_core, &["ZUNIONSTORE", "dst", "0", "a"]),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_zscan_iterates_all_members() {
+        let mut data_core = new_data_core();
+        for i in 0..25 {
+            run(
+                &mut data_core,
+                &["ZADD", "z", &i.to_string(), &format!("m{}", i)],
+            );
+        }
+
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        loop {
+            let reply = run(&mut data_core, &["ZSCAN", "z", &cursor, "COUNT", "7"]);
+            let reply = reply.to_vec().unwrap();
+            cursor = reply[0].to_string().unwrap();
+            seen.extend(members(reply[1].clone()).into_iter().step_by(2));
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(25, seen.len());
+
+        let reply = run(
+            &mut data_core,
+            &["ZSCAN", "z", "0", "MATCH", "m1*", "COUNT", "100"],
+        );
+        let matched = members(reply.to_vec().unwrap()[1].clone());
+        assert_eq!(22, matched.len());
+        assert_eq!(vec!["m1", "1"], matched[..2].to_vec());
+    }
+
+    #[test]
+    fn test_small_sorted_sets_use_listpack_encoding() {
+        let mut data_core = new_data_core();
+        run(
+            &mut data_core,
+            &["CONFIG", "SET", "zset-max-listpack-entries", "2"],
+        );
+        run(&mut data_core, &["ZADD", "z", "1", "a", "2", "b"]);
+        assert_eq!(
+            ParserValue::BulkString("listpack".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "z"])
+        );
+        run(&mut data_core, &["ZADD", "z", "3", "c"]);
+        assert_eq!(
+            ParserValue::BulkString("skiplist".into()),
+            run(&mut data_core, &["OBJECT", "ENCODING", "z"])
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["ZRANK", "z", "c"])
+        );
+        assert_eq!(
+            vec!["c", "b"],
+            members(run(&mut data_core, &["ZRANGE", "z", "0", "1", "REV"]))
+        );
+    }
+}
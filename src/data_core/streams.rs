@@ -0,0 +1,1214 @@
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::binary::ByteString;
+use crate::data_core::{check_arity, CommandError, DataCore, DataValue, Value};
+use crate::parser::ParserValue;
+use crate::stream::{Fields, IdSpec, Stream, StreamId, TrimStrategy, NODE_MAX_ENTRIES};
+
+fn invalid_stream_id() -> CommandError {
+    CommandError::Other("ERR Invalid stream ID specified as stream command argument".to_string())
+}
+
+/// Parses an ID argument, `ms-seq` or a bare `ms` for `ms-<default_seq>`.
+fn parse_id(id: &[u8], default_seq: u64) -> Result<StreamId, CommandError> {
+    std::str::from_utf8(id)
+        .ok()
+        .and_then(|id| StreamId::parse(id, default_seq))
+        .ok_or_else(invalid_stream_id)
+}
+
+/// Parses an XRANGE bound: `-`, `+`, an ID, or an ID prefixed with `(` to make
+/// it exclusive. A bare `ms` covers every sequence number in that millisecond.
+fn parse_range_bound(bound: &[u8], is_start: bool) -> Result<StreamId, CommandError> {
+    let invalid_interval = || {
+        CommandError::Other(format!(
+            "ERR invalid {} ID for the interval",
+            if is_start { "start" } else { "end" }
+        ))
+    };
+    match bound {
+        b"-" => Ok(StreamId::MIN),
+        b"+" => Ok(StreamId::MAX),
+        _ => {
+            let default_seq = if is_start { 0 } else { u64::MAX };
+            match bound.strip_prefix(b"(") {
+                Some(id) => {
+                    let id = parse_id(id, default_seq)?;
+                    match is_start {
+                        true => id.next(),
+                        false => id.previous(),
+                    }
+                    .ok_or_else(invalid_interval)
+                }
+                None => parse_id(bound, default_seq),
+            }
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    Utc::now().timestamp_millis().max(0) as u64
+}
+
+fn no_group(key: &ByteString, group: &ByteString) -> CommandError {
+    CommandError::Other(format!(
+        "NOGROUP No such consumer group '{}' for key name '{}'",
+        group, key
+    ))
+}
+
+fn no_such_key() -> CommandError {
+    CommandError::Other("ERR no such key".to_string())
+}
+
+/// The `MAXLEN | MINID [= | ~] threshold [LIMIT count]` clause of XADD and XTRIM.
+struct TrimOptions {
+    strategy: TrimStrategy,
+    approximate: bool,
+    limit: Option<usize>,
+}
+
+/// Parses a trim clause starting at `arguments[index]`, returning it with the
+/// index of the first argument after it.
+fn parse_trim_options(
+    arguments: &[ByteString],
+    mut index: usize,
+) -> Result<(TrimOptions, usize), CommandError> {
+    let strategy = arguments[index].to_lowercase();
+    index += 1;
+    let approximate = match arguments.get(index).map(ByteString::as_bytes) {
+        Some(b"~") => true,
+        Some(b"=") => false,
+        _ => {
+            index -= 1;
+            false
+        }
+    };
+    index += 1;
+    let threshold = arguments.get(index).ok_or(CommandError::Syntax)?;
+    let strategy = match strategy.as_str() {
+        "maxlen" => match threshold.parse::<i64>() {
+            Ok(max_len) if max_len >= 0 => TrimStrategy::MaxLen(max_len as usize),
+            Ok(_) => {
+                return Err(CommandError::Other(
+                    "ERR The MAXLEN argument must be >= 0.".to_string(),
+                ))
+            }
+            Err(_) => return Err(CommandError::NotInteger),
+        },
+        _ => TrimStrategy::MinId(parse_id(threshold, 0)?),
+    };
+    index += 1;
+
+    // Approximate trimming is capped by default so a single call stays cheap;
+    // LIMIT 0 removes the cap.
+    let mut limit = approximate.then_some(100 * NODE_MAX_ENTRIES);
+    if arguments
+        .get(index)
+        .is_some_and(|option| option.eq_ignore_ascii_case("limit"))
+    {
+        let count = arguments
+            .get(index + 1)
+            .ok_or(CommandError::Syntax)?
+            .parse::<i64>()
+            .map_err(|_| CommandError::NotInteger)?;
+        if count < 0 {
+            return Err(CommandError::Other(
+                "ERR The LIMIT argument must be >= 0.".to_string(),
+            ));
+        }
+        if !approximate {
+            return Err(CommandError::Other(
+                "ERR syntax error, LIMIT cannot be used without the special ~ option".to_string(),
+            ));
+        }
+        limit = match count {
+            0 => None,
+            count => Some(count as usize),
+        };
+        index += 2;
+    }
+    Ok((
+        TrimOptions {
+            strategy,
+            approximate,
+            limit,
+        },
+        index,
+    ))
+}
+
+/// Options shared by XREAD and XREADGROUP.
+struct ReadOptions<'a> {
+    count: usize,
+    /// Set when BLOCK was given, holding None to block forever.
+    block: Option<Option<Duration>>,
+    no_ack: bool,
+    /// Position of the STREAMS keyword in the arguments.
+    streams_index: usize,
+    keys: &'a [ByteString],
+    ids: &'a [ByteString],
+}
+
+/// Parses `[COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key ... id ...`
+/// starting at `arguments[index]`.
+fn parse_read_options(
+    arguments: &[ByteString],
+    mut index: usize,
+    allow_no_ack: bool,
+) -> Result<ReadOptions<'_>, CommandError> {
+    let mut count = usize::MAX;
+    let mut block = None;
+    let mut no_ack = false;
+    loop {
+        let has_value = index + 1 < arguments.len();
+        match arguments
+            .get(index)
+            .map(|option| option.to_lowercase())
+            .as_deref()
+        {
+            Some("count") if has_value => {
+                count = match arguments[index + 1]
+                    .parse::<i64>()
+                    .map_err(|_| CommandError::NotInteger)?
+                {
+                    count if count < 0 => {
+                        return Err(CommandError::Other(
+                            "ERR value is out of range, must be positive".to_string(),
+                        ))
+                    }
+                    // As in Redis, COUNT 0 means no limit.
+                    0 => usize::MAX,
+                    count => count as usize,
+                };
+                index += 2;
+            }
+            Some("block") if has_value => {
+                let milliseconds = arguments[index + 1]
+                    .parse::<i64>()
+                    .map_err(|_| CommandError::NotInteger)?;
+                if milliseconds < 0 {
+                    return Err(CommandError::Other("ERR timeout is negative".to_string()));
+                }
+                block = Some(match milliseconds {
+                    0 => None,
+                    milliseconds => Some(Duration::from_millis(milliseconds as u64)),
+                });
+                index += 2;
+            }
+            Some("noack") if allow_no_ack => {
+                no_ack = true;
+                index += 1;
+            }
+            Some("streams") => break,
+            _ => return Err(CommandError::Syntax),
+        }
+    }
+
+    let streams = &arguments[index + 1..];
+    if streams.is_empty() || streams.len() % 2 == 1 {
+        return Err(CommandError::Other(format!(
+            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+            arguments[0].to_lowercase(),
+            if allow_no_ack { ">" } else { "$" }
+        )));
+    }
+    let (keys, ids) = streams.split_at(streams.len() / 2);
+    Ok(ReadOptions {
+        count,
+        block,
+        no_ack,
+        streams_index: index,
+        keys,
+        ids,
+    })
+}
+
+/// Formats one stream's entries in an XREAD style reply.
+fn stream_reply(key: &[u8], entries: Vec<ParserValue>) -> ParserValue {
+    ParserValue::Array(vec![
+        ParserValue::BulkString(key.into()),
+        ParserValue::Array(entries),
+    ])
+}
+
+/// Formats an entry as the `[id, [field, value, ...]]` pair used by every
+/// stream reply.
+pub(crate) fn entry_reply(id: &StreamId, fields: &Fields) -> ParserValue {
+    ParserValue::Array(vec![
+        ParserValue::BulkString(id.to_string().into()),
+        ParserValue::Array(
+            fields
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        ParserValue::BulkString(field.clone()),
+                        ParserValue::BulkString(value.clone()),
+                    ]
+                })
+                .collect(),
+        ),
+    ])
+}
+
+impl DataCore {
+    fn get_stream(self: &mut DataCore, key: &[u8]) -> Result<Option<&Stream>, CommandError> {
+        match self.lookup(key) {
+            Some(DataValue {
+                value: Value::Stream(stream),
+                ..
+            }) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn get_stream_mut(
+        self: &mut DataCore,
+        key: &[u8],
+    ) -> Result<Option<&mut Stream>, CommandError> {
+        match self.lookup_mut(key) {
+            Some(DataValue {
+                value: Value::Stream(stream),
+                ..
+            }) => Ok(Some(stream)),
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// XADD key [NOMKSTREAM] [MAXLEN | MINID [= | ~] threshold [LIMIT count]]
+    /// <* | ms-* | ms-seq> field value [field value ...]
+    pub(crate) fn xadd(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 5, usize::MAX)?;
+        let key = &arguments[1];
+
+        let mut no_mkstream = false;
+        let mut trim = None;
+        let mut index = 2;
+        while index < arguments.len() {
+            match arguments[index].to_lowercase().as_str() {
+                "nomkstream" => {
+                    no_mkstream = true;
+                    index += 1;
+                }
+                "maxlen" | "minid" => {
+                    let (options, next) = parse_trim_options(arguments, index)?;
+                    trim = Some(options);
+                    index = next;
+                }
+                _ => break,
+            }
+        }
+        let fields = arguments.get(index + 1..).unwrap_or_default();
+        if fields.is_empty() || fields.len() % 2 == 1 {
+            return Err(CommandError::WrongArity(arguments[0].to_lowercase()));
+        }
+        let spec = arguments[index]
+            .to_str()
+            .and_then(IdSpec::parse)
+            .ok_or_else(invalid_stream_id)?;
+
+        let created = self.get_stream(key)?.is_none();
+        if created {
+            if no_mkstream {
+                return Ok(ParserValue::NullBulkString);
+            }
+            self.insert_value(key, DataValue::new(Value::Stream(Stream::new())));
+        }
+        let stream = self.get_stream_mut(key)?.unwrap();
+        let id = match stream.next_id(spec, now_ms()) {
+            Ok(id) => id,
+            Err(err) => {
+                // Don't leave behind the empty stream created above.
+                if created {
+                    self.remove_value(key);
+                }
+                return Err(CommandError::Other(err.to_string()));
+            }
+        };
+        let fields = fields
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+        stream.add(id, fields);
+        if let Some(trim) = trim {
+            stream.trim(trim.strategy, trim.approximate, trim.limit);
+        }
+        Ok(ParserValue::BulkString(id.to_string().into()))
+    }
+
+    /// XRANGE key start end [COUNT count] and XREVRANGE key end start [COUNT count]
+    pub(crate) fn xrange(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+        rev: bool,
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, 6)?;
+        let (start, end) = match rev {
+            false => (&arguments[2], &arguments[3]),
+            true => (&arguments[3], &arguments[2]),
+        };
+        let start = parse_range_bound(start, true)?;
+        let end = parse_range_bound(end, false)?;
+        let count = match &arguments[4..] {
+            [] => usize::MAX,
+            [option, count] if option.eq_ignore_ascii_case("count") => count
+                .parse::<i64>()
+                .map_err(|_| CommandError::NotInteger)?
+                .max(0)
+                as usize,
+            _ => return Err(CommandError::Syntax),
+        };
+
+        let entries = match self.get_stream(&arguments[1])? {
+            Some(stream) => {
+                let range = stream.range(start, end);
+                match rev {
+                    false => range
+                        .take(count)
+                        .map(|(id, fields)| entry_reply(id, fields))
+                        .collect(),
+                    true => range
+                        .rev()
+                        .take(count)
+                        .map(|(id, fields)| entry_reply(id, fields))
+                        .collect(),
+                }
+            }
+            None => Vec::new(),
+        };
+        Ok(ParserValue::Array(entries))
+    }
+
+    /// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+    pub(crate) fn xread(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let options = parse_read_options(arguments, 1, false)?;
+        let mut last_ids = Vec::with_capacity(options.keys.len());
+        for (key, id) in options.keys.iter().zip(options.ids) {
+            let last_id = match id.as_bytes() {
+                b"$" => self.get_stream(key)?.map_or(StreamId::MIN, Stream::last_id),
+                _ => parse_id(id, 0)?,
+            };
+            last_ids.push(last_id);
+        }
+
+        let mut replies = Vec::new();
+        for (key, last_id) in options.keys.iter().zip(&last_ids) {
+            let Some(stream) = self.get_stream(key)? else {
+                continue;
+            };
+            let Some(start) = last_id.next() else {
+                continue;
+            };
+            let entries = stream
+                .range(start, StreamId::MAX)
+                .take(options.count)
+                .map(|(id, fields)| entry_reply(id, fields))
+                .collect::<Vec<_>>();
+            if !entries.is_empty() {
+                replies.push(stream_reply(key, entries));
+            }
+        }
+        if !replies.is_empty() {
+            return Ok(ParserValue::Array(replies));
+        }
+
+        match options.block {
+            None => Ok(ParserValue::NullArray),
+            Some(timeout) => {
+                // Block with the IDs resolved now, so `$` means "entries added
+                // after this call" rather than being re-evaluated on retry.
+                let mut retry = arguments[..options.streams_index + 1].to_vec();
+                retry.extend(options.keys.iter().cloned());
+                retry.extend(last_ids.iter().map(|id| id.to_string().into()));
+                self.block_with_arguments(options.keys, timeout, ParserValue::NullArray, retry)
+            }
+        }
+    }
+
+    /// XREADGROUP GROUP group consumer [COUNT count] [BLOCK milliseconds] [NOACK]
+    /// STREAMS key [key ...] id [id ...]
+    ///
+    /// The `>` ID delivers entries never delivered to the group; any other ID
+    /// re-delivers the consumer's own pending entries after it.
+    pub(crate) fn xreadgroup(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 7, usize::MAX)?;
+        if !arguments[1].eq_ignore_ascii_case("group") {
+            return Err(CommandError::Syntax);
+        }
+        let (group, consumer) = (&arguments[2], &arguments[3]);
+        let options = parse_read_options(arguments, 4, true)?;
+        let mut after_ids = Vec::with_capacity(options.ids.len());
+        for id in options.ids {
+            after_ids.push(match id.as_bytes() {
+                b">" => None,
+                _ => Some(parse_id(id, 0)?),
+            });
+        }
+        for key in options.keys {
+            if !self
+                .get_stream(key)?
+                .is_some_and(|stream| stream.groups().contains_key(group))
+            {
+                return Err(CommandError::Other(format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group
+                )));
+            }
+        }
+
+        let now_ms = now_ms();
+        let mut replies = Vec::new();
+        for (key, after) in options.keys.iter().zip(&after_ids) {
+            let stream = self.get_stream_mut(key)?.unwrap();
+            match after {
+                None => {
+                    let entries = stream
+                        .read_group_new(group, consumer, options.count, options.no_ack, now_ms)
+                        .unwrap_or_default();
+                    if !entries.is_empty() {
+                        let entries = entries
+                            .iter()
+                            .map(|(id, fields)| entry_reply(id, fields))
+                            .collect();
+                        replies.push(stream_reply(key, entries));
+                    }
+                }
+                // History is always reported, even when empty.
+                Some(after) => {
+                    let entries = stream
+                        .read_group_pending(group, consumer, *after, options.count, now_ms)
+                        .unwrap_or_default();
+                    let entries = entries
+                        .iter()
+                        .map(|(id, fields)| match fields {
+                            Some(fields) => entry_reply(id, fields),
+                            None => ParserValue::Array(vec![
+                                ParserValue::BulkString(id.to_string().into()),
+                                ParserValue::NullArray,
+                            ]),
+                        })
+                        .collect();
+                    replies.push(stream_reply(key, entries));
+                }
+            }
+        }
+        if !replies.is_empty() {
+            return Ok(ParserValue::Array(replies));
+        }
+
+        match options.block {
+            None => Ok(ParserValue::NullArray),
+            Some(timeout) => self.block(options.keys, timeout, ParserValue::NullArray),
+        }
+    }
+
+    /// XGROUP CREATE | DESTROY | CREATECONSUMER | DELCONSUMER
+    pub(crate) fn xgroup(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        let arity = match subcommand.as_str() {
+            "create" => (5, 6),
+            "destroy" => (4, 4),
+            "createconsumer" | "delconsumer" => (5, 5),
+            _ => {
+                return Err(CommandError::UnknownSubcommand(
+                    arguments[1].clone(),
+                    "XGROUP".to_string(),
+                ))
+            }
+        };
+        if arguments.len() < arity.0 || arguments.len() > arity.1 {
+            return Err(CommandError::WrongArity(format!("xgroup|{}", subcommand)));
+        }
+        let (key, group) = (&arguments[2], &arguments[3]);
+
+        if subcommand == "create" {
+            let mkstream = match arguments.get(5) {
+                Some(option) if option.eq_ignore_ascii_case("mkstream") => true,
+                Some(_) => return Err(CommandError::Syntax),
+                None => false,
+            };
+            if mkstream && self.get_stream(key)?.is_none() {
+                self.insert_value(key, DataValue::new(Value::Stream(Stream::new())));
+            }
+        }
+        let Some(stream) = self.get_stream_mut(key)? else {
+            return Err(CommandError::Other(
+                "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string(),
+            ));
+        };
+        if subcommand == "create" {
+            let last_delivered_id = match arguments[4].as_bytes() {
+                b"$" => stream.last_id(),
+                id => parse_id(id, 0)?,
+            };
+            if !stream.create_group(group, last_delivered_id) {
+                return Err(CommandError::Other(
+                    "BUSYGROUP Consumer Group name already exists".to_string(),
+                ));
+            }
+            return Ok(ParserValue::SimpleString("OK".to_string()));
+        }
+        if subcommand == "destroy" {
+            return Ok(ParserValue::Integer(stream.destroy_group(group) as i64));
+        }
+
+        let Some(group_state) = stream.group_mut(group) else {
+            return Err(no_group(key, group));
+        };
+        let consumer = &arguments[4];
+        Ok(match subcommand.as_str() {
+            "createconsumer" => {
+                ParserValue::Integer(group_state.touch_consumer(consumer, now_ms()) as i64)
+            }
+            _ => ParserValue::Integer(group_state.remove_consumer(consumer).unwrap_or(0) as i64),
+        })
+    }
+
+    /// XACK key group id [id ...]
+    pub(crate) fn xack(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        let ids = arguments[3..]
+            .iter()
+            .map(|id| parse_id(id, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let acknowledged = match self
+            .get_stream_mut(&arguments[1])?
+            .and_then(|stream| stream.group_mut(&arguments[2]))
+        {
+            Some(group) => ids.into_iter().filter(|id| group.ack(*id)).count(),
+            None => 0,
+        };
+        Ok(ParserValue::Integer(acknowledged as i64))
+    }
+
+    /// XDEL key id [id ...]
+    pub(crate) fn xdel(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, usize::MAX)?;
+        let ids = arguments[2..]
+            .iter()
+            .map(|id| parse_id(id, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let deleted = match self.get_stream_mut(&arguments[1])? {
+            Some(stream) => ids.into_iter().filter(|id| stream.delete(*id)).count(),
+            None => 0,
+        };
+        Ok(ParserValue::Integer(deleted as i64))
+    }
+
+    /// XTRIM key MAXLEN | MINID [= | ~] threshold [LIMIT count]
+    pub(crate) fn xtrim(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 4, usize::MAX)?;
+        if !matches!(arguments[2].to_lowercase().as_str(), "maxlen" | "minid") {
+            return Err(CommandError::Syntax);
+        }
+        let (trim, next) = parse_trim_options(arguments, 2)?;
+        if next != arguments.len() {
+            return Err(CommandError::Syntax);
+        }
+        let trimmed = match self.get_stream_mut(&arguments[1])? {
+            Some(stream) => stream.trim(trim.strategy, trim.approximate, trim.limit),
+            None => 0,
+        };
+        Ok(ParserValue::Integer(trimmed as i64))
+    }
+
+    /// XSETID key last-id [ENTRIESADDED entries-added] [MAXDELETEDID max-deleted-id]
+    pub(crate) fn xsetid(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 3, 7)?;
+        let id = parse_id(&arguments[2], 0)?;
+        let mut entries_added = None;
+        let mut max_deleted_id = None;
+        for option in arguments[3..].chunks(2) {
+            match (option[0].to_lowercase().as_str(), option.get(1)) {
+                ("entriesadded", Some(value)) => {
+                    let value = value.parse::<i64>().map_err(|_| CommandError::NotInteger)?;
+                    if value < 0 {
+                        return Err(CommandError::Other(
+                            "ERR entries_added must be positive".to_string(),
+                        ));
+                    }
+                    entries_added = Some(value as u64);
+                }
+                ("maxdeletedid", Some(value)) => {
+                    max_deleted_id = Some(parse_id(value, 0)?);
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+        let stream = self
+            .get_stream_mut(&arguments[1])?
+            .ok_or_else(no_such_key)?;
+        stream
+            .set_last_id(id, entries_added, max_deleted_id)
+            .map_err(|err| CommandError::Other(err.to_string()))?;
+        Ok(ParserValue::SimpleString("OK".to_string()))
+    }
+
+    /// XINFO STREAM key | GROUPS key | CONSUMERS key group
+    pub(crate) fn xinfo(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let subcommand = arguments[1].to_lowercase();
+        let arity = match subcommand.as_str() {
+            "stream" | "groups" => 3,
+            "consumers" => 4,
+            _ => {
+                return Err(CommandError::UnknownSubcommand(
+                    arguments[1].clone(),
+                    "XINFO".to_string(),
+                ))
+            }
+        };
+        if arguments.len() != arity {
+            return Err(CommandError::WrongArity(format!("xinfo|{}", subcommand)));
+        }
+        let key = &arguments[2];
+        let stream = self.get_stream(key)?.ok_or_else(no_such_key)?;
+        let bulk = |value: &str| ParserValue::BulkString(value.into());
+        let integer = |value: usize| ParserValue::Integer(value as i64);
+
+        match subcommand.as_str() {
+            "stream" => {
+                let entry = |entry: Option<(&StreamId, &Fields)>| {
+                    entry.map_or(ParserValue::NullBulkString, |(id, fields)| {
+                        entry_reply(id, fields)
+                    })
+                };
+                let first_id = stream.first_entry().map_or(StreamId::MIN, |(id, _)| *id);
+                let nodes = stream.len().div_ceil(NODE_MAX_ENTRIES);
+                Ok(ParserValue::Array(vec![
+                    bulk("length"),
+                    integer(stream.len()),
+                    bulk("radix-tree-keys"),
+                    integer(nodes),
+                    bulk("radix-tree-nodes"),
+                    integer(nodes + 1),
+                    bulk("last-generated-id"),
+                    bulk(&stream.last_id().to_string()),
+                    bulk("max-deleted-entry-id"),
+                    bulk(&stream.max_deleted_id().to_string()),
+                    bulk("entries-added"),
+                    ParserValue::Integer(stream.entries_added() as i64),
+                    bulk("recorded-first-entry-id"),
+                    bulk(&first_id.to_string()),
+                    bulk("groups"),
+                    integer(stream.groups().len()),
+                    bulk("first-entry"),
+                    entry(stream.first_entry()),
+                    bulk("last-entry"),
+                    entry(stream.last_entry()),
+                ]))
+            }
+            "groups" => Ok(ParserValue::Array(
+                stream
+                    .groups()
+                    .iter()
+                    .map(|(name, group)| {
+                        let optional = |value: Option<u64>| {
+                            value.map_or(ParserValue::NullBulkString, |value| {
+                                ParserValue::Integer(value as i64)
+                            })
+                        };
+                        let lag = group
+                            .entries_read
+                            .map(|read| stream.entries_added().saturating_sub(read));
+                        ParserValue::Array(vec![
+                            bulk("name"),
+                            ParserValue::BulkString(name.clone()),
+                            bulk("consumers"),
+                            integer(group.consumers.len()),
+                            bulk("pending"),
+                            integer(group.pending.len()),
+                            bulk("last-delivered-id"),
+                            bulk(&group.last_delivered_id.to_string()),
+                            bulk("entries-read"),
+                            optional(group.entries_read),
+                            bulk("lag"),
+                            optional(lag),
+                        ])
+                    })
+                    .collect(),
+            )),
+            _ => {
+                let group = stream
+                    .groups()
+                    .get(&arguments[3])
+                    .ok_or_else(|| no_group(key, &arguments[3]))?;
+                let now_ms = now_ms();
+                Ok(ParserValue::Array(
+                    group
+                        .consumers
+                        .iter()
+                        .map(|(name, consumer)| {
+                            let inactive = consumer
+                                .active_time_ms
+                                .map_or(-1, |active| now_ms.saturating_sub(active) as i64);
+                            ParserValue::Array(vec![
+                                bulk("name"),
+                                ParserValue::BulkString(name.clone()),
+                                bulk("pending"),
+                                integer(consumer.pending.len()),
+                                bulk("idle"),
+                                ParserValue::Integer(
+                                    now_ms.saturating_sub(consumer.seen_time_ms) as i64
+                                ),
+                                bulk("inactive"),
+                                ParserValue::Integer(inactive),
+                            ])
+                        })
+                        .collect(),
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn xlen(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, 2)?;
+        let len = self
+            .get_stream(&arguments[1])?
+            .map_or(0, |stream| stream.len());
+        Ok(ParserValue::Integer(len as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::ByteString;
+    use crate::data_core::tests::{new_data_core, run};
+    use crate::parser::ParserValue;
+
+    #[test]
+    fn test_xadd_ids() {
+        let mut data_core = new_data_core();
+        assert_eq!(
+            ParserValue::BulkString("1-1".into()),
+            run(&mut data_core, &["XADD", "s", "1-1", "f", "v"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("1-2".into()),
+            run(&mut data_core, &["XADD", "s", "1-*", "f", "v"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("2-0".into()),
+            run(&mut data_core, &["XADD", "s", "2", "f", "v"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XADD", "s", "1-5", "f", "v"]),
+            ParserValue::Error(e) if e.contains("equal or smaller")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["XADD", "other", "0-0", "f", "v"]),
+            ParserValue::Error(e) if e.contains("greater than 0-0")
+        ));
+        assert!(!data_core.data_set.contains_key("other".as_bytes()));
+
+        let auto = run(&mut data_core, &["XADD", "s", "*", "f", "v"]);
+        assert!(matches!(auto, ParserValue::BulkString(id) if id.ends_with(b"-0")));
+
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(
+                &mut data_core,
+                &["XADD", "new", "NOMKSTREAM", "*", "f", "v"]
+            )
+        );
+        assert!(!data_core.data_set.contains_key("new".as_bytes()));
+        assert!(matches!(
+            run(&mut data_core, &["XADD", "s", "*", "f"]),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_xrange_bounds_and_count() {
+        let mut data_core = new_data_core();
+        for id in ["1-0", "1-1", "2-0", "3-5"] {
+            run(&mut data_core, &["XADD", "s", id, "f", id]);
+        }
+        let ids = |value: ParserValue| {
+            value
+                .to_vec()
+                .unwrap()
+                .iter()
+                .map(|entry| entry.to_vec().unwrap()[0].to_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["1-0", "1-1", "2-0", "3-5"],
+            ids(run(&mut data_core, &["XRANGE", "s", "-", "+"]))
+        );
+        assert_eq!(
+            vec!["1-0", "1-1"],
+            ids(run(&mut data_core, &["XRANGE", "s", "1", "1"]))
+        );
+        assert_eq!(
+            vec!["1-1", "2-0"],
+            ids(run(&mut data_core, &["XRANGE", "s", "(1-0", "(3-5"]))
+        );
+        assert_eq!(
+            vec!["3-5", "2-0"],
+            ids(run(
+                &mut data_core,
+                &["XREVRANGE", "s", "+", "-", "COUNT", "2"]
+            ))
+        );
+        assert!(ids(run(&mut data_core, &["XRANGE", "s", "3", "1"])).is_empty());
+        assert_eq!(ParserValue::Integer(4), run(&mut data_core, &["XLEN", "s"]));
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["XLEN", "missing"])
+        );
+
+        let entry = run(&mut data_core, &["XRANGE", "s", "2", "2"])
+            .to_vec()
+            .unwrap()[0]
+            .clone();
+        assert_eq!(
+            ParserValue::Array(vec![
+                ParserValue::BulkString("2-0".into()),
+                ParserValue::Array(vec![
+                    ParserValue::BulkString("f".into()),
+                    ParserValue::BulkString("2-0".into()),
+                ]),
+            ]),
+            entry
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XRANGE", "s", "abc", "+"]),
+            ParserValue::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_xread_from_multiple_streams() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["XADD", "a", "1-1", "f", "v"]);
+        run(&mut data_core, &["XADD", "a", "1-2", "f", "v"]);
+        run(&mut data_core, &["XADD", "b", "2-1", "f", "v"]);
+
+        let reply = run(
+            &mut data_core,
+            &["XREAD", "COUNT", "1", "STREAMS", "a", "b", "0", "2-1"],
+        );
+        let streams = reply.to_vec().unwrap();
+        assert_eq!(1, streams.len());
+        let stream = streams[0].to_vec().unwrap();
+        assert_eq!(ParserValue::BulkString("a".into()), stream[0]);
+        assert_eq!(1, stream[1].to_vec().unwrap().len());
+
+        assert_eq!(
+            ParserValue::NullArray,
+            run(&mut data_core, &["XREAD", "STREAMS", "a", "$"])
+        );
+        assert_eq!(
+            ParserValue::NullArray,
+            run(
+                &mut data_core,
+                &["XREAD", "BLOCK", "10", "STREAMS", "a", "$"]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XREAD", "STREAMS", "a", "b", "0"]),
+            ParserValue::Error(e) if e.contains("Unbalanced")
+        ));
+
+        let out_of_range =
+            ParserValue::Error("ERR value is out of range, must be positive".to_string());
+        assert_eq!(
+            out_of_range,
+            run(
+                &mut data_core,
+                &["XREAD", "COUNT", "-1", "STREAMS", "a", "0"]
+            )
+        );
+        run(&mut data_core, &["XGROUP", "CREATE", "a", "g", "0"]);
+        assert_eq!(
+            out_of_range,
+            run(
+                &mut data_core,
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    "c",
+                    "COUNT",
+                    "-1",
+                    "STREAMS",
+                    "a",
+                    ">"
+                ]
+            )
+        );
+        let reply = run(
+            &mut data_core,
+            &["XREAD", "COUNT", "0", "STREAMS", "a", "0"],
+        );
+        let stream = reply.to_vec().unwrap()[0].to_vec().unwrap();
+        assert_eq!(2, stream[1].to_vec().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_xread_block_wakes_on_xadd() {
+        use tokio::sync::{mpsc, oneshot};
+
+        use crate::data_core::{Command, DataCore, ReplicationRole};
+        use crate::tokenizer::{serialize_tokens, Token};
+
+        let (command_tx, command_rx) = mpsc::channel::<Command>(32);
+        let mut data_core = DataCore::new(command_rx, ReplicationRole::Master, None, None);
+        tokio::spawn(async move {
+            data_core.process_command().await;
+        });
+        let send = |arguments: &[&str]| {
+            let (tx, rx) = oneshot::channel();
+            let arguments = arguments
+                .iter()
+                .map(|argument| ByteString::from(*argument))
+                .collect();
+            (Command::new(arguments, tx), rx)
+        };
+
+        let (existing, existing_rx) = send(&["XADD", "s", "1-1", "f", "old"]);
+        command_tx.send(existing).await.unwrap();
+        let (blocked, blocked_rx) = send(&["XREAD", "BLOCK", "0", "STREAMS", "s", "$"]);
+        command_tx.send(blocked).await.unwrap();
+        let (timed_out, timed_out_rx) = send(&["XREAD", "BLOCK", "50", "STREAMS", "t", "$"]);
+        command_tx.send(timed_out).await.unwrap();
+        let (xadd, xadd_rx) = send(&["XADD", "s", "1-2", "f", "new"]);
+        command_tx.send(xadd).await.unwrap();
+
+        let serialize = |tokens: Vec<Token>| serialize_tokens(&tokens).unwrap();
+        serialize(existing_rx.await.unwrap());
+        assert_eq!("$3\r\n1-2\r\n", serialize(xadd_rx.await.unwrap()));
+        assert_eq!(
+            "*1\r\n*2\r\n$1\r\ns\r\n*1\r\n*2\r\n$3\r\n1-2\r\n*2\r\n$1\r\nf\r\n$3\r\nnew\r\n",
+            serialize(blocked_rx.await.unwrap())
+        );
+        assert_eq!("*-1\r\n", serialize(timed_out_rx.await.unwrap()));
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let mut data_core = new_data_core();
+        assert!(matches!(
+            run(&mut data_core, &["XGROUP", "CREATE", "s", "g", "$"]),
+            ParserValue::Error(e) if e.contains("MKSTREAM")
+        ));
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(
+                &mut data_core,
+                &["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XGROUP", "CREATE", "s", "g", "0"]),
+            ParserValue::Error(e) if e.starts_with("BUSYGROUP")
+        ));
+        for id in ["1-1", "1-2", "1-3"] {
+            run(&mut data_core, &["XADD", "s", id, "f", id]);
+        }
+        let ids = |value: ParserValue| match value {
+            ParserValue::Array(streams) => streams[0].to_vec().unwrap()[1]
+                .to_vec()
+                .unwrap()
+                .iter()
+                .map(|entry| entry.to_vec().unwrap()[0].to_string().unwrap())
+                .collect::<Vec<_>>(),
+            _ => Vec::new(),
+        };
+
+        let read = |data_core: &mut _, consumer, id| {
+            run(
+                data_core,
+                &[
+                    "XREADGROUP",
+                    "GROUP",
+                    "g",
+                    consumer,
+                    "COUNT",
+                    "2",
+                    "STREAMS",
+                    "s",
+                    id,
+                ],
+            )
+        };
+        assert_eq!(vec!["1-1", "1-2"], ids(read(&mut data_core, "alice", ">")));
+        assert_eq!(vec!["1-3"], ids(read(&mut data_core, "bob", ">")));
+        assert_eq!(ParserValue::NullArray, read(&mut data_core, "bob", ">"));
+        assert_eq!(vec!["1-1", "1-2"], ids(read(&mut data_core, "alice", "0")));
+
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["XACK", "s", "g", "1-1", "9-9"])
+        );
+        assert_eq!(vec!["1-2"], ids(read(&mut data_core, "alice", "0")));
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["XGROUP", "DELCONSUMER", "s", "g", "bob"])
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(
+                &mut data_core,
+                &["XGROUP", "CREATECONSUMER", "s", "g", "carol"]
+            )
+        );
+        assert!(matches!(
+            read(&mut data_core, "alice", ">"),
+            ParserValue::NullArray
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["XREADGROUP", "GROUP", "nope", "c", "STREAMS", "s", ">"]),
+            ParserValue::Error(e) if e.starts_with("NOGROUP")
+        ));
+        assert_eq!(
+            ParserValue::Integer(1),
+            run(&mut data_core, &["XGROUP", "DESTROY", "s", "g"])
+        );
+    }
+
+    #[test]
+    fn test_xdel_xtrim_and_xsetid() {
+        let mut data_core = new_data_core();
+        for seq in 1..=10 {
+            run(
+                &mut data_core,
+                &["XADD", "s", &format!("1-{}", seq), "f", "v"],
+            );
+        }
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["XDEL", "s", "1-1", "1-2", "1-99"])
+        );
+        assert_eq!(
+            ParserValue::Integer(3),
+            run(&mut data_core, &["XTRIM", "s", "MAXLEN", "5"])
+        );
+        assert_eq!(
+            ParserValue::Integer(0),
+            run(&mut data_core, &["XTRIM", "s", "MAXLEN", "~", "1"])
+        );
+        assert_eq!(
+            ParserValue::Integer(2),
+            run(&mut data_core, &["XTRIM", "s", "MINID", "1-8"])
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XTRIM", "s", "MAXLEN", "1", "LIMIT", "1"]),
+            ParserValue::Error(e) if e.contains("LIMIT")
+        ));
+        run(
+            &mut data_core,
+            &["XADD", "s", "MAXLEN", "2", "2-0", "f", "v"],
+        );
+        assert_eq!(ParserValue::Integer(2), run(&mut data_core, &["XLEN", "s"]));
+
+        assert!(matches!(
+            run(&mut data_core, &["XSETID", "s", "1-0"]),
+            ParserValue::Error(e) if e.contains("top item")
+        ));
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            run(
+                &mut data_core,
+                &["XSETID", "s", "5-0", "ENTRIESADDED", "20"]
+            )
+        );
+        assert!(matches!(
+            run(&mut data_core, &["XADD", "s", "4-0", "f", "v"]),
+            ParserValue::Error(e) if e.contains("equal or smaller")
+        ));
+        assert!(matches!(
+            run(&mut data_core, &["XSETID", "missing", "1-0"]),
+            ParserValue::Error(e) if e == "ERR no such key"
+        ));
+    }
+
+    #[test]
+    fn test_xinfo() {
+        let mut data_core = new_data_core();
+        run(&mut data_core, &["XADD", "s", "1-1", "f", "v"]);
+        run(&mut data_core, &["XADD", "s", "1-2", "f", "v"]);
+        run(&mut data_core, &["XDEL", "s", "1-1"]);
+        run(&mut data_core, &["XGROUP", "CREATE", "s", "g", "0"]);
+        run(
+            &mut data_core,
+            &["XREADGROUP", "GROUP", "g", "alice", "STREAMS", "s", ">"],
+        );
+
+        let info = run(&mut data_core, &["XINFO", "STREAM", "s"])
+            .to_vec()
+            .unwrap()
+            .clone();
+        let field = |info: &[ParserValue], name: &str| {
+            let position = info
+                .iter()
+                .position(|value| value == &ParserValue::BulkString(name.into()))
+                .unwrap();
+            info[position + 1].clone()
+        };
+        assert_eq!(ParserValue::Integer(1), field(&info, "length"));
+        assert_eq!(ParserValue::Integer(2), field(&info, "entries-added"));
+        assert_eq!(
+            ParserValue::BulkString("1-1".into()),
+            field(&info, "max-deleted-entry-id")
+        );
+        assert_eq!(ParserValue::Integer(1), field(&info, "groups"));
+
+        let groups = run(&mut data_core, &["XINFO", "GROUPS", "s"])
+            .to_vec()
+            .unwrap()
+            .clone();
+        let group = groups[0].to_vec().unwrap();
+        assert_eq!(ParserValue::Integer(1), field(group, "pending"));
+        assert_eq!(ParserValue::Integer(0), field(group, "lag"));
+
+        let consumers = run(&mut data_core, &["XINFO", "CONSUMERS", "s", "g"])
+            .to_vec()
+            .unwrap()
+            .clone();
+        let consumer = consumers[0].to_vec().unwrap();
+        assert_eq!(
+            ParserValue::BulkString("alice".into()),
+            field(consumer, "name")
+        );
+        assert_eq!(ParserValue::Integer(1), field(consumer, "pending"));
+    }
+}
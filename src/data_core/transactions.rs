@@ -0,0 +1,518 @@
+use tracing::warn;
+
+use crate::binary::ByteString;
+use crate::data_core::commands;
+use crate::data_core::{check_arity, client_required, Client, CommandError, DataCore, Key};
+use crate::parser::ParserValue;
+
+/// Commands that act on the transaction itself instead of being queued.
+const TRANSACTION_COMMANDS: &[&str] = &[
+    "multi", "exec", "discard", "watch", "unwatch", "quit", "reset",
+];
+
+/// What the transaction of commands without a client, like the ones of
+/// the master's stream or of the AOF file, is kept under. Clients are
+/// numbered from 1.
+const UNATTENDED: u64 = 0;
+
+/// Commands queued by a client between MULTI and EXEC.
+#[derive(Debug)]
+pub(crate) struct Transaction {
+    client: Option<Client>,
+    commands: Vec<Vec<ByteString>>,
+    /// Set when a command could not be queued, so EXEC discards the
+    /// transaction instead of running part of it.
+    failed: bool,
+}
+
+/// Keys a client watches, with the version each had when it was watched.
+#[derive(Debug)]
+pub(crate) struct WatchedKeys {
+    client: Client,
+    keys: Vec<(Key, u64)>,
+}
+
+impl DataCore {
+    /// Queues the command if its client is inside MULTI, returning the
+    /// `+QUEUED` reply. Unknown commands and wrong arities are rejected
+    /// right away and fail the whole transaction; any other error only shows
+    /// up in its slot of the EXEC reply.
+    pub(crate) fn queue_in_transaction(
+        self: &mut DataCore,
+        name: &str,
+        arguments: &[ByteString],
+    ) -> Option<Result<ParserValue, CommandError>> {
+        if TRANSACTION_COMMANDS.contains(&name) {
+            return None;
+        }
+        let id = self.transaction_id();
+        let transaction = self.transactions.get_mut(&id)?;
+        let error = match commands::lookup(name) {
+            None => Some(CommandError::UnknownCommand(arguments[0].clone())),
+            Some(spec) if !spec.accepts_arity(arguments.len()) => {
+                Some(CommandError::WrongArity(name.to_string()))
+            }
+            Some(_) => None,
+        };
+        if let Some(error) = error {
+            transaction.failed = true;
+            return Some(Err(error));
+        }
+        transaction.commands.push(arguments.to_vec());
+        Some(Ok(ParserValue::SimpleString(String::from("QUEUED"))))
+    }
+
+    /// The commands the current client queued, while it is inside MULTI.
+    pub(crate) fn queued_commands(self: &DataCore) -> Option<&[Vec<ByteString>]> {
+        let transaction = self.transactions.get(&self.transaction_id())?;
+        Some(&transaction.commands)
+    }
+
+    /// What the transaction of the current client is kept under.
+    fn transaction_id(self: &DataCore) -> u64 {
+        self.client.as_ref().map_or(UNATTENDED, |client| client.id)
+    }
+
+    /// Drops a transaction of commands without a client that never got its
+    /// EXEC, like one cut short at the end of the AOF file or by a lost
+    /// link to the master.
+    pub(crate) fn discard_unattended_transaction(self: &mut DataCore) {
+        if self.transactions.remove(&UNATTENDED).is_some() {
+            warn!("discarding a transaction that was never executed");
+        }
+    }
+
+    /// How many commands `client` queued, while it is inside MULTI.
+    pub(crate) fn transaction_length(self: &DataCore, client: u64) -> Option<usize> {
+        self.transactions
+            .get(&client)
+            .map(|transaction| transaction.commands.len())
+    }
+
+    /// How many keys `client` watches.
+    pub(crate) fn watched_key_count(self: &DataCore, client: u64) -> usize {
+        self.watched_keys
+            .get(&client)
+            .map_or(0, |watched| watched.keys.len())
+    }
+
+    /// Aborts the current client's transaction because a command was
+    /// rejected before running: EXEC discards it, other commands fail it.
+    pub(crate) fn reject_in_transaction(self: &mut DataCore, name: &str) {
+        let Some(client) = self.client.as_ref().map(|client| client.id) else {
+            return;
+        };
+        if name == "exec" {
+            if self.transactions.remove(&client).is_some() {
+                self.unwatch_all(client);
+            }
+        } else if !TRANSACTION_COMMANDS.contains(&name) {
+            if let Some(transaction) = self.transactions.get_mut(&client) {
+                transaction.failed = true;
+            }
+        }
+    }
+
+    /// Bumps the version of `key` so transactions watching it abort.
+    pub(crate) fn touch_key(self: &mut DataCore, key: &[u8]) {
+        if let Some(version) = self.key_versions.get_mut(key) {
+            *version += 1;
+        }
+    }
+
+    /// Touches the keys of a write command that ran successfully, and marks
+    /// them ready for the clients blocked on them.
+    pub(crate) fn signal_modified_keys(self: &mut DataCore, name: &str, arguments: &[ByteString]) {
+        let Some(spec) = commands::lookup(name) else {
+            return;
+        };
+        if spec.is_write() {
+            for key in spec.keys(arguments) {
+                self.touch_key(key);
+                self.blocked_clients.signal_key_as_ready(key);
+            }
+        }
+    }
+
+    /// Forgets the watched keys of `client`, dropping versions nobody
+    /// watches anymore.
+    fn unwatch_all(self: &mut DataCore, client: u64) {
+        let Some(watched) = self.watched_keys.remove(&client) else {
+            return;
+        };
+        for (key, _) in watched.keys {
+            let still_watched = self
+                .watched_keys
+                .values()
+                .any(|watched| watched.keys.iter().any(|(watched, _)| *watched == key));
+            if !still_watched {
+                self.key_versions.remove(&key);
+            }
+        }
+    }
+
+    /// Whether a key watched by `client` changed since it was watched.
+    fn watched_key_modified(self: &DataCore, client: u64) -> bool {
+        self.watched_keys.get(&client).is_some_and(|watched| {
+            watched
+                .keys
+                .iter()
+                .any(|(key, version)| self.key_versions.get(key) != Some(version))
+        })
+    }
+
+    /// WATCH key [key ...]
+    pub(crate) fn watch(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 2, usize::MAX)?;
+        let client = self
+            .client
+            .clone()
+            .ok_or_else(|| client_required("watch"))?;
+        if self.transactions.contains_key(&client.id) {
+            return Err(CommandError::Other(
+                "ERR WATCH inside MULTI is not allowed".to_string(),
+            ));
+        }
+        let disconnected = self
+            .watched_keys
+            .iter()
+            .filter(|(_, watched)| watched.client.push_channel.is_closed())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in disconnected {
+            self.unwatch_all(id);
+        }
+
+        for key in &arguments[1..] {
+            let key = self.intern_key(key);
+            let version = *self.key_versions.entry(key.clone()).or_insert(0);
+            let watched = self
+                .watched_keys
+                .entry(client.id)
+                .or_insert_with(|| WatchedKeys {
+                    client: client.clone(),
+                    keys: Vec::new(),
+                });
+            if !watched.keys.iter().any(|(watched, _)| *watched == key) {
+                watched.keys.push((key, version));
+            }
+        }
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// UNWATCH
+    pub(crate) fn unwatch(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| client_required("unwatch"))?;
+        self.unwatch_all(client.id);
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// MULTI
+    pub(crate) fn multi(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        let client = self.client.clone();
+        let id = self.transaction_id();
+        if self.transactions.contains_key(&id) {
+            return Err(CommandError::Other(
+                "ERR MULTI calls can not be nested".to_string(),
+            ));
+        }
+        // Transactions of clients that disconnected mid-MULTI are never executed.
+        self.transactions.retain(|_, transaction| {
+            !transaction
+                .client
+                .as_ref()
+                .is_some_and(|client| client.push_channel.is_closed())
+        });
+        self.transactions.insert(
+            id,
+            Transaction {
+                client,
+                commands: Vec::new(),
+                failed: false,
+            },
+        );
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+
+    /// EXEC
+    pub(crate) fn exec(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        let client = self.transaction_id();
+        let transaction = self
+            .transactions
+            .remove(&client)
+            .ok_or_else(|| CommandError::Other("ERR EXEC without MULTI".to_string()))?;
+        let aborted = self.watched_key_modified(client);
+        self.unwatch_all(client);
+        if transaction.failed {
+            return Err(CommandError::Other(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            ));
+        }
+        if aborted {
+            return Ok(ParserValue::NullArray);
+        }
+
+        // Commands run back to back, so no other client observes a partial
+        // transaction. Blocking commands behave as if they timed out, and a
+        // command failing at runtime doesn't stop the ones after it. Writes
+        // reach the AOF and replicas between MULTI and EXEC, so they are
+        // applied together there too.
+        let writes = transaction.commands.iter().any(|arguments| {
+            commands::lookup(&arguments[0].to_lowercase()).is_some_and(|spec| spec.is_write())
+        });
+        if writes {
+            self.propagate_command(&["MULTI"]);
+        }
+        let replies = transaction
+            .commands
+            .iter()
+            .map(|arguments| {
+                let reply = self
+                    .dispatch(arguments)
+                    .unwrap_or_else(|err| ParserValue::Error(err.to_string()));
+                self.block_request = None;
+                reply
+            })
+            .collect();
+        if writes {
+            self.propagate_command(&["EXEC"]);
+        }
+        Ok(ParserValue::Array(replies))
+    }
+
+    /// DISCARD
+    pub(crate) fn discard(
+        self: &mut DataCore,
+        arguments: &[ByteString],
+    ) -> Result<ParserValue, CommandError> {
+        check_arity(arguments, 1, 1)?;
+        let client = self.transaction_id();
+        self.transactions
+            .remove(&client)
+            .ok_or_else(|| CommandError::Other("ERR DISCARD without MULTI".to_string()))?;
+        self.unwatch_all(client);
+        Ok(ParserValue::SimpleString(String::from("OK")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use crate::data_core::tests::{new_data_core, run, run_as};
+    use crate::data_core::Client;
+    use crate::parser::ParserValue;
+    use crate::tokenizer::{serialize_tokens, Token};
+
+    fn simple_string(value: &str) -> ParserValue {
+        ParserValue::SimpleString(value.to_string())
+    }
+
+    #[test]
+    fn test_exec_runs_queued_commands() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(1, push_tx);
+
+        assert_eq!(
+            simple_string("OK"),
+            run_as(&mut data_core, &client, &["MULTI"])
+        );
+        assert_eq!(
+            simple_string("QUEUED"),
+            run_as(&mut data_core, &client, &["SET", "k", "v"])
+        );
+        assert_eq!(
+            simple_string("QUEUED"),
+            run_as(&mut data_core, &client, &["GET", "k"])
+        );
+        // Other clients do not see the queued writes before EXEC.
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "k"])
+        );
+        assert_eq!(
+            ParserValue::Array(vec![
+                simple_string("OK"),
+                ParserValue::BulkString("v".into()),
+            ]),
+            run_as(&mut data_core, &client, &["EXEC"])
+        );
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["EXEC"]),
+            ParserValue::Error(e) if e == "ERR EXEC without MULTI"
+        ));
+    }
+
+    #[test]
+    fn test_discard_drops_queued_commands() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(1, push_tx);
+
+        run_as(&mut data_core, &client, &["MULTI"]);
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["MULTI"]),
+            ParserValue::Error(e) if e == "ERR MULTI calls can not be nested"
+        ));
+        run_as(&mut data_core, &client, &["SET", "k", "v"]);
+        assert_eq!(
+            simple_string("OK"),
+            run_as(&mut data_core, &client, &["DISCARD"])
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "k"])
+        );
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["DISCARD"]),
+            ParserValue::Error(e) if e == "ERR DISCARD without MULTI"
+        ));
+    }
+
+    #[test]
+    fn test_exec_aborts_when_watched_key_changes() {
+        let mut data_core = new_data_core();
+        let (first_tx, _first_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let (second_tx, _second_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let first = Client::new(1, first_tx);
+        let second = Client::new(2, second_tx);
+
+        run_as(&mut data_core, &first, &["WATCH", "k"]);
+        run_as(&mut data_core, &first, &["MULTI"]);
+        assert!(matches!(
+            run_as(&mut data_core, &first, &["WATCH", "k"]),
+            ParserValue::Error(e) if e == "ERR WATCH inside MULTI is not allowed"
+        ));
+        run_as(&mut data_core, &first, &["SET", "k", "first"]);
+        // Reads of the watched key do not abort the transaction.
+        run_as(&mut data_core, &second, &["GET", "k"]);
+        run_as(&mut data_core, &second, &["SET", "k", "second"]);
+        assert_eq!(
+            ParserValue::NullArray,
+            run_as(&mut data_core, &first, &["EXEC"])
+        );
+        assert_eq!(
+            ParserValue::BulkString("second".into()),
+            run(&mut data_core, &["GET", "k"])
+        );
+        assert!(data_core.key_versions.is_empty());
+
+        // EXEC unwatched the key, so the next transaction goes through.
+        run_as(&mut data_core, &first, &["WATCH", "k"]);
+        run_as(&mut data_core, &first, &["UNWATCH"]);
+        run_as(&mut data_core, &second, &["SET", "k", "second"]);
+        run_as(&mut data_core, &first, &["MULTI"]);
+        run_as(&mut data_core, &first, &["SET", "k", "first"]);
+        assert_eq!(
+            ParserValue::Array(vec![simple_string("OK")]),
+            run_as(&mut data_core, &first, &["EXEC"])
+        );
+    }
+
+    #[test]
+    fn test_queueing_errors_abort_and_runtime_errors_do_not() {
+        let mut data_core = new_data_core();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(1, push_tx);
+
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "a", "1"]);
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["NOSUCHCOMMAND"]),
+            ParserValue::Error(e) if e.starts_with("ERR unknown command")
+        ));
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["GET"]),
+            ParserValue::Error(e) if e.starts_with("ERR wrong number of arguments")
+        ));
+        assert!(matches!(
+            run_as(&mut data_core, &client, &["EXEC"]),
+            ParserValue::Error(e) if e.starts_with("EXECABORT")
+        ));
+        assert_eq!(
+            ParserValue::NullBulkString,
+            run(&mut data_core, &["GET", "a"])
+        );
+
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SADD", "s", "member"]);
+        run_as(&mut data_core, &client, &["GET", "s"]);
+        run_as(&mut data_core, &client, &["SET", "a", "1"]);
+        let replies = run_as(&mut data_core, &client, &["EXEC"]);
+        let replies = replies.to_vec().unwrap();
+        assert_eq!(ParserValue::Integer(1), replies[0]);
+        assert!(matches!(&replies[1], ParserValue::Error(e) if e.starts_with("WRONGTYPE")));
+        assert_eq!(simple_string("OK"), replies[2]);
+    }
+
+    #[test]
+    fn test_transactions_are_propagated_whole() {
+        let mut data_core = new_data_core();
+        let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let replica = Client::new(1, push_tx);
+        run_as(&mut data_core, &replica, &["PSYNC", "?", "-1"]);
+        push_rx.try_recv().unwrap();
+        let (push_tx, _push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+        let client = Client::new(2, push_tx);
+
+        // Transactions that only read aren't propagated.
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["GET", "k"]);
+        run_as(&mut data_core, &client, &["EXEC"]);
+        assert!(push_rx.try_recv().is_err());
+
+        run_as(&mut data_core, &client, &["MULTI"]);
+        run_as(&mut data_core, &client, &["SET", "k", "v"]);
+        run_as(&mut data_core, &client, &["GET", "k"]);
+        run_as(&mut data_core, &client, &["SADD", "s", "a"]);
+        run_as(&mut data_core, &client, &["EXEC"]);
+        let mut propagated = String::new();
+        while let Ok(command) = push_rx.try_recv() {
+            propagated.push_str(&serialize_tokens(&command).unwrap());
+        }
+        assert_eq!(
+            "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n*1\r\n$4\r\nEXEC\r\n",
+            propagated
+        );
+
+        // Replicas and AOF replay run them without a client, as one
+        // transaction too.
+        let mut replica = new_data_core();
+        assert_eq!(simple_string("OK"), run(&mut replica, &["MULTI"]));
+        assert_eq!(
+            simple_string("QUEUED"),
+            run(&mut replica, &["SET", "k", "v"])
+        );
+        assert!(!replica.data_set.contains_key("k".as_bytes()));
+        assert_eq!(
+            ParserValue::Array(vec![simple_string("OK")]),
+            run(&mut replica, &["EXEC"])
+        );
+        assert!(replica.data_set.contains_key("k".as_bytes()));
+
+        // One cut short is dropped.
+        run(&mut replica, &["MULTI"]);
+        run(&mut replica, &["DEL", "k"]);
+        replica.discard_unattended_transaction();
+        assert_eq!(ParserValue::Integer(1), run(&mut replica, &["DEL", "k"]));
+    }
+}
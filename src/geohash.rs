@@ -0,0 +1,100 @@
+//! The 52-bit geohash Redis stores as sorted set scores for the geo commands.
+
+pub const LONGITUDE_MIN: f64 = -180.0;
+pub const LONGITUDE_MAX: f64 = 180.0;
+/// Latitudes are limited to what the Web Mercator projection can represent.
+pub const LATITUDE_MIN: f64 = -85.051_128_78;
+pub const LATITUDE_MAX: f64 = 85.051_128_78;
+/// Bits per coordinate; interleaved they make a 52-bit hash that an `f64`
+/// score holds exactly.
+const STEP: u32 = 26;
+/// Earth's radius in meters, as used by Redis.
+const EARTH_RADIUS: f64 = 6_372_797.560_856;
+
+pub fn is_valid(longitude: f64, latitude: f64) -> bool {
+    (LONGITUDE_MIN..=LONGITUDE_MAX).contains(&longitude)
+        && (LATITUDE_MIN..=LATITUDE_MAX).contains(&latitude)
+}
+
+/// Spreads the low 32 bits of `value` to the even bit positions.
+fn spread(value: u32) -> u64 {
+    let mut value = value as u64;
+    value = (value | (value << 16)) & 0x0000_ffff_0000_ffff;
+    value = (value | (value << 8)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value << 2)) & 0x3333_3333_3333_3333;
+    (value | (value << 1)) & 0x5555_5555_5555_5555
+}
+
+/// Collects the even bit positions of `value`.
+fn squash(value: u64) -> u32 {
+    let mut value = value & 0x5555_5555_5555_5555;
+    value = (value | (value >> 1)) & 0x3333_3333_3333_3333;
+    value = (value | (value >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    value = (value | (value >> 4)) & 0x00ff_00ff_00ff_00ff;
+    value = (value | (value >> 8)) & 0x0000_ffff_0000_ffff;
+    ((value | (value >> 16)) & 0x0000_0000_ffff_ffff) as u32
+}
+
+/// Encodes a position, interleaving latitude bits in the even positions and
+/// longitude bits in the odd ones. The caller checks [`is_valid`] first.
+pub fn encode(longitude: f64, latitude: f64) -> u64 {
+    let cells = (1u64 << STEP) as f64;
+    let scale = |value: f64, min: f64, max: f64| {
+        (((value - min) / (max - min)) * cells).min(cells - 1.0) as u32
+    };
+    let latitude = scale(latitude, LATITUDE_MIN, LATITUDE_MAX);
+    let longitude = scale(longitude, LONGITUDE_MIN, LONGITUDE_MAX);
+    spread(latitude) | (spread(longitude) << 1)
+}
+
+/// Decodes a hash to the center of its cell as `(longitude, latitude)`.
+pub fn decode(hash: u64) -> (f64, f64) {
+    let cells = (1u64 << STEP) as f64;
+    let center = |cell: u32, min: f64, max: f64| {
+        let low = min + (cell as f64 / cells) * (max - min);
+        let high = min + ((cell as f64 + 1.0) / cells) * (max - min);
+        ((low + high) / 2.0).clamp(min, max)
+    };
+    (
+        center(squash(hash >> 1), LONGITUDE_MIN, LONGITUDE_MAX),
+        center(squash(hash), LATITUDE_MIN, LATITUDE_MAX),
+    )
+}
+
+/// Great-circle distance in meters between two positions given as
+/// `(longitude, latitude)`, using the haversine formula.
+pub fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (from_longitude, from_latitude) = (from.0.to_radians(), from.1.to_radians());
+    let (to_longitude, to_latitude) = (to.0.to_radians(), to.1.to_radians());
+    let u = ((to_latitude - from_latitude) / 2.0).sin();
+    let v = ((to_longitude - from_longitude) / 2.0).sin();
+    let a = u * u + from_latitude.cos() * to_latitude.cos() * v * v;
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_and_distance() {
+        let palermo = (13.361389, 38.115556);
+        let catania = (15.087269, 37.502669);
+        // The scores Redis stores for these positions.
+        assert_eq!(3479099956230698, encode(palermo.0, palermo.1));
+        assert_eq!(3479447370796909, encode(catania.0, catania.1));
+
+        let (longitude, latitude) = decode(encode(palermo.0, palermo.1));
+        assert!((longitude - palermo.0).abs() < 1e-5);
+        assert!((latitude - palermo.1).abs() < 1e-5);
+
+        let meters = distance(
+            decode(encode(palermo.0, palermo.1)),
+            decode(encode(catania.0, catania.1)),
+        );
+        assert!((meters - 166274.1516).abs() < 0.01, "{}", meters);
+        assert!(!is_valid(181.0, 0.0));
+        assert!(!is_valid(0.0, 86.0));
+    }
+}
@@ -0,0 +1,121 @@
+/// Matches `text` against a Redis style glob pattern supporting `*`, `?`,
+/// `[abc]`, `[^abc]`, `[a-z]` and `\` escapes, as used by MATCH, KEYS and
+/// pattern subscriptions.
+pub fn glob_match(pattern: impl AsRef<[u8]>, text: impl AsRef<[u8]>) -> bool {
+    let (pattern, text) = (pattern.as_ref(), text.as_ref());
+    let (mut p, mut t) = (0, 0);
+    // Position to resume from when the last `*` has to swallow another byte.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, t));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                byte => {
+                    if byte == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        match backtrack {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+/// Matches `byte` against the character class starting at `pattern[start]`
+/// (a `[`), returning whether it matched and the index after the class.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut p = start + 1;
+    let negate = p < pattern.len() && pattern[p] == b'^';
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (low, high) = match pattern[p] <= pattern[p + 2] {
+                true => (pattern[p], pattern[p + 2]),
+                false => (pattern[p + 2], pattern[p]),
+            };
+            matched |= low <= byte && byte <= high;
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+    if p == pattern.len() {
+        // An unterminated class never matches, like in Redis.
+        return None;
+    }
+    Some((matched != negate, p + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-b]llo", "hbllo"));
+        assert!(glob_match("news.*", "news.tech"));
+        assert!(glob_match("a\\*b", "a*b"));
+        assert!(!glob_match("a\\*b", "axb"));
+        assert!(!glob_match("h*llo", "hellx"));
+        assert!(glob_match("*a*b", "xxaxxb"));
+    }
+}
@@ -0,0 +1,229 @@
+//! HyperLogLog cardinality estimation using the dense representation of
+//! Redis: a 16 byte `HYLL` header followed by 16384 six-bit registers, so the
+//! values stay compatible with the string commands.
+
+/// Bits of the hash used to select a register.
+const P: u32 = 14;
+pub const REGISTERS: usize = 1 << P;
+/// Bits of the hash left for counting leading zeros.
+const Q: u32 = 64 - P;
+const REGISTER_BITS: usize = 6;
+const REGISTER_MAX: u8 = (1 << REGISTER_BITS) - 1;
+const HEADER_SIZE: usize = 16;
+const DENSE_SIZE: usize = HEADER_SIZE + (REGISTERS * REGISTER_BITS).div_ceil(8);
+const MAGIC: &[u8] = b"HYLL";
+const ENCODING_DENSE: u8 = 0;
+/// Header offset of the little-endian cached cardinality. The most
+/// significant bit of its last byte marks the cache as stale.
+const CARDINALITY_OFFSET: usize = 8;
+const ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+/// MurmurHash64A, the hash Redis uses for HyperLogLog elements.
+fn murmur_hash_64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut hash = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut blocks = key.chunks_exact(8);
+    for block in blocks.by_ref() {
+        let mut k = u64::from_le_bytes(block.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        hash ^= k;
+        hash = hash.wrapping_mul(M);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        for (index, byte) in tail.iter().enumerate() {
+            hash ^= (*byte as u64) << (8 * index);
+        }
+        hash = hash.wrapping_mul(M);
+    }
+
+    hash ^= hash >> R;
+    hash = hash.wrapping_mul(M);
+    hash ^= hash >> R;
+    hash
+}
+
+/// Returns the register an element maps to and the length of the run of
+/// zeros (plus one) observed in the rest of its hash.
+fn pattern(element: &[u8]) -> (usize, u8) {
+    let hash = murmur_hash_64a(element, 0xadc8_3b19);
+    let index = (hash & (REGISTERS as u64 - 1)) as usize;
+    // The sentinel bit bounds the count at Q + 1.
+    let rest = (hash >> P) | (1 << Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn get_register(registers: &[u8], index: usize) -> u8 {
+    let bit = index * REGISTER_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let low = registers[byte] as u16;
+    let high = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((low | high << 8) >> shift) as u8) & REGISTER_MAX
+}
+
+fn set_register(registers: &mut [u8], index: usize, value: u8) {
+    let bit = index * REGISTER_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let mask = (REGISTER_MAX as u16) << shift;
+    let value = (value as u16) << shift;
+    registers[byte] = (registers[byte] & !(mask as u8)) | value as u8;
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next = (*next & !((mask >> 8) as u8)) | (value >> 8) as u8;
+    }
+}
+
+fn invalidate_cache(hll: &mut [u8]) {
+    hll[CARDINALITY_OFFSET + 7] |= 0x80;
+}
+
+/// An empty dense HyperLogLog.
+pub fn new() -> Vec<u8> {
+    let mut hll = vec![0; DENSE_SIZE];
+    hll[..MAGIC.len()].copy_from_slice(MAGIC);
+    hll[MAGIC.len()] = ENCODING_DENSE;
+    hll
+}
+
+/// Whether `bytes` hold a HyperLogLog this module can read.
+pub fn is_valid(bytes: &[u8]) -> bool {
+    bytes.len() == DENSE_SIZE && bytes.starts_with(MAGIC) && bytes[MAGIC.len()] == ENCODING_DENSE
+}
+
+/// Adds an element, returning whether any register changed.
+pub fn add(hll: &mut [u8], element: &[u8]) -> bool {
+    let (index, count) = pattern(element);
+    let registers = &mut hll[HEADER_SIZE..];
+    if get_register(registers, index) >= count {
+        return false;
+    }
+    set_register(registers, index, count);
+    invalidate_cache(hll);
+    true
+}
+
+/// Unpacks the registers of `hll` into `max_registers`, keeping the larger
+/// value of each pair, as used to merge several HyperLogLogs.
+pub fn merge_into(max_registers: &mut [u8], hll: &[u8]) {
+    let registers = &hll[HEADER_SIZE..];
+    for (index, max) in max_registers.iter_mut().enumerate() {
+        *max = (*max).max(get_register(registers, index));
+    }
+}
+
+/// Packs unpacked registers into a new dense HyperLogLog.
+pub fn from_registers(max_registers: &[u8]) -> Vec<u8> {
+    let mut hll = new();
+    for (index, value) in max_registers.iter().enumerate() {
+        set_register(&mut hll[HEADER_SIZE..], index, *value);
+    }
+    invalidate_cache(&mut hll);
+    hll
+}
+
+/// Cardinality of `hll`, using and refreshing the cached value in its header.
+pub fn cardinality(hll: &mut [u8]) -> u64 {
+    let cached = &hll[CARDINALITY_OFFSET..CARDINALITY_OFFSET + 8];
+    if cached[7] & 0x80 == 0 {
+        return u64::from_le_bytes(cached.try_into().unwrap());
+    }
+    let mut registers = vec![0; REGISTERS];
+    merge_into(&mut registers, hll);
+    let estimate = estimate(&registers);
+    hll[CARDINALITY_OFFSET..CARDINALITY_OFFSET + 8].copy_from_slice(&estimate.to_le_bytes());
+    estimate
+}
+
+/// Estimates the cardinality of unpacked registers with the improved
+/// estimator from Otmar Ertl's "New cardinality estimation algorithms for
+/// HyperLogLog sketches", as Redis does.
+pub fn estimate(registers: &[u8]) -> u64 {
+    let m = REGISTERS as f64;
+    let mut histogram = [0u32; Q as usize + 2];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+
+    let mut z = m * tau((m - histogram[Q as usize + 1] as f64) / m);
+    for count in histogram[1..=Q as usize].iter().rev() {
+        z += *count as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (ALPHA_INF * m * m / z).round() as u64
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_round_trip() {
+        let mut registers = vec![0; DENSE_SIZE - HEADER_SIZE];
+        for index in [0, 1, 2, 3, 1000, REGISTERS - 1] {
+            set_register(&mut registers, index, (index % 64) as u8);
+        }
+        set_register(&mut registers, 2, 63);
+        assert_eq!(1, get_register(&registers, 1));
+        assert_eq!(63, get_register(&registers, 2));
+        assert_eq!(3, get_register(&registers, 3));
+        assert_eq!(1000 % 64, get_register(&registers, 1000) as usize);
+        assert_eq!(63, get_register(&registers, REGISTERS - 1));
+    }
+
+    #[test]
+    fn test_estimates_within_error() {
+        let mut hll = new();
+        assert!(is_valid(&hll));
+        assert_eq!(0, cardinality(&mut hll));
+        for i in 0..100_000 {
+            add(&mut hll, format!("element:{}", i).as_bytes());
+        }
+        assert!(!add(&mut hll, b"element:0"));
+        let estimate = cardinality(&mut hll) as f64;
+        // The standard error with 16384 registers is 0.81%.
+        assert!(
+            (estimate - 100_000.0).abs() / 100_000.0 < 0.02,
+            "{}",
+            estimate
+        );
+        assert_eq!(estimate as u64, cardinality(&mut hll));
+    }
+}
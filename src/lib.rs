@@ -1,5 +1,27 @@
 extern crate core;
 
+pub mod backlog;
+pub mod binary;
+pub mod bitmap;
+pub mod cluster;
+pub mod config_file;
+pub mod connections;
 pub mod data_core;
+pub mod geohash;
+pub mod glob;
+pub mod hyperloglog;
+pub mod listpack;
+pub mod memory;
 pub mod parser;
+pub mod replication;
+pub mod server;
+pub mod set;
+pub mod skiplist;
+pub mod sorted_set;
+pub mod stream;
+pub mod string;
+pub mod testing;
 pub mod tokenizer;
+mod transport;
+
+pub use server::{Server, ServerConfig, ServerHandle};
@@ -0,0 +1,209 @@
+use anyhow::{anyhow, bail};
+
+use crate::binary::ByteString;
+
+/// An element of a listpack, the compact list encoding Redis uses inside RDB
+/// files for streams and small collections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListpackEntry {
+    Integer(i64),
+    String(Vec<u8>),
+}
+
+impl ListpackEntry {
+    pub fn string(value: &[u8]) -> ListpackEntry {
+        ListpackEntry::String(value.to_vec())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            ListpackEntry::Integer(value) => Some(*value),
+            ListpackEntry::String(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+        }
+    }
+
+    /// The element as a string, integers in decimal.
+    pub fn into_string(self) -> ByteString {
+        match self {
+            ListpackEntry::Integer(value) => ByteString::from(value.to_string()),
+            ListpackEntry::String(bytes) => ByteString::from(bytes),
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 6;
+const END: u8 = 0xff;
+
+/// Serializes entries as a listpack: a header with the total size and the
+/// element count, then each element's encoding, data and back length.
+pub fn encode(entries: &[ListpackEntry]) -> Vec<u8> {
+    let mut listpack = vec![0; HEADER_SIZE];
+    for entry in entries {
+        let start = listpack.len();
+        match entry {
+            ListpackEntry::Integer(value) => encode_integer(&mut listpack, *value),
+            ListpackEntry::String(bytes) => encode_string(&mut listpack, bytes),
+        }
+        let length = listpack.len() - start;
+        encode_back_length(&mut listpack, length);
+    }
+    listpack.push(END);
+
+    let total = listpack.len() as u32;
+    listpack[..4].copy_from_slice(&total.to_le_bytes());
+    // Counts that don't fit are recomputed by readers.
+    let count = u16::try_from(entries.len()).unwrap_or(u16::MAX);
+    listpack[4..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+    listpack
+}
+
+fn encode_integer(output: &mut Vec<u8>, value: i64) {
+    if (0..=127).contains(&value) {
+        output.push(value as u8);
+    } else if (-4096..4096).contains(&value) {
+        let value = (value as u16) & 0x1fff;
+        output.extend_from_slice(&[0xc0 | (value >> 8) as u8, value as u8]);
+    } else if let Ok(value) = i16::try_from(value) {
+        output.push(0xf1);
+        output.extend_from_slice(&value.to_le_bytes());
+    } else if (-(1 << 23)..1 << 23).contains(&value) {
+        output.push(0xf2);
+        output.extend_from_slice(&(value as i32).to_le_bytes()[..3]);
+    } else if let Ok(value) = i32::try_from(value) {
+        output.push(0xf3);
+        output.extend_from_slice(&value.to_le_bytes());
+    } else {
+        output.push(0xf4);
+        output.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn encode_string(output: &mut Vec<u8>, bytes: &[u8]) {
+    let length = bytes.len();
+    if length < 1 << 6 {
+        output.push(0x80 | length as u8);
+    } else if length < 1 << 12 {
+        output.extend_from_slice(&[0xe0 | (length >> 8) as u8, length as u8]);
+    } else {
+        output.push(0xf0);
+        output.extend_from_slice(&(length as u32).to_le_bytes());
+    }
+    output.extend_from_slice(bytes);
+}
+
+/// Appends the length of the element before it, seven bits per byte with
+/// the most significant first, so the listpack can be walked backwards.
+fn encode_back_length(output: &mut Vec<u8>, length: usize) {
+    let size = back_length_size(length);
+    for group in (0..size).rev() {
+        let byte = ((length >> (7 * group)) & 0x7f) as u8;
+        output.push(if group == size - 1 { byte } else { byte | 0x80 });
+    }
+}
+
+/// Parses a listpack back into its entries.
+pub fn decode(listpack: &[u8]) -> anyhow::Result<Vec<ListpackEntry>> {
+    let truncated = || anyhow!("truncated listpack");
+    let header = listpack.get(..HEADER_SIZE).ok_or_else(truncated)?;
+    let total = u32::from_le_bytes(header[..4].try_into()?) as usize;
+    if total != listpack.len() {
+        bail!("listpack size doesn't match its header");
+    }
+
+    let mut entries = Vec::new();
+    let mut position = HEADER_SIZE;
+    loop {
+        let encoding = *listpack.get(position).ok_or_else(truncated)?;
+        if encoding == END {
+            break;
+        }
+        let data = |offset: usize, length: usize| {
+            listpack
+                .get(position + offset..position + offset + length)
+                .ok_or_else(truncated)
+        };
+        let sign_extend = |value: u64, bits: u32| ((value << (64 - bits)) as i64) >> (64 - bits);
+        let (entry, length) = match encoding {
+            0x00..=0x7f => (ListpackEntry::Integer(encoding as i64), 1),
+            0x80..=0xbf => {
+                let length = (encoding & 0x3f) as usize;
+                (ListpackEntry::String(data(1, length)?.to_vec()), 1 + length)
+            }
+            0xc0..=0xdf => {
+                let value = (((encoding & 0x1f) as u64) << 8) | data(1, 1)?[0] as u64;
+                (ListpackEntry::Integer(sign_extend(value, 13)), 2)
+            }
+            0xe0..=0xef => {
+                let length = (((encoding & 0x0f) as usize) << 8) | data(1, 1)?[0] as usize;
+                (ListpackEntry::String(data(2, length)?.to_vec()), 2 + length)
+            }
+            0xf0 => {
+                let length = u32::from_le_bytes(data(1, 4)?.try_into()?) as usize;
+                (ListpackEntry::String(data(5, length)?.to_vec()), 5 + length)
+            }
+            0xf1..=0xf4 => {
+                let size = [2, 3, 4, 8][(encoding - 0xf1) as usize];
+                let mut bytes = [0; 8];
+                bytes[..size].copy_from_slice(data(1, size)?);
+                let value = u64::from_le_bytes(bytes);
+                (
+                    ListpackEntry::Integer(sign_extend(value, 8 * size as u32)),
+                    1 + size,
+                )
+            }
+            encoding => bail!("unknown listpack encoding {:#04x}", encoding),
+        };
+        entries.push(entry);
+        position += length + back_length_size(length);
+    }
+    Ok(entries)
+}
+
+/// The size of an element's back length, with the same thresholds as
+/// Redis.
+fn back_length_size(length: usize) -> usize {
+    match length {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2_097_150 => 3,
+        2_097_151..=268_435_454 => 4,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::ByteString;
+    use crate::listpack::{decode, encode, ListpackEntry};
+
+    #[test]
+    fn test_listpack_round_trip() {
+        let entries = vec![
+            ListpackEntry::Integer(0),
+            ListpackEntry::Integer(127),
+            ListpackEntry::Integer(-1),
+            ListpackEntry::Integer(4095),
+            ListpackEntry::Integer(-30_000),
+            ListpackEntry::Integer(1 << 22),
+            ListpackEntry::Integer(-(1 << 30)),
+            ListpackEntry::Integer(1_700_000_000_000),
+            ListpackEntry::string(b"field"),
+            ListpackEntry::String(vec![b'x'; 200]),
+            ListpackEntry::String(vec![b'y'; 5000]),
+        ];
+        let listpack = encode(&entries);
+        assert_eq!(
+            b"\x09\x00\x00\x00\x01\x00\x05\x01\xff",
+            &encode(&[ListpackEntry::Integer(5)])[..]
+        );
+        assert_eq!(entries, decode(&listpack).unwrap());
+        assert!(decode(&listpack[..listpack.len() - 1]).is_err());
+
+        let invalid = ByteString::from(vec![0xff]);
+        assert_eq!(
+            ListpackEntry::String(vec![0xff]),
+            ListpackEntry::string(&invalid)
+        );
+        assert_eq!(invalid, ListpackEntry::string(&invalid).into_string());
+    }
+}
@@ -1,136 +1,198 @@
-use std::str;
-use std::sync::Arc;
+use std::path::PathBuf;
 
-use clap::Parser;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::{mpsc, oneshot};
+use clap::{CommandFactory, Parser};
+use tracing::info;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
 
-use redis_starter_rust::data_core::{Command, ReplicationRole};
-use redis_starter_rust::tokenizer::Token;
-use redis_starter_rust::{data_core, parser, tokenizer};
+use redis_starter_rust::config_file::read_config_file;
+use redis_starter_rust::data_core::{parse_memory, AppendFsync};
+use redis_starter_rust::memory::CountingAllocator;
+use redis_starter_rust::server::{self, BindAddress};
+use redis_starter_rust::{Server, ServerConfig};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 #[derive(clap::Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Args {
+    /// A redis.conf file whose directives are read before the flags, which
+    /// override them.
+    config_file: Option<PathBuf>,
+
     #[arg(short, long, default_value = "6379")]
-    port: u64,
+    port: u16,
+
+    /// Space separated addresses to listen on, IPv4 or IPv6. `*` and `::*`
+    /// stand for all interfaces, and addresses prefixed with `-` may fail to
+    /// bind.
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        allow_hyphen_values = true,
+        default_value = "*"
+    )]
+    bind: Vec<BindAddress>,
 
     #[arg(short, long)]
     replicaof: Option<String>,
-}
 
-#[tokio::main]
-async fn main() {
-    eprintln!("Logs from your program will appear here!");
+    #[arg(long)]
+    replica_announce_ip: Option<String>,
 
-    let args = Args::parse();
+    #[arg(long)]
+    replica_announce_port: Option<u64>,
 
-    let mut replication_role = ReplicationRole::Master;
-    let mut master_host: Option<String> = None;
-    let mut master_port: Option<u64> = None;
+    #[arg(long, default_value = ".")]
+    dir: String,
 
-    if let Some(replica_of) = args.replicaof {
-        eprintln!("Replica of {}", replica_of);
-        replication_role = ReplicationRole::Slave;
-        let (master_host_str, master_host_port_str) = replica_of
-            .split_once(' ')
-            .expect("replica_of split should have two values");
-        master_host = Some(master_host_str.to_string());
-        master_port = Some(master_host_port_str.parse::<u64>().unwrap());
-    }
+    #[arg(long, default_value = "dump.rdb")]
+    dbfilename: String,
 
-    let (tx, rx) = mpsc::channel::<Command>(32);
+    /// RDB save rules as `seconds changes` pairs, or "" to never save.
+    #[arg(long, default_value = "3600 1 300 100 60 10000")]
+    save: String,
 
-    let mut data_core = data_core::DataCore::new(rx, replication_role, master_host, master_port);
+    #[arg(long, default_value = "no", value_parser = ["yes", "no"])]
+    appendonly: String,
 
-    if data_core.is_slave() {
-        data_core
-            .initialize_slaves(args.port)
-            .await
-            .expect("should be able to initialize slaves");
-    }
+    #[arg(long, default_value = "everysec")]
+    appendfsync: AppendFsync,
 
-    let _ = tokio::spawn(async move {
-        data_core.process_command().await;
-    });
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
 
-    let addr = format!("0.0.0.0:{}", args.port.to_string());
+    #[arg(long, default_value = "no", value_parser = ["yes", "no"])]
+    cluster_enabled: String,
 
-    let listener = TcpListener::bind(addr)
-        .await
-        .expect("cannot listen on port 6379");
+    /// The password clients authenticate with as the default user.
+    #[arg(long, default_value = "")]
+    requirepass: String,
+
+    /// `name new-name` to make clients call a command by another name, or
+    /// just `name` to disable it. May be repeated.
+    #[arg(long)]
+    rename_command: Vec<String>,
+
+    /// How much to log: `debug` includes every command and reply,
+    /// `verbose` every command, `notice` what happens to the server and
+    /// `warning` only problems. RUST_LOG overrides it when set.
+    #[arg(
+        long,
+        default_value = "notice",
+        value_parser = ["debug", "verbose", "notice", "warning", "nothing"]
+    )]
+    loglevel: String,
 
-    loop {
-        let tx = tx.clone();
-        let (socket, _) = listener.accept().await.expect("cannot accept connections");
-        tokio::spawn(async move {
-            process_request(socket, &tx).await;
+    #[arg(long, default_value = "512mb", value_parser = parse_memory_arg)]
+    proto_max_bulk_len: usize,
+
+    #[arg(long, default_value = "511")]
+    tcp_backlog: u32,
+
+    #[arg(long, default_value = "300")]
+    tcp_keepalive: u64,
+
+    #[arg(long, default_value = "yes", value_parser = ["yes", "no"])]
+    tcp_nodelay: String,
+}
+
+fn parse_memory_arg(value: &str) -> Result<usize, String> {
+    parse_memory(value)
+        .and_then(|bytes| usize::try_from(bytes).ok())
+        .ok_or_else(|| format!("invalid memory size: {}", value))
+}
+
+/// Parses the command line, with the directives of the config file given
+/// as the first argument, like `redis-server redis.conf --port 7000`,
+/// inserted before the flags so they override the file. The directives that
+/// aren't flags are returned to be set like CONFIG SET does.
+fn parse_args() -> (Args, Vec<(String, String)>) {
+    let mut arguments: Vec<String> = std::env::args().collect();
+    let mut parameters = Vec::new();
+    if let Some(path) = arguments.get(1).filter(|path| !path.starts_with('-')) {
+        let command = Args::command();
+        let known: Vec<&str> = command
+            .get_arguments()
+            .filter_map(|argument| argument.get_long())
+            .filter(|name| !["help", "version"].contains(name))
+            .collect();
+        let config = read_config_file(path.as_ref(), &known).unwrap_or_else(|err| {
+            eprintln!("*** FATAL CONFIG FILE ERROR *** {:#}", err);
+            std::process::exit(1)
         });
+        arguments.splice(1..1, config.arguments);
+        parameters = config.parameters;
     }
+    (Args::parse_from(arguments), parameters)
+}
+
+/// Logs to stderr at the level of `loglevel`, in Redis' terms.
+fn init_logging(loglevel: &str) {
+    let level = match loglevel {
+        "debug" => LevelFilter::TRACE,
+        "verbose" => LevelFilter::DEBUG,
+        "notice" => LevelFilter::INFO,
+        "warning" => LevelFilter::WARN,
+        _ => LevelFilter::OFF,
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()));
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(filter)
+        .init();
 }
 
-async fn process_request<'c>(mut socket: TcpStream, core_tx: &Sender<Command>) {
-    eprintln!("accepted new connection");
-
-    loop {
-        let mut buf = vec![0; 1024];
-        match socket.read(&mut buf).await {
-            Ok(n) => {
-                if n != 0 {
-                    let s = match str::from_utf8(&buf[..n]) {
-                        Ok(v) => v,
-                        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                    };
-
-                    eprintln!("received {:?}", s);
-
-                    let tokens =
-                        tokenizer::parse_resp_tokens_from_str(s).expect("cannot tokenize request");
-                    eprintln!("Tokens: {:?}", tokens);
-
-                    let parser_value =
-                        parser::parse_tokens(&tokens).expect("cannot parse values from tokens");
-                    eprintln!("Parser Value: {:?}", parser_value);
-
-                    if !parser_value.is_array() {
-                        eprintln!("Parent parser value is not an array, exiting");
-                        socket
-                            .shutdown()
-                            .await
-                            .expect("unable to shutdown tcpstream");
-                        break;
-                    }
-
-                    let (tx, rx) = oneshot::channel::<Vec<Token>>();
-
-                    let parser_values = parser_value
-                        .to_vec()
-                        .expect("could not get vec of parser values");
-
-                    let command = Command::new(Arc::new(parser_values.clone()), tx);
-                    core_tx
-                        .send(command)
-                        .await
-                        .expect("should be able to send commands to data core");
-
-                    let response = rx
-                        .await
-                        .expect("should be able to receive a response from data core");
-
-                    let response = tokenizer::serialize_tokens(&response)
-                        .expect("cannot serialize response tokens");
-
-                    socket
-                        .write_all(response.as_bytes())
-                        .await
-                        .expect("cannot write response to tcpstream");
-                    socket.flush().await.expect("cannot flush socket");
-                }
-            }
-            Err(_) => break,
-        }
+#[tokio::main]
+async fn main() {
+    let (args, parameters) = parse_args();
+    init_logging(&args.loglevel);
+    if let Some(config_file) = &args.config_file {
+        info!("configuration loaded from {}", config_file.display());
+    }
+
+    let mut config = ServerConfig::default()
+        .with_port(args.port)
+        .with_bind(args.bind)
+        .with_replica_announce(args.replica_announce_ip, args.replica_announce_port)
+        .with_rdb_file(args.dir, args.dbfilename)
+        .with_save(args.save)
+        .with_append_only(
+            args.appendonly == "yes",
+            args.appendfsync,
+            args.appendfilename,
+        )
+        .with_cluster_enabled(args.cluster_enabled == "yes")
+        .with_requirepass(args.requirepass)
+        .with_proto_max_bulk_len(args.proto_max_bulk_len)
+        .with_tcp(
+            args.tcp_backlog,
+            args.tcp_keepalive,
+            args.tcp_nodelay == "yes",
+        );
+    for (name, value) in parameters {
+        config = config.with_config_parameter(name, value);
+    }
+    for rename in args.rename_command {
+        let (name, new_name) = rename.split_once(' ').unwrap_or((&rename, ""));
+        config = config.with_renamed_command(name.to_string(), new_name.trim().to_string());
+    }
+    if let Some(replica_of) = args.replicaof {
+        info!("replica of {}", replica_of);
+        let (host, port) = replica_of
+            .split_once(' ')
+            .expect("replica_of split should have two values");
+        config = config.with_replicaof(
+            host.to_string(),
+            port.parse().expect("the master port should be a number"),
+        );
     }
-    eprint!("end of process_request")
+
+    let server = Server::spawn(config)
+        .await
+        .unwrap_or_else(|err| panic!("cannot start the server: {:#}", err));
+    server::shutdown_signal().await;
+    server.shutdown().await;
 }
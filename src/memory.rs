@@ -0,0 +1,145 @@
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+use std::alloc::System;
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The allocator that does the work behind [`CountingAllocator`]: jemalloc
+/// or mimalloc with their features, jemalloc if both are on, and the system
+/// allocator otherwise.
+#[cfg(feature = "jemalloc")]
+static INNER: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+static INNER: mimalloc::MiMalloc = mimalloc::MiMalloc;
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+static INNER: System = System;
+
+/// The name of the allocator in use, for the `mem_allocator` field of INFO.
+pub const ALLOCATOR_NAME: &str = if cfg!(feature = "jemalloc") {
+    "jemalloc"
+} else if cfg!(feature = "mimalloc") {
+    "mimalloc"
+} else {
+    "libc"
+};
+
+/// The allocator picked by the features, counting the bytes in use for the
+/// `used_memory` fields of INFO. Programs opt in by registering it, like
+/// `main` does:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOCATOR: CountingAllocator = CountingAllocator;
+/// ```
+pub struct CountingAllocator;
+
+fn allocated(size: usize) {
+    let total = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(total, Ordering::Relaxed);
+}
+
+fn freed(size: usize) {
+    ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = INNER.alloc(layout);
+        if !pointer.is_null() {
+            allocated(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let pointer = INNER.alloc_zeroed(layout);
+        if !pointer.is_null() {
+            allocated(layout.size());
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        INNER.dealloc(pointer, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_pointer = INNER.realloc(pointer, layout, new_size);
+        if !new_pointer.is_null() {
+            freed(layout.size());
+            allocated(new_size);
+        }
+        new_pointer
+    }
+}
+
+/// The bytes allocated through [`CountingAllocator`] and the most there
+/// ever were, both 0 when it isn't the global allocator.
+pub fn used_memory() -> (usize, usize) {
+    (
+        ALLOCATED.load(Ordering::Relaxed),
+        PEAK.load(Ordering::Relaxed),
+    )
+}
+
+/// The resident set size of the process, on Linux.
+pub fn resident_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// The seconds of system and user CPU time the process used, on Linux.
+pub fn cpu_time() -> Option<(f64, f64)> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name in parentheses may contain spaces, the fields
+    // after it don't. utime and stime are the 14th and 15th fields.
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let user = fields.get(11)?.parse::<u64>().ok()?;
+    let system = fields.get(12)?.parse::<u64>().ok()?;
+    // Linux reports them in clock ticks of USER_HZ, which is 100.
+    Some((system as f64 / 100.0, user as f64 / 100.0))
+}
+
+/// Formats a byte count like Redis' `*_human` fields: `1.50M`.
+pub fn human_bytes(bytes: usize) -> String {
+    let units = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    match units.iter().find(|(_, size)| bytes >= *size) {
+        Some((unit, size)) => format!("{:.2}{}", bytes as f64 / *size as f64, unit),
+        None => format!("{}B", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{cpu_time, human_bytes, resident_memory};
+
+    #[test]
+    fn test_human_bytes() {
+        assert_eq!("512B", human_bytes(512));
+        assert_eq!("1.50K", human_bytes(1536));
+        assert_eq!("2.00M", human_bytes(2 * 1024 * 1024));
+        assert_eq!("1.00G", human_bytes(1 << 30));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_reads_process_usage() {
+        assert!(resident_memory().is_some_and(|rss| rss > 0));
+        assert!(cpu_time().is_some());
+    }
+}
@@ -2,15 +2,20 @@ use core::slice::Iter;
 use std::iter::Peekable;
 
 use anyhow::anyhow;
+use tracing::debug;
 
+use crate::binary::ByteString;
 use crate::tokenizer::Token;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParserValue {
     SimpleString(String),
-    BulkString(String),
+    BulkString(ByteString),
     Array(Vec<ParserValue>),
     NullBulkString,
+    Integer(i64),
+    Error(String),
+    NullArray,
 }
 
 impl ParserValue {
@@ -22,9 +27,20 @@ impl ParserValue {
         matches!(self, ParserValue::Array(_))
     }
 
+    /// The value of a simple or bulk string, with the bytes of bulk strings
+    /// that aren't UTF-8 replaced.
     pub fn to_string(self: &ParserValue) -> Option<String> {
         match self {
             ParserValue::SimpleString(s) => Some(s.clone()),
+            ParserValue::BulkString(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The bytes of a simple or bulk string.
+    pub fn to_bytes(self: &ParserValue) -> Option<ByteString> {
+        match self {
+            ParserValue::SimpleString(s) => Some(ByteString::from(s.as_str())),
             ParserValue::BulkString(s) => Some(s.clone()),
             _ => None,
         }
@@ -40,53 +56,50 @@ impl ParserValue {
     pub fn to_tokens(self: &ParserValue) -> Vec<Token> {
         match self {
             ParserValue::SimpleString(s) => {
-                let mut tokens: Vec<Token> = Vec::with_capacity(3);
-                tokens.push(Token::Plus);
-                tokens.push(Token::String(s.clone()));
-                tokens.push(Token::Separator);
-                return tokens;
+                vec![Token::Plus, Token::String(s.clone()), Token::Separator]
             }
             ParserValue::BulkString(s) => {
-                let mut tokens: Vec<Token> = Vec::with_capacity(3);
-                tokens.push(Token::Dollar);
-                tokens.push(Token::Number(s.len() as i64));
-                tokens.push(Token::Separator);
-                tokens.push(Token::String(s.clone()));
-                tokens.push(Token::Separator);
-                return tokens;
+                vec![
+                    Token::Dollar,
+                    Token::Number(s.len() as i64),
+                    Token::Separator,
+                    Token::Bytes(s.to_vec()),
+                    Token::Separator,
+                ]
             }
             ParserValue::Array(arr) => {
-                let mut tokens: Vec<Token> = Vec::with_capacity(3);
-                tokens.push(Token::Asterisk);
-                tokens.push(Token::Number(arr.len() as i64));
-                tokens.push(Token::Separator);
+                let mut tokens: Vec<Token> = vec![
+                    Token::Asterisk,
+                    Token::Number(arr.len() as i64),
+                    Token::Separator,
+                ];
                 for parser_value in arr {
                     tokens.append(&mut parser_value.to_tokens());
                 }
-                return tokens;
+                tokens
             }
             ParserValue::NullBulkString => {
-                let mut tokens: Vec<Token> = Vec::with_capacity(3);
-                tokens.push(Token::Dollar);
-                tokens.push(Token::Number(-1));
-                tokens.push(Token::Separator);
-                return tokens;
+                vec![Token::Dollar, Token::Number(-1), Token::Separator]
+            }
+            ParserValue::Integer(n) => vec![Token::Colon, Token::Number(*n), Token::Separator],
+            ParserValue::Error(s) => {
+                vec![Token::Hyphen, Token::String(s.clone()), Token::Separator]
+            }
+            ParserValue::NullArray => {
+                vec![Token::Asterisk, Token::Number(-1), Token::Separator]
             }
-            _ => todo!(),
         }
     }
 }
 
-pub fn parse_tokens(tokens: &Vec<Token>) -> Option<ParserValue> {
-    if tokens.len() < 1 {
+pub fn parse_tokens(tokens: &[Token]) -> Option<ParserValue> {
+    if tokens.is_empty() {
         return None;
     }
 
     let mut tokens_iter = tokens.iter().peekable();
     let first = tokens_iter.peek().expect("must have at least one token");
 
-    eprintln!("First Token {:?}", first);
-
     match first {
         // Simple String
         Token::Plus => {
@@ -108,7 +121,7 @@ pub fn parse_tokens(tokens: &Vec<Token>) -> Option<ParserValue> {
         Token::Asterisk => match tokens_to_array(&mut tokens_iter) {
             Ok(arr) => Some(arr),
             Err(err) => {
-                eprintln!("{:?}", err);
+                debug!("cannot parse an array: {:#}", err);
                 None
             }
         },
@@ -120,7 +133,7 @@ fn tokens_to_simple_string(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Re
     if !token_iter.next().is_some_and(|t| t.is_plus()) {
         return Err(anyhow!("first token in simple string must be a plus"));
     }
-    let mut str_token = token_iter
+    let str_token = token_iter
         .next()
         .expect("should have a second token for simple string");
 
@@ -165,61 +178,62 @@ fn tokens_to_bulk_string(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Resu
     if !separator_token.is_separator() {
         return Err(anyhow!("fifth token in bulk string must be a separator"));
     }
-    let mut s = String::with_capacity(size_token.to_usize().expect("size_token must be a usize"));
+    let mut s = Vec::with_capacity(size_token.to_usize().expect("size_token must be a usize"));
     for t in str_tokens.iter() {
         match t {
             Token::Plus => {
-                s.push('+');
+                s.push(b'+');
             }
             Token::Hyphen => {
-                s.push('-');
+                s.push(b'-');
             }
             Token::Colon => {
-                s.push(':');
+                s.push(b':');
             }
             Token::Dollar => {
-                s.push('$');
+                s.push(b'$');
             }
             Token::Asterisk => {
-                s.push('*');
+                s.push(b'*');
             }
             Token::Underscore => {
-                s.push('_');
+                s.push(b'_');
             }
             Token::PoundSign => {
-                s.push('#');
+                s.push(b'#');
             }
             Token::Comma => {
-                s.push(',');
+                s.push(b',');
             }
             Token::LeftParenthesis => {
-                s.push('(');
+                s.push(b'(');
             }
             Token::Exclamation => {
-                s.push('!');
+                s.push(b'!');
             }
             Token::Equals => {
-                s.push('=');
+                s.push(b'=');
             }
             Token::Percentage => {
-                s.push('%');
+                s.push(b'%');
             }
             Token::Tilda => {
-                s.push('~');
+                s.push(b'~');
             }
             Token::GreaterThan => {
-                s.push('>');
+                s.push(b'>');
             }
-            Token::String(ts) => s.push_str(ts),
-            Token::Number(n) => s.push_str(n.to_string().as_str()),
+            Token::String(ts) => s.extend_from_slice(ts.as_bytes()),
+            Token::Number(n) => s.extend_from_slice(n.to_string().as_bytes()),
             Token::Separator => {}
+            Token::Bytes(bytes) => s.extend_from_slice(bytes),
         }
     }
     if s.len() != size_token.to_usize().expect("size_token must be a usize") {
         return Err(anyhow!("incorrect string size in bulk token"));
     }
 
-    Ok(ParserValue::BulkString(s))
+    Ok(ParserValue::BulkString(ByteString::from(s)))
 }
 
 fn tokens_to_array(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Result<ParserValue> {
@@ -227,12 +241,10 @@ fn tokens_to_array(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Result<Par
         return Err(anyhow!("first token in bulk string must be an asterisk"));
     }
     let length = token_iter.next().expect("should have a length token");
-    eprintln!("Length Token: {:?}", length);
     if !length.is_number() {
         return Err(anyhow!("second token in array should be length"));
     }
     let length = length.to_i64().expect("number token should have i64");
-    eprintln!("Length: {:?}", length);
     if length < 0 {
         return Err(anyhow!("array length cannot be negative"));
     }
@@ -248,7 +260,6 @@ fn tokens_to_array(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Result<Par
     let mut values: Vec<ParserValue> = Vec::with_capacity(length as usize);
     for _ in 0..length {
         let first = token_iter.peek().expect("should have next token in array");
-        eprintln!("First Array Token: {:?}", first);
         match first {
             Token::Plus => {
                 let simple_string = tokens_to_simple_string(token_iter);
@@ -263,9 +274,7 @@ fn tokens_to_array(token_iter: &mut Peekable<Iter<Token>>) -> anyhow::Result<Par
                 if let Ok(bulk_string) = bulk_string {
                     values.push(bulk_string);
                 } else {
-                    let err = bulk_string.err().unwrap();
-                    eprintln!("{:?}", err);
-                    return Err(err);
+                    return Err(bulk_string.err().unwrap());
                 }
             }
             Token::Asterisk => {
@@ -289,7 +298,7 @@ mod tests {
 
     #[test]
     fn test_parses_bulk_string_with_negative_number() {
-        let tokens = vec![
+        let tokens = [
             Token::Dollar,
             Token::Number(2),
             Token::Separator,
@@ -300,16 +309,14 @@ mod tests {
         let result = tokens_to_bulk_string(&mut tokens.iter().peekable());
         assert!(result.is_ok());
         assert_eq!(
-            ParserValue::BulkString("-1".to_string())
-                .to_string()
-                .unwrap(),
+            ParserValue::BulkString("-1".into()).to_string().unwrap(),
             result.unwrap().to_string().unwrap()
         );
     }
 
     #[test]
     fn test_parses_bulk_string() {
-        let tokens = vec![
+        let tokens = [
             Token::Dollar,
             Token::Number(5),
             Token::Separator,
@@ -320,9 +327,7 @@ mod tests {
         let result = tokens_to_bulk_string(&mut tokens.iter().peekable());
         assert!(result.is_ok());
         assert_eq!(
-            ParserValue::BulkString("PSYNC".to_string())
-                .to_string()
-                .unwrap(),
+            ParserValue::BulkString("PSYNC".into()).to_string().unwrap(),
             result.unwrap().to_string().unwrap()
         );
     }
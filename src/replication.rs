@@ -0,0 +1,563 @@
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::binary::ByteString;
+use crate::data_core::Command;
+use crate::parser::ParserValue;
+use crate::tokenizer;
+use crate::tokenizer::Token;
+
+/// Length of the delimiter that ends an RDB file streamed with `$EOF:`.
+const EOF_DELIMITER_LENGTH: usize = 40;
+
+/// Most arguments a command can have, as in Redis. Larger `*<count>` headers
+/// are refused before anything is allocated for them.
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Longest `*<count>` or `$<length>` line, as in Redis' PROTO_INLINE_MAX_SIZE.
+/// A peer that sends more without ending the line is refused instead of
+/// buffered, and no line is searched further than this on each read.
+const MAX_HEADER_LINE_LEN: usize = 64 * 1024;
+
+/// Arguments reserved up front. The count comes from the peer, so room for
+/// the rest is made as they arrive.
+const PREALLOCATED_ARGUMENTS: usize = 16;
+
+/// A replica's connection to its master. Reads are buffered so the RDB
+/// payload and the command stream that follow the handshake can arrive in
+/// any chunks.
+#[derive(Debug)]
+pub struct MasterLink {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    /// The largest bulk string accepted in the command stream, like the
+    /// proto-max-bulk-len of client connections.
+    max_bulk_len: usize,
+}
+
+impl MasterLink {
+    pub async fn connect(address: &str) -> anyhow::Result<MasterLink> {
+        let stream = TcpStream::connect(address).await?;
+        Ok(MasterLink {
+            stream,
+            buffer: Vec::new(),
+            max_bulk_len: 512 * 1024 * 1024,
+        })
+    }
+
+    pub fn with_max_bulk_len(self: MasterLink, max_bulk_len: usize) -> MasterLink {
+        MasterLink {
+            max_bulk_len,
+            ..self
+        }
+    }
+
+    async fn fill_buffer(self: &mut MasterLink) -> anyhow::Result<()> {
+        let mut chunk = [0; 4096];
+        let read = self.stream.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("master closed the replication link");
+        }
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(())
+    }
+
+    pub async fn send(self: &mut MasterLink, arguments: &[&str]) -> anyhow::Result<()> {
+        let command = ParserValue::Array(
+            arguments
+                .iter()
+                .map(|argument| ParserValue::BulkString(ByteString::from(*argument)))
+                .collect(),
+        );
+        let command = tokenizer::serialize_tokens_to_bytes(&command.to_tokens())?;
+        self.stream.write_all(&command).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a single reply line, without its CRLF.
+    pub async fn read_line(self: &mut MasterLink) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = find_line_end(&self.buffer, 0) {
+                let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                self.buffer.drain(..end + 2);
+                return Ok(line);
+            }
+            self.fill_buffer().await?;
+        }
+    }
+
+    /// Reads the RDB file sent after FULLRESYNC, either as
+    /// `$<length>\r\n<bytes>`, which unlike a bulk string has no trailing
+    /// CRLF, or streamed as `$EOF:<delimiter>\r\n<bytes><delimiter>` by a
+    /// diskless master.
+    pub async fn read_rdb(self: &mut MasterLink) -> anyhow::Result<Vec<u8>> {
+        let header = self.read_line().await?;
+        if let Some(delimiter) = header.strip_prefix("$EOF:") {
+            if delimiter.len() != EOF_DELIMITER_LENGTH {
+                bail!("invalid RDB delimiter {:?}", delimiter);
+            }
+            return self.read_until(delimiter.as_bytes()).await;
+        }
+        let length = header
+            .strip_prefix('$')
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or_else(|| anyhow!("expected an RDB payload, got {:?}", header))?;
+        while self.buffer.len() < length {
+            self.fill_buffer().await?;
+        }
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    /// Reads up to `delimiter`, returning what came before it.
+    async fn read_until(self: &mut MasterLink, delimiter: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut searched = 0;
+        loop {
+            if let Some(position) = self.buffer[searched..]
+                .windows(delimiter.len())
+                .position(|window| window == delimiter)
+            {
+                let mut bytes = self
+                    .buffer
+                    .drain(..searched + position + delimiter.len())
+                    .collect::<Vec<u8>>();
+                bytes.truncate(searched + position);
+                return Ok(bytes);
+            }
+            searched = self.buffer.len().saturating_sub(delimiter.len() - 1);
+            self.fill_buffer().await?;
+        }
+    }
+
+    /// Reads the next command of the replication stream, with the number of
+    /// bytes it took. Bulk strings longer than the link's limit are refused
+    /// from their header on, before they are buffered.
+    pub async fn read_command(self: &mut MasterLink) -> anyhow::Result<(Vec<ByteString>, usize)> {
+        loop {
+            if let Some((command, length)) = parse_bounded_command(&self.buffer, self.max_bulk_len)?
+            {
+                self.buffer.drain(..length);
+                return Ok((command, length));
+            }
+            self.fill_buffer().await?;
+        }
+    }
+}
+
+/// Steps of the replication handshake, each sending one command and
+/// checking the master's reply before moving on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeState {
+    Ping,
+    ListeningPort,
+    IpAddress,
+    Capabilities,
+    Psync,
+}
+
+/// The master's answer to PSYNC.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PsyncReply {
+    /// The RDB payload follows, see [`MasterLink::read_rdb`].
+    FullResync { replication_id: String, offset: i64 },
+    /// The stream continues from the requested offset, possibly under a new
+    /// replication ID.
+    Continue { replication_id: Option<String> },
+}
+
+impl MasterLink {
+    /// Runs the replication handshake up to the master's reply to PSYNC,
+    /// announcing `listening_port` and, when given, `ip_address` as where
+    /// this replica can be reached. Asks to continue from `resume` (a
+    /// replication ID and the offset of the next byte wanted) when given.
+    pub async fn handshake(
+        self: &mut MasterLink,
+        listening_port: u64,
+        ip_address: Option<&str>,
+        resume: Option<(&str, i64)>,
+    ) -> anyhow::Result<PsyncReply> {
+        let listening_port = listening_port.to_string();
+        let (replication_id, offset) = match resume {
+            Some((replication_id, offset)) => (replication_id.to_string(), offset.to_string()),
+            None => ("?".to_string(), "-1".to_string()),
+        };
+        let mut state = HandshakeState::Ping;
+        loop {
+            let command = match state {
+                HandshakeState::Ping => vec!["PING"],
+                HandshakeState::ListeningPort => {
+                    vec!["REPLCONF", "listening-port", listening_port.as_str()]
+                }
+                HandshakeState::IpAddress => {
+                    vec!["REPLCONF", "ip-address", ip_address.unwrap_or_default()]
+                }
+                HandshakeState::Capabilities => {
+                    vec!["REPLCONF", "capa", "eof", "capa", "psync2"]
+                }
+                HandshakeState::Psync => vec!["PSYNC", replication_id.as_str(), offset.as_str()],
+            };
+            self.send(&command).await?;
+            let reply = self.read_line().await?;
+            debug!(?state, reply, "handshake reply from the master");
+
+            state = match (state, reply.as_str()) {
+                (HandshakeState::Ping, "+PONG") => HandshakeState::ListeningPort,
+                (HandshakeState::ListeningPort, "+OK") if ip_address.is_some() => {
+                    HandshakeState::IpAddress
+                }
+                (HandshakeState::ListeningPort | HandshakeState::IpAddress, "+OK") => {
+                    HandshakeState::Capabilities
+                }
+                (HandshakeState::Capabilities, "+OK") => HandshakeState::Psync,
+                (HandshakeState::Psync, reply) => return parse_psync_reply(reply),
+                (state, reply) => {
+                    bail!("unexpected reply during handshake {:?}: {:?}", state, reply)
+                }
+            };
+        }
+    }
+}
+
+fn is_replication_id(replication_id: &str) -> bool {
+    replication_id.len() == 40 && replication_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Parses a `+FULLRESYNC <replication id> <offset>` or
+/// `+CONTINUE [replication id]` reply.
+fn parse_psync_reply(reply: &str) -> anyhow::Result<PsyncReply> {
+    let invalid = || anyhow!("unexpected reply to PSYNC: {:?}", reply);
+    let mut parts = reply.strip_prefix('+').ok_or_else(invalid)?.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("FULLRESYNC"), Some(replication_id), Some(offset), None)
+            if is_replication_id(replication_id) =>
+        {
+            Ok(PsyncReply::FullResync {
+                replication_id: replication_id.to_string(),
+                offset: offset.parse().map_err(|_| invalid())?,
+            })
+        }
+        (Some("CONTINUE"), None, _, _) => Ok(PsyncReply::Continue {
+            replication_id: None,
+        }),
+        (Some("CONTINUE"), Some(replication_id), None, _) if is_replication_id(replication_id) => {
+            Ok(PsyncReply::Continue {
+                replication_id: Some(replication_id.to_string()),
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+pub(crate) fn find_line_end(buffer: &[u8], start: usize) -> Option<usize> {
+    buffer
+        .get(start..)?
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .map(|position| start + position)
+}
+
+/// Parses a RESP array of bulk strings from the front of `buffer`, returning
+/// it with the number of bytes it took, or `None` if the buffer only holds
+/// part of it.
+pub fn parse_command(buffer: &[u8]) -> anyhow::Result<Option<(Vec<ByteString>, usize)>> {
+    parse_bounded_command(buffer, usize::MAX)
+}
+
+/// Like [`parse_command`], but fails as soon as a bulk string header
+/// announces more than `max_bulk_len` bytes, before they are received.
+/// Either way, commands with more than [`MAX_MULTIBULK_LEN`] arguments, or
+/// header lines longer than [`MAX_HEADER_LINE_LEN`], are refused as soon as
+/// they are received.
+pub fn parse_bounded_command(
+    buffer: &[u8],
+    max_bulk_len: usize,
+) -> anyhow::Result<Option<(Vec<ByteString>, usize)>> {
+    let header = |start: usize, prefix: char| -> anyhow::Result<Option<(usize, usize)>> {
+        let line_limit = buffer.len().min(start + MAX_HEADER_LINE_LEN + 2);
+        let Some(end) = find_line_end(&buffer[..line_limit], start) else {
+            if buffer.len() - start <= MAX_HEADER_LINE_LEN {
+                return Ok(None);
+            }
+            match (start, buffer[start]) {
+                (0, b'*') => bail!("invalid multibulk length"),
+                (0, _) => bail!("too big inline request"),
+                _ => bail!("invalid bulk length"),
+            }
+        };
+        let line = String::from_utf8_lossy(&buffer[start..end]);
+        let value = line
+            .strip_prefix(prefix)
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| anyhow!("unexpected line in command: {:?}", line))?;
+        Ok(Some((value, end + 2)))
+    };
+
+    let Some((count, mut position)) = header(0, '*')? else {
+        return Ok(None);
+    };
+    if count > MAX_MULTIBULK_LEN {
+        bail!("invalid multibulk length");
+    }
+    let mut arguments = Vec::with_capacity(count.min(PREALLOCATED_ARGUMENTS));
+    for _ in 0..count {
+        let Some((length, start)) = header(position, '$')? else {
+            return Ok(None);
+        };
+        if length > max_bulk_len {
+            bail!("invalid bulk length {}", length);
+        }
+        let end = start
+            .checked_add(length)
+            .ok_or_else(|| anyhow!("invalid bulk length {}", length))?;
+        if buffer.len() < end.saturating_add(2) {
+            return Ok(None);
+        }
+        if &buffer[end..end + 2] != b"\r\n" {
+            bail!("expected CRLF after a bulk string");
+        }
+        arguments.push(ByteString::from(&buffer[start..end]));
+        position = end + 2;
+    }
+    Ok(Some((arguments, position)))
+}
+
+/// The replication link of a replica's data core, with the commands read by
+/// [`follow_master`] arriving on `commands`. Dropping it closes the link.
+#[derive(Debug)]
+pub struct MasterConnection {
+    commands: Receiver<Command>,
+    task: JoinHandle<()>,
+    pub connected_at: Instant,
+}
+
+impl MasterConnection {
+    /// Follows `link`, whose stream continues after replication offset
+    /// `offset`.
+    pub fn spawn(link: MasterLink, offset: i64) -> MasterConnection {
+        let (tx, commands) = mpsc::channel::<Command>(32);
+        MasterConnection {
+            commands,
+            task: tokio::spawn(follow_master(link, tx, offset)),
+            connected_at: Instant::now(),
+        }
+    }
+
+    pub async fn recv(self: &mut MasterConnection) -> Option<Command> {
+        self.commands.recv().await
+    }
+}
+
+impl Drop for MasterConnection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Applies the commands the master propagates to the data core until the
+/// link closes, along with their size so the data core's replication offset
+/// follows the master's. The master doesn't expect replies to them, except for
+/// `REPLCONF GETACK`, which is answered with the offset of the stream
+/// processed before it, starting from `offset`.
+pub async fn follow_master(mut link: MasterLink, core_tx: Sender<Command>, mut offset: i64) {
+    loop {
+        let (arguments, length) = match link.read_command().await {
+            Ok(command) => command,
+            Err(err) => {
+                warn!("replication link closed: {:#}", err);
+                break;
+            }
+        };
+        debug!(?arguments, "replicated command");
+
+        if is_getack(&arguments) {
+            if let Err(err) = link.send(&["REPLCONF", "ACK", &offset.to_string()]).await {
+                warn!("replication link closed: {:#}", err);
+                break;
+            }
+        }
+        offset += length as i64;
+
+        let (tx, _) = oneshot::channel::<Vec<Token>>();
+        if core_tx
+            .send(Command::new(arguments, tx).from_master(length))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn is_getack(arguments: &[ByteString]) -> bool {
+    matches!(
+        arguments,
+        [command, subcommand, _]
+            if command.eq_ignore_ascii_case("replconf") && subcommand.eq_ignore_ascii_case("getack")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use crate::data_core::Command;
+    use crate::replication::{
+        follow_master, parse_bounded_command, parse_command, parse_psync_reply, MasterLink,
+        PsyncReply,
+    };
+
+    #[test]
+    fn test_parses_commands_from_a_partial_stream() {
+        let stream = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$1\r\n1\r\n*1\r\n$4\r\nPI";
+        let (command, length) = parse_command(stream).unwrap().unwrap();
+        assert_eq!(vec!["SET", "foo", "1"], command);
+        assert_eq!(29, length);
+        assert!(parse_command(&stream[length..]).unwrap().is_none());
+        assert!(parse_command(b"+OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_refuses_oversized_multibulk_headers() {
+        let err = parse_command(b"*100000000000\r\n").unwrap_err();
+        assert_eq!("invalid multibulk length", err.to_string());
+        assert!(parse_command(b"*1048577\r\n").is_err());
+        // The largest count allowed waits for its arguments.
+        assert!(parse_command(b"*1048576\r\n$4\r\nPING\r\n")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_refuses_unterminated_header_lines() {
+        let long = vec![b'1'; 64 * 1024];
+        let multibulk = [&b"*"[..], &long].concat();
+        assert!(parse_command(&multibulk[..64 * 1024]).unwrap().is_none());
+        let err = parse_command(&multibulk).unwrap_err();
+        assert_eq!("invalid multibulk length", err.to_string());
+        let err = parse_command(&[&b"*1\r\n$"[..], &long].concat()).unwrap_err();
+        assert_eq!("invalid bulk length", err.to_string());
+        let err = parse_command(&[&b"PING "[..], &long].concat()).unwrap_err();
+        assert_eq!("too big inline request", err.to_string());
+    }
+
+    #[test]
+    fn test_refuses_malformed_bulk_strings() {
+        let err = parse_command(b"*1\r\n$4\r\nPINGxx").unwrap_err();
+        assert_eq!("expected CRLF after a bulk string", err.to_string());
+        let huge = format!("*1\r\n${}\r\nPING\r\n", usize::MAX);
+        let err = parse_command(huge.as_bytes()).unwrap_err();
+        assert_eq!(
+            format!("invalid bulk length {}", usize::MAX),
+            err.to_string()
+        );
+        // Lengths above the limit are refused before the bytes arrive.
+        let err = parse_bounded_command(b"*1\r\n$17\r\n", 16).unwrap_err();
+        assert_eq!("invalid bulk length 17", err.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_acknowledges_processed_offset() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let link = MasterLink::connect(&address).await.unwrap();
+        let (mut master, _) = listener.accept().await.unwrap();
+        let (core_tx, mut core_rx) = mpsc::channel::<Command>(32);
+        tokio::spawn(follow_master(link, core_tx, 0));
+
+        let getack = "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+        let ping = "*1\r\n$4\r\nPING\r\n";
+        master
+            .write_all(format!("{}{}{}", getack, ping, getack).as_bytes())
+            .await
+            .unwrap();
+
+        let mut lengths = 0;
+        for name in ["REPLCONF", "PING", "REPLCONF"] {
+            let command = core_rx.recv().await.unwrap();
+            assert_eq!(name, command.arguments[0]);
+            lengths += command.replication_length.unwrap();
+        }
+        assert_eq!(2 * getack.len() + ping.len(), lengths);
+        let expected = format!(
+            "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$1\r\n0\r\n*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$2\r\n{}\r\n",
+            getack.len() + ping.len()
+        );
+        let mut replies = vec![0; expected.len()];
+        master.read_exact(&mut replies).await.unwrap();
+        assert_eq!(expected, String::from_utf8(replies).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_batched_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut link = MasterLink::connect(&address).await.unwrap();
+        let (mut master, _) = listener.accept().await.unwrap();
+
+        let replication_id = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+        let replies = format!(
+            "+PONG\r\n+OK\r\n+OK\r\n+FULLRESYNC {} 7\r\n$4\r\nRDB!*1\r\n$4\r\nPING\r\n",
+            replication_id
+        );
+        master.write_all(replies.as_bytes()).await.unwrap();
+
+        assert_eq!(
+            PsyncReply::FullResync {
+                replication_id: replication_id.to_string(),
+                offset: 7
+            },
+            link.handshake(6380, None, None).await.unwrap()
+        );
+        assert_eq!(b"RDB!".to_vec(), link.read_rdb().await.unwrap());
+        assert_eq!(vec!["PING"], link.read_command().await.unwrap().0);
+
+        assert!(parse_psync_reply("+FULLRESYNC short 0").is_err());
+        assert!(parse_psync_reply("-ERR no").is_err());
+        assert_eq!(
+            PsyncReply::Continue {
+                replication_id: None
+            },
+            parse_psync_reply("+CONTINUE").unwrap()
+        );
+        assert_eq!(
+            PsyncReply::Continue {
+                replication_id: Some(replication_id.to_string())
+            },
+            parse_psync_reply(&format!("+CONTINUE {}", replication_id)).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reads_eof_delimited_rdb() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut link = MasterLink::connect(&address).await.unwrap();
+        let (mut master, _) = listener.accept().await.unwrap();
+
+        let delimiter = "d".repeat(40);
+        let chunks = [
+            format!("$EOF:{}\r\nRDB", delimiter),
+            format!("!!{}", &delimiter[..25]),
+            format!("{}*1\r\n$4\r\nPING\r\n", &delimiter[25..]),
+        ];
+        tokio::spawn(async move {
+            for chunk in chunks {
+                master.write_all(chunk.as_bytes()).await.unwrap();
+                master.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            master
+        });
+
+        assert_eq!(b"RDB!!".to_vec(), link.read_rdb().await.unwrap());
+        assert_eq!(vec!["PING"], link.read_command().await.unwrap().0);
+    }
+}
@@ -0,0 +1,1174 @@
+use std::future::{poll_fn, Future};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+
+use anyhow::{anyhow, bail};
+use bytes::{Buf, BytesMut};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc::{Sender, UnboundedReceiver};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, error, field, info, info_span, warn, Instrument};
+
+use crate::cluster::bus::BUS_PORT_OFFSET;
+use crate::connections::Connections;
+use crate::data_core::{AppendFsync, Client, Command, DataCore, ReplicationRole};
+use crate::replication::parse_bounded_command;
+use crate::tokenizer;
+use crate::tokenizer::Token;
+use crate::transport::Transport;
+
+/// Settings of client connections. The data core owns them, so CONFIG SET
+/// also changes them for the connections already open.
+#[derive(Debug)]
+pub struct ConnectionConfig {
+    /// The largest bulk string a client may send, in bytes.
+    pub proto_max_bulk_len: AtomicUsize,
+    /// How many seconds a client may stay idle before it is disconnected,
+    /// or 0 to never disconnect idle clients.
+    pub timeout: AtomicU64,
+    /// The interval of TCP keepalive probes in seconds, or 0 to not send
+    /// them.
+    pub tcp_keepalive: AtomicU64,
+    /// Whether to set TCP_NODELAY, so small replies aren't delayed.
+    pub tcp_nodelay: AtomicBool,
+    /// The length of the queue of connections not accepted yet, only used
+    /// when binding.
+    pub tcp_backlog: AtomicU32,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> ConnectionConfig {
+        ConnectionConfig {
+            proto_max_bulk_len: AtomicUsize::new(512 * 1024 * 1024),
+            timeout: AtomicU64::new(0),
+            tcp_keepalive: AtomicU64::new(300),
+            tcp_nodelay: AtomicBool::new(true),
+            tcp_backlog: AtomicU32::new(511),
+        }
+    }
+}
+
+/// Listens on `address` with the backlog of `config`.
+pub fn bind(address: SocketAddr, config: &ConnectionConfig) -> io::Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            // Leave IPv4 to its own listener, which can share the port.
+            SockRef::from(&socket).set_only_v6(true)?;
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    socket.listen(config.tcp_backlog.load(Ordering::Relaxed))
+}
+
+/// Applies the TCP options of `config` to an accepted connection.
+pub(crate) fn configure_socket<S>(socket: &S, config: &ConnectionConfig) -> io::Result<()>
+where
+    for<'s> SockRef<'s>: From<&'s S>,
+{
+    let socket = SockRef::from(socket);
+    socket.set_nodelay(config.tcp_nodelay.load(Ordering::Relaxed))?;
+    let keepalive = config.tcp_keepalive.load(Ordering::Relaxed);
+    if keepalive > 0 {
+        // Like Redis: probes start after the interval, then are sent every
+        // third of it.
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(keepalive))
+            .with_interval(Duration::from_secs((keepalive / 3).max(1)));
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Accepts a connection on whichever listener gets one first.
+async fn accept(listeners: &[TcpListener]) -> io::Result<TcpStream> {
+    poll_fn(|cx| {
+        for listener in listeners {
+            if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                return Poll::Ready(accepted.map(|(socket, _)| socket));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Accepts connections on all `listeners` and serves their commands until `shutdown`
+/// completes. Then no more connections are accepted, clients are told to
+/// stop once their in-flight command is answered, and this returns when
+/// they all closed. Dropping the last sender of `core_tx` then lets the data
+/// core finish.
+///
+/// With the `io-uring` feature, connections are served with io_uring when
+/// the kernel allows it.
+pub(crate) async fn serve(
+    listeners: Vec<TcpListener>,
+    core_tx: Sender<Command>,
+    config: Arc<ConnectionConfig>,
+    connections: Connections,
+    shutdown: impl Future<Output = ()>,
+) {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if crate::transport::uring::is_supported() {
+        return crate::transport::uring::serve(listeners, core_tx, config, connections, shutdown)
+            .await;
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut clients = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        let socket = tokio::select! {
+            accepted = accept(&listeners) => match accepted {
+                Ok(socket) => {
+                    if let Err(err) = configure_socket(&socket, &config) {
+                        warn!("cannot set socket options: {}", err);
+                    }
+                    socket
+                }
+                Err(err) => {
+                    warn!("cannot accept connection: {}", err);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let core_tx = core_tx.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        let config = config.clone();
+        let connections = connections.clone();
+        clients.spawn(async move {
+            let address = socket.peer_addr().ok();
+            process_request(
+                socket,
+                address,
+                &connections,
+                &core_tx,
+                &config,
+                shutdown_rx,
+            )
+            .await;
+        });
+        while let Some(served) = clients.try_join_next() {
+            log_panic(served);
+        }
+    }
+
+    info!(
+        clients = clients.len(),
+        "shutting down, waiting for clients"
+    );
+    drop(listeners);
+    let _ = shutdown_tx.send(true);
+    while let Some(served) = clients.join_next().await {
+        log_panic(served);
+    }
+}
+
+/// Reports a connection task that panicked. Only that connection is lost:
+/// its socket was closed as the task unwound.
+pub(crate) fn log_panic(served: Result<(), JoinError>) {
+    if let Err(err) = served {
+        if err.is_panic() {
+            error!("a connection task panicked: {}", err);
+        }
+    }
+}
+
+/// Completes on CTRL-C, or on SIGTERM on Unix.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("cannot listen for CTRL-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("cannot listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// An address to listen on. Binding optional ones may fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    pub optional: bool,
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    /// Parses addresses like the `bind` directive of Redis: `*` and `::*`
+    /// stand for all interfaces, and a `-` prefix makes them optional.
+    fn from_str(value: &str) -> Result<BindAddress, String> {
+        let (optional, address) = match value.strip_prefix('-') {
+            Some(address) => (true, address),
+            None => (false, value),
+        };
+        let ip = match address {
+            "*" => IpAddr::from([0, 0, 0, 0]),
+            "::*" => IpAddr::from([0u16; 8]),
+            address => address
+                .parse()
+                .map_err(|_| format!("invalid bind address: {}", address))?,
+        };
+        Ok(BindAddress { ip, optional })
+    }
+}
+
+/// Everything a server is started with. The defaults give a server on an
+/// ephemeral port of the loopback interface that keeps its data in memory
+/// only, as suits embedding it; the other settings start like in Redis.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    port: u16,
+    bind: Vec<BindAddress>,
+    replicaof: Option<(String, u16)>,
+    replica_announce_ip: Option<String>,
+    replica_announce_port: Option<u64>,
+    dir: String,
+    dbfilename: String,
+    save: String,
+    appendonly: bool,
+    appendfsync: AppendFsync,
+    appendfilename: String,
+    cluster_enabled: bool,
+    requirepass: String,
+    renamed_commands: Vec<(String, String)>,
+    config_parameters: Vec<(String, String)>,
+    proto_max_bulk_len: usize,
+    tcp_backlog: u32,
+    tcp_keepalive: u64,
+    tcp_nodelay: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            port: 0,
+            bind: vec![BindAddress {
+                ip: IpAddr::from([127, 0, 0, 1]),
+                optional: false,
+            }],
+            replicaof: None,
+            replica_announce_ip: None,
+            replica_announce_port: None,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            save: String::new(),
+            appendonly: false,
+            appendfsync: AppendFsync::Everysec,
+            appendfilename: "appendonly.aof".to_string(),
+            cluster_enabled: false,
+            requirepass: String::new(),
+            renamed_commands: Vec::new(),
+            config_parameters: Vec::new(),
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            tcp_backlog: 511,
+            tcp_keepalive: 300,
+            tcp_nodelay: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The port to listen on, or 0 for one picked by the system.
+    pub fn with_port(self: ServerConfig, port: u16) -> ServerConfig {
+        ServerConfig { port, ..self }
+    }
+
+    /// The addresses to listen on, all with the same port.
+    pub fn with_bind(self: ServerConfig, bind: Vec<BindAddress>) -> ServerConfig {
+        ServerConfig { bind, ..self }
+    }
+
+    /// Starts as a replica of the master at `host` and `port`.
+    pub fn with_replicaof(self: ServerConfig, host: String, port: u16) -> ServerConfig {
+        ServerConfig {
+            replicaof: Some((host, port)),
+            ..self
+        }
+    }
+
+    pub fn with_replica_announce(
+        self: ServerConfig,
+        ip: Option<String>,
+        port: Option<u64>,
+    ) -> ServerConfig {
+        ServerConfig {
+            replica_announce_ip: ip,
+            replica_announce_port: port,
+            ..self
+        }
+    }
+
+    pub fn with_rdb_file(self: ServerConfig, dir: String, dbfilename: String) -> ServerConfig {
+        ServerConfig {
+            dir,
+            dbfilename,
+            ..self
+        }
+    }
+
+    /// The RDB save rules, as in `CONFIG SET save`. Empty to never save.
+    pub fn with_save(self: ServerConfig, save: String) -> ServerConfig {
+        ServerConfig { save, ..self }
+    }
+
+    pub fn with_append_only(
+        self: ServerConfig,
+        appendonly: bool,
+        appendfsync: AppendFsync,
+        appendfilename: String,
+    ) -> ServerConfig {
+        ServerConfig {
+            appendonly,
+            appendfsync,
+            appendfilename,
+            ..self
+        }
+    }
+
+    /// Joins a cluster, with its bus on the port plus
+    /// [`BUS_PORT_OFFSET`].
+    pub fn with_cluster_enabled(self: ServerConfig, cluster_enabled: bool) -> ServerConfig {
+        ServerConfig {
+            cluster_enabled,
+            ..self
+        }
+    }
+
+    /// The password of the default user, empty to need none.
+    pub fn with_requirepass(self: ServerConfig, requirepass: String) -> ServerConfig {
+        ServerConfig {
+            requirepass,
+            ..self
+        }
+    }
+
+    /// Makes clients call the command `name` by `new_name`, or disables
+    /// it when `new_name` is empty.
+    pub fn with_renamed_command(
+        mut self: ServerConfig,
+        name: String,
+        new_name: String,
+    ) -> ServerConfig {
+        self.renamed_commands.push((name, new_name));
+        self
+    }
+
+    /// Sets a parameter like CONFIG SET would, once the server starts.
+    pub fn with_config_parameter(
+        mut self: ServerConfig,
+        name: String,
+        value: String,
+    ) -> ServerConfig {
+        self.config_parameters.push((name, value));
+        self
+    }
+
+    pub fn with_proto_max_bulk_len(self: ServerConfig, proto_max_bulk_len: usize) -> ServerConfig {
+        ServerConfig {
+            proto_max_bulk_len,
+            ..self
+        }
+    }
+
+    pub fn with_tcp(
+        self: ServerConfig,
+        tcp_backlog: u32,
+        tcp_keepalive: u64,
+        tcp_nodelay: bool,
+    ) -> ServerConfig {
+        ServerConfig {
+            tcp_backlog,
+            tcp_keepalive,
+            tcp_nodelay,
+            ..self
+        }
+    }
+}
+
+/// A server running in the background of the current Tokio runtime, for
+/// `main` as well as programs and tests that embed it instead of starting
+/// a process.
+pub struct Server;
+
+impl Server {
+    /// Loads the data set, binds the listeners, syncs with the master of
+    /// a replica and starts serving.
+    pub async fn spawn(config: ServerConfig) -> anyhow::Result<ServerHandle> {
+        let (core_tx, core_rx) = mpsc::channel::<Command>(32);
+        let (role, master_host, master_port) = match config.replicaof {
+            Some((host, port)) => (ReplicationRole::Slave, Some(host), Some(port as u64)),
+            None => (ReplicationRole::Master, None, None),
+        };
+        let mut data_core = DataCore::new(core_rx, role, master_host, master_port)
+            .with_replica_announce(config.replica_announce_ip, config.replica_announce_port)
+            .with_rdb_file(config.dir, config.dbfilename)
+            .with_save_rules(&config.save)?
+            .with_append_only(config.appendonly, config.appendfsync, config.appendfilename)
+            .with_cluster_enabled(config.cluster_enabled)
+            .with_requirepass(&config.requirepass)
+            .with_proto_max_bulk_len(config.proto_max_bulk_len)
+            .with_tcp(config.tcp_backlog, config.tcp_keepalive, config.tcp_nodelay);
+        for (name, new_name) in &config.renamed_commands {
+            data_core = data_core.with_renamed_command(name, new_name)?;
+        }
+        for (name, value) in &config.config_parameters {
+            data_core = data_core.with_config_parameter(name, value)?;
+        }
+        let connection_config = data_core.connection_config();
+
+        // With port 0 the first listener picks the port the others share.
+        let mut port = config.port;
+        let mut listeners = Vec::new();
+        for address in config.bind {
+            match bind(SocketAddr::new(address.ip, port), &connection_config) {
+                Ok(listener) => {
+                    port = listener.local_addr()?.port();
+                    listeners.push(listener);
+                }
+                Err(err) if address.optional => {
+                    warn!("cannot listen on {}: {}", address.ip, err)
+                }
+                Err(err) => bail!("cannot listen on {}:{}: {}", address.ip, port, err),
+            }
+        }
+        let local_addr = listeners
+            .first()
+            .ok_or_else(|| anyhow!("cannot listen on any of the bind addresses"))?
+            .local_addr()?;
+
+        data_core = data_core.with_port(port as u64);
+        data_core.load_data()?;
+        if data_core.is_slave() {
+            data_core
+                .initialize_slaves()
+                .await
+                .map_err(|err| anyhow!("cannot sync with the master: {}", err))?;
+        }
+        if config.cluster_enabled {
+            let bus_port = port as u64 + BUS_PORT_OFFSET;
+            let bus_listener = TcpListener::bind(format!("0.0.0.0:{}", bus_port)).await?;
+            data_core = data_core.with_cluster_bus(bus_listener);
+        }
+
+        let connections = data_core.connections();
+        let handle_connections = connections.clone();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let data_core = tokio::spawn(async move {
+                data_core.process_command().await;
+                data_core.shutdown().await;
+            });
+            serve(listeners, core_tx, connection_config, connections, async {
+                let _ = shutdown_rx.await;
+            })
+            .await;
+            if let Err(err) = data_core.await {
+                error!("the data core failed: {}", err);
+            }
+        });
+        Ok(ServerHandle {
+            local_addr,
+            connections: handle_connections,
+            shutdown: shutdown_tx,
+            task,
+        })
+    }
+}
+
+/// Controls a server started with [`Server::spawn`]. Dropping it leaves the
+/// server running.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    connections: Connections,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// The address of the first listener.
+    pub fn local_addr(self: &ServerHandle) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The clients connected to the server.
+    pub fn connections(self: &ServerHandle) -> &Connections {
+        &self.connections
+    }
+
+    /// Stops the server like a SIGTERM would, returning once clients were
+    /// drained and the data core saved its data.
+    pub async fn shutdown(self: ServerHandle) {
+        let _ = self.shutdown.send(());
+        if let Err(err) = self.task.await {
+            error!("the server failed: {}", err);
+        }
+    }
+}
+
+/// Replies are sent once this many bytes are waiting, even in the middle
+/// of a pipeline.
+const OUTPUT_FLUSH_SIZE: usize = 16 * 1024;
+
+/// While a command waits for its reply, the client is still read from to
+/// notice it disconnecting, until this many bytes of the commands it sent
+/// after are pending.
+const BLOCKED_INPUT_LIMIT: usize = 1024 * 1024;
+
+/// Serves the commands of the client at `address` until it disconnects or
+/// is disconnected.
+pub(crate) async fn process_request(
+    mut socket: impl Transport,
+    address: Option<SocketAddr>,
+    connections: &Connections,
+    core_tx: &Sender<Command>,
+    config: &ConnectionConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (push_tx, mut push_rx) = mpsc::unbounded_channel::<Vec<Token>>();
+    // Unregisters the connection however this returns.
+    let registration = connections.register(address, push_tx.clone());
+    let client_id = registration.id;
+    let span = info_span!("connection", id = client_id, peer = field::Empty);
+    if let Some(address) = address {
+        span.record("peer", field::display(address));
+    }
+    debug!(parent: &span, "accepted new connection");
+    let client = Client::new(client_id, push_tx);
+    let client = match address {
+        Some(address) => client.with_address(address),
+        None => client,
+    };
+
+    // Replies and pushed messages are gathered here and sent with as few
+    // writes as possible, like the FULLRESYNC reply and the RDB file that
+    // follows it.
+    let mut output = Vec::new();
+
+    // Commands can arrive split across reads, so bytes accumulate here
+    // until a whole one was received. The buffer grows with what is
+    // pending, so values of any size up to proto-max-bulk-len fit.
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut last_command = Instant::now();
+    let served: Result<(), ConnectionError> = async {
+        loop {
+            // Clients may pipeline commands: run every whole one received,
+            // in order, and send all their replies together.
+            let mut quit = false;
+            loop {
+                let max_bulk_len = config.proto_max_bulk_len.load(Ordering::Relaxed);
+                let Some((arguments, length)) = parse_bounded_command(&buffer, max_bulk_len)
+                    .map_err(ConnectionError::Protocol)?
+                else {
+                    break;
+                };
+                buffer.advance(length);
+                last_command = Instant::now();
+                debug!(?arguments, "command");
+                // Commands pipelined after QUIT are never run.
+                quit = arguments
+                    .first()
+                    .is_some_and(|name| name.eq_ignore_ascii_case("quit"));
+
+                let (tx, rx) = oneshot::channel::<Vec<Token>>();
+                let command = Command::new(arguments, tx).with_client(client.clone());
+                core_tx
+                    .send(command)
+                    .await
+                    .map_err(|_| ConnectionError::DataCoreStopped)?;
+
+                // Blocking commands may wait longer than the server runs, or
+                // than the client does: one that disconnects drops `rx`, so
+                // the data core stops waiting to serve it.
+                tokio::pin!(rx);
+                let response = loop {
+                    tokio::select! {
+                        biased;
+                        response = &mut rx => {
+                            break response.map_err(|_| ConnectionError::DataCoreStopped)?
+                        }
+                        read = socket.read_buf(&mut buffer), if buffer.len() < BLOCKED_INPUT_LIMIT => {
+                            if read? == 0 {
+                                return Ok(());
+                            }
+                        }
+                        _ = shutdown.changed() => return Ok(()),
+                        _ = registration.killed() => return Ok(()),
+                    }
+                };
+                write_tokens(&mut output, &response)?;
+                if output.len() >= OUTPUT_FLUSH_SIZE {
+                    flush(&mut socket, &mut output).await?;
+                }
+                if quit {
+                    break;
+                }
+            }
+
+            write_pushed_messages(&mut output, &mut push_rx)?;
+            flush(&mut socket, &mut output).await?;
+            if quit {
+                return Ok(());
+            }
+
+            // Blocked clients wait for their reply above, so only idle ones
+            // get here.
+            let timeout = config.timeout.load(Ordering::Relaxed);
+            let idle_timeout = timeout > 0 && !client.no_idle_timeout.load(Ordering::Relaxed);
+            let read = tokio::select! {
+                read = socket.read_buf(&mut buffer) => read?,
+                _ = sleep_until(last_command + Duration::from_secs(timeout)), if idle_timeout => {
+                    debug!("closing the idle connection");
+                    return Ok(());
+                }
+                Some(message) = push_rx.recv() => {
+                    write_tokens(&mut output, &message)?;
+                    continue;
+                }
+                _ = shutdown.changed() => return Ok(()),
+                _ = registration.killed() => return Ok(()),
+            };
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+    .instrument(span.clone())
+    .await;
+
+    // Whatever went wrong stays with this connection: the client is told
+    // when it still can be, and the socket is closed either way.
+    if let Err(err) = served {
+        debug!(parent: &span, "closing the connection: {}", err);
+        if let Some(reply) = err.reply() {
+            output.extend_from_slice(reply.as_bytes());
+        }
+    }
+    let _ = flush(&mut socket, &mut output).await;
+    let _ = socket.shutdown().await;
+}
+
+/// Why a connection could not be served any longer.
+#[derive(Debug, thiserror::Error)]
+enum ConnectionError {
+    #[error("Protocol error: {0}")]
+    Protocol(anyhow::Error),
+    #[error("the data core stopped")]
+    DataCoreStopped,
+    #[error("cannot serialize a reply: {0}")]
+    Serialize(anyhow::Error),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+impl ConnectionError {
+    /// The error reply for the client, unless the socket itself failed.
+    fn reply(self: &ConnectionError) -> Option<String> {
+        match self {
+            ConnectionError::Io(_) => None,
+            err => Some(format!("-ERR {}\r\n", err)),
+        }
+    }
+}
+
+/// Adds a reply or pushed message to the output, without sending it.
+/// Commands that answer nothing, like REPLCONF ACK, have no tokens to write.
+fn write_tokens(output: &mut Vec<u8>, tokens: &[Token]) -> Result<(), ConnectionError> {
+    if tokens.is_empty() {
+        return Ok(());
+    }
+    let bytes = tokenizer::serialize_tokens_to_bytes(tokens).map_err(ConnectionError::Serialize)?;
+    if output.is_empty() {
+        *output = bytes;
+    } else {
+        output.extend_from_slice(&bytes);
+    }
+    Ok(())
+}
+
+/// Adds the messages already pushed to a client to the output, without
+/// sending them.
+fn write_pushed_messages(
+    output: &mut Vec<u8>,
+    push_rx: &mut UnboundedReceiver<Vec<Token>>,
+) -> Result<(), ConnectionError> {
+    while let Ok(message) = push_rx.try_recv() {
+        write_tokens(output, &message)?;
+    }
+    Ok(())
+}
+
+/// Sends the output to the client.
+async fn flush(socket: &mut impl Transport, output: &mut Vec<u8>) -> io::Result<()> {
+    if !output.is_empty() {
+        socket.write_all(output).await?;
+        output.clear();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use socket2::SockRef;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    use crate::connections::Connections;
+    use crate::data_core::{Command, DataCore, ReplicationRole};
+    use crate::parser::ParserValue;
+    use crate::server::{
+        bind, configure_socket, serve, BindAddress, ConnectionConfig, Server, ServerConfig,
+    };
+    use crate::testing::TestServer;
+
+    fn simple(value: &str) -> ParserValue {
+        ParserValue::SimpleString(value.to_string())
+    }
+
+    fn bulk(value: &str) -> ParserValue {
+        ParserValue::BulkString(value.into())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_clients_and_stops_the_data_core() {
+        let server = TestServer::start().await.unwrap();
+        let address = server.address();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            simple("OK"),
+            client.command(&["SET", "k", "v"]).await.unwrap()
+        );
+
+        // Stopping returns once the data core saw every sender go.
+        server.stop().await;
+        client.closed().await.unwrap();
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_commands_split_across_reads() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        for part in ["*3\r\n$3\r\nSE", "T\r\n$1\r\nk\r", "\n$5\r\nva", "lue\r\n"] {
+            client.send_raw(part.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(simple("OK"), client.read_reply().await.unwrap());
+        assert_eq!(bulk("value"), client.command(&["GET", "k"]).await.unwrap());
+
+        client.send_raw(b"*1\r\n+PING\r\n").await.unwrap();
+        assert!(matches!(
+            client.read_reply().await.unwrap(),
+            ParserValue::Error(err) if err.starts_with("ERR Protocol error")
+        ));
+        client.closed().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_are_all_answered() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        client
+            .send_raw(
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET",
+            )
+            .await
+            .unwrap();
+        assert_eq!(simple("OK"), client.read_reply().await.unwrap());
+        assert_eq!(bulk("v"), client.read_reply().await.unwrap());
+        assert_eq!(simple("PONG"), client.read_reply().await.unwrap());
+
+        // The partial command at the end is completed by the next read.
+        client.send_raw(b"\r\n$1\r\nk\r\n").await.unwrap();
+        assert_eq!(bulk("v"), client.read_reply().await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_replied_before_closing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (core_tx, mut core_rx) = mpsc::channel::<Command>(32);
+        // Drops commands without answering, as if it had crashed.
+        tokio::spawn(async move { while core_rx.recv().await.is_some() {} });
+        tokio::spawn(serve(
+            vec![listener],
+            core_tx,
+            Arc::default(),
+            Connections::default(),
+            std::future::pending(),
+        ));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(b"-ERR the data core stopped\r\n", &reply[..]);
+    }
+
+    #[tokio::test]
+    async fn test_quit_closes_the_connection() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        client
+            .send_raw(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nQUIT\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await
+            .unwrap();
+        assert_eq!(simple("PONG"), client.read_reply().await.unwrap());
+        assert_eq!(simple("OK"), client.read_reply().await.unwrap());
+        client.closed().await.unwrap();
+
+        // The SET pipelined after QUIT was not run.
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            ParserValue::NullBulkString,
+            client.command(&["GET", "k"]).await.unwrap()
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_large_values_up_to_proto_max_bulk_len() {
+        let config = ServerConfig::default().with_proto_max_bulk_len(4 * 1024 * 1024);
+        let server = TestServer::start_with(config).await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        let value = "x".repeat(3 * 1024 * 1024);
+        client.send(&["SET", "k", &value]).await.unwrap();
+        client.send(&["GET", "k"]).await.unwrap();
+        assert_eq!(simple("OK"), client.read_reply().await.unwrap());
+        assert!(client.read_reply().await.unwrap() == bulk(&value));
+
+        // Oversized values are refused from their header on.
+        assert_eq!(
+            simple("OK"),
+            client
+                .command(&["CONFIG", "SET", "proto-max-bulk-len", "1mb"])
+                .await
+                .unwrap()
+        );
+        client
+            .send_raw(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1048577\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            ParserValue::Error("ERR Protocol error: invalid bulk length 1048577".to_string()),
+            client.read_reply().await.unwrap()
+        );
+        client.closed().await.unwrap();
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_multibulk_headers_are_refused() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.send_raw(b"*100000000000\r\n").await.unwrap();
+        assert_eq!(
+            ParserValue::Error("ERR Protocol error: invalid multibulk length".to_string()),
+            client.read_reply().await.unwrap()
+        );
+        client.closed().await.unwrap();
+
+        // Only that connection was closed.
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(simple("PONG"), client.command(&["PING"]).await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_unterminated_request_lines_are_refused() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        client.send_raw(&vec![b'a'; 100 * 1024]).await.unwrap();
+        assert_eq!(
+            ParserValue::Error("ERR Protocol error: too big inline request".to_string()),
+            client.read_reply().await.unwrap()
+        );
+        client.closed().await.unwrap();
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_a_panicking_command_does_not_stop_the_data_core() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            simple("OK"),
+            client.command(&["SET", "k", "v"]).await.unwrap()
+        );
+        for command in [
+            &["DEBUG", "PANIC"][..],
+            &["ZRANDMEMBER", "k", "-9223372036854775808"],
+            &["BZPOPMIN", "z", "1e300"],
+            &["DEBUG", "SLEEP", "1e300"],
+            &["SET", "k", "v", "PX", "99999999999999999"],
+        ] {
+            assert!(matches!(
+                client.command(command).await.unwrap(),
+                ParserValue::Error(_)
+            ));
+        }
+        assert_eq!(
+            ParserValue::Error("ERR internal error running 'debug'".to_string()),
+            client.command(&["DEBUG", "PANIC"]).await.unwrap()
+        );
+
+        // Every client, old and new, is still served.
+        assert_eq!(bulk("v"), client.command(&["GET", "k"]).await.unwrap());
+        let mut other = server.connect().await.unwrap();
+        assert_eq!(simple("PONG"), other.command(&["PING"]).await.unwrap());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_blocked_clients_that_disconnect_are_not_served() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        let blocked_clients = |info: ParserValue| match info {
+            ParserValue::BulkString(info) => info.to_string().contains("blocked_clients:1"),
+            reply => panic!("unexpected INFO reply {:?}", reply),
+        };
+        let mut blocked = server.connect().await.unwrap();
+        blocked.send(&["BZPOPMIN", "q", "0"]).await.unwrap();
+        while !blocked_clients(client.command(&["INFO", "clients"]).await.unwrap()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        drop(blocked);
+        while blocked_clients(client.command(&["INFO", "clients"]).await.unwrap()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The member stays for the next client instead of going to the
+        // closed connection.
+        assert_eq!(
+            ParserValue::Integer(1),
+            client.command(&["ZADD", "q", "1", "job"]).await.unwrap()
+        );
+        assert_eq!(
+            ParserValue::Integer(1),
+            client.command(&["ZCARD", "q"]).await.unwrap()
+        );
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_psync_reply_is_followed_by_the_rdb_file() {
+        let server = TestServer::start().await.unwrap();
+        // The RDB file is a bulk string without its CRLF, which the test
+        // client can't read, so this one talks RESP itself.
+        let mut client = TcpStream::connect(server.address()).await.unwrap();
+        client
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        let rdb_received = |reply: &[u8]| {
+            let reply = String::from_utf8_lossy(reply);
+            let Some((_, rdb)) = reply.split_once("\r\n$") else {
+                return false;
+            };
+            let Some((length, rdb)) = rdb.split_once("\r\n") else {
+                return false;
+            };
+            rdb.len() >= length.parse::<usize>().unwrap()
+        };
+        while !rdb_received(&reply) {
+            let mut chunk = [0; 1024];
+            let read = client.read(&mut chunk).await.unwrap();
+            assert!(read > 0);
+            reply.extend_from_slice(&chunk[..read]);
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        assert!(reply.starts_with("+FULLRESYNC "));
+        let (_, rdb) = reply.split_once("\r\n$").unwrap();
+        assert!(rdb.split_once("\r\n").unwrap().1.starts_with("REDIS"));
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_idle_clients_are_disconnected() {
+        let server = TestServer::start().await.unwrap();
+        let mut idle = server.connect().await.unwrap();
+        assert_eq!(
+            simple("OK"),
+            idle.command(&["CONFIG", "SET", "timeout", "1"])
+                .await
+                .unwrap()
+        );
+
+        let mut subscriber = server.connect().await.unwrap();
+        subscriber.command(&["SUBSCRIBE", "c"]).await.unwrap();
+
+        let started = std::time::Instant::now();
+        idle.closed().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        // Subscribers only receive messages, so they stay connected.
+        let quiet =
+            tokio::time::timeout(Duration::from_millis(1500), subscriber.read_reply()).await;
+        assert!(quiet.is_err());
+        server.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_registered_and_killed() {
+        let server = TestServer::start().await.unwrap();
+        let connections = server.connections();
+        let mut client = server.connect().await.unwrap();
+        let mut other = server.connect().await.unwrap();
+        while connections.list().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let ParserValue::Integer(other_id) = other.command(&["CLIENT", "ID"]).await.unwrap() else {
+            panic!("CLIENT ID replies with an integer");
+        };
+
+        assert!(connections.kill(other_id as u64));
+        other.closed().await.unwrap();
+        while connections.get(other_id as u64).is_some() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(simple("PONG"), client.command(&["PING"]).await.unwrap());
+        assert_eq!(1, connections.list().len());
+        server.stop().await;
+    }
+
+    #[test]
+    fn test_parses_bind_addresses() {
+        let address = |value: &str| value.parse::<BindAddress>();
+        assert_eq!(
+            Ok(BindAddress {
+                ip: IpAddr::from([0, 0, 0, 0]),
+                optional: false
+            }),
+            address("*")
+        );
+        assert_eq!(
+            Ok(BindAddress {
+                ip: IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+                optional: true
+            }),
+            address("-::1")
+        );
+        assert_eq!(IpAddr::from([0u16; 8]), address("::*").unwrap().ip);
+        assert!(address("localhost").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_embedded_server() {
+        let dir = std::env::temp_dir().join(format!("embedded-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig::default()
+            .with_rdb_file(dir.to_string_lossy().into_owned(), "dump.rdb".to_string())
+            .with_save("3600 1".to_string());
+        let server = Server::spawn(config.clone()).await.unwrap();
+        let address = server.local_addr();
+        assert_ne!(0, address.port());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"+OK\r\n", &reply);
+
+        server.shutdown().await;
+        assert!(TcpStream::connect(address).await.is_err());
+
+        // The data saved on shutdown is loaded by the next server.
+        let server = Server::spawn(config).await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"$1\r\nv\r\n", &reply);
+        drop(client);
+        server.shutdown().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let config = ConnectionConfig::default();
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        configure_socket(&socket, &config).unwrap();
+        assert!(socket.nodelay().unwrap());
+        assert!(SockRef::from(&socket).keepalive().unwrap());
+
+        config.tcp_nodelay.store(false, Ordering::Relaxed);
+        config.tcp_keepalive.store(0, Ordering::Relaxed);
+        configure_socket(&client, &config).unwrap();
+        assert!(!client.nodelay().unwrap());
+        assert!(!SockRef::from(&client).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_serves_every_listener() {
+        let config = ConnectionConfig::default();
+        let listeners = vec![
+            bind("127.0.0.1:0".parse().unwrap(), &config).unwrap(),
+            bind("127.0.0.1:0".parse().unwrap(), &config).unwrap(),
+        ];
+        let addresses = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let (core_tx, core_rx) = mpsc::channel::<Command>(32);
+        tokio::spawn(async move {
+            let mut data_core = DataCore::new(core_rx, ReplicationRole::Master, None, None);
+            data_core.process_command().await;
+        });
+        tokio::spawn(serve(
+            listeners,
+            core_tx,
+            Arc::default(),
+            Connections::default(),
+            std::future::pending(),
+        ));
+
+        for address in addresses.into_iter().rev() {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+            let mut reply = [0; 7];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(b"+PONG\r\n", &reply);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashSet;
+
+use crate::binary::ByteString;
+
+/// Thresholds deciding when a small set outgrows its compact encoding, mirroring
+/// `set-max-intset-entries`, `set-max-listpack-entries` and `set-max-listpack-value`.
+#[derive(Debug, Clone, Copy)]
+pub struct SetLimits {
+    pub max_intset_entries: usize,
+    pub max_listpack_entries: usize,
+    pub max_listpack_value: usize,
+}
+
+impl Default for SetLimits {
+    fn default() -> Self {
+        SetLimits {
+            max_intset_entries: 512,
+            max_listpack_entries: 128,
+            max_listpack_value: 64,
+        }
+    }
+}
+
+/// A Redis set value.
+///
+/// Sets made only of integers are kept as a sorted `Vec<i64>` (intset), other
+/// small sets as a flat `Vec<ByteString>` (listpack). Both are upgraded to a
+/// `HashSet` once they grow past the configured limits and never downgraded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisSet {
+    IntSet(Vec<i64>),
+    ListPack(Vec<ByteString>),
+    HashTable(HashSet<ByteString>),
+}
+
+/// Parses `member` as an integer only if it round-trips exactly, so `"01"` or
+/// `"+1"` are kept as strings.
+fn as_integer(member: &[u8]) -> Option<i64> {
+    let member = std::str::from_utf8(member).ok()?;
+    member
+        .parse::<i64>()
+        .ok()
+        .filter(|n| n.to_string() == member)
+}
+
+fn integer_member(n: &i64) -> ByteString {
+    ByteString::from(n.to_string())
+}
+
+impl RedisSet {
+    /// Creates an empty set whose encoding suits `first_member`.
+    pub fn new(first_member: &[u8], limits: &SetLimits) -> RedisSet {
+        if limits.max_intset_entries > 0 && as_integer(first_member).is_some() {
+            RedisSet::IntSet(Vec::new())
+        } else if limits.max_listpack_entries > 0 && first_member.len() <= limits.max_listpack_value
+        {
+            RedisSet::ListPack(Vec::new())
+        } else {
+            RedisSet::HashTable(HashSet::new())
+        }
+    }
+
+    pub fn from_members<I>(members: I, limits: &SetLimits) -> RedisSet
+    where
+        I: IntoIterator<Item = ByteString>,
+    {
+        let mut members = members.into_iter().peekable();
+        let mut set = match members.peek() {
+            Some(first) => RedisSet::new(first, limits),
+            None => RedisSet::IntSet(Vec::new()),
+        };
+        for member in members {
+            set.insert(member, limits);
+        }
+        set
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            RedisSet::IntSet(_) => "intset",
+            RedisSet::ListPack(_) => "listpack",
+            RedisSet::HashTable(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RedisSet::IntSet(members) => members.len(),
+            RedisSet::ListPack(members) => members.len(),
+            RedisSet::HashTable(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            RedisSet::IntSet(members) => {
+                as_integer(member).is_some_and(|n| members.binary_search(&n).is_ok())
+            }
+            RedisSet::ListPack(members) => members.iter().any(|m| m.as_bytes() == member),
+            RedisSet::HashTable(members) => members.contains(member),
+        }
+    }
+
+    /// Adds `member`, upgrading the encoding when needed. Returns whether the
+    /// member was newly added.
+    pub fn insert(&mut self, member: ByteString, limits: &SetLimits) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        match self {
+            RedisSet::IntSet(members) => match as_integer(&member) {
+                Some(n) if members.len() < limits.max_intset_entries => {
+                    let position = members.binary_search(&n).unwrap_err();
+                    members.insert(position, n);
+                    return true;
+                }
+                Some(_) => self.upgrade_to_hash_table(),
+                None => {
+                    if members.len() < limits.max_listpack_entries
+                        && member.len() <= limits.max_listpack_value
+                    {
+                        *self = RedisSet::ListPack(members.iter().map(integer_member).collect());
+                    } else {
+                        self.upgrade_to_hash_table();
+                    }
+                }
+            },
+            RedisSet::ListPack(members) => {
+                if members.len() >= limits.max_listpack_entries
+                    || member.len() > limits.max_listpack_value
+                {
+                    self.upgrade_to_hash_table();
+                }
+            }
+            RedisSet::HashTable(_) => {}
+        }
+
+        match self {
+            RedisSet::IntSet(_) => unreachable!("integer inserts return early"),
+            RedisSet::ListPack(members) => members.push(member),
+            RedisSet::HashTable(members) => {
+                members.insert(member);
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            RedisSet::IntSet(members) => {
+                match as_integer(member).and_then(|n| members.binary_search(&n).ok()) {
+                    Some(position) => {
+                        members.remove(position);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            RedisSet::ListPack(members) => {
+                match members.iter().position(|m| m.as_bytes() == member) {
+                    Some(position) => {
+                        members.swap_remove(position);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            RedisSet::HashTable(members) => members.remove(member),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = ByteString> + '_> {
+        match self {
+            RedisSet::IntSet(members) => Box::new(members.iter().map(integer_member)),
+            RedisSet::ListPack(members) => Box::new(members.iter().cloned()),
+            RedisSet::HashTable(members) => Box::new(members.iter().cloned()),
+        }
+    }
+
+    fn upgrade_to_hash_table(&mut self) {
+        *self = RedisSet::HashTable(self.iter().collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrades_encodings() {
+        let limits = SetLimits {
+            max_intset_entries: 2,
+            max_listpack_entries: 3,
+            max_listpack_value: 4,
+        };
+
+        let mut set = RedisSet::new("1".as_bytes(), &limits);
+        assert!(set.insert("1".into(), &limits));
+        assert!(set.insert("2".into(), &limits));
+        assert!(!set.insert("1".into(), &limits));
+        assert_eq!("intset", set.encoding());
+
+        assert!(set.insert("a".into(), &limits));
+        assert_eq!("listpack", set.encoding());
+        assert!(set.contains(b"1") && set.contains(b"a"));
+
+        assert!(set.insert("b".into(), &limits));
+        assert_eq!("hashtable", set.encoding());
+        assert_eq!(4, set.len());
+
+        let mut set = RedisSet::new("01".as_bytes(), &limits);
+        set.insert("01".into(), &limits);
+        assert_eq!("listpack", set.encoding());
+        set.insert("too long".into(), &limits);
+        assert_eq!("hashtable", set.encoding());
+    }
+}
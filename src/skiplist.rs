@@ -0,0 +1,357 @@
+use std::cmp::Ordering;
+
+use rand::{thread_rng, Rng};
+
+use crate::binary::ByteString;
+
+const MAX_LEVEL: usize = 32;
+const LEVEL_PROBABILITY: f64 = 0.25;
+const HEADER: usize = 0;
+
+/// Orders sorted set entries by score, then by the bytes of the member.
+pub fn compare(score: f64, member: &[u8], other_score: f64, other_member: &[u8]) -> Ordering {
+    score
+        .total_cmp(&other_score)
+        .then_with(|| member.cmp(other_member))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Level {
+    forward: Option<usize>,
+    /// Number of nodes skipped by following `forward`, or the number of nodes
+    /// left until the end of the list when there is no forward node.
+    span: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Node {
+    member: ByteString,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+/// The skiplist behind large sorted sets, modelled on Redis' `zskiplist`.
+///
+/// Nodes live in an arena and link to each other by index. Every forward link
+/// records its span so ranks can be computed in O(log n) while descending.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkipList {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    length: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        let header = Node {
+            member: ByteString::default(),
+            score: 0.0,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+        };
+        SkipList {
+            nodes: vec![header],
+            free: Vec::new(),
+            tail: None,
+            length: 0,
+            level: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn random_level() -> usize {
+        let mut rng = thread_rng();
+        let mut level = 1;
+        while level < MAX_LEVEL && rng.gen::<f64>() < LEVEL_PROBABILITY {
+            level += 1;
+        }
+        level
+    }
+
+    fn allocate(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn is_before(&self, node: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[node];
+        compare(node.score, &node.member, score, member) == Ordering::Less
+    }
+
+    /// Inserts an entry. The caller guarantees `member` is not already present.
+    pub fn insert(&mut self, member: ByteString, score: f64) {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !self.is_before(next, score, &member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = SkipList::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEADER;
+                self.nodes[HEADER].levels[i].span = self.length;
+            }
+            self.level = level;
+        }
+
+        let new = self.allocate(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+        });
+        for i in 0..level {
+            let previous = update[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[new].levels[i] = Level {
+                forward: self.nodes[previous].levels[i].forward,
+                span: self.nodes[previous].levels[i].span - skipped,
+            };
+            self.nodes[previous].levels[i] = Level {
+                forward: Some(new),
+                span: skipped + 1,
+            };
+        }
+        for (i, &previous) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[previous].levels[i].span += 1;
+        }
+
+        self.nodes[new].backward = match update[0] {
+            HEADER => None,
+            previous => Some(previous),
+        };
+        match self.nodes[new].levels[0].forward {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+        self.length += 1;
+    }
+
+    /// Removes the entry with exactly this score and member, if present.
+    pub fn remove(&mut self, member: &[u8], score: f64) -> bool {
+        let mut update = [HEADER; MAX_LEVEL];
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !self.is_before(next, score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let target = match self.nodes[x].levels[0].forward {
+            Some(next) if self.nodes[next].score == score && self.nodes[next].member == member => {
+                next
+            }
+            _ => return false,
+        };
+
+        for (i, &previous) in update.iter().enumerate().take(self.level) {
+            if self.nodes[previous].levels[i].forward == Some(target) {
+                let removed = self.nodes[target].levels[i];
+                self.nodes[previous].levels[i] = Level {
+                    forward: removed.forward,
+                    span: self.nodes[previous].levels[i].span + removed.span - 1,
+                };
+            } else {
+                self.nodes[previous].levels[i].span -= 1;
+            }
+        }
+        match self.nodes[target].levels[0].forward {
+            Some(next) => self.nodes[next].backward = self.nodes[target].backward,
+            None => self.tail = self.nodes[target].backward,
+        }
+        while self.level > 1 && self.nodes[HEADER].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[target].member = ByteString::default();
+        self.nodes[target].levels = Vec::new();
+        self.free.push(target);
+        self.length -= 1;
+        true
+    }
+
+    /// Counts the leading entries for which `predicate` holds. The predicate
+    /// must be monotone: once false it stays false for every later entry.
+    pub fn count_while<F>(&self, predicate: F) -> usize
+    where
+        F: Fn(f64, &[u8]) -> bool,
+    {
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if !predicate(self.nodes[next].score, &self.nodes[next].member) {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+        }
+        traversed
+    }
+
+    /// The node at the one based `rank`.
+    fn node_by_rank(&self, rank: usize) -> Option<usize> {
+        let mut traversed = 0;
+        let mut x = HEADER;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].levels[i].forward {
+                if traversed + self.nodes[x].levels[i].span > rank {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == rank && x != HEADER {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// Iterates the entries with zero based ranks in `start..end`.
+    pub fn range(&self, start: usize, end: usize) -> Iter<'_> {
+        let end = end.min(self.length);
+        if start >= end {
+            return Iter {
+                list: self,
+                front: None,
+                back: None,
+                remaining: 0,
+            };
+        }
+        Iter {
+            list: self,
+            front: self.node_by_rank(start + 1),
+            back: match end == self.length {
+                true => self.tail,
+                false => self.node_by_rank(end),
+            },
+            remaining: end - start,
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    front: Option<usize>,
+    back: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.front?];
+        self.front = node.levels[0].forward;
+        self.remaining -= 1;
+        Some((node.member.as_bytes(), node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.list.nodes[self.back?];
+        self.back = node.backward;
+        self.remaining -= 1;
+        Some((node.member.as_bytes(), node.score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove_and_rank() {
+        let mut list = SkipList::new();
+        for i in (0..200).rev() {
+            list.insert(format!("m{:03}", i).into(), i as f64);
+        }
+        assert_eq!(200, list.len());
+        assert_eq!(50, list.count_while(|score, _| score < 50.0));
+        assert_eq!(
+            vec![(&b"m010"[..], 10.0), (b"m011", 11.0)],
+            list.range(10, 12).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(&b"m199"[..], 199.0), (b"m198", 198.0)],
+            list.range(198, 500).rev().collect::<Vec<_>>()
+        );
+
+        for i in (0..200).step_by(2) {
+            assert!(list.remove(format!("m{:03}", i).as_bytes(), i as f64));
+        }
+        assert!(!list.remove(b"m000", 0.0));
+        assert_eq!(100, list.len());
+        assert_eq!(25, list.count_while(|score, _| score < 50.0));
+        assert_eq!(
+            (0..200).skip(1).step_by(2).collect::<Vec<_>>(),
+            list.range(0, 100)
+                .map(|(_, score)| score as i32)
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::slice;
+
+use crate::binary::ByteString;
+use crate::skiplist::{self, compare, SkipList};
+
+/// Formats a score the way Redis replies with it, e.g. `1`, `1.5` or `-inf`.
+pub fn format_score(score: f64) -> String {
+    if score.is_infinite() {
+        if score > 0.0 {
+            "inf".to_string()
+        } else {
+            "-inf".to_string()
+        }
+    } else if score == 0.0 {
+        "0".to_string()
+    } else {
+        score.to_string()
+    }
+}
+
+/// One end of a score range, as written with or without a `(` prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+impl ScoreRange {
+    pub fn above_min(&self, score: f64) -> bool {
+        match self.min {
+            ScoreBound::Inclusive(min) => score >= min,
+            ScoreBound::Exclusive(min) => score > min,
+        }
+    }
+
+    pub fn below_max(&self, score: f64) -> bool {
+        match self.max {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        }
+    }
+
+    pub fn contains(&self, score: f64) -> bool {
+        self.above_min(score) && self.below_max(score)
+    }
+}
+
+/// One end of a lexicographical range: `[member`, `(member`, `-` or `+`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    Inclusive(ByteString),
+    Exclusive(ByteString),
+    NegativeInfinity,
+    PositiveInfinity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    pub fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::Inclusive(min) => member >= min.as_bytes(),
+            LexBound::Exclusive(min) => member > min.as_bytes(),
+            LexBound::NegativeInfinity => true,
+            LexBound::PositiveInfinity => false,
+        }
+    }
+
+    pub fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::Inclusive(max) => member <= max.as_bytes(),
+            LexBound::Exclusive(max) => member < max.as_bytes(),
+            LexBound::NegativeInfinity => false,
+            LexBound::PositiveInfinity => true,
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.above_min(member) && self.below_max(member)
+    }
+}
+
+/// Thresholds deciding when a sorted set outgrows its listpack encoding,
+/// mirroring `zset-max-listpack-entries` and `zset-max-listpack-value`.
+#[derive(Debug, Clone, Copy)]
+pub struct SortedSetLimits {
+    pub max_listpack_entries: usize,
+    pub max_listpack_value: usize,
+}
+
+impl Default for SortedSetLimits {
+    fn default() -> Self {
+        SortedSetLimits {
+            max_listpack_entries: 128,
+            max_listpack_value: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Encoding {
+    /// Entries kept sorted by `(score, member)` in a flat vector.
+    ListPack(Vec<(ByteString, f64)>),
+    /// A member to score map plus a skiplist ordered by `(score, member)`.
+    SkipList {
+        scores: HashMap<ByteString, f64>,
+        list: SkipList,
+    },
+}
+
+/// A Redis sorted set.
+///
+/// Small sets are stored as a sorted vector (listpack) and converted to a
+/// skiplist once they grow past the configured limits, so ranks and ranged
+/// queries stay O(log n) for large sets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSet {
+    encoding: Encoding,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        SortedSet::new()
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        SortedSet {
+            encoding: Encoding::ListPack(Vec::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self.encoding {
+            Encoding::ListPack(_) => "listpack",
+            Encoding::SkipList { .. } => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            Encoding::ListPack(entries) => entries.len(),
+            Encoding::SkipList { list, .. } => list.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        match &self.encoding {
+            Encoding::ListPack(entries) => entries
+                .iter()
+                .find(|(entry, _)| entry == member)
+                .map(|(_, score)| *score),
+            Encoding::SkipList { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    fn convert_to_skip_list(&mut self) {
+        if let Encoding::ListPack(entries) = &mut self.encoding {
+            let mut scores = HashMap::with_capacity(entries.len());
+            let mut list = SkipList::new();
+            for (member, score) in entries.drain(..) {
+                scores.insert(member.clone(), score);
+                list.insert(member, score);
+            }
+            self.encoding = Encoding::SkipList { scores, list };
+        }
+    }
+
+    /// Inserts `member` or updates its score. Returns true if it was newly added.
+    pub fn insert(&mut self, member: ByteString, score: f64, limits: &SortedSetLimits) -> bool {
+        // -0 and 0 are the same score, but not under total_cmp.
+        let score = score + 0.0;
+        let added = !self.remove(&member);
+        if let Encoding::ListPack(entries) = &self.encoding {
+            if entries.len() >= limits.max_listpack_entries
+                || member.len() > limits.max_listpack_value
+            {
+                self.convert_to_skip_list();
+            }
+        }
+
+        match &mut self.encoding {
+            Encoding::ListPack(entries) => {
+                let position = entries.partition_point(|(entry, entry_score)| {
+                    compare(*entry_score, entry, score, &member) == Ordering::Less
+                });
+                entries.insert(position, (member, score));
+            }
+            Encoding::SkipList { scores, list } => {
+                scores.insert(member.clone(), score);
+                list.insert(member, score);
+            }
+        }
+        added
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match &mut self.encoding {
+            Encoding::ListPack(entries) => {
+                match entries.iter().position(|(entry, _)| entry == member) {
+                    Some(position) => {
+                        entries.remove(position);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Encoding::SkipList { scores, list } => match scores.remove(member) {
+                Some(score) => list.remove(member, score),
+                None => false,
+            },
+        }
+    }
+
+    /// Removes and returns up to `count` members with the lowest scores, or the
+    /// highest scores when `max` is set.
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(ByteString, f64)> {
+        let popped = match max {
+            true => self.range_by_rank(0, count as i64 - 1, true),
+            false => self.range_by_rank(0, count as i64 - 1, false),
+        }
+        .map(|(member, score)| (ByteString::from(member), score))
+        .collect::<Vec<_>>();
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+        popped
+    }
+
+    /// Counts the leading members for which the monotone `predicate` holds.
+    fn count_while<F>(&self, predicate: F) -> usize
+    where
+        F: Fn(f64, &[u8]) -> bool,
+    {
+        match &self.encoding {
+            Encoding::ListPack(entries) => {
+                entries.partition_point(|(member, score)| predicate(*score, member))
+            }
+            Encoding::SkipList { list, .. } => list.count_while(predicate),
+        }
+    }
+
+    /// The zero based position of `member` in ascending order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(self.count_while(|entry_score, entry| {
+            compare(entry_score, entry, score, member) == Ordering::Less
+        }))
+    }
+
+    /// Iterates members in ascending `(score, member)` order.
+    pub fn iter(&self) -> Iter<'_> {
+        self.iter_range(0, self.len())
+    }
+
+    /// Iterates the members with zero based ranks in `start..end`.
+    pub fn iter_range(&self, start: usize, end: usize) -> Iter<'_> {
+        let end = end.min(self.len());
+        let start = start.min(end);
+        match &self.encoding {
+            Encoding::ListPack(entries) => Iter::ListPack(entries[start..end].iter()),
+            Encoding::SkipList { list, .. } => Iter::SkipList(list.range(start, end)),
+        }
+    }
+
+    fn ranks_in_order(
+        &self,
+        start: usize,
+        end: usize,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&[u8], f64)> + '_> {
+        match rev {
+            false => Box::new(self.iter_range(start, end)),
+            true => Box::new(self.iter_range(start, end).rev()),
+        }
+    }
+
+    /// Members between the zero based ranks `start` and `stop` (inclusive),
+    /// where negative ranks count from the end. With `rev` ranks are counted
+    /// from the highest score.
+    pub fn range_by_rank(
+        &self,
+        start: i64,
+        stop: i64,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&[u8], f64)> + '_> {
+        let len = self.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return Box::new(std::iter::empty());
+        }
+        match rev {
+            false => self.ranks_in_order(start as usize, stop as usize + 1, false),
+            true => self.ranks_in_order((len - 1 - stop) as usize, (len - start) as usize, true),
+        }
+    }
+
+    pub fn range_by_score(
+        &self,
+        range: &ScoreRange,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&[u8], f64)> + '_> {
+        let start = self.count_while(|score, _| !range.above_min(score));
+        let end = self.count_while(|score, _| range.below_max(score));
+        self.ranks_in_order(start, end.max(start), rev)
+    }
+
+    /// Members within `range`, assuming every member has the same score as
+    /// Redis does for lexicographical ranges.
+    pub fn range_by_lex(
+        &self,
+        range: &LexRange,
+        rev: bool,
+    ) -> Box<dyn Iterator<Item = (&[u8], f64)> + '_> {
+        let start = self.count_while(|_, member| !range.above_min(member));
+        let end = self.count_while(|_, member| range.below_max(member));
+        self.ranks_in_order(start, end.max(start), rev)
+    }
+}
+
+pub enum Iter<'a> {
+    ListPack(slice::Iter<'a, (ByteString, f64)>),
+    SkipList(skiplist::Iter<'a>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::ListPack(entries) => entries
+                .next()
+                .map(|(member, score)| (member.as_bytes(), *score)),
+            Iter::SkipList(entries) => entries.next(),
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::ListPack(entries) => entries
+                .next_back()
+                .map(|(member, score)| (member.as_bytes(), *score)),
+            Iter::SkipList(entries) => entries.next_back(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_orders_by_score_then_member() {
+        let limits = SortedSetLimits::default();
+        let mut sorted_set = SortedSet::new();
+        assert!(sorted_set.insert("b".into(), 1.0, &limits));
+        assert!(sorted_set.insert("a".into(), 1.0, &limits));
+        assert!(sorted_set.insert("c".into(), 0.5, &limits));
+        assert!(!sorted_set.insert("c".into(), 2.0, &limits));
+
+        let members = sorted_set.iter().map(|(m, _)| m).collect::<Vec<_>>();
+        assert_eq!(vec![&b"a"[..], b"b", b"c"], members);
+        assert_eq!(Some(2.0), sorted_set.score(b"c"));
+
+        assert!(sorted_set.remove(b"a"));
+        assert_eq!(2, sorted_set.len());
+        assert_eq!("-inf", format_score(f64::NEG_INFINITY));
+        assert_eq!("1.5", format_score(1.5));
+        assert_eq!("3", format_score(3.0));
+    }
+
+    #[test]
+    fn test_encodings_answer_the_same_queries() {
+        let small = SortedSetLimits::default();
+        let tiny = SortedSetLimits {
+            max_listpack_entries: 4,
+            max_listpack_value: 64,
+        };
+        let mut listpack = SortedSet::new();
+        let mut skiplist = SortedSet::new();
+        for i in 0..50 {
+            listpack.insert(format!("m{}", i).into(), (i % 7) as f64, &small);
+            skiplist.insert(format!("m{}", i).into(), (i % 7) as f64, &tiny);
+        }
+        assert_eq!("listpack", listpack.encoding());
+        assert_eq!("skiplist", skiplist.encoding());
+
+        let range = ScoreRange {
+            min: ScoreBound::Exclusive(1.0),
+            max: ScoreBound::Inclusive(3.0),
+        };
+        for rev in [false, true] {
+            assert_eq!(
+                listpack.range_by_score(&range, rev).collect::<Vec<_>>(),
+                skiplist.range_by_score(&range, rev).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                listpack.range_by_rank(3, -5, rev).collect::<Vec<_>>(),
+                skiplist.range_by_rank(3, -5, rev).collect::<Vec<_>>()
+            );
+        }
+        for i in 0..50 {
+            let member = format!("m{}", i);
+            assert_eq!(
+                listpack.rank(member.as_bytes()),
+                skiplist.rank(member.as_bytes())
+            );
+        }
+        assert_eq!(listpack.pop(3, true), skiplist.pop(3, true));
+        assert_eq!(47, skiplist.len());
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::btree_map::Range;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::binary::ByteString;
+
+/// A stream entry ID: a millisecond timestamp and a sequence number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or a bare `ms` whose sequence becomes `default_seq`.
+    pub fn parse(id: &str, default_seq: u64) -> Option<StreamId> {
+        match id.split_once('-') {
+            Some((ms, seq)) => Some(StreamId::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(StreamId::new(id.parse().ok()?, default_seq)),
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<StreamId> {
+        match (self.seq.checked_add(1), self.ms.checked_add(1)) {
+            (Some(seq), _) => Some(StreamId::new(self.ms, seq)),
+            (None, Some(ms)) => Some(StreamId::new(ms, 0)),
+            (None, None) => None,
+        }
+    }
+
+    /// The largest ID smaller than this one.
+    pub fn previous(self) -> Option<StreamId> {
+        match (self.seq.checked_sub(1), self.ms.checked_sub(1)) {
+            (Some(seq), _) => Some(StreamId::new(self.ms, seq)),
+            (None, Some(ms)) => Some(StreamId::new(ms, u64::MAX)),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument given to XADD.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdSpec {
+    /// `*`: both parts generated.
+    Auto,
+    /// `ms-*`: the sequence number is generated.
+    AutoSequence(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    pub fn parse(id: &str) -> Option<IdSpec> {
+        if id == "*" {
+            return Some(IdSpec::Auto);
+        }
+        match id.split_once('-') {
+            Some((ms, "*")) => Some(IdSpec::AutoSequence(ms.parse().ok()?)),
+            _ => StreamId::parse(id, 0).map(IdSpec::Explicit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, thiserror::Error)]
+pub enum SetIdError {
+    #[error("ERR The ID specified in XSETID is smaller than the target stream top item")]
+    BelowTopItem,
+    #[error("ERR The entries_added specified in XSETID is smaller than the target stream length")]
+    EntriesAddedBelowLength,
+    #[error("ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id")]
+    BelowMaxDeleted,
+}
+
+/// How XTRIM and XADD decide which entries to evict.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+/// Entries per node of the Redis radix tree. Approximate trimming only evicts
+/// whole nodes, which is emulated by evicting in multiples of this size.
+pub const NODE_MAX_ENTRIES: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, thiserror::Error)]
+pub enum StreamIdError {
+    #[error("ERR The ID specified in XADD must be greater than 0-0")]
+    Zero,
+    #[error("ERR The ID specified in XADD is equal or smaller than the target stream top item")]
+    NotIncreasing,
+}
+
+pub type Fields = Vec<(ByteString, ByteString)>;
+
+/// An entry delivered to a consumer but not yet acknowledged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: ByteString,
+    pub delivery_time_ms: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Consumer {
+    pub seen_time_ms: u64,
+    /// When the consumer last had entries delivered, if ever.
+    pub active_time_ms: Option<u64>,
+    /// IDs in the group's pending entries list owned by this consumer.
+    pub pending: BTreeSet<StreamId>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConsumerGroup {
+    pub last_delivered_id: StreamId,
+    /// Logical number of entries read by the group, when it can be known.
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<ByteString, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered_id: StreamId, entries_read: Option<u64>) -> ConsumerGroup {
+        ConsumerGroup {
+            last_delivered_id,
+            entries_read,
+            ..ConsumerGroup::default()
+        }
+    }
+
+    /// Creates `name` if needed and marks it as seen. Returns whether it was created.
+    pub fn touch_consumer(&mut self, name: &[u8], now_ms: u64) -> bool {
+        let created = !self.consumers.contains_key(name);
+        self.consumers
+            .entry(ByteString::from(name))
+            .or_default()
+            .seen_time_ms = now_ms;
+        created
+    }
+
+    /// Removes a consumer along with its pending entries, returning how many
+    /// entries it still had pending.
+    pub fn remove_consumer(&mut self, name: &[u8]) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Acknowledges `id`, returning whether it was pending.
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(entry) => {
+                if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+                    consumer.pending.remove(&id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records a delivery of `id` to `consumer`, moving ownership if another
+    /// consumer had it pending.
+    fn deliver(&mut self, id: StreamId, consumer: &[u8], now_ms: u64) {
+        let delivery_count = match self.pending.get(&id) {
+            Some(entry) => {
+                if entry.consumer != consumer {
+                    if let Some(owner) = self.consumers.get_mut(&entry.consumer) {
+                        owner.pending.remove(&id);
+                    }
+                }
+                entry.delivery_count + 1
+            }
+            None => 1,
+        };
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: ByteString::from(consumer),
+                delivery_time_ms: now_ms,
+                delivery_count,
+            },
+        );
+        self.consumers
+            .entry(ByteString::from(consumer))
+            .or_default()
+            .pending
+            .insert(id);
+    }
+}
+
+/// A Redis stream: entries ordered by ID plus the bookkeeping needed to keep
+/// new IDs increasing even after entries are deleted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    groups: BTreeMap<ByteString, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        Stream::default()
+    }
+
+    /// Rebuilds a stream from its parts, as stored in an RDB file.
+    pub fn from_parts(
+        entries: BTreeMap<StreamId, Fields>,
+        last_id: StreamId,
+        entries_added: u64,
+        max_deleted_id: StreamId,
+        groups: BTreeMap<ByteString, ConsumerGroup>,
+    ) -> Stream {
+        Stream {
+            entries,
+            last_id,
+            entries_added,
+            max_deleted_id,
+            groups,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn first_entry(&self) -> Option<(&StreamId, &Fields)> {
+        self.entries.first_key_value()
+    }
+
+    pub fn last_entry(&self) -> Option<(&StreamId, &Fields)> {
+        self.entries.last_key_value()
+    }
+
+    /// Deletes an entry, returning whether it existed.
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        if self.entries.remove(&id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(id);
+        true
+    }
+
+    /// Evicts the oldest entries according to `strategy`, at most `limit` of
+    /// them when given. Returns how many were evicted.
+    pub fn trim(
+        &mut self,
+        strategy: TrimStrategy,
+        approximate: bool,
+        limit: Option<usize>,
+    ) -> usize {
+        let mut evict = match strategy {
+            TrimStrategy::MaxLen(max_len) => self.len().saturating_sub(max_len),
+            TrimStrategy::MinId(min_id) => self.entries.range(..min_id).count(),
+        };
+        if approximate {
+            evict -= evict % NODE_MAX_ENTRIES;
+        }
+        if let Some(limit) = limit {
+            evict = evict.min(limit);
+        }
+        for _ in 0..evict {
+            if let Some((id, _)) = self.entries.pop_first() {
+                self.max_deleted_id = self.max_deleted_id.max(id);
+            }
+        }
+        evict
+    }
+
+    /// XSETID: moves the last ID forward and optionally overrides the
+    /// entries-added counter and maximal deleted ID.
+    pub fn set_last_id(
+        &mut self,
+        id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: Option<StreamId>,
+    ) -> Result<(), SetIdError> {
+        if entries_added.is_some_and(|entries_added| entries_added < self.len() as u64) {
+            return Err(SetIdError::EntriesAddedBelowLength);
+        }
+        if max_deleted_id.is_some_and(|max_deleted_id| id < max_deleted_id) {
+            return Err(SetIdError::BelowMaxDeleted);
+        }
+        if self.last_entry().is_some_and(|(top, _)| id < *top) {
+            return Err(SetIdError::BelowTopItem);
+        }
+        self.last_id = id;
+        if let Some(entries_added) = entries_added {
+            self.entries_added = entries_added;
+        }
+        if let Some(max_deleted_id) = max_deleted_id {
+            self.max_deleted_id = max_deleted_id;
+        }
+        Ok(())
+    }
+
+    /// Resolves the ID for a new entry, rejecting IDs that would not be
+    /// strictly greater than the last one. `now_ms` is used for `*`.
+    pub fn next_id(&self, spec: IdSpec, now_ms: u64) -> Result<StreamId, StreamIdError> {
+        let last = self.last_id;
+        let id = match spec {
+            IdSpec::Auto if now_ms > last.ms => StreamId::new(now_ms, 0),
+            IdSpec::Auto => last.next().ok_or(StreamIdError::NotIncreasing)?,
+            IdSpec::AutoSequence(ms) if ms == last.ms => StreamId::new(
+                ms,
+                last.seq
+                    .checked_add(1)
+                    .ok_or(StreamIdError::NotIncreasing)?,
+            ),
+            // 0-0 is never a valid entry ID, so the first ID at 0 ms is 0-1.
+            IdSpec::AutoSequence(0) => StreamId::new(0, 1),
+            IdSpec::AutoSequence(ms) => StreamId::new(ms, 0),
+            IdSpec::Explicit(id) => id,
+        };
+        if id == StreamId::MIN {
+            return Err(StreamIdError::Zero);
+        }
+        if id <= last {
+            return Err(StreamIdError::NotIncreasing);
+        }
+        Ok(id)
+    }
+
+    /// Appends an entry. The caller obtains `id` from [`Stream::next_id`].
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    /// Entries with IDs between `start` and `end`, both inclusive.
+    pub fn range(&self, start: StreamId, end: StreamId) -> Range<'_, StreamId, Fields> {
+        match start <= end {
+            true => self.entries.range(start..=end),
+            false => self.entries.range(start..start),
+        }
+    }
+
+    pub fn groups(&self) -> &BTreeMap<ByteString, ConsumerGroup> {
+        &self.groups
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Creates a consumer group, returning false if it already exists.
+    pub fn create_group(&mut self, name: &[u8], last_delivered_id: StreamId) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+        // The read counter is only known when the group starts at either end
+        // of a stream that never had entries deleted.
+        let entries_read = if last_delivered_id >= self.last_id {
+            Some(self.entries_added)
+        } else if last_delivered_id == StreamId::MIN && self.max_deleted_id == StreamId::MIN {
+            Some(0)
+        } else {
+            None
+        };
+        self.groups.insert(
+            ByteString::from(name),
+            ConsumerGroup::new(last_delivered_id, entries_read),
+        );
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Delivers up to `count` entries the group has not seen yet to
+    /// `consumer`, adding them to the pending entries list unless `no_ack`.
+    /// Returns None if the group doesn't exist.
+    pub fn read_group_new(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        count: usize,
+        no_ack: bool,
+        now_ms: u64,
+    ) -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer, now_ms);
+        let entries = match group.last_delivered_id.next() {
+            Some(start) => self
+                .entries
+                .range(start..)
+                .take(count)
+                .map(|(id, fields)| (*id, fields.clone()))
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        for (id, _) in &entries {
+            group.last_delivered_id = *id;
+            if !no_ack {
+                group.deliver(*id, consumer, now_ms);
+            }
+        }
+        if !entries.is_empty() {
+            group.consumers.get_mut(consumer).unwrap().active_time_ms = Some(now_ms);
+            group.entries_read = match group.last_delivered_id == self.last_id {
+                true => Some(self.entries_added),
+                false => group.entries_read.map(|read| read + entries.len() as u64),
+            };
+        }
+        Some(entries)
+    }
+
+    /// Re-delivers up to `count` of the consumer's pending entries with IDs
+    /// greater than `after`. Entries deleted since delivery have no fields.
+    /// Returns None if the group doesn't exist.
+    pub fn read_group_pending(
+        &mut self,
+        group: &[u8],
+        consumer: &[u8],
+        after: StreamId,
+        count: usize,
+        now_ms: u64,
+    ) -> Option<Vec<(StreamId, Option<Fields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer, now_ms);
+        let Some(start) = after.next() else {
+            return Some(Vec::new());
+        };
+        let ids = group.consumers[consumer]
+            .pending
+            .range(start..)
+            .take(count)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            group.deliver(id, consumer, now_ms);
+            entries.push((id, self.entries.get(&id).cloned()));
+        }
+        Some(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generates_increasing_ids() {
+        let mut stream = Stream::new();
+        assert_eq!(
+            Err(StreamIdError::Zero),
+            stream.next_id(IdSpec::Explicit(StreamId::MIN), 0)
+        );
+        assert_eq!(
+            Ok(StreamId::new(0, 1)),
+            stream.next_id(IdSpec::AutoSequence(0), 0)
+        );
+
+        let id = stream.next_id(IdSpec::Auto, 1000).unwrap();
+        assert_eq!(StreamId::new(1000, 0), id);
+        stream.add(id, vec![("a".into(), "1".into())]);
+
+        // The clock going backwards must not produce a smaller ID.
+        assert_eq!(Ok(StreamId::new(1000, 1)), stream.next_id(IdSpec::Auto, 5));
+        assert_eq!(
+            Ok(StreamId::new(1000, 1)),
+            stream.next_id(IdSpec::AutoSequence(1000), 0)
+        );
+        assert_eq!(
+            Err(StreamIdError::NotIncreasing),
+            stream.next_id(IdSpec::AutoSequence(999), 0)
+        );
+        assert_eq!(
+            Err(StreamIdError::NotIncreasing),
+            stream.next_id(IdSpec::Explicit(StreamId::new(1000, 0)), 0)
+        );
+        assert_eq!(Some(IdSpec::AutoSequence(5)), IdSpec::parse("5-*"));
+        assert_eq!(None, IdSpec::parse("5-x"));
+    }
+
+    #[test]
+    fn test_consumer_group_delivery_and_ack() {
+        let mut stream = Stream::new();
+        for seq in 1..=3 {
+            stream.add(StreamId::new(1, seq), Vec::new());
+        }
+        assert!(stream.create_group(b"g", StreamId::MIN));
+        assert!(!stream.create_group(b"g", StreamId::MIN));
+
+        let delivered = stream.read_group_new(b"g", b"alice", 2, false, 10).unwrap();
+        assert_eq!(2, delivered.len());
+        let rest = stream.read_group_new(b"g", b"bob", 10, false, 10).unwrap();
+        assert_eq!(
+            vec![StreamId::new(1, 3)],
+            rest.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert!(stream
+            .read_group_new(b"g", b"bob", 10, false, 10)
+            .unwrap()
+            .is_empty());
+
+        let pending = stream
+            .read_group_pending(b"g", b"alice", StreamId::MIN, 10, 20)
+            .unwrap();
+        assert_eq!(2, pending.len());
+        let group = stream.group_mut(b"g").unwrap();
+        assert_eq!(2, group.pending[&StreamId::new(1, 1)].delivery_count);
+
+        assert!(group.ack(StreamId::new(1, 1)));
+        assert!(!group.ack(StreamId::new(1, 1)));
+        assert_eq!(1, group.consumers[&b"alice"[..]].pending.len());
+        assert_eq!(Some(1), group.remove_consumer(b"bob"));
+        assert_eq!(1, group.pending.len());
+        assert!(stream
+            .read_group_new(b"missing", b"alice", 1, false, 0)
+            .is_none());
+    }
+}
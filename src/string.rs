@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// The longest strings kept inline, like Redis' embstr encoding.
+pub const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Integers from 0 up to this are shared, like Redis' shared integers.
+pub const SHARED_INTEGERS: i64 = 10_000;
+
+/// The digits of the shared integers, formatted once for the whole process
+/// so that reading a counter or a flag back doesn't allocate.
+static SHARED_INTEGER_DIGITS: LazyLock<Vec<Box<[u8]>>> = LazyLock::new(|| {
+    (0..SHARED_INTEGERS)
+        .map(|n| n.to_string().into_bytes().into_boxed_slice())
+        .collect()
+});
+
+/// A Redis string value.
+///
+/// Strings that spell an integer, like `"42"`, are kept as an `i64` (int),
+/// other strings of up to [`EMBSTR_SIZE_LIMIT`] bytes inline without an
+/// allocation of their own (embstr) and longer ones as a `Vec<u8>` (raw).
+/// Commands that modify strings in place, like SETBIT, make them raw.
+/// Integers below [`SHARED_INTEGERS`] read their digits from a shared table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedisString {
+    Int(i64),
+    EmbStr {
+        length: u8,
+        bytes: [u8; EMBSTR_SIZE_LIMIT],
+    },
+    Raw(Vec<u8>),
+}
+
+/// Parses `bytes` as an integer only if it round-trips exactly, so `"01"`
+/// or `"+1"` are kept as strings.
+fn as_integer(bytes: &[u8]) -> Option<i64> {
+    // i64::MIN takes 20 characters.
+    if bytes.len() > 20 {
+        return None;
+    }
+    let string = std::str::from_utf8(bytes).ok()?;
+    string
+        .parse::<i64>()
+        .ok()
+        .filter(|n| n.to_string() == string)
+}
+
+impl RedisString {
+    /// Creates a string in the most compact encoding that fits `bytes`.
+    pub fn new(bytes: Vec<u8>) -> RedisString {
+        RedisString::compact(&bytes).unwrap_or(RedisString::Raw(bytes))
+    }
+
+    /// Like [`RedisString::new`], but only copies `bytes` to the heap when
+    /// they are too long to be kept inline.
+    pub fn from_slice(bytes: &[u8]) -> RedisString {
+        RedisString::compact(bytes).unwrap_or_else(|| RedisString::Raw(bytes.to_vec()))
+    }
+
+    /// The int or embstr encoding of `bytes`, if one fits.
+    fn compact(bytes: &[u8]) -> Option<RedisString> {
+        if let Some(n) = as_integer(bytes) {
+            return Some(RedisString::Int(n));
+        }
+        if bytes.len() > EMBSTR_SIZE_LIMIT {
+            return None;
+        }
+        let mut inline = [0; EMBSTR_SIZE_LIMIT];
+        inline[..bytes.len()].copy_from_slice(bytes);
+        Some(RedisString::EmbStr {
+            length: bytes.len() as u8,
+            bytes: inline,
+        })
+    }
+
+    pub fn encoding(self: &RedisString) -> &'static str {
+        match self {
+            RedisString::Int(_) => "int",
+            RedisString::EmbStr { .. } => "embstr",
+            RedisString::Raw(_) => "raw",
+        }
+    }
+
+    /// Whether the string is one of the shared integers.
+    pub fn is_shared(self: &RedisString) -> bool {
+        matches!(self, RedisString::Int(n) if (0..SHARED_INTEGERS).contains(n))
+    }
+
+    pub fn len(self: &RedisString) -> usize {
+        match self {
+            RedisString::Int(_) => self.to_bytes().len(),
+            RedisString::EmbStr { length, .. } => *length as usize,
+            RedisString::Raw(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(self: &RedisString) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes of the string, formatted if it is an integer.
+    pub fn to_bytes(self: &RedisString) -> Cow<'_, [u8]> {
+        match self {
+            RedisString::Int(n) if self.is_shared() => {
+                Cow::Borrowed(&SHARED_INTEGER_DIGITS[*n as usize])
+            }
+            RedisString::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            RedisString::EmbStr { length, bytes } => Cow::Borrowed(&bytes[..*length as usize]),
+            RedisString::Raw(bytes) => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// The integer the string spells, without parsing it again when it is
+    /// int encoded.
+    pub fn to_integer(self: &RedisString) -> Option<i64> {
+        match self {
+            RedisString::Int(n) => Some(*n),
+            _ => as_integer(&self.to_bytes()),
+        }
+    }
+
+    /// Turns the string raw, to modify its bytes in place.
+    pub fn make_raw(self: &mut RedisString) -> &mut Vec<u8> {
+        if !matches!(self, RedisString::Raw(_)) {
+            *self = RedisString::Raw(self.to_bytes().into_owned());
+        }
+        match self {
+            RedisString::Raw(bytes) => bytes,
+            _ => unreachable!("the string was just made raw"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::string::{RedisString, SHARED_INTEGERS};
+
+    #[test]
+    fn test_picks_the_compact_encodings() {
+        for (value, encoding) in [
+            ("12345", "int"),
+            ("-9223372036854775808", "int"),
+            ("9223372036854775808", "embstr"),
+            ("007", "embstr"),
+            ("+1", "embstr"),
+            ("", "embstr"),
+            ("hello", "embstr"),
+            (&"x".repeat(44), "embstr"),
+            (&"x".repeat(45), "raw"),
+        ] {
+            let string = RedisString::new(value.as_bytes().to_vec());
+            assert_eq!(encoding, string.encoding(), "{:?}", value);
+            assert_eq!(value.as_bytes(), &*string.to_bytes());
+            assert_eq!(value.len(), string.len());
+            assert_eq!(string, RedisString::from_slice(value.as_bytes()));
+        }
+        assert_eq!(Some(-5), RedisString::new(b"-5".to_vec()).to_integer());
+        assert_eq!(None, RedisString::new(b"5x".to_vec()).to_integer());
+
+        let mut string = RedisString::new(b"10".to_vec());
+        string.make_raw().push(b'0');
+        assert_eq!(RedisString::Raw(b"100".to_vec()), string);
+    }
+
+    #[test]
+    fn test_shares_small_integers() {
+        for (value, shared) in [("0", true), ("9999", true), ("10000", false), ("-1", false)] {
+            let string = RedisString::new(value.as_bytes().to_vec());
+            assert_eq!(shared, string.is_shared(), "{:?}", value);
+            assert_eq!(shared, matches!(string.to_bytes(), Cow::Borrowed(_)));
+            assert_eq!(value.as_bytes(), &*string.to_bytes());
+            assert_eq!(value.len(), string.len());
+        }
+        let last = RedisString::Int(SHARED_INTEGERS - 1);
+        assert_eq!(b"9999", &*last.to_bytes());
+    }
+}
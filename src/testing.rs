@@ -0,0 +1,261 @@
+//! Runs a server inside the test process and talks to it over RESP, so
+//! tests of new commands go through the network like real clients do:
+//!
+//! ```ignore
+//! let server = TestServer::start().await?;
+//! let mut client = server.connect().await?;
+//! assert_eq!(ParserValue::SimpleString("PONG".to_string()), client.command(&["PING"]).await?);
+//! server.stop().await;
+//! ```
+
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::connections::Connections;
+use crate::parser::ParserValue;
+use crate::replication::find_line_end;
+use crate::tokenizer;
+use crate::{Server, ServerConfig, ServerHandle};
+
+/// A server on an ephemeral port of the loopback interface, keeping its
+/// data in memory only.
+pub struct TestServer {
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    pub async fn start() -> anyhow::Result<TestServer> {
+        TestServer::start_with(ServerConfig::default()).await
+    }
+
+    /// Starts a server with `config`, which should keep port 0 so tests
+    /// can run side by side.
+    pub async fn start_with(config: ServerConfig) -> anyhow::Result<TestServer> {
+        Ok(TestServer {
+            handle: Server::spawn(config).await?,
+        })
+    }
+
+    pub fn address(self: &TestServer) -> SocketAddr {
+        self.handle.local_addr()
+    }
+
+    pub fn connections(self: &TestServer) -> &Connections {
+        self.handle.connections()
+    }
+
+    pub async fn connect(self: &TestServer) -> anyhow::Result<TestClient> {
+        TestClient::connect(self.address()).await
+    }
+
+    /// Shuts the server down, returning once its clients are closed.
+    pub async fn stop(self: TestServer) {
+        self.handle.shutdown().await
+    }
+}
+
+/// A minimal RESP client: sends commands as arrays of bulk strings and
+/// reads replies back as [`ParserValue`]s.
+pub struct TestClient {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl TestClient {
+    pub async fn connect(address: SocketAddr) -> anyhow::Result<TestClient> {
+        Ok(TestClient {
+            stream: TcpStream::connect(address).await?,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Sends a command without waiting for its reply, to pipeline commands
+    /// or to send ones that block.
+    pub async fn send(self: &mut TestClient, arguments: &[&str]) -> anyhow::Result<()> {
+        let command = ParserValue::Array(
+            arguments
+                .iter()
+                .map(|argument| ParserValue::BulkString((*argument).into()))
+                .collect(),
+        );
+        let bytes = tokenizer::serialize_tokens_to_bytes(&command.to_tokens())?;
+        self.stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Sends bytes as they are, to split commands across writes or to send
+    /// malformed ones.
+    pub async fn send_raw(self: &mut TestClient, bytes: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Reads the next reply or pushed message.
+    pub async fn read_reply(self: &mut TestClient) -> anyhow::Result<ParserValue> {
+        loop {
+            if let Some((reply, length)) = parse_reply(&self.buffer, 0)? {
+                self.buffer.drain(..length);
+                return Ok(reply);
+            }
+            let mut chunk = [0; 4096];
+            let read = self.stream.read(&mut chunk).await?;
+            if read == 0 {
+                bail!("the server closed the connection");
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Waits for the server to close the connection, failing if anything
+    /// else arrives first.
+    pub async fn closed(self: &mut TestClient) -> anyhow::Result<()> {
+        let mut chunk = [0; 4096];
+        match self.stream.read(&mut chunk).await? {
+            0 if self.buffer.is_empty() => Ok(()),
+            0 => bail!("the connection closed after {:?}", self.buffer),
+            read => bail!("received {:?}", String::from_utf8_lossy(&chunk[..read])),
+        }
+    }
+
+    /// Sends a command and reads its reply.
+    pub async fn command(self: &mut TestClient, arguments: &[&str]) -> anyhow::Result<ParserValue> {
+        self.send(arguments).await?;
+        self.read_reply().await
+    }
+}
+
+/// Parses the reply starting at `start` of `buffer`, returning it with the
+/// offset right after it, or `None` if the buffer only holds part of it.
+fn parse_reply(buffer: &[u8], start: usize) -> anyhow::Result<Option<(ParserValue, usize)>> {
+    let Some(end) = find_line_end(buffer, start) else {
+        return Ok(None);
+    };
+    let line = String::from_utf8_lossy(&buffer[start + 1..end]).into_owned();
+    let next = end + 2;
+    let length = || {
+        line.parse::<i64>()
+            .map_err(|_| anyhow!("invalid length {:?}", line))
+    };
+    let reply = match buffer[start] {
+        b'+' => ParserValue::SimpleString(line),
+        b'-' => ParserValue::Error(line),
+        b':' => ParserValue::Integer(length()?),
+        b'$' => match usize::try_from(length()?) {
+            Err(_) => ParserValue::NullBulkString,
+            Ok(length) => {
+                if buffer.len() < next + length + 2 {
+                    return Ok(None);
+                }
+                let value = buffer[next..next + length].into();
+                return Ok(Some((ParserValue::BulkString(value), next + length + 2)));
+            }
+        },
+        b'*' => match usize::try_from(length()?) {
+            Err(_) => ParserValue::NullArray,
+            Ok(count) => {
+                let mut values = Vec::with_capacity(count);
+                let mut position = next;
+                for _ in 0..count {
+                    let Some((value, after)) = parse_reply(buffer, position)? else {
+                        return Ok(None);
+                    };
+                    values.push(value);
+                    position = after;
+                }
+                return Ok(Some((ParserValue::Array(values), position)));
+            }
+        },
+        byte => bail!("unexpected reply type {:?}", byte as char),
+    };
+    Ok(Some((reply, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::parser::ParserValue;
+    use crate::testing::{parse_reply, TestServer};
+
+    #[test]
+    fn test_parse_reply() {
+        let buffer = b"*3\r\n$3\r\nfoo\r\n:-1\r\n*-1\r\n$-1\r\n";
+        assert_eq!(
+            Some((
+                ParserValue::Array(vec![
+                    ParserValue::BulkString("foo".into()),
+                    ParserValue::Integer(-1),
+                    ParserValue::NullArray,
+                ]),
+                23
+            )),
+            parse_reply(buffer, 0).unwrap()
+        );
+        assert_eq!(
+            Some((ParserValue::NullBulkString, 28)),
+            parse_reply(buffer, 23).unwrap()
+        );
+        assert_eq!(None, parse_reply(&buffer[..10], 0).unwrap());
+        assert!(parse_reply(b"?\r\n", 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_and_client() {
+        let server = TestServer::start().await.unwrap();
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            ParserValue::SimpleString("OK".to_string()),
+            client.command(&["SET", "k", "v"]).await.unwrap()
+        );
+
+        // Replies to pipelined commands come back in order.
+        client.send(&["GET", "k"]).await.unwrap();
+        client.send(&["GET", "missing"]).await.unwrap();
+        assert_eq!(
+            ParserValue::BulkString("v".into()),
+            client.read_reply().await.unwrap()
+        );
+        assert_eq!(
+            ParserValue::NullBulkString,
+            client.read_reply().await.unwrap()
+        );
+        assert!(matches!(
+            client.command(&["NOSUCHCOMMAND"]).await.unwrap(),
+            ParserValue::Error(err) if err.starts_with("ERR unknown command")
+        ));
+
+        server.stop().await;
+        assert!(client.command(&["PING"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_binary_values_over_the_network() {
+        let server = TestServer::start().await.unwrap();
+        let value = b"\x00\xff\r\n\xc3(";
+        let mut stream = tokio::net::TcpStream::connect(server.address())
+            .await
+            .unwrap();
+        let mut request = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$6\r\n".to_vec();
+        request.extend_from_slice(value);
+        request.extend_from_slice(b"\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        stream.write_all(&request).await.unwrap();
+
+        let mut expected = b"+OK\r\n$6\r\n".to_vec();
+        expected.extend_from_slice(value);
+        expected.extend_from_slice(b"\r\n");
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(expected, reply);
+
+        // The test client sends and reads values the same way.
+        let mut client = server.connect().await.unwrap();
+        assert_eq!(
+            ParserValue::BulkString(value[..].into()),
+            client.command(&["GET", "k"]).await.unwrap()
+        );
+        server.stop().await;
+    }
+}
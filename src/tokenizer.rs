@@ -1,6 +1,7 @@
 use std::str;
 
 use anyhow::anyhow;
+use tracing::trace;
 
 use crate::tokenizer::Token::Separator;
 
@@ -23,6 +24,9 @@ pub enum Token {
     String(String),
     Number(i64),
     Separator,
+    /// Raw bytes written out as they are, for payloads like RDB files that
+    /// aren't valid UTF-8.
+    Bytes(Vec<u8>),
 }
 
 impl Token {
@@ -64,14 +68,14 @@ impl Token {
 
     pub fn to_i64(self: &Token) -> Option<i64> {
         match self {
-            Token::Number(n) => Some(n.clone()),
+            Token::Number(n) => Some(*n),
             _ => None,
         }
     }
 
     pub fn to_usize(self: &Token) -> Option<usize> {
         match self {
-            Token::Number(n) => n.clone().try_into().ok(),
+            Token::Number(n) => (*n).try_into().ok(),
             _ => None,
         }
     }
@@ -141,42 +145,42 @@ pub fn parse_resp_tokens_from_str(input: &str) -> anyhow::Result<Vec<Token>> {
     Ok(tokens)
 }
 
-pub fn serialize_tokens(tokens: &Vec<Token>) -> anyhow::Result<String> {
-    if tokens.len() < 1 {
+pub fn serialize_tokens(tokens: &[Token]) -> anyhow::Result<String> {
+    Ok(String::from_utf8(serialize_tokens_to_bytes(tokens)?)?)
+}
+
+pub fn serialize_tokens_to_bytes(tokens: &[Token]) -> anyhow::Result<Vec<u8>> {
+    if tokens.is_empty() {
         return Err(anyhow!("cannot serialize empty vector of tokens"));
     }
 
-    let mut chars: Vec<char> = Vec::new();
+    let mut bytes: Vec<u8> = Vec::new();
     for token in tokens {
         match token {
-            Token::Number(n) => chars.append(&mut n.to_string().chars().collect::<Vec<char>>()),
-            Token::Asterisk => chars.push('*'),
-            Token::Dollar => chars.push('$'),
-            Token::String(s) => chars.append(&mut s.as_str().chars().collect::<Vec<char>>()),
-            Token::Plus => chars.push('+'),
-            Separator => {
-                chars.push('\r');
-                chars.push('\n');
-            }
-            Token::GreaterThan => chars.push('>'),
-            Token::Tilda => chars.push('~'),
-            Token::Percentage => chars.push('%'),
-            Token::Equals => chars.push('='),
-            Token::Exclamation => chars.push('!'),
-            Token::LeftParenthesis => chars.push('('),
-            Token::Comma => chars.push(','),
-            Token::PoundSign => chars.push('#'),
-            Token::Underscore => chars.push('_'),
-            Token::Colon => chars.push(':'),
-            Token::Hyphen => chars.push('-'),
+            Token::Number(n) => bytes.extend_from_slice(n.to_string().as_bytes()),
+            Token::Asterisk => bytes.push(b'*'),
+            Token::Dollar => bytes.push(b'$'),
+            Token::String(s) => bytes.extend_from_slice(s.as_bytes()),
+            Token::Plus => bytes.push(b'+'),
+            Separator => bytes.extend_from_slice(b"\r\n"),
+            Token::GreaterThan => bytes.push(b'>'),
+            Token::Tilda => bytes.push(b'~'),
+            Token::Percentage => bytes.push(b'%'),
+            Token::Equals => bytes.push(b'='),
+            Token::Exclamation => bytes.push(b'!'),
+            Token::LeftParenthesis => bytes.push(b'('),
+            Token::Comma => bytes.push(b','),
+            Token::PoundSign => bytes.push(b'#'),
+            Token::Underscore => bytes.push(b'_'),
+            Token::Colon => bytes.push(b':'),
+            Token::Hyphen => bytes.push(b'-'),
+            Token::Bytes(b) => bytes.extend_from_slice(b),
         }
     }
 
-    let s = chars.into_iter().collect::<String>();
-
-    eprintln!("Serialized Tokens: {:?}", s);
+    trace!(bytes = ?String::from_utf8_lossy(&bytes), "serialized tokens");
 
-    return Ok(s);
+    Ok(bytes)
 }
 
 #[cfg(test)]
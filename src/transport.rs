@@ -0,0 +1,41 @@
+//! The sockets client connections are served over. The server reads
+//! commands and writes replies through [`Transport`], so the same code
+//! serves tokio's sockets and, with the `io-uring` feature on Linux,
+//! sockets driven by io_uring.
+
+use std::io;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub(crate) mod uring;
+
+/// A connection to a client.
+pub(crate) trait Transport {
+    /// Reads the next bytes the client sent into `buffer`, returning how
+    /// many, or 0 once the client closed the connection. Connections wait
+    /// for other events at the same time, so a read that is cancelled must
+    /// not lose any bytes.
+    async fn read_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize>;
+
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Tells the client nothing more will be sent.
+    async fn shutdown(&mut self) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    async fn read_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize> {
+        AsyncReadExt::read_buf(self, buffer).await
+    }
+
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(self, bytes).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        AsyncWriteExt::shutdown(self).await
+    }
+}
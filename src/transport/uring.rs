@@ -0,0 +1,271 @@
+//! Serves connections with io_uring, through tokio-uring. Its runtime runs
+//! on a thread of its own next to the tokio runtime of the rest of the
+//! server, and connections talk to the data core over the same channels.
+
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use socket2::SockRef;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+use crate::connections::Connections;
+use crate::data_core::Command;
+use crate::server::{configure_socket, log_panic, process_request, ConnectionConfig};
+use crate::transport::Transport;
+
+/// How many bytes a read asks for at most.
+const READ_SIZE: usize = 16 * 1024;
+
+/// A client connection whose reads and writes are submitted to io_uring.
+pub(crate) struct UringStream {
+    stream: Rc<tokio_uring::net::TcpStream>,
+    reads: mpsc::Receiver<io::Result<Vec<u8>>>,
+}
+
+impl UringStream {
+    /// Starts reading from `stream`. A read submitted to io_uring can't be
+    /// cancelled without losing what it read, so a task keeps one in flight
+    /// and passes on what it read through a channel, which can be.
+    fn new(stream: tokio_uring::net::TcpStream) -> UringStream {
+        let stream = Rc::new(stream);
+        let (reads_tx, reads) = mpsc::channel(1);
+        let reader = stream.clone();
+        tokio_uring::spawn(async move {
+            loop {
+                let (read, bytes) = reader.read(Vec::with_capacity(READ_SIZE)).await;
+                let closed = !matches!(read, Ok(length) if length > 0);
+                if reads_tx.send(read.map(|_| bytes)).await.is_err() || closed {
+                    break;
+                }
+            }
+        });
+        UringStream { stream, reads }
+    }
+}
+
+impl Transport for UringStream {
+    async fn read_buf(&mut self, buffer: &mut BytesMut) -> io::Result<usize> {
+        match self.reads.recv().await {
+            Some(Ok(bytes)) => {
+                buffer.extend_from_slice(&bytes);
+                Ok(bytes.len())
+            }
+            Some(Err(err)) => Err(err),
+            None => Ok(0),
+        }
+    }
+
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes.to_vec()).await.0
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+}
+
+impl Drop for UringStream {
+    /// Ends the read in flight, so the reading task lets go of the socket
+    /// and it gets closed.
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Whether io_uring can be used. Kernels may not have it, and container
+/// runtimes often forbid it.
+pub(crate) fn is_supported() -> bool {
+    match tokio_uring::uring_builder().build(8) {
+        Ok(_) => true,
+        Err(err) => {
+            warn!("io_uring is unavailable, serving with epoll: {}", err);
+            false
+        }
+    }
+}
+
+/// Like [`serve`](crate::server::serve), but serves the connections of
+/// `listeners` on an io_uring runtime.
+pub(crate) async fn serve(
+    listeners: Vec<TcpListener>,
+    core_tx: Sender<Command>,
+    config: Arc<ConnectionConfig>,
+    connections: Connections,
+    shutdown: impl Future<Output = ()>,
+) {
+    let listeners = match listeners
+        .into_iter()
+        .map(|listener| listener.into_std())
+        .collect::<io::Result<Vec<_>>>()
+    {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!("cannot hand the listeners over to io_uring: {}", err);
+            return;
+        }
+    };
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (stopped_tx, stopped_rx) = oneshot::channel::<()>();
+    let spawned = std::thread::Builder::new()
+        .name("io-uring".to_string())
+        .spawn(move || {
+            tokio_uring::start(accept_connections(
+                listeners,
+                core_tx,
+                config,
+                connections,
+                shutdown_rx,
+            ));
+            let _ = stopped_tx.send(());
+        });
+    if let Err(err) = spawned {
+        error!("cannot start the io_uring thread: {}", err);
+        return;
+    }
+    shutdown.await;
+    let _ = shutdown_tx.send(true);
+    let _ = stopped_rx.await;
+}
+
+/// Accepts connections and serves them until `shutdown` changes, then
+/// waits for the clients to close.
+async fn accept_connections(
+    listeners: Vec<std::net::TcpListener>,
+    core_tx: Sender<Command>,
+    config: Arc<ConnectionConfig>,
+    connections: Connections,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(1);
+    let mut listening = Vec::new();
+    let mut acceptors = JoinSet::new();
+    for listener in listeners {
+        let listener = Rc::new(tokio_uring::net::TcpListener::from_std(listener));
+        listening.push(listener.clone());
+        let accepted_tx = accepted_tx.clone();
+        acceptors.spawn_local(async move {
+            while accepted_tx.send(listener.accept().await).await.is_ok() {}
+        });
+    }
+
+    let mut clients = JoinSet::new();
+    loop {
+        let (stream, address) = tokio::select! {
+            Some(accepted) = accepted_rx.recv() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("cannot accept connection: {}", err);
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        // SAFETY: the stream owns the descriptor and outlives this borrow.
+        let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+        if let Err(err) = configure_socket(&fd, &config) {
+            warn!("cannot set socket options: {}", err);
+        }
+        let core_tx = core_tx.clone();
+        let shutdown = shutdown.clone();
+        let config = config.clone();
+        let connections = connections.clone();
+        clients.spawn_local(async move {
+            let socket = UringStream::new(stream);
+            process_request(
+                socket,
+                Some(address),
+                &connections,
+                &core_tx,
+                &config,
+                shutdown,
+            )
+            .await;
+        });
+        while let Some(served) = clients.try_join_next() {
+            log_panic(served);
+        }
+    }
+
+    info!(
+        clients = clients.len(),
+        "shutting down, waiting for clients"
+    );
+    // Accepts in flight fail once their listener is shut down, and then
+    // their tasks close it.
+    drop(accepted_rx);
+    for listener in listening.drain(..) {
+        // SAFETY: the listener owns the descriptor and outlives this borrow.
+        let fd = unsafe { BorrowedFd::borrow_raw(listener.as_raw_fd()) };
+        let _ = SockRef::from(&fd).shutdown(Shutdown::Read);
+    }
+    acceptors.join_all().await;
+    while let Some(served) = clients.join_next().await {
+        log_panic(served);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::{mpsc, oneshot};
+
+    use crate::connections::Connections;
+    use crate::data_core::{Command, DataCore, ReplicationRole};
+    use crate::server::{bind, ConnectionConfig};
+    use crate::transport::uring::{is_supported, serve};
+
+    #[tokio::test]
+    async fn test_serves_with_io_uring() {
+        if !is_supported() {
+            return;
+        }
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &ConnectionConfig::default()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (core_tx, core_rx) = mpsc::channel::<Command>(32);
+        tokio::spawn(async move {
+            let mut data_core = DataCore::new(core_rx, ReplicationRole::Master, None, None);
+            data_core.process_command().await;
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            vec![listener],
+            core_tx,
+            Arc::default(),
+            Connections::default(),
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        // A command split across writes and a pipelined one.
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk")
+            .await
+            .unwrap();
+        client
+            .write_all(b"\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(b"+OK\r\n$1\r\nv\r\n", &reply);
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap();
+        assert_eq!(0, client.read(&mut [0; 16]).await.unwrap());
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+}